@@ -0,0 +1,79 @@
+//! The evaluation of profiles with many intervals, like the ones made from soundings, with and
+//! without remembering the last interval.
+
+use atm_refraction::air::atmosphere::vertical_profile::{
+    FunctionDef, IntervalHint, VerticalProfile, VerticalProfileBuilder,
+};
+use atm_refraction::air::{Atmosphere, AtmosphereDefBuilder};
+use atm_refraction::{EarthShape, Environment, RefractiveIndexModel};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+/// The spacing of the points of the sounding in meters
+const SOUNDING_STEP: f64 = 10.0;
+/// The number of the points of the sounding
+const SOUNDING_POINTS: usize = 2000;
+
+/// Returns the temperatures of a sounding: a standard lapse rate with small fluctuations
+fn sounding() -> FunctionDef {
+    let points = (0..SOUNDING_POINTS)
+        .map(|i| {
+            let h = i as f64 * SOUNDING_STEP;
+            (h, 288.0 - 0.0065 * h + 0.2 * (h / 37.0).sin())
+        })
+        .collect();
+    FunctionDef::Table {
+        points,
+        extrapolation: Default::default(),
+    }
+}
+
+fn sounding_profile() -> VerticalProfile {
+    VerticalProfileBuilder::new(sounding())
+        .build()
+        .expect("a table should make a profile")
+}
+
+fn sounding_env() -> Environment {
+    let def = AtmosphereDefBuilder::new(sounding())
+        .build()
+        .expect("a table should make an atmosphere");
+    Environment {
+        shape: EarthShape::Spherical {
+            radius: 6_371_000.0,
+        },
+        atmosphere: Atmosphere::try_from_def(def).expect("the sounding should be valid"),
+        wavelength: 530e-9,
+        index_model: RefractiveIndexModel::Optical,
+        top_of_atmosphere: None,
+    }
+}
+
+fn profile_sweep(c: &mut Criterion) {
+    let profile = sounding_profile();
+    // the altitudes of a ray climbing slowly through the sounding
+    let hs: Vec<f64> = (0..10_000).map(|i| i as f64 * 1.5).collect();
+
+    let mut group = c.benchmark_group("profile sweep");
+    group.bench_function("binary search", |b| {
+        b.iter(|| hs.iter().map(|&h| profile.eval(black_box(h))).sum::<f64>())
+    });
+    group.bench_function("interval hint", |b| {
+        b.iter(|| {
+            let hint = IntervalHint::default();
+            hs.iter()
+                .map(|&h| profile.eval_with_hint(black_box(h), &hint))
+                .sum::<f64>()
+        })
+    });
+    group.finish();
+}
+
+fn ray_through_sounding(c: &mut Criterion) {
+    let env = sounding_env();
+    c.bench_function("ray through a sounding", |b| {
+        b.iter(|| env.cast_ray(10.0, black_box(0.01), false).h_at_dist(100e3))
+    });
+}
+
+criterion_group!(benches, profile_sweep, ray_through_sounding);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+//! The refractive index over a grid of altitudes, evaluated altitude by altitude and layer by
+//! layer.
+
+use atm_refraction::air::us76_atmosphere;
+use atm_refraction::{EarthShape, Environment, RefractiveIndexModel};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+fn n_grid(c: &mut Criterion) {
+    let env = Environment {
+        shape: EarthShape::Spherical {
+            radius: 6_371_000.0,
+        },
+        atmosphere: us76_atmosphere(),
+        wavelength: 530e-9,
+        index_model: RefractiveIndexModel::Optical,
+        top_of_atmosphere: None,
+    };
+    // a grid from the ground up to 80 km, crossing all the layers of the atmosphere
+    let hs: Vec<f64> = (0..10_000).map(|i| i as f64 * 8.0).collect();
+
+    let mut group = c.benchmark_group("n over a grid");
+    group.bench_function("n", |b| {
+        b.iter(|| {
+            black_box(&hs)
+                .iter()
+                .map(|&h| env.n(h))
+                .collect::<Vec<f64>>()
+        })
+    });
+    group.bench_function("n_many", |b| b.iter(|| env.n_many(black_box(&hs))));
+    group.finish();
+}
+
+criterion_group!(benches, n_grid);
+criterion_main!(benches);
@@ -0,0 +1,135 @@
+use super::{
+    pressure_profile::PressureProfile,
+    vertical_profile::{FunctionDef, VerticalProfileBuilder},
+    Atmosphere, AtmosphereHints, HumidityKind, GAS_CONSTANT,
+};
+
+impl Atmosphere {
+    /// Returns an atmosphere in between `a` and `b`, with the temperature and the humidity
+    /// equal to `(1 - weight)` times the ones of `a` plus `weight` times the ones of `b` at
+    /// every altitude, e.g. for interpolating between two soundings in time.
+    ///
+    /// The pressure is calculated anew from the temperatures, starting from the blended pressure
+    /// at the altitude 0. The gravity and the molar mass are blended the same way; the altitudes
+    /// are treated as geopotential ones if they are in `a`. If the humidities of the atmospheres
+    /// are given by different quantities, the relative humidities are blended. The profile of the
+    /// optical turbulence is taken from `a`.
+    pub fn blend(a: &Atmosphere, b: &Atmosphere, weight: f64) -> Atmosphere {
+        let mix = |value_a: f64, value_b: f64| (1.0 - weight) * value_a + weight * value_b;
+        let temperature = a.temperature.blend(&b.temperature, weight);
+        let (humidity, humidity_kind) = if a.humidity_kind == b.humidity_kind {
+            (a.humidity.blend(&b.humidity, weight), a.humidity_kind)
+        } else {
+            let (a, b) = (a.clone(), b.clone());
+            let function = FunctionDef::custom(move |z| {
+                let (hints_a, hints_b) = (AtmosphereHints::default(), AtmosphereHints::default());
+                (1.0 - weight) * a.relative_humidity_at(z, &hints_a)
+                    + weight * b.relative_humidity_at(z, &hints_b)
+            });
+            let humidity = VerticalProfileBuilder::new(function)
+                .build()
+                .expect("a custom function should make a profile");
+            (humidity, HumidityKind::Relative)
+        };
+        let gravity = mix(a.gravity, b.gravity);
+        let molar_mass = mix(a.molar_mass, b.molar_mass);
+
+        let mu_g_r = molar_mass * gravity / GAS_CONSTANT;
+        let mut pressure = PressureProfile::from_temperature_profile(
+            &temperature,
+            mix(a.pressure.eval(0.0), b.pressure.eval(0.0)),
+            0.0,
+            mu_g_r,
+        );
+        let virtual_temperature = a.virtual_temperature || b.virtual_temperature;
+        if virtual_temperature {
+            let altitudes: Vec<f64> = temperature
+                .internals()
+                .0
+                .iter()
+                .chain(humidity.internals().0)
+                .copied()
+                .collect();
+            pressure = pressure.with_vapor_correction(
+                &temperature,
+                |z, t, p| humidity_kind.vapor_pressure(humidity.eval(z), t, p),
+                &altitudes,
+                0.0,
+                mu_g_r,
+            );
+        }
+
+        Atmosphere {
+            pressure,
+            temperature,
+            humidity,
+            gravity,
+            molar_mass,
+            geopotential_radius: a.geopotential_radius,
+            virtual_temperature,
+            humidity_kind,
+            turbulence: a.turbulence.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::{AtmosphereDef, AtmosphereDefBuilder};
+
+    #[test]
+    fn test_blend() {
+        let summer = Atmosphere::try_from_def(AtmosphereDef::midlatitude_summer()).unwrap();
+        let winter = Atmosphere::try_from_def(AtmosphereDef::midlatitude_winter()).unwrap();
+        assert_eq!(
+            Atmosphere::blend(&summer, &winter, 0.0).temperature(3500.0),
+            summer.temperature(3500.0)
+        );
+
+        let blended = Atmosphere::blend(&summer, &winter, 0.25);
+        for h in [0.0, 500.0, 1000.0, 7300.0, 30e3] {
+            let temperature = 0.75 * summer.temperature(h) + 0.25 * winter.temperature(h);
+            assert!((blended.temperature(h) - temperature).abs() < 1e-9);
+            let humidity = 0.75 * summer.humidity(h) + 0.25 * winter.humidity(h);
+            assert!((blended.humidity(h) - humidity).abs() < 1e-9);
+        }
+        let pressure = 0.75 * summer.pressure(0.0) + 0.25 * winter.pressure(0.0);
+        assert!((blended.pressure(0.0) - pressure).abs() < 1e-6);
+        let ratio = blended.pressure(5e3) / summer.pressure(5e3);
+        assert!(ratio > 0.98 && ratio < 1.0);
+
+        // the hydrostatic equation holds across the layer boundaries of the blended profiles
+        let epsilon = 0.01;
+        for h in [999.0, 1000.0, 12e3] {
+            let numerical =
+                (blended.pressure(h + epsilon) - blended.pressure(h - epsilon)) / 2.0 / epsilon;
+            assert!((blended.dpressure(h) - numerical).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_blend_humidity_kinds() {
+        let builder = AtmosphereDefBuilder::new(FunctionDef::Linear { gradient: -0.0065 })
+            .with_temperature(0.0, 288.0);
+        let relative = builder
+            .clone()
+            .with_first_humidity_function(FunctionDef::Linear { gradient: 0.0 })
+            .with_humidity(0.0, 50.0)
+            .build()
+            .unwrap();
+        let dew_point = builder
+            .with_first_humidity_function(FunctionDef::Linear { gradient: -0.002 })
+            .with_humidity(0.0, 280.0)
+            .with_humidity_kind(HumidityKind::DewPoint)
+            .build()
+            .unwrap();
+        let relative = Atmosphere::try_from_def(relative).unwrap();
+        let dew_point = Atmosphere::try_from_def(dew_point).unwrap();
+        let blended = Atmosphere::blend(&relative, &dew_point, 0.5);
+        for h in [0.0, 1000.0] {
+            let humidity = 0.5 * relative.humidity(h) + 0.5 * dew_point.humidity(h);
+            assert!((blended.humidity(h) - humidity).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use super::{
+    validation::AtmosphereDefError, vertical_profile::FunctionDef, AtmosphereDef,
+    FunctionDefWithAlt, HumidityFixedPoint, HumidityKind, PressureFixedPoint,
+    TemperatureFixedPoint,
+};
+
+/// A builder for atmosphere definitions, allowing everything that can be given in a serialized
+/// definition.
+///
+/// The pressure, the gravity and the molar mass default to the values of the US-1976 standard,
+/// and the relative humidity defaults to 0 everywhere.
+#[derive(Clone, Debug)]
+pub struct AtmosphereDefBuilder {
+    def: AtmosphereDef,
+}
+
+impl AtmosphereDefBuilder {
+    /// Starts a definition with the given temperature function, used below the altitudes of the
+    /// functions added later
+    pub fn new(first_temperature_function: FunctionDef) -> Self {
+        Self {
+            def: AtmosphereDef {
+                first_temperature_function,
+                next_functions: vec![],
+                temperature_fixed_point: None,
+                ..AtmosphereDef::us_76()
+            },
+        }
+    }
+
+    /// Sets the pressure in Pa at the given altitude
+    pub fn with_pressure(mut self, altitude: f64, pressure: f64) -> Self {
+        self.def.pressure = PressureFixedPoint { altitude, pressure };
+        self
+    }
+
+    /// Adds a temperature function used from the given altitude up to the next function
+    pub fn with_temperature_function(mut self, altitude: f64, function: FunctionDef) -> Self {
+        self.def
+            .next_functions
+            .push(FunctionDefWithAlt { altitude, function });
+        self
+    }
+
+    /// Sets the temperature in K at the given altitude, which fixes the linear functions
+    pub fn with_temperature(mut self, altitude: f64, temperature: f64) -> Self {
+        self.def.temperature_fixed_point = Some(TemperatureFixedPoint {
+            altitude,
+            temperature,
+        });
+        self
+    }
+
+    /// Sets the humidity function used below the altitudes of the other humidity functions,
+    /// removing the fixed humidity
+    pub fn with_first_humidity_function(mut self, function: FunctionDef) -> Self {
+        self.def.first_humidity_function = function;
+        self.def.humidity_fixed_point = None;
+        self
+    }
+
+    /// Adds a humidity function used from the given altitude up to the next function
+    pub fn with_humidity_function(mut self, altitude: f64, function: FunctionDef) -> Self {
+        self.def
+            .next_humidity_functions
+            .push(FunctionDefWithAlt { altitude, function });
+        self
+    }
+
+    /// Sets the humidity at the given altitude, which fixes the linear functions; it is the
+    /// relative humidity in percent, unless set otherwise with `with_humidity_kind`
+    pub fn with_humidity(mut self, altitude: f64, humidity: f64) -> Self {
+        self.def.humidity_fixed_point = Some(HumidityFixedPoint { altitude, humidity });
+        self
+    }
+
+    /// Sets the quantity given by the humidity functions; see `AtmosphereDef::with_humidity_kind`
+    pub fn with_humidity_kind(mut self, humidity_kind: HumidityKind) -> Self {
+        self.def = self.def.with_humidity_kind(humidity_kind);
+        self
+    }
+
+    /// Sets the gravitational acceleration in m/s^2
+    pub fn with_gravity(mut self, gravity: f64) -> Self {
+        self.def = self.def.with_gravity(gravity);
+        self
+    }
+
+    /// Sets the molar mass of the gas in kg/mol
+    pub fn with_molar_mass(mut self, molar_mass: f64) -> Self {
+        self.def = self.def.with_molar_mass(molar_mass);
+        self
+    }
+
+    /// Makes the gravity decrease with altitude; see `AtmosphereDef::with_variable_gravity`
+    pub fn with_variable_gravity(mut self, radius: f64) -> Self {
+        self.def = self.def.with_variable_gravity(radius);
+        self
+    }
+
+    /// Sets whether the pressure accounts for the water vapor; see
+    /// `AtmosphereDef::with_virtual_temperature`
+    pub fn with_virtual_temperature(mut self, virtual_temperature: bool) -> Self {
+        self.def = self.def.with_virtual_temperature(virtual_temperature);
+        self
+    }
+
+    /// Returns the definition, if it is valid
+    pub fn build(self) -> Result<AtmosphereDef, AtmosphereDefError> {
+        self.def.validate()?;
+        Ok(self.def)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::{atmosphere::validation::Quantity, Atmosphere};
+
+    #[test]
+    fn test_builder() {
+        let def = AtmosphereDefBuilder::new(FunctionDef::Linear { gradient: -0.0065 })
+            .with_temperature_function(11e3, FunctionDef::Linear { gradient: 0.0 })
+            .with_temperature(0.0, 288.0)
+            .with_pressure(0.0, 101325.0)
+            .build()
+            .unwrap();
+        let built = Atmosphere::try_from_def(def).unwrap();
+        let standard = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        for h in [0.0, 5e3, 15e3] {
+            assert!((built.temperature(h) - standard.temperature(h)).abs() < 1e-9);
+            assert!((built.pressure(h) / standard.pressure(h) - 1.0).abs() < 1e-9);
+        }
+        assert_eq!(built.humidity(0.0), 0.0);
+
+        let humid = AtmosphereDefBuilder::new(FunctionDef::Linear { gradient: -0.0065 })
+            .with_temperature(0.0, 288.0)
+            .with_first_humidity_function(FunctionDef::Linear { gradient: -0.01 })
+            .with_humidity_function(1e3, FunctionDef::Linear { gradient: 0.0 })
+            .with_humidity(0.0, 80.0)
+            .build()
+            .unwrap();
+        let humid = Atmosphere::try_from_def(humid).unwrap();
+        assert!((humid.humidity(500.0) - 75.0).abs() < 1e-9);
+        assert!((humid.humidity(2e3) - 70.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let result = AtmosphereDefBuilder::new(FunctionDef::Linear { gradient: -0.0065 }).build();
+        assert!(matches!(
+            result,
+            Err(AtmosphereDefError::Profile {
+                quantity: Quantity::Temperature,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+//! Comparison of atmospheres, e.g. for checking that an imported sounding matches a fitted
+//! analytic profile.
+
+use super::Atmosphere;
+use crate::air::air_index;
+
+/// The differences between two atmospheres over a range of altitudes
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct AtmosphereDifference {
+    /// The largest absolute difference of the temperatures, in kelvins
+    pub max_temperature: f64,
+    /// The root mean square of the differences of the temperatures, in kelvins
+    pub rms_temperature: f64,
+    /// The largest absolute difference of the pressures, in pascals
+    pub max_pressure: f64,
+    /// The root mean square of the differences of the pressures, in pascals
+    pub rms_pressure: f64,
+    /// The largest absolute difference of the refractive indices
+    pub max_n: f64,
+    /// The root mean square of the differences of the refractive indices
+    pub rms_n: f64,
+    /// The altitude (in meters) at which the refractive indices differ the most
+    pub max_n_altitude: f64,
+}
+
+impl Atmosphere {
+    /// Compares the atmosphere with `other` at altitudes spaced regularly by `step` meters
+    /// between the ends of `range`. The refractive indices are calculated for visible light of
+    /// the given wavelength (in meters).
+    pub fn compare(
+        &self,
+        other: &Atmosphere,
+        range: (f64, f64),
+        step: f64,
+        wavelength: f64,
+    ) -> AtmosphereDifference {
+        let n_at = |atmosphere: &Atmosphere, h: f64| {
+            air_index(
+                wavelength,
+                atmosphere.pressure(h),
+                atmosphere.temperature(h),
+                atmosphere.humidity(h),
+            )
+        };
+        let (start, end) = range;
+        let n = ((end - start) / step).ceil().max(1.0) as usize;
+        let mut result = AtmosphereDifference {
+            max_temperature: 0.0,
+            rms_temperature: 0.0,
+            max_pressure: 0.0,
+            rms_pressure: 0.0,
+            max_n: 0.0,
+            rms_n: 0.0,
+            max_n_altitude: start,
+        };
+        for i in 0..=n {
+            let h = start + (end - start) * i as f64 / n as f64;
+            let temperature = (self.temperature(h) - other.temperature(h)).abs();
+            let pressure = (self.pressure(h) - other.pressure(h)).abs();
+            let index = (n_at(self, h) - n_at(other, h)).abs();
+            result.max_temperature = result.max_temperature.max(temperature);
+            result.max_pressure = result.max_pressure.max(pressure);
+            if index > result.max_n {
+                result.max_n = index;
+                result.max_n_altitude = h;
+            }
+            result.rms_temperature += temperature * temperature;
+            result.rms_pressure += pressure * pressure;
+            result.rms_n += index * index;
+        }
+        let count = (n + 1) as f64;
+        result.rms_temperature = (result.rms_temperature / count).sqrt();
+        result.rms_pressure = (result.rms_pressure / count).sqrt();
+        result.rms_n = (result.rms_n / count).sqrt();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::AtmosphereDef;
+
+    #[test]
+    fn test_compare() {
+        let standard = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        let same = standard.compare(&standard, (0.0, 10e3), 100.0, 530e-9);
+        assert_eq!(same.max_temperature, 0.0);
+        assert_eq!(same.rms_n, 0.0);
+
+        let warmer =
+            Atmosphere::try_from_def(AtmosphereDef::us_76().with_temperature_offset(2.0)).unwrap();
+        let difference = standard.compare(&warmer, (0.0, 10e3), 100.0, 530e-9);
+        assert!((difference.max_temperature - 2.0).abs() < 1e-9);
+        assert!((difference.rms_temperature - 2.0).abs() < 1e-9);
+        assert!(difference.max_pressure > 0.0);
+        assert!(difference.rms_n > 0.0 && difference.rms_n <= difference.max_n);
+        // the difference of the densities is the largest at the ground
+        assert_eq!(difference.max_n_altitude, 0.0);
+    }
+}
@@ -0,0 +1,246 @@
+use super::{tabulated_def, HumidityUnit, LengthUnit, PressureUnit, TemperatureUnit};
+use crate::air::AtmosphereDef;
+use std::io::{self, BufRead, BufReader, Read};
+
+/// A column of a CSV file
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum Column {
+    /// The column with the given index, starting from 0
+    Index(usize),
+    /// The column with the given name in the header, ignoring the case
+    Name(String),
+}
+
+/// The description of the columns of a CSV file containing a vertical profile
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct ColumnMapping {
+    /// The column with the altitudes
+    pub altitude: Column,
+    /// The column with the temperatures
+    pub temperature: Column,
+    /// The column with the pressures, if any
+    pub pressure: Option<Column>,
+    /// The column with the relative humidities, if any
+    pub humidity: Option<Column>,
+    pub altitude_unit: LengthUnit,
+    pub temperature_unit: TemperatureUnit,
+    pub pressure_unit: PressureUnit,
+    pub humidity_unit: HumidityUnit,
+    /// The character separating the fields
+    pub delimiter: char,
+    /// Whether the first line of the file contains the names of the columns
+    pub header: bool,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self {
+            altitude: Column::Name("altitude".to_owned()),
+            temperature: Column::Name("temperature".to_owned()),
+            pressure: None,
+            humidity: None,
+            altitude_unit: LengthUnit::Meters,
+            temperature_unit: TemperatureUnit::Kelvin,
+            pressure_unit: PressureUnit::Pascal,
+            humidity_unit: HumidityUnit::Percent,
+            delimiter: ',',
+            header: true,
+        }
+    }
+}
+
+/// An error in reading a profile from a CSV file
+#[derive(Clone, Debug, PartialEq)]
+pub enum CsvError {
+    /// Reading the file failed
+    Io(io::ErrorKind),
+    /// The column with the given name isn't in the header, or the file has no header
+    MissingColumn(String),
+    /// A field doesn't contain a number; the line and column numbers start from 1
+    InvalidValue { line: usize, column: usize },
+    /// The file contains no data
+    NoData,
+    /// The altitude appears in more than one row
+    DuplicateAltitude(f64),
+}
+
+impl From<io::Error> for CsvError {
+    fn from(error: io::Error) -> Self {
+        CsvError::Io(error.kind())
+    }
+}
+
+impl AtmosphereDef {
+    /// Reads a vertical profile from a CSV file, with the columns described by `mapping`.
+    ///
+    /// The temperatures and humidities are interpolated linearly between the altitudes, and
+    /// continued with the values at the ends outside of them. Empty humidity and pressure
+    /// fields are skipped. The pressure profile is calculated from the temperatures, starting
+    /// from the pressure at the lowest altitude; the other pressures are ignored. Empty lines
+    /// and lines starting with `#` are skipped.
+    pub fn from_csv<R: Read>(reader: R, mapping: &ColumnMapping) -> Result<Self, CsvError> {
+        let mut lines = BufReader::new(reader)
+            .lines()
+            .enumerate()
+            .map(|(i, line)| line.map(|line| (i + 1, line)))
+            .filter(|line| {
+                line.as_ref().map_or(true, |(_, line)| {
+                    !line.trim().is_empty() && !line.starts_with('#')
+                })
+            });
+
+        let header: Vec<String> = if mapping.header {
+            match lines.next() {
+                Some(line) => split(&line?.1, mapping.delimiter)
+                    .map(|name| name.to_lowercase())
+                    .collect(),
+                None => return Err(CsvError::NoData),
+            }
+        } else {
+            vec![]
+        };
+        let index = |column: &Column| match column {
+            Column::Index(index) => Ok(*index),
+            Column::Name(name) => header
+                .iter()
+                .position(|field| *field == name.to_lowercase())
+                .ok_or_else(|| CsvError::MissingColumn(name.clone())),
+        };
+        let altitude_index = index(&mapping.altitude)?;
+        let temperature_index = index(&mapping.temperature)?;
+        let pressure_index = mapping.pressure.as_ref().map(index).transpose()?;
+        let humidity_index = mapping.humidity.as_ref().map(index).transpose()?;
+
+        // (altitude, temperature, pressure, humidity)
+        let mut rows = vec![];
+        for line in lines {
+            let (line_number, line) = line?;
+            let fields: Vec<&str> = split(&line, mapping.delimiter).collect();
+            let field = |index: usize| {
+                fields
+                    .get(index)
+                    .filter(|field| !field.is_empty())
+                    .map(|field| {
+                        field.parse::<f64>().map_err(|_| CsvError::InvalidValue {
+                            line: line_number,
+                            column: index + 1,
+                        })
+                    })
+                    .transpose()
+            };
+            let required = |index: usize| {
+                field(index)?.ok_or(CsvError::InvalidValue {
+                    line: line_number,
+                    column: index + 1,
+                })
+            };
+            let optional = |index: Option<usize>| index.map_or(Ok(None), field);
+            rows.push((
+                mapping.altitude_unit.to_meters(required(altitude_index)?),
+                mapping
+                    .temperature_unit
+                    .to_kelvin(required(temperature_index)?),
+                optional(pressure_index)?.map(|p| mapping.pressure_unit.to_pascal(p)),
+                optional(humidity_index)?.map(|rh| mapping.humidity_unit.to_percent(rh)),
+            ));
+        }
+        if rows.is_empty() {
+            return Err(CsvError::NoData);
+        }
+        rows.sort_by(|row1, row2| row1.0.total_cmp(&row2.0));
+        if let Some(pair) = rows.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(CsvError::DuplicateAltitude(pair[0].0));
+        }
+
+        let temperature = rows.iter().map(|row| (row.0, row.1)).collect();
+        let humidity = rows
+            .iter()
+            .filter_map(|row| row.3.map(|rh| (row.0, rh)))
+            .collect();
+        let pressure = rows.iter().find_map(|row| row.2.map(|p| (row.0, p)));
+        Ok(tabulated_def(temperature, humidity, pressure))
+    }
+}
+
+/// Splits a line into trimmed fields, with the quotes removed
+fn split(line: &str, delimiter: char) -> impl Iterator<Item = &str> {
+    line.split(delimiter)
+        .map(|field| field.trim().trim_matches('"').trim())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::Atmosphere;
+
+    const SOUNDING: &str = "\
+# a made-up sounding
+Height,Temp,Press,RH
+0.1,12.0,1000.0,50
+0.0,13.0,1012.0,60
+
+0.5,10.0,,
+";
+
+    #[test]
+    fn test_from_csv() {
+        let mapping = ColumnMapping {
+            altitude: Column::Name("height".to_owned()),
+            temperature: Column::Index(1),
+            pressure: Some(Column::Name("press".to_owned())),
+            humidity: Some(Column::Name("rh".to_owned())),
+            altitude_unit: LengthUnit::Kilometers,
+            temperature_unit: TemperatureUnit::Celsius,
+            pressure_unit: PressureUnit::Hectopascal,
+            ..Default::default()
+        };
+        let def = AtmosphereDef::from_csv(SOUNDING.as_bytes(), &mapping).unwrap();
+        let atmosphere = Atmosphere::try_from_def(def).unwrap();
+        assert!((atmosphere.temperature(0.0) - 286.15).abs() < 1e-9);
+        assert!((atmosphere.temperature(50.0) - 285.65).abs() < 1e-9);
+        assert!((atmosphere.temperature(300.0) - 284.15).abs() < 1e-9);
+        assert!((atmosphere.temperature(1e3) - 283.15).abs() < 1e-9);
+        assert!((atmosphere.pressure(0.0) - 101200.0).abs() < 1e-6);
+        assert!((atmosphere.humidity(50.0) - 55.0).abs() < 1e-9);
+        assert!((atmosphere.humidity(500.0) - 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_csv_errors() {
+        let mapping = ColumnMapping::default();
+        assert_eq!(
+            AtmosphereDef::from_csv(SOUNDING.as_bytes(), &mapping).unwrap_err(),
+            CsvError::MissingColumn("altitude".to_owned())
+        );
+
+        let mapping = ColumnMapping {
+            altitude: Column::Index(0),
+            temperature: Column::Index(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            AtmosphereDef::from_csv(SOUNDING.as_bytes(), &mapping).unwrap_err(),
+            CsvError::InvalidValue { line: 6, column: 3 }
+        );
+
+        let mapping = ColumnMapping {
+            temperature: Column::Index(1),
+            header: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            AtmosphereDef::from_csv("1,2\n1,3\n".as_bytes(), &mapping).unwrap_err(),
+            CsvError::MissingColumn("altitude".to_owned())
+        );
+        let mapping = ColumnMapping {
+            altitude: Column::Index(0),
+            ..mapping
+        };
+        assert_eq!(
+            AtmosphereDef::from_csv("1,2\n1,3\n".as_bytes(), &mapping).unwrap_err(),
+            CsvError::DuplicateAltitude(1.0)
+        );
+    }
+}
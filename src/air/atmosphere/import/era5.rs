@@ -0,0 +1,201 @@
+use super::levels::{pressure_levels_def, relative_humidity, PressureLevel, STANDARD_GRAVITY};
+use crate::air::AtmosphereDef;
+use netcdf::{Extent, File, Variable};
+use std::{convert::TryFrom, path::Path};
+
+/// The names of the time coordinate used in ERA5 files
+const TIME_NAMES: [&str; 2] = ["valid_time", "time"];
+/// The names of the pressure level coordinate used in ERA5 files
+const LEVEL_NAMES: [&str; 3] = ["pressure_level", "level", "isobaricInhPa"];
+/// The names of the latitude coordinate used in ERA5 files
+const LATITUDE_NAMES: [&str; 2] = ["latitude", "lat"];
+/// The names of the longitude coordinate used in ERA5 files
+const LONGITUDE_NAMES: [&str; 2] = ["longitude", "lon"];
+
+/// The point of an ERA5 grid from which a profile is extracted
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct Era5Point {
+    /// The latitude in degrees
+    pub latitude: f64,
+    /// The longitude in degrees, in any range
+    pub longitude: f64,
+    /// The time in the units of the time coordinate of the file (usually hours since 1900 or
+    /// seconds since 1970); if not set, the first time in the file is used
+    pub time: Option<f64>,
+}
+
+/// An error in reading a profile from a NetCDF file
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetcdfError {
+    /// Reading the file failed, with the message from the NetCDF library
+    Netcdf(String),
+    /// The file doesn't contain the variable or coordinate with the given name
+    MissingVariable(String),
+    /// The variable has a dimension that isn't a time, level, latitude or longitude
+    UnexpectedDimension { variable: String, dimension: String },
+    /// The file contains no levels
+    NoData,
+}
+
+impl From<netcdf::Error> for NetcdfError {
+    fn from(error: netcdf::Error) -> Self {
+        NetcdfError::Netcdf(error.to_string())
+    }
+}
+
+impl AtmosphereDef {
+    /// Reads a vertical profile from an ERA5 file on pressure levels, at the grid point and time
+    /// nearest to `point`.
+    ///
+    /// The file has to contain the temperature `t` and the geopotential `z`, and can contain the
+    /// specific humidity `q`. The temperatures and humidities are interpolated linearly between
+    /// the levels, and the pressure profile is calculated from the temperatures, starting from
+    /// the lowest level. The levels are expected in hPa, like in the files from the Climate Data
+    /// Store; files on model levels aren't supported.
+    pub fn from_era5<P: AsRef<Path>>(path: P, point: &Era5Point) -> Result<Self, NetcdfError> {
+        let file = netcdf::open(path)?;
+        let (latitude_name, latitudes) = coordinate(&file, &LATITUDE_NAMES)?;
+        let (longitude_name, longitudes) = coordinate(&file, &LONGITUDE_NAMES)?;
+        let (level_name, pressures) = coordinate(&file, &LEVEL_NAMES)?;
+        let time = coordinate(&file, &TIME_NAMES).ok();
+        let selection = Selection {
+            time: time.map(|(name, times)| {
+                let index = point
+                    .time
+                    .map_or(0, |time| nearest(&times, |t| (t - time).abs()));
+                (name, index)
+            }),
+            level: level_name,
+            latitude: (
+                latitude_name,
+                nearest(&latitudes, |lat| (lat - point.latitude).abs()),
+            ),
+            longitude: (
+                longitude_name,
+                nearest(&longitudes, |lon| {
+                    let difference = (lon - point.longitude).rem_euclid(360.0);
+                    difference.min(360.0 - difference)
+                }),
+            ),
+        };
+
+        let temperatures = selection.column(&file, "t")?;
+        let geopotentials = selection.column(&file, "z")?;
+        let humidities = match file.variable("q") {
+            Some(variable) => Some(selection.values(&variable)?),
+            None => None,
+        };
+        let levels = pressures
+            .iter()
+            .enumerate()
+            .map(|(i, &pressure)| {
+                let pressure = pressure * 100.0;
+                let temperature = temperatures[i];
+                PressureLevel {
+                    pressure,
+                    geopotential_height: geopotentials[i] / STANDARD_GRAVITY,
+                    temperature,
+                    humidity: humidities
+                        .as_ref()
+                        .map(|q| relative_humidity(q[i], pressure, temperature)),
+                }
+            })
+            .filter(|level| level.temperature.is_finite() && level.geopotential_height.is_finite())
+            .collect();
+        pressure_levels_def(levels).ok_or(NetcdfError::NoData)
+    }
+}
+
+/// The indices of the values making up a single column
+struct Selection {
+    time: Option<(String, usize)>,
+    level: String,
+    latitude: (String, usize),
+    longitude: (String, usize),
+}
+
+impl Selection {
+    /// Reads the values of the variable with the given name in the column
+    fn column(&self, file: &File, name: &str) -> Result<Vec<f64>, NetcdfError> {
+        let variable = file
+            .variable(name)
+            .ok_or_else(|| NetcdfError::MissingVariable(name.to_owned()))?;
+        self.values(&variable)
+    }
+
+    /// Reads the values of the variable in the column, unpacked and with the missing values
+    /// replaced by NaNs
+    fn values(&self, variable: &Variable) -> Result<Vec<f64>, NetcdfError> {
+        let extents = variable
+            .dimensions()
+            .iter()
+            .map(|dimension| {
+                let name = dimension.name();
+                let index = self
+                    .time
+                    .iter()
+                    .chain([&self.latitude, &self.longitude])
+                    .find(|(coordinate, _)| *coordinate == name)
+                    .map(|(_, index)| *index);
+                match index {
+                    Some(index) => Ok(Extent::from(index)),
+                    None if name == self.level => Ok(Extent::from(..)),
+                    // ERA5 files can have extra dimensions with a single value, like the
+                    // experiment version
+                    None if dimension.len() == 1 => Ok(Extent::from(0)),
+                    None => Err(NetcdfError::UnexpectedDimension {
+                        variable: variable.name(),
+                        dimension: name,
+                    }),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let values = variable.get_values::<f64, _>(extents)?;
+        let scale_factor = attribute(variable, "scale_factor")?.unwrap_or(1.0);
+        let add_offset = attribute(variable, "add_offset")?.unwrap_or(0.0);
+        let fill_value = match attribute(variable, "_FillValue")? {
+            Some(value) => Some(value),
+            None => attribute(variable, "missing_value")?,
+        };
+        Ok(values
+            .into_iter()
+            .map(|value| {
+                if Some(value) == fill_value {
+                    f64::NAN
+                } else {
+                    value * scale_factor + add_offset
+                }
+            })
+            .collect())
+    }
+}
+
+/// Reads the numeric attribute with the given name, if it is present
+fn attribute(variable: &Variable, name: &str) -> Result<Option<f64>, NetcdfError> {
+    match variable.attribute_value(name).transpose()? {
+        Some(value) => Ok(Some(f64::try_from(value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads the first coordinate with one of the given names, returning its name and values
+fn coordinate(file: &File, names: &[&str]) -> Result<(String, Vec<f64>), NetcdfError> {
+    let (name, variable) = names
+        .iter()
+        .find_map(|name| {
+            file.variable(name)
+                .map(|variable| (name.to_string(), variable))
+        })
+        .ok_or_else(|| NetcdfError::MissingVariable(names[0].to_owned()))?;
+    Ok((name, variable.get_values::<f64, _>(..)?))
+}
+
+/// Returns the index of the value with the smallest distance
+fn nearest<F: Fn(f64) -> f64>(values: &[f64], distance: F) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .min_by(|(_, value1), (_, value2)| distance(**value1).total_cmp(&distance(**value2)))
+        .map_or(0, |(index, _)| index)
+}
@@ -0,0 +1,244 @@
+use super::levels::{pressure_levels_def, relative_humidity, PressureLevel, STANDARD_GRAVITY};
+use crate::air::AtmosphereDef;
+use grib::{codetables::grib2::Table4_4, ForecastTime, Grib2SubmessageDecoder, Name};
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+/// The discipline of the messages with meteorological products
+const METEOROLOGICAL_PRODUCTS: u8 = 0;
+/// The type of isobaric surfaces, with the values in Pa
+const ISOBARIC_SURFACE: u8 = 100;
+
+/// The location from which a profile is extracted from a GRIB2 file
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct GribPoint {
+    /// The latitude in degrees
+    pub latitude: f64,
+    /// The longitude in degrees, in any range
+    pub longitude: f64,
+    /// If set, only the messages with this forecast time in hours are read
+    pub forecast_hours: Option<u32>,
+}
+
+/// An error in reading a profile from a GRIB2 file
+#[derive(Clone, Debug, PartialEq)]
+pub enum GribError {
+    /// Opening the file failed
+    Io(io::ErrorKind),
+    /// Parsing or decoding the file failed, with the message from the GRIB2 decoder
+    Grib(String),
+    /// The file contains no pressure levels with both the temperature and the geopotential
+    NoData,
+}
+
+impl From<io::Error> for GribError {
+    fn from(error: io::Error) -> Self {
+        GribError::Io(error.kind())
+    }
+}
+
+impl From<grib::GribError> for GribError {
+    fn from(error: grib::GribError) -> Self {
+        GribError::Grib(error.to_string())
+    }
+}
+
+/// A quantity given on the pressure levels
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Parameter {
+    /// The temperature in K
+    Temperature,
+    /// The specific humidity in kg/kg
+    SpecificHumidity,
+    /// The relative humidity in percent
+    RelativeHumidity,
+    /// The geopotential in m^2/s^2
+    Geopotential,
+    /// The geopotential height in meters
+    GeopotentialHeight,
+}
+
+impl Parameter {
+    /// Returns the parameter with the given category and number of the meteorological products
+    fn from_numbers(category: u8, number: u8) -> Option<Self> {
+        match (category, number) {
+            (0, 0) => Some(Parameter::Temperature),
+            (1, 0) => Some(Parameter::SpecificHumidity),
+            (1, 1) => Some(Parameter::RelativeHumidity),
+            (3, 4) => Some(Parameter::Geopotential),
+            (3, 5) => Some(Parameter::GeopotentialHeight),
+            _ => None,
+        }
+    }
+}
+
+/// The values of the parameters read at a pressure level
+#[derive(Clone, Copy, Debug, Default)]
+struct LevelValues {
+    temperature: Option<f64>,
+    specific_humidity: Option<f64>,
+    relative_humidity: Option<f64>,
+    geopotential: Option<f64>,
+    geopotential_height: Option<f64>,
+}
+
+impl LevelValues {
+    fn value_mut(&mut self, parameter: Parameter) -> &mut Option<f64> {
+        match parameter {
+            Parameter::Temperature => &mut self.temperature,
+            Parameter::SpecificHumidity => &mut self.specific_humidity,
+            Parameter::RelativeHumidity => &mut self.relative_humidity,
+            Parameter::Geopotential => &mut self.geopotential,
+            Parameter::GeopotentialHeight => &mut self.geopotential_height,
+        }
+    }
+
+    /// Converts the values into a level, if the temperature and the height are known
+    fn level(&self, pressure: f64) -> Option<PressureLevel> {
+        let temperature = self.temperature?;
+        let geopotential_height = self
+            .geopotential_height
+            .or_else(|| self.geopotential.map(|z| z / STANDARD_GRAVITY))?;
+        let humidity = self.relative_humidity.or_else(|| {
+            self.specific_humidity
+                .map(|q| relative_humidity(q, pressure, temperature))
+        });
+        Some(PressureLevel {
+            pressure,
+            geopotential_height,
+            temperature,
+            humidity,
+        })
+    }
+}
+
+impl AtmosphereDef {
+    /// Reads a vertical profile from a GRIB2 file with the output of a weather model, at the grid
+    /// point nearest to `point`.
+    ///
+    /// The temperature, the geopotential height (or the geopotential) and the relative (or
+    /// specific) humidity are read from the messages on isobaric surfaces; when a quantity is
+    /// given more than once at a level, the first message is used. The temperatures and
+    /// humidities are interpolated linearly between the levels, and the pressure profile is
+    /// calculated from the temperatures, starting from the lowest level.
+    pub fn from_grib2<P: AsRef<Path>>(path: P, point: &GribPoint) -> Result<Self, GribError> {
+        let grib2 = grib::from_reader(BufReader::new(File::open(path)?))?;
+        // (pressure, values)
+        let mut levels: Vec<(f64, LevelValues)> = vec![];
+        for (_, submessage) in grib2.iter() {
+            if submessage.indicator().discipline != METEOROLOGICAL_PRODUCTS {
+                continue;
+            }
+            let prod_def = submessage.prod_def();
+            let parameter = match prod_def
+                .parameter_category()
+                .zip(prod_def.parameter_number())
+                .and_then(|(category, number)| Parameter::from_numbers(category, number))
+            {
+                Some(parameter) => parameter,
+                None => continue,
+            };
+            let pressure = match prod_def.fixed_surfaces() {
+                Some((surface, _)) if surface.surface_type == ISOBARIC_SURFACE => surface.value(),
+                _ => continue,
+            };
+            if let Some(hours) = point.forecast_hours {
+                match prod_def.forecast_time() {
+                    Some(ForecastTime {
+                        unit: Name(Table4_4::Hour),
+                        value,
+                    }) if value == hours => (),
+                    _ => continue,
+                }
+            }
+            let index = match levels.iter().position(|(p, _)| *p == pressure) {
+                Some(index) => index,
+                None => {
+                    levels.push((pressure, LevelValues::default()));
+                    levels.len() - 1
+                }
+            };
+            if levels[index].1.value_mut(parameter).is_some() {
+                continue;
+            }
+
+            let point_index = nearest(submessage.latlons()?, point).ok_or(GribError::NoData)?;
+            let decoder = Grib2SubmessageDecoder::from(submessage)?;
+            let value = decoder
+                .dispatch()?
+                .nth(point_index)
+                .map(f64::from)
+                .filter(|value| value.is_finite());
+            *levels[index].1.value_mut(parameter) = value;
+        }
+        let levels = levels
+            .iter()
+            .filter_map(|(pressure, values)| values.level(*pressure))
+            .collect();
+        pressure_levels_def(levels).ok_or(GribError::NoData)
+    }
+}
+
+/// Returns the index of the grid point nearest to the given point
+fn nearest<I: Iterator<Item = (f32, f32)>>(latlons: I, point: &GribPoint) -> Option<usize> {
+    let latitude = point.latitude.to_radians();
+    let longitude = point.longitude.to_radians();
+    latlons
+        .map(|(lat, lon)| {
+            let (lat, lon) = (f64::from(lat).to_radians(), f64::from(lon).to_radians());
+            // the cosine of the angular distance
+            lat.sin() * latitude.sin() + lat.cos() * latitude.cos() * (lon - longitude).cos()
+        })
+        .enumerate()
+        .max_by(|(_, cos1), (_, cos2)| cos1.total_cmp(cos2))
+        .map(|(index, _)| index)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_level_values() {
+        let values = LevelValues {
+            temperature: Some(280.0),
+            specific_humidity: Some(0.005),
+            geopotential: Some(15000.0),
+            ..Default::default()
+        };
+        let level = values.level(85000.0).unwrap();
+        assert!((level.geopotential_height - 15000.0 / STANDARD_GRAVITY).abs() < 1e-9);
+        let humidity = level.humidity.unwrap();
+        assert!(humidity > 0.0 && humidity < 100.0);
+
+        let values = LevelValues {
+            relative_humidity: Some(40.0),
+            geopotential_height: Some(1500.0),
+            ..values
+        };
+        let level = values.level(85000.0).unwrap();
+        assert_eq!(level.geopotential_height, 1500.0);
+        assert_eq!(level.humidity, Some(40.0));
+
+        let values = LevelValues {
+            temperature: None,
+            ..values
+        };
+        assert!(values.level(85000.0).is_none());
+    }
+
+    #[test]
+    fn test_nearest() {
+        let point = GribPoint {
+            latitude: 52.2,
+            longitude: -0.9,
+            forecast_hours: None,
+        };
+        let latlons = vec![(52.0, 0.0), (52.0, 359.0), (53.0, 359.0), (52.0, 1.0)];
+        assert_eq!(nearest(latlons.into_iter(), &point), Some(1));
+    }
+}
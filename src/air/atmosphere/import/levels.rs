@@ -0,0 +1,98 @@
+//! Helpers for the profiles given on the pressure levels of weather models.
+
+use super::tabulated_def;
+use crate::air::{p_sv, AtmosphereDef};
+
+/// The standard gravity used for defining the geopotential height, in m/s^2
+pub(super) const STANDARD_GRAVITY: f64 = 9.80665;
+/// The Earth radius used for converting geopotential heights to altitudes, in meters
+const EARTH_RADIUS: f64 = 6_371_000.0;
+/// The ratio of the molar masses of water and dry air
+const WATER_AIR_RATIO: f64 = 0.622;
+
+/// The state of the air at a pressure level of a weather model
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) struct PressureLevel {
+    /// The pressure in Pa
+    pub pressure: f64,
+    /// The geopotential height in meters
+    pub geopotential_height: f64,
+    /// The temperature in K
+    pub temperature: f64,
+    /// The relative humidity in percent
+    pub humidity: Option<f64>,
+}
+
+impl PressureLevel {
+    /// Returns the geometric altitude of the level
+    fn altitude(&self) -> f64 {
+        EARTH_RADIUS * self.geopotential_height / (EARTH_RADIUS - self.geopotential_height)
+    }
+}
+
+/// Calculates the relative humidity in percent from the specific humidity in kg/kg, the pressure
+/// and the temperature
+pub(super) fn relative_humidity(specific_humidity: f64, pressure: f64, temperature: f64) -> f64 {
+    let vapor_pressure = specific_humidity * pressure
+        / (WATER_AIR_RATIO + (1.0 - WATER_AIR_RATIO) * specific_humidity);
+    100.0 * vapor_pressure / p_sv(temperature)
+}
+
+/// Builds a definition from the pressure levels of a weather model, in any order. The pressure
+/// is taken from the lowest level, and the levels with equal altitudes are skipped.
+pub(super) fn pressure_levels_def(mut levels: Vec<PressureLevel>) -> Option<AtmosphereDef> {
+    levels.sort_by(|level1, level2| level1.altitude().total_cmp(&level2.altitude()));
+    levels.dedup_by(|level1, level2| level1.altitude() == level2.altitude());
+    let lowest = levels.first()?;
+    let pressure = Some((lowest.altitude(), lowest.pressure));
+    let temperature = levels
+        .iter()
+        .map(|level| (level.altitude(), level.temperature))
+        .collect();
+    let humidity = levels
+        .iter()
+        .filter_map(|level| level.humidity.map(|rh| (level.altitude(), rh)))
+        .collect();
+    Some(tabulated_def(temperature, humidity, pressure))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::Atmosphere;
+
+    #[test]
+    fn test_pressure_levels() {
+        let levels = vec![
+            PressureLevel {
+                pressure: 85000.0,
+                geopotential_height: 1500.0,
+                temperature: 280.0,
+                humidity: Some(50.0),
+            },
+            PressureLevel {
+                pressure: 100000.0,
+                geopotential_height: 100.0,
+                temperature: 290.0,
+                humidity: Some(relative_humidity(0.005, 100000.0, 290.0)),
+            },
+        ];
+        let atmosphere = Atmosphere::try_from_def(pressure_levels_def(levels).unwrap()).unwrap();
+        let altitude = levels_altitude(100.0);
+        assert!((atmosphere.pressure(altitude) - 100000.0).abs() < 1e-6);
+        assert!((atmosphere.temperature(altitude) - 290.0).abs() < 1e-9);
+        // 0.005 kg/kg at 290 K is a bit over 40%
+        assert!((atmosphere.humidity(altitude) - 41.4).abs() < 0.5);
+        assert!((atmosphere.temperature(levels_altitude(1500.0)) - 280.0).abs() < 1e-9);
+    }
+
+    fn levels_altitude(geopotential_height: f64) -> f64 {
+        PressureLevel {
+            pressure: 0.0,
+            geopotential_height,
+            temperature: 0.0,
+            humidity: None,
+        }
+        .altitude()
+    }
+}
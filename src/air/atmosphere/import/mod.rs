@@ -0,0 +1,140 @@
+//! Importing atmospheric profiles from measured or modeled data.
+
+mod csv;
+#[cfg(feature = "netcdf")]
+mod era5;
+#[cfg(feature = "grib")]
+mod grib2;
+#[cfg(any(feature = "netcdf", feature = "grib"))]
+mod levels;
+
+pub use self::csv::*;
+#[cfg(feature = "netcdf")]
+pub use self::era5::*;
+#[cfg(feature = "grib")]
+pub use self::grib2::*;
+
+use super::{
+    vertical_profile::{Extrapolation, FunctionDef},
+    AtmosphereDef, PressureFixedPoint,
+};
+
+/// Builds a definition from tables of (altitude, temperature) and (altitude, relative humidity)
+/// pairs, sorted by the altitude, and the pressure at some altitude. The tables are continued
+/// with the values at their ends; a missing pressure is taken from the US-1976 atmosphere, and
+/// a missing humidity is zero.
+pub(super) fn tabulated_def(
+    temperature: Vec<(f64, f64)>,
+    humidity: Vec<(f64, f64)>,
+    pressure: Option<(f64, f64)>,
+) -> AtmosphereDef {
+    let us_76 = AtmosphereDef::us_76();
+    let table = |points| FunctionDef::Table {
+        points,
+        extrapolation: Extrapolation::Constant,
+    };
+    let (first_humidity_function, humidity_fixed_point) = if humidity.is_empty() {
+        (us_76.first_humidity_function, us_76.humidity_fixed_point)
+    } else {
+        (table(humidity), None)
+    };
+    AtmosphereDef {
+        pressure: pressure.map_or(us_76.pressure, |(altitude, pressure)| PressureFixedPoint {
+            altitude,
+            pressure,
+        }),
+        first_temperature_function: table(temperature),
+        next_functions: vec![],
+        temperature_fixed_point: None,
+        first_humidity_function,
+        next_humidity_functions: vec![],
+        humidity_fixed_point,
+        ..us_76
+    }
+}
+
+/// A unit of altitude
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum LengthUnit {
+    #[default]
+    Meters,
+    Kilometers,
+    Feet,
+}
+
+impl LengthUnit {
+    /// Converts a value in this unit to meters
+    pub fn to_meters(self, value: f64) -> f64 {
+        match self {
+            LengthUnit::Meters => value,
+            LengthUnit::Kilometers => value * 1e3,
+            LengthUnit::Feet => value * 0.3048,
+        }
+    }
+}
+
+/// A unit of temperature
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum TemperatureUnit {
+    #[default]
+    Kelvin,
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    /// Converts a value in this unit to kelvins
+    pub fn to_kelvin(self, value: f64) -> f64 {
+        match self {
+            TemperatureUnit::Kelvin => value,
+            TemperatureUnit::Celsius => value + 273.15,
+            TemperatureUnit::Fahrenheit => (value - 32.0) / 1.8 + 273.15,
+        }
+    }
+}
+
+/// A unit of pressure
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum PressureUnit {
+    #[default]
+    Pascal,
+    /// Hectopascals, equal to millibars
+    Hectopascal,
+    Kilopascal,
+    InchOfMercury,
+}
+
+impl PressureUnit {
+    /// Converts a value in this unit to pascals
+    pub fn to_pascal(self, value: f64) -> f64 {
+        match self {
+            PressureUnit::Pascal => value,
+            PressureUnit::Hectopascal => value * 100.0,
+            PressureUnit::Kilopascal => value * 1e3,
+            PressureUnit::InchOfMercury => value * 3386.389,
+        }
+    }
+}
+
+/// A unit of relative humidity
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum HumidityUnit {
+    #[default]
+    Percent,
+    /// A fraction between 0 and 1
+    Fraction,
+}
+
+impl HumidityUnit {
+    /// Converts a value in this unit to percent
+    pub fn to_percent(self, value: f64) -> f64 {
+        match self {
+            HumidityUnit::Percent => value,
+            HumidityUnit::Fraction => value * 100.0,
+        }
+    }
+}
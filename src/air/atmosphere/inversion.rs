@@ -0,0 +1,283 @@
+//! Temperature inversions inserted into existing atmospheric models.
+
+use super::{
+    validation::AtmosphereDefError, vertical_profile::FunctionDef, AtmosphereDef,
+    FunctionDefWithAlt, TemperatureFixedPoint,
+};
+
+/// The number of intervals into which each smooth transition at the edges of an inversion is
+/// divided when approximating it with a spline
+const TRANSITION_INTERVALS: usize = 8;
+
+/// A layer in which the temperature rises linearly with altitude
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct Inversion {
+    /// The altitude of the bottom of the layer in meters
+    pub base: f64,
+    /// The thickness of the layer in meters
+    pub thickness: f64,
+    /// The rise of the temperature across the layer in kelvins
+    pub jump: f64,
+    /// If set, the width in meters of the transitions in which the gradient changes smoothly at
+    /// the bottom and at the top of the layer; otherwise, the gradient changes abruptly. The
+    /// width is limited to the thickness of the layer.
+    pub transition: Option<f64>,
+}
+
+impl Default for Inversion {
+    fn default() -> Self {
+        Self {
+            base: 0.0,
+            thickness: 100.0,
+            jump: 5.0,
+            transition: None,
+        }
+    }
+}
+
+impl AtmosphereDef {
+    /// Inserts the given inversion into the temperature profile.
+    ///
+    /// The temperatures below the inversion stay the same, and the profile above it is shifted
+    /// so that it continues from the top of the inversion. The smooth transitions are
+    /// represented with splines.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the temperature profile of the definition can't be built or the inversion is
+    /// invalid; see `try_with_inversion`.
+    pub fn with_inversion(self, inversion: &Inversion) -> Self {
+        match self.try_with_inversion(inversion) {
+            Ok(def) => def,
+            Err(error) => panic!("couldn't insert the inversion: {:?}", error),
+        }
+    }
+
+    /// Inserts the given inversion into the temperature profile like `with_inversion`, or
+    /// returns the problem that prevents it.
+    ///
+    /// The temperature functions of the definition have to make a valid profile. The base and
+    /// the jump of the inversion have to be finite, its thickness finite and positive, and the
+    /// width of the transitions (if set) finite and non-negative.
+    pub fn try_with_inversion(self, inversion: &Inversion) -> Result<Self, AtmosphereDefError> {
+        let parameters = [
+            ("base", inversion.base, inversion.base.is_finite()),
+            (
+                "thickness",
+                inversion.thickness,
+                inversion.thickness.is_finite() && inversion.thickness > 0.0,
+            ),
+            ("jump", inversion.jump, inversion.jump.is_finite()),
+        ];
+        let transition = inversion
+            .transition
+            .map(|width| ("transition", width, width.is_finite() && width >= 0.0));
+        for (name, value, valid) in parameters.iter().copied().chain(transition) {
+            if !valid {
+                return Err(AtmosphereDefError::InvalidParameter { name, value });
+            }
+        }
+        let profile = self.checked_temperature_profile()?;
+        let half_width = 0.5 * inversion.transition.unwrap_or(0.0).min(inversion.thickness);
+        let bottom = inversion.base - half_width;
+        let top = inversion.base + inversion.thickness + half_width;
+        let gradient = inversion.jump / inversion.thickness;
+        let bottom_temperature = profile.eval(bottom);
+
+        let mut functions = vec![];
+        let top_temperature = if half_width > 0.0 {
+            let width = 2.0 * half_width;
+            let layer = SmoothLayer {
+                bottom,
+                width,
+                top,
+                bottom_temperature,
+                gradients: (
+                    profile.eval_derivative(bottom),
+                    gradient,
+                    profile.eval_derivative(top),
+                ),
+            };
+            // the temperature is quadratic in the transitions, so the splines are exact there
+            functions.push((bottom, layer.transition(bottom)));
+            if top - bottom > 2.0 * width {
+                functions.push((bottom + width, FunctionDef::Linear { gradient }));
+            }
+            functions.push((top - width, layer.transition(top - width)));
+            layer.temperature(top).0
+        } else {
+            functions.push((bottom, FunctionDef::Linear { gradient }));
+            bottom_temperature + inversion.jump
+        };
+        let offset = top_temperature - profile.eval(top);
+
+        let mut next_functions: Vec<_> = self
+            .next_functions
+            .iter()
+            .filter(|fun_def| fun_def.altitude <= bottom)
+            .cloned()
+            .collect();
+        next_functions.extend(
+            functions
+                .into_iter()
+                .map(|(altitude, function)| FunctionDefWithAlt { altitude, function }),
+        );
+        next_functions.push(FunctionDefWithAlt {
+            altitude: top,
+            function: self.temperature_function_at(top).clone().shifted(offset),
+        });
+        next_functions.extend(
+            self.next_functions
+                .iter()
+                .filter(|fun_def| fun_def.altitude > top)
+                .map(|fun_def| FunctionDefWithAlt {
+                    altitude: fun_def.altitude,
+                    function: fun_def.function.clone().shifted(offset),
+                }),
+        );
+        Ok(AtmosphereDef {
+            next_functions,
+            temperature_fixed_point: Some(TemperatureFixedPoint {
+                altitude: bottom,
+                temperature: bottom_temperature,
+            }),
+            ..self
+        })
+    }
+}
+
+/// An inversion with smooth transitions, in which the gradient changes linearly from the one
+/// below to the one inside the inversion, and from that to the one above
+struct SmoothLayer {
+    bottom: f64,
+    width: f64,
+    top: f64,
+    bottom_temperature: f64,
+    gradients: (f64, f64, f64),
+}
+
+impl SmoothLayer {
+    /// Returns the temperature and its derivative at the altitude `h`
+    fn temperature(&self, h: f64) -> (f64, f64) {
+        let (below, inside, above) = self.gradients;
+        let t = (h - self.bottom).min(self.width);
+        let mut temperature =
+            self.bottom_temperature + below * t + (inside - below) * t * t / (2.0 * self.width);
+        let mut gradient = below + (inside - below) * t / self.width;
+        let upper_start = self.top - self.width;
+        if h > self.bottom + self.width {
+            temperature += inside * (h.min(upper_start) - self.bottom - self.width);
+            gradient = inside;
+        }
+        if h > upper_start {
+            let t = h - upper_start;
+            temperature += inside * t + (above - inside) * t * t / (2.0 * self.width);
+            gradient = inside + (above - inside) * t / self.width;
+        }
+        (temperature, gradient)
+    }
+
+    /// Returns the temperature in the transition starting at the altitude `start`, approximated
+    /// with a spline
+    fn transition(&self, start: f64) -> FunctionDef {
+        let altitudes: Vec<f64> = (0..=TRANSITION_INTERVALS)
+            .map(|i| start + self.width * i as f64 / TRANSITION_INTERVALS as f64)
+            .collect();
+        FunctionDef::sampled(
+            |h| self.temperature(h).0,
+            |h| self.temperature(h).1,
+            &altitudes,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::Atmosphere;
+
+    #[test]
+    fn test_sharp_inversion() {
+        let inversion = Inversion {
+            base: 500.0,
+            thickness: 200.0,
+            jump: 4.0,
+            transition: None,
+        };
+        let standard = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        let atmosphere =
+            Atmosphere::try_from_def(AtmosphereDef::us_76().with_inversion(&inversion)).unwrap();
+        assert!((atmosphere.temperature(100.0) - standard.temperature(100.0)).abs() < 1e-9);
+        assert!((atmosphere.temperature(700.0) - atmosphere.temperature(500.0) - 4.0).abs() < 1e-9);
+        assert!((atmosphere.dtemperature(600.0) - 0.02).abs() < 1e-12);
+        let offset = atmosphere.temperature(700.0) - standard.temperature(700.0);
+        for h in [1e3, 12e3, 30e3] {
+            assert!((atmosphere.temperature(h) - standard.temperature(h) - offset).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_smooth_inversion() {
+        let inversion = Inversion {
+            base: 500.0,
+            thickness: 200.0,
+            jump: 4.0,
+            transition: Some(40.0),
+        };
+        let standard = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        let sharp = Atmosphere::try_from_def(AtmosphereDef::us_76().with_inversion(&Inversion {
+            transition: None,
+            ..inversion
+        }))
+        .unwrap();
+        let smooth =
+            Atmosphere::try_from_def(AtmosphereDef::us_76().with_inversion(&inversion)).unwrap();
+        assert!((smooth.temperature(470.0) - standard.temperature(470.0)).abs() < 1e-9);
+        assert!((smooth.dtemperature(600.0) - 0.02).abs() < 1e-12);
+        for h in [600.0, 1e3, 12e3] {
+            assert!((smooth.temperature(h) - sharp.temperature(h)).abs() < 1e-6);
+        }
+        for h in [500.0, 680.0, 1e3] {
+            assert!((smooth.pressure(h) / sharp.pressure(h) - 1.0).abs() < 1e-3);
+        }
+        // the gradient changes gradually in the transitions
+        let gradient = smooth.dtemperature(500.0);
+        assert!(gradient > -0.0065 + 0.005 && gradient < 0.02 - 0.005);
+        let gradient = smooth.dtemperature(700.0);
+        assert!(gradient > -0.0065 + 0.005 && gradient < 0.02 - 0.005);
+    }
+
+    #[test]
+    fn test_invalid_inversion() {
+        let inversion = Inversion {
+            thickness: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            AtmosphereDef::us_76().try_with_inversion(&inversion).err(),
+            Some(AtmosphereDefError::InvalidParameter {
+                name: "thickness",
+                value: 0.0
+            })
+        );
+        let inversion = Inversion {
+            transition: Some(f64::INFINITY),
+            ..Default::default()
+        };
+        let result = AtmosphereDef::us_76().try_with_inversion(&inversion);
+        assert!(matches!(
+            result,
+            Err(AtmosphereDefError::InvalidParameter {
+                name: "transition",
+                ..
+            })
+        ));
+
+        // a definition without a known temperature
+        let mut def = AtmosphereDef::us_76();
+        def.temperature_fixed_point = None;
+        let result = def.try_with_inversion(&Inversion::default());
+        assert!(matches!(result, Err(AtmosphereDefError::Profile { .. })));
+    }
+}
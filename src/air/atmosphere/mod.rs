@@ -1,17 +1,57 @@
+mod blend;
+mod builder;
+pub mod comparison;
+pub mod import;
+pub mod inversion;
+mod perturbation;
+pub mod presets;
 mod pressure_profile;
+mod reference;
+pub mod resampling;
+pub mod surface_layer;
+mod time_varying;
+pub mod turbulence;
+pub mod validation;
 pub mod vertical_profile;
 
+pub use self::builder::AtmosphereDefBuilder;
+pub use self::time_varying::TimeVaryingAtmosphere;
+pub use self::turbulence::TurbulenceProfile;
+
 use self::{
     pressure_profile::PressureProfile,
-    vertical_profile::{FunctionDef, VerticalProfile, VerticalProfileBuilder},
+    validation::AtmosphereDefError,
+    vertical_profile::{
+        FunctionDef, IntervalHint, VerticalProfile, VerticalProfileBuilder, VerticalProfileError,
+    },
 };
 
+use super::{d_vapor_pressure, dp_sv, p_sv, vapor_pressure};
+
 #[cfg(feature = "serialization")]
 use cubic_splines::BoundaryCondition;
 
 /// mu*g/R
 pub const A: f64 = 0.03416320331088684;
 
+/// The universal gas constant, in J/(mol*K)
+pub const GAS_CONSTANT: f64 = 8.31432;
+
+/// The standard gravitational acceleration at the Earth's surface, in m/s^2
+pub const EARTH_GRAVITY: f64 = 9.80665;
+
+/// The molar mass of dry air, in kg/mol
+pub const DRY_AIR_MOLAR_MASS: f64 = 0.0289644;
+
+/// The ratio of the molar mass of water vapor to the molar mass of dry air
+pub const EPSILON_VAPOR: f64 = 0.622;
+
+/// The ratio of the gas constant to the molar heat capacity of dry air at constant pressure
+pub const KAPPA: f64 = 2.0 / 7.0;
+
+/// The reference pressure of the potential temperature, in Pa
+pub const REFERENCE_PRESSURE: f64 = 100_000.0;
+
 #[derive(Clone, Copy, Debug)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub struct PressureFixedPoint {
@@ -40,6 +80,44 @@ pub struct HumidityFixedPoint {
     humidity: f64,
 }
 
+/// The quantity given by the humidity functions of an atmosphere definition
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum HumidityKind {
+    /// The relative humidity in percent
+    #[default]
+    Relative,
+    /// The dew point in K; it has to be positive, so dry air can't be described by it
+    DewPoint,
+    /// The mass mixing ratio of water vapor to dry air, in kg/kg
+    MixingRatio,
+}
+
+impl HumidityKind {
+    /// Returns the partial pressure of water vapor for the given value of the humidity, at the
+    /// given temperature and pressure
+    fn vapor_pressure(self, value: f64, temperature: f64, pressure: f64) -> f64 {
+        match self {
+            HumidityKind::Relative => vapor_pressure(value, temperature),
+            HumidityKind::DewPoint => p_sv(value),
+            HumidityKind::MixingRatio => value * pressure / (EPSILON_VAPOR + value),
+        }
+    }
+
+    /// Returns the derivative of the partial pressure of water vapor, given the humidity
+    /// (`value`), temperature (`temp`), pressure (`p`) and their derivatives
+    fn d_vapor_pressure(self, (value, dvalue): (f64, f64), temp: (f64, f64), p: (f64, f64)) -> f64 {
+        match self {
+            HumidityKind::Relative => d_vapor_pressure(value, temp.0, dvalue, temp.1),
+            HumidityKind::DewPoint => dp_sv(value) * dvalue,
+            HumidityKind::MixingRatio => {
+                let sum = EPSILON_VAPOR + value;
+                EPSILON_VAPOR * p.0 / sum / sum * dvalue + value / sum * p.1
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub struct AtmosphereDef {
@@ -58,6 +136,24 @@ pub struct AtmosphereDef {
     #[cfg_attr(feature = "serialization", serde(default))]
     next_humidity_functions: Vec<FunctionDefWithAlt>,
     humidity_fixed_point: Option<HumidityFixedPoint>,
+    /// The quantity given by the humidity functions and the humidity fixed point
+    #[cfg_attr(feature = "serialization", serde(default))]
+    humidity_kind: HumidityKind,
+
+    /// The gravitational acceleration in m/s^2
+    #[cfg_attr(feature = "serialization", serde(default = "default_gravity"))]
+    gravity: f64,
+    /// The molar mass of the gas in kg/mol
+    #[cfg_attr(feature = "serialization", serde(default = "default_molar_mass"))]
+    molar_mass: f64,
+    /// If set, gravity decreases with altitude as g0 * (R / (R + h))^2, where R is this radius;
+    /// the altitudes in the definition are then treated as geopotential altitudes
+    #[cfg_attr(feature = "serialization", serde(default))]
+    geopotential_radius: Option<f64>,
+    /// If set, the pressure profile is calculated with the virtual temperature, so that it
+    /// accounts for the water vapor given by the humidity profile
+    #[cfg_attr(feature = "serialization", serde(default))]
+    virtual_temperature: bool,
 }
 
 impl AtmosphereDef {
@@ -108,8 +204,104 @@ impl AtmosphereDef {
                 altitude: 0.0,
                 humidity: 0.0,
             }),
+            gravity: EARTH_GRAVITY,
+            molar_mass: DRY_AIR_MOLAR_MASS,
+            geopotential_radius: None,
+            virtual_temperature: false,
+            humidity_kind: HumidityKind::Relative,
         }
     }
+
+    /// Sets the gravitational acceleration (in m/s^2) used for calculating the pressure profile
+    pub fn with_gravity(mut self, gravity: f64) -> Self {
+        self.gravity = gravity;
+        self
+    }
+
+    /// Sets the molar mass of the gas (in kg/mol) used for calculating the pressure profile and
+    /// the density
+    pub fn with_molar_mass(mut self, molar_mass: f64) -> Self {
+        self.molar_mass = molar_mass;
+        self
+    }
+
+    /// Makes the gravity decrease with altitude as g0 * (R / (R + h))^2, where R is the given
+    /// planet radius in meters.
+    ///
+    /// This is done by treating the altitudes in the definition as geopotential altitudes, like
+    /// the US-1976 standard does.
+    pub fn with_variable_gravity(mut self, radius: f64) -> Self {
+        self.geopotential_radius = Some(radius);
+        self
+    }
+
+    /// Sets the quantity given by the humidity functions and the humidity fixed point. The
+    /// relative humidity of the atmosphere is calculated from it, using the temperature and
+    /// the pressure.
+    pub fn with_humidity_kind(mut self, humidity_kind: HumidityKind) -> Self {
+        self.humidity_kind = humidity_kind;
+        self
+    }
+
+    /// Sets whether the hydrostatic equation is integrated with the virtual temperature, making
+    /// the pressure profile consistent with the humidity profile. By default, the pressure is
+    /// calculated as if the air was dry.
+    pub fn with_virtual_temperature(mut self, virtual_temperature: bool) -> Self {
+        self.virtual_temperature = virtual_temperature;
+        self
+    }
+
+    fn temperature_profile(&self) -> VerticalProfile {
+        self.try_temperature_profile().unwrap()
+    }
+
+    fn humidity_profile(&self) -> VerticalProfile {
+        self.try_humidity_profile().unwrap()
+    }
+
+    fn try_temperature_profile(&self) -> Result<VerticalProfile, VerticalProfileError> {
+        build_profile(
+            &self.first_temperature_function,
+            &self.next_functions,
+            self.temperature_fixed_point
+                .map(|point| (point.altitude, point.temperature)),
+        )
+    }
+
+    fn try_humidity_profile(&self) -> Result<VerticalProfile, VerticalProfileError> {
+        build_profile(
+            &self.first_humidity_function,
+            &self.next_humidity_functions,
+            self.humidity_fixed_point
+                .map(|point| (point.altitude, point.humidity)),
+        )
+    }
+
+    /// Returns the temperature function in effect at the altitude `h`
+    fn temperature_function_at(&self, h: f64) -> &FunctionDef {
+        self.next_functions
+            .iter()
+            .rev()
+            .find(|fun_def| fun_def.altitude <= h)
+            .map_or(&self.first_temperature_function, |fun_def| {
+                &fun_def.function
+            })
+    }
+}
+
+fn build_profile(
+    first_function: &FunctionDef,
+    next_functions: &[FunctionDefWithAlt],
+    fixed_value: Option<(f64, f64)>,
+) -> Result<VerticalProfile, VerticalProfileError> {
+    let mut builder = VerticalProfileBuilder::new(first_function.clone());
+    if let Some((altitude, value)) = fixed_value {
+        builder = builder.with_fixed_value(altitude, value);
+    }
+    for fun_def in next_functions {
+        builder = builder.with_next_function(fun_def.altitude, fun_def.function.clone());
+    }
+    builder.build()
 }
 
 #[cfg(feature = "serialization")]
@@ -120,6 +312,16 @@ fn default_pressure() -> PressureFixedPoint {
     }
 }
 
+#[cfg(feature = "serialization")]
+fn default_gravity() -> f64 {
+    EARTH_GRAVITY
+}
+
+#[cfg(feature = "serialization")]
+fn default_molar_mass() -> f64 {
+    DRY_AIR_MOLAR_MASS
+}
+
 #[cfg(feature = "serialization")]
 fn default_first_humidity_function() -> FunctionDef {
     FunctionDef::Spline {
@@ -128,6 +330,16 @@ fn default_first_humidity_function() -> FunctionDef {
     }
 }
 
+/// The hints of the intervals of the profiles of an atmosphere, owned by a single integration and
+/// passed to the evaluations of the atmosphere along it - see `IntervalHint`
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AtmosphereHints {
+    temperature: IntervalHint,
+    pressure: IntervalHint,
+    vapor_correction: IntervalHint,
+    humidity: IntervalHint,
+}
+
 /// A structure representing an atmospheric model. It provides the temperature and density as
 /// functions of altitude
 #[derive(Debug, Clone)]
@@ -136,72 +348,302 @@ pub struct Atmosphere {
     pressure: PressureProfile,
     temperature: VerticalProfile,
     humidity: VerticalProfile,
+    #[cfg_attr(feature = "serialization", serde(default = "default_gravity"))]
+    gravity: f64,
+    #[cfg_attr(feature = "serialization", serde(default = "default_molar_mass"))]
+    molar_mass: f64,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    geopotential_radius: Option<f64>,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    virtual_temperature: bool,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    humidity_kind: HumidityKind,
+    #[cfg_attr(feature = "serialization", serde(default))]
+    turbulence: Option<TurbulenceProfile>,
 }
 
 impl Atmosphere {
     /// Creates the atmospheric model from a parsed definition.
+    ///
+    /// Panics if the definition is invalid.
+    #[deprecated(note = "use `Atmosphere::try_from_def`, which returns the errors")]
     pub fn from_def(def: AtmosphereDef) -> Atmosphere {
-        let mut builder = VerticalProfileBuilder::new(def.first_temperature_function);
-        if let Some(point) = def.temperature_fixed_point {
-            builder = builder.with_fixed_value(point.altitude, point.temperature);
-        }
-        for fun_def in def.next_functions {
-            builder = builder.with_next_function(fun_def.altitude, fun_def.function);
+        match Self::try_from_def(def) {
+            Ok(atmosphere) => atmosphere,
+            Err(error) => panic!("invalid atmosphere definition: {:?}", error),
         }
-        let temperature = builder.build().unwrap();
+    }
 
-        let mut builder = VerticalProfileBuilder::new(def.first_humidity_function);
-        if let Some(point) = def.humidity_fixed_point {
-            builder = builder.with_fixed_value(point.altitude, point.humidity);
-        }
-        for fun_def in def.next_humidity_functions {
-            builder = builder.with_next_function(fun_def.altitude, fun_def.function);
-        }
-        let humidity = builder.build().unwrap();
+    /// Creates the atmospheric model from a parsed definition, or returns the first problem
+    /// found in the definition; see `AtmosphereDef::validate`.
+    pub fn try_from_def(def: AtmosphereDef) -> Result<Atmosphere, AtmosphereDefError> {
+        let (temperature, humidity) = def.checked_profiles()?;
 
-        let pressure = PressureProfile::from_temperature_profile(
+        let mu_g_r = def.molar_mass * def.gravity / GAS_CONSTANT;
+        let mut pressure = PressureProfile::from_temperature_profile(
             &temperature,
             def.pressure.pressure,
             def.pressure.altitude,
+            mu_g_r,
         );
+        let sample_altitudes = def.sample_altitudes(&temperature);
+        if def.virtual_temperature {
+            pressure = pressure.with_vapor_correction(
+                &temperature,
+                |h, t, p| def.humidity_kind.vapor_pressure(humidity.eval(h), t, p),
+                &sample_altitudes,
+                def.pressure.altitude,
+                mu_g_r,
+            );
+        }
+        for h in sample_altitudes {
+            let value = pressure.eval(h);
+            if !value.is_finite() || value <= 0.0 {
+                return Err(AtmosphereDefError::InvalidPressureProfile {
+                    layer: def.layer_at(h),
+                    altitude: h,
+                    pressure: value,
+                });
+            }
+        }
 
-        Atmosphere {
+        Ok(Atmosphere {
             pressure,
             temperature,
             humidity,
+            gravity: def.gravity,
+            molar_mass: def.molar_mass,
+            geopotential_radius: def.geopotential_radius,
+            virtual_temperature: def.virtual_temperature,
+            humidity_kind: def.humidity_kind,
+            turbulence: None,
+        })
+    }
+
+    /// Returns the altitude at which the profiles should be evaluated, and its derivative with
+    /// respect to the geometric altitude `h`
+    #[inline]
+    fn profile_altitude(&self, h: f64) -> (f64, f64) {
+        match self.geopotential_radius {
+            Some(r) => (r * h / (r + h), r * r / (r + h) / (r + h)),
+            None => (h, 1.0),
+        }
+    }
+
+    /// Returns the altitudes at which the profiles should be evaluated for the geometric
+    /// altitudes `hs`
+    fn profile_altitudes(&self, hs: &[f64]) -> Vec<f64> {
+        hs.iter().map(|&h| self.profile_altitude(h).0).collect()
+    }
+
+    /// Returns the hydrostatic constant mu*g/R of the atmosphere at the surface, in K/m
+    pub fn hydrostatic_constant(&self) -> f64 {
+        self.molar_mass * self.gravity / GAS_CONSTANT
+    }
+
+    /// Returns the gravitational acceleration at the surface in m/s^2
+    pub fn gravity(&self) -> f64 {
+        self.gravity
+    }
+
+    /// Returns the gravitational acceleration at the given altitude in m/s^2
+    pub fn gravity_at(&self, h: f64) -> f64 {
+        self.gravity * self.profile_altitude(h).1
+    }
+
+    /// Returns the molar mass of the gas in kg/mol
+    pub fn molar_mass(&self) -> f64 {
+        self.molar_mass
+    }
+
+    /// Returns the same atmosphere, but with the relative humidity equal to 0 everywhere
+    pub fn without_humidity(self) -> Atmosphere {
+        Atmosphere {
+            humidity: VerticalProfile::constant(0.0),
+            humidity_kind: HumidityKind::Relative,
+            ..self
         }
     }
 
     /// Returns the temperature at the given altitude
     pub fn temperature(&self, h: f64) -> f64 {
-        self.temperature.eval(h)
+        self.temperature_with_hints(h, &AtmosphereHints::default())
+    }
+
+    pub(crate) fn temperature_with_hints(&self, h: f64, hints: &AtmosphereHints) -> f64 {
+        let (z, _) = self.profile_altitude(h);
+        self.temperature.eval_with_hint(z, &hints.temperature)
     }
 
     /// Returns the derivative of temperature with respect to altitude at the given altitude
     pub fn dtemperature(&self, h: f64) -> f64 {
-        self.temperature.eval_derivative(h)
+        self.dtemperature_with_hints(h, &AtmosphereHints::default())
+    }
+
+    pub(crate) fn dtemperature_with_hints(&self, h: f64, hints: &AtmosphereHints) -> f64 {
+        let (z, dz) = self.profile_altitude(h);
+        self.temperature
+            .eval_derivative_with_hint(z, &hints.temperature)
+            * dz
+    }
+
+    /// Returns the lapse rate - the rate at which the temperature decreases with altitude - at
+    /// the given altitude, in K/m
+    pub fn lapse_rate(&self, h: f64) -> f64 {
+        -self.dtemperature(h)
+    }
+
+    /// Returns the potential temperature at the given altitude: the temperature the air would
+    /// have if brought adiabatically to the reference pressure of 1000 hPa
+    pub fn potential_temperature(&self, h: f64) -> f64 {
+        self.temperature(h) * (REFERENCE_PRESSURE / self.pressure(h)).powf(KAPPA)
+    }
+
+    /// Returns the derivative of the potential temperature with respect to altitude at the given
+    /// altitude; the air is statically stable where it's positive
+    pub fn dpotential_temperature(&self, h: f64) -> f64 {
+        let t = self.temperature(h);
+        let p = self.pressure(h);
+        self.potential_temperature(h) * (self.dtemperature(h) / t - KAPPA * self.dpressure(h) / p)
     }
 
     /// Returns the pressure at the given altitude
     pub fn pressure(&self, h: f64) -> f64 {
-        self.pressure.eval(h)
+        self.pressure_with_hints(h, &AtmosphereHints::default())
+    }
+
+    pub(crate) fn pressure_with_hints(&self, h: f64, hints: &AtmosphereHints) -> f64 {
+        let (z, _) = self.profile_altitude(h);
+        self.pressure.eval_with_hints(z, hints)
     }
 
     /// Returns the derivative of pressure at the given altitude
     pub fn dpressure(&self, h: f64) -> f64 {
-        let p = self.pressure(h);
-        let t = self.temperature(h);
-        -A * p / t
+        self.dpressure_with_hints(h, &AtmosphereHints::default())
     }
 
-    /// Returns the temperature at the given altitude
+    pub(crate) fn dpressure_with_hints(&self, h: f64, hints: &AtmosphereHints) -> f64 {
+        let p = self.pressure_with_hints(h, hints);
+        let t = if self.virtual_temperature {
+            self.virtual_temperature_with_hints(h, hints)
+        } else {
+            self.temperature_with_hints(h, hints)
+        };
+        -self.molar_mass * self.gravity_at(h) / GAS_CONSTANT * p / t
+    }
+
+    /// Returns the virtual temperature at the given altitude: the temperature at which dry air
+    /// would have the same density as the moist air
+    pub fn virtual_temperature(&self, h: f64) -> f64 {
+        self.virtual_temperature_with_hints(h, &AtmosphereHints::default())
+    }
+
+    fn virtual_temperature_with_hints(&self, h: f64, hints: &AtmosphereHints) -> f64 {
+        let p = self.pressure_with_hints(h, hints);
+        let e = self.vapor_pressure_with_hints(h, hints);
+        self.temperature_with_hints(h, hints) / (1.0 - (1.0 - EPSILON_VAPOR) * e / p)
+    }
+
+    /// Returns the relative humidity in percent at the given altitude
     pub fn humidity(&self, h: f64) -> f64 {
-        self.humidity.eval(h)
+        self.humidity_with_hints(h, &AtmosphereHints::default())
     }
 
-    /// Returns the derivative of temperature with respect to altitude at the given altitude
+    pub(crate) fn humidity_with_hints(&self, h: f64, hints: &AtmosphereHints) -> f64 {
+        let (z, _) = self.profile_altitude(h);
+        self.relative_humidity_at(z, hints)
+    }
+
+    /// Returns the relative humidity in percent at the altitude `z` of the profiles
+    fn relative_humidity_at(&self, z: f64, hints: &AtmosphereHints) -> f64 {
+        let value = self.humidity.eval_with_hint(z, &hints.humidity);
+        match self.humidity_kind {
+            HumidityKind::Relative => value,
+            kind => {
+                let t = self.temperature.eval_with_hint(z, &hints.temperature);
+                let p = self.pressure.eval_with_hints(z, hints);
+                100.0 * kind.vapor_pressure(value, t, p) / p_sv(t)
+            }
+        }
+    }
+
+    /// Returns the derivative of the relative humidity with respect to altitude at the given
+    /// altitude
     pub fn dhumidity(&self, h: f64) -> f64 {
-        self.humidity.eval_derivative(h)
+        self.dhumidity_with_hints(h, &AtmosphereHints::default())
+    }
+
+    pub(crate) fn dhumidity_with_hints(&self, h: f64, hints: &AtmosphereHints) -> f64 {
+        let (z, dz) = self.profile_altitude(h);
+        let humidity = |z| self.humidity.eval_with_hint(z, &hints.humidity);
+        let dhumidity = |z| self.humidity.eval_derivative_with_hint(z, &hints.humidity) * dz;
+        match self.humidity_kind {
+            HumidityKind::Relative => dhumidity(z),
+            kind => {
+                let value = (humidity(z), dhumidity(z));
+                let t = self.temperature_with_hints(h, hints);
+                let dt = self.dtemperature_with_hints(h, hints);
+                let p = (
+                    self.pressure_with_hints(h, hints),
+                    self.dpressure_with_hints(h, hints),
+                );
+                let e = kind.vapor_pressure(value.0, t, p.0);
+                let de = kind.d_vapor_pressure(value, (t, dt), p);
+                let p_sat = p_sv(t);
+                100.0 * (de - e * dp_sv(t) * dt / p_sat) / p_sat
+            }
+        }
+    }
+
+    /// Returns the partial pressure of water vapor at the given altitude
+    pub fn vapor_pressure(&self, h: f64) -> f64 {
+        self.vapor_pressure_with_hints(h, &AtmosphereHints::default())
+    }
+
+    fn vapor_pressure_with_hints(&self, h: f64, hints: &AtmosphereHints) -> f64 {
+        let (z, _) = self.profile_altitude(h);
+        self.humidity_kind.vapor_pressure(
+            self.humidity.eval_with_hint(z, &hints.humidity),
+            self.temperature_with_hints(h, hints),
+            self.pressure_with_hints(h, hints),
+        )
+    }
+
+    /// Returns the temperatures at the given altitudes, evaluated layer by layer like in
+    /// `VerticalProfile::eval_many`
+    pub fn temperature_many(&self, hs: &[f64]) -> Vec<f64> {
+        self.temperature.eval_many(&self.profile_altitudes(hs))
+    }
+
+    /// Returns the pressures at the given altitudes, evaluated layer by layer like in
+    /// `VerticalProfile::eval_many`
+    pub fn pressure_many(&self, hs: &[f64]) -> Vec<f64> {
+        self.pressure.eval_many(&self.profile_altitudes(hs))
+    }
+
+    /// Returns the relative humidities in percent at the given altitudes, evaluated layer by
+    /// layer like in `VerticalProfile::eval_many`
+    pub fn humidity_many(&self, hs: &[f64]) -> Vec<f64> {
+        let zs = self.profile_altitudes(hs);
+        let values = self.humidity.eval_many(&zs);
+        match self.humidity_kind {
+            HumidityKind::Relative => values,
+            kind => {
+                let t = self.temperature.eval_many(&zs);
+                let p = self.pressure.eval_many(&zs);
+                (0..zs.len())
+                    .map(|i| 100.0 * kind.vapor_pressure(values[i], t[i], p[i]) / p_sv(t[i]))
+                    .collect()
+            }
+        }
+    }
+
+    /// Returns the density of the (moist) air at the given altitude, in kg/m^3
+    pub fn density(&self, h: f64) -> f64 {
+        let p = self.pressure(h);
+        let t = self.temperature(h);
+        let e = self.vapor_pressure(h);
+        (p - (1.0 - EPSILON_VAPOR) * e) * self.molar_mass / GAS_CONSTANT / t
     }
 }
 
@@ -210,18 +652,21 @@ impl Atmosphere {
 /// The temperatures are expressed in kelvins (K), and the pressure in hectopascals (hPa).
 pub fn us76_atmosphere() -> Atmosphere {
     let atm_def = AtmosphereDef::us_76();
-    Atmosphere::from_def(atm_def)
+    Atmosphere::try_from_def(atm_def).expect("the US-1976 atmosphere should be valid")
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_support::us76_atmosphere_env;
+    use crate::{EarthShape, Environment};
 
+    use self::{validation::Quantity, vertical_profile::Extrapolation};
     use cubic_splines::BoundaryCondition;
 
     #[test]
     fn test_us76() {
-        let atmosphere = Atmosphere::from_def(AtmosphereDef::us_76());
+        let atmosphere = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
         assert_eq!(atmosphere.pressure(0.0), 101325.0);
         assert_eq!(atmosphere.temperature(0.0), 288.0);
     }
@@ -247,7 +692,7 @@ mod test {
             temperature_fixed_point: None,
             ..AtmosphereDef::us_76()
         };
-        let atmosphere = Atmosphere::from_def(atmosphere_def);
+        let atmosphere = Atmosphere::try_from_def(atmosphere_def).unwrap();
         for i in 0..600 {
             let h = i as f64 * 0.5;
             println!(
@@ -258,4 +703,219 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn test_try_from_def() {
+        let mut def = AtmosphereDef::us_76();
+        def.next_functions[1].function = FunctionDef::Table {
+            points: vec![(20e3, 230.0), (25e3, 235.0), (30e3, 240.0)],
+            extrapolation: Extrapolation::Constant,
+        };
+        match Atmosphere::try_from_def(def) {
+            Err(AtmosphereDefError::Profile {
+                quantity: Quantity::Temperature,
+                error: VerticalProfileError::FixedPointConflict { index1, index2, .. },
+            }) => assert_eq!((index1, index2), (1, 2)),
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_custom_temperature() {
+        let linear = us76_atmosphere();
+        let custom = Atmosphere::try_from_def(AtmosphereDef {
+            first_temperature_function: FunctionDef::custom(|h| 288.0 - 0.0065 * h),
+            next_functions: vec![FunctionDefWithAlt {
+                altitude: 11e3,
+                function: FunctionDef::Linear { gradient: 0.0 },
+            }],
+            temperature_fixed_point: None,
+            ..AtmosphereDef::us_76()
+        })
+        .unwrap();
+        for h in [-100.0, 0.0, 500.0, 5e3, 11e3, 15e3] {
+            assert!((custom.temperature(h) - linear.temperature(h)).abs() < 1e-9);
+            assert!((custom.dtemperature(h) - linear.dtemperature(h)).abs() < 1e-6);
+            assert!((custom.pressure(h) / linear.pressure(h) - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_humidity_affects_refractive_index() {
+        let dry = Environment {
+            shape: EarthShape::Flat,
+            ..us76_atmosphere_env()
+        };
+        let humid = Environment {
+            atmosphere: Atmosphere::try_from_def(AtmosphereDef {
+                first_humidity_function: FunctionDef::Linear { gradient: -0.01 },
+                humidity_fixed_point: Some(HumidityFixedPoint {
+                    altitude: 0.0,
+                    humidity: 80.0,
+                }),
+                ..AtmosphereDef::us_76()
+            })
+            .unwrap(),
+            ..dry.clone()
+        };
+        // water vapor lowers the optical refractive index
+        assert!(humid.n(0.0) < dry.n(0.0));
+        assert!(humid.dn(0.0) != dry.dn(0.0));
+    }
+
+    #[test]
+    fn test_density() {
+        let atmosphere = us76_atmosphere();
+        assert!((atmosphere.density(0.0) - 1.225).abs() < 1e-3);
+        assert!((atmosphere.density(11e3) - 0.3639).abs() < 1e-3);
+
+        let humid = Atmosphere::try_from_def(AtmosphereDef {
+            humidity_fixed_point: Some(HumidityFixedPoint {
+                altitude: 0.0,
+                humidity: 100.0,
+            }),
+            ..AtmosphereDef::us_76()
+        })
+        .unwrap();
+        // moist air is lighter than dry air
+        assert!(humid.density(0.0) < atmosphere.density(0.0));
+    }
+
+    #[test]
+    fn test_potential_temperature() {
+        let atmosphere = us76_atmosphere();
+        assert!((atmosphere.lapse_rate(1000.0) - 0.0065).abs() < 1e-9);
+        // equal to the temperature at the reference pressure
+        let h = 110.9;
+        let expected = atmosphere.temperature(h);
+        assert!((atmosphere.potential_temperature(h) - expected).abs() < 0.02);
+        // the standard troposphere is stable, and the dry-adiabatic one is neutral
+        assert!(atmosphere.dpotential_temperature(5e3) > 0.0);
+        let gradient = -atmosphere.gravity() * DRY_AIR_MOLAR_MASS / GAS_CONSTANT * KAPPA;
+        let adiabatic = Atmosphere::try_from_def(
+            AtmosphereDefBuilder::new(FunctionDef::Linear { gradient })
+                .with_temperature(0.0, 288.0)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        let epsilon = 0.01;
+        for h in [0.0, 2e3, 8e3] {
+            assert!(adiabatic.dpotential_temperature(h).abs() < 1e-9);
+            let numerical = (atmosphere.potential_temperature(h + epsilon)
+                - atmosphere.potential_temperature(h - epsilon))
+                / 2.0
+                / epsilon;
+            assert!((atmosphere.dpotential_temperature(h) - numerical).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_gravity_and_molar_mass() {
+        let earth = us76_atmosphere();
+        assert!((earth.hydrostatic_constant() - A).abs() < 1e-7);
+
+        let low_gravity =
+            Atmosphere::try_from_def(AtmosphereDef::us_76().with_gravity(EARTH_GRAVITY / 2.0))
+                .unwrap();
+        let heavy_gas = Atmosphere::try_from_def(
+            AtmosphereDef::us_76().with_molar_mass(DRY_AIR_MOLAR_MASS * 2.0),
+        )
+        .unwrap();
+        // the scale height is inversely proportional to mu*g
+        assert!(low_gravity.pressure(5e3) > earth.pressure(5e3));
+        assert!(heavy_gas.pressure(5e3) < earth.pressure(5e3));
+        assert!((heavy_gas.density(0.0) - 2.0 * earth.density(0.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_variable_gravity() {
+        let constant = us76_atmosphere();
+        let variable =
+            Atmosphere::try_from_def(AtmosphereDef::us_76().with_variable_gravity(6_356_766.0))
+                .unwrap();
+
+        // US-1976 tabulated values at geometric altitudes (which assume 288.15 K at the surface)
+        assert!((variable.pressure(20e3) / 5529.3 - 1.0).abs() < 0.01);
+        assert!((variable.pressure(50e3) / 79.779 - 1.0).abs() < 0.01);
+        assert!((variable.temperature(50e3) - 270.65).abs() < 0.2);
+        assert!(variable.pressure(50e3) > constant.pressure(50e3));
+
+        // the hydrostatic equation still holds
+        let epsilon = 0.01;
+        let h = 30e3;
+        let numerical =
+            (variable.pressure(h + epsilon) - variable.pressure(h - epsilon)) / 2.0 / epsilon;
+        assert!((variable.dpressure(h) - numerical).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_virtual_temperature() {
+        let dry = Atmosphere::try_from_def(AtmosphereDef::tropical()).unwrap();
+        let moist =
+            Atmosphere::try_from_def(AtmosphereDef::tropical().with_virtual_temperature(true))
+                .unwrap();
+        assert!((moist.pressure(0.0) - 101300.0).abs() < 1e-6);
+        // the lighter moist air makes the pressure decrease slower
+        let ratio = moist.pressure(5e3) / dry.pressure(5e3);
+        assert!(ratio > 1.001 && ratio < 1.01, "{}", ratio);
+        assert!(moist.virtual_temperature(0.0) > moist.temperature(0.0) + 2.0);
+        assert!((moist.virtual_temperature(40e3) - moist.temperature(40e3)).abs() < 1e-3);
+
+        // the hydrostatic equation holds with the density of the moist air
+        let epsilon = 0.01;
+        for h in [50.0, 1500.0, 8e3] {
+            let numerical =
+                (moist.pressure(h + epsilon) - moist.pressure(h - epsilon)) / 2.0 / epsilon;
+            assert!((moist.dpressure(h) - numerical).abs() < 1e-5);
+            assert!((moist.dpressure(h) + moist.density(h) * moist.gravity_at(h)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_humidity_kinds() {
+        let builder = AtmosphereDefBuilder::new(FunctionDef::Linear { gradient: -0.0065 })
+            .with_temperature(0.0, 288.0);
+        let dew_point = builder
+            .clone()
+            .with_first_humidity_function(FunctionDef::Linear { gradient: -0.002 })
+            .with_humidity(0.0, 280.0)
+            .with_humidity_kind(HumidityKind::DewPoint)
+            .build()
+            .unwrap();
+        let dew_point = Atmosphere::try_from_def(dew_point).unwrap();
+        assert!((dew_point.vapor_pressure(0.0) - p_sv(280.0)).abs() < 1e-9);
+        let expected = 100.0 * p_sv(280.0) / p_sv(288.0);
+        assert!((dew_point.humidity(0.0) - expected).abs() < 1e-9);
+
+        let mixing_ratio = builder
+            .with_first_humidity_function(FunctionDef::Table {
+                points: vec![(0.0, 0.008), (3e3, 0.002)],
+                extrapolation: Default::default(),
+            })
+            .with_humidity_kind(HumidityKind::MixingRatio)
+            .build()
+            .unwrap();
+        let mixing_ratio = Atmosphere::try_from_def(mixing_ratio).unwrap();
+        let p = mixing_ratio.pressure(0.0);
+        let expected = 0.008 * p / (EPSILON_VAPOR + 0.008);
+        assert!((mixing_ratio.vapor_pressure(0.0) - expected).abs() < 1e-9);
+
+        let epsilon = 0.01;
+        for atmosphere in [dew_point, mixing_ratio] {
+            for h in [100.0, 1500.0] {
+                let numerical = (atmosphere.humidity(h + epsilon)
+                    - atmosphere.humidity(h - epsilon))
+                    / 2.0
+                    / epsilon;
+                assert!((atmosphere.dhumidity(h) - numerical).abs() < 1e-8);
+            }
+
+            let hs = [3e3, 100.0, -50.0, 1500.0, 20e3, 1500.0];
+            let humidity: Vec<f64> = hs.iter().map(|&h| atmosphere.humidity(h)).collect();
+            let pressure: Vec<f64> = hs.iter().map(|&h| atmosphere.pressure(h)).collect();
+            assert_eq!(atmosphere.humidity_many(&hs), humidity);
+            assert_eq!(atmosphere.pressure_many(&hs), pressure);
+        }
+    }
 }
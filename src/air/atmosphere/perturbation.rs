@@ -0,0 +1,322 @@
+//! Modifications of the temperature profiles of existing definitions, e.g. for studying the
+//! sensitivity of the results to the temperatures.
+
+use super::{
+    validation::{validate_function, AtmosphereDefError, Quantity},
+    vertical_profile::{FunctionDef, VerticalProfile, VerticalProfileBuilder},
+    AtmosphereDef, FunctionDefWithAlt, TemperatureFixedPoint,
+};
+use std::ops::Range;
+
+/// Returns an error if the ends of the range aren't finite
+fn check_range(range: &Range<f64>) -> Result<(), AtmosphereDefError> {
+    for (name, value) in [("range start", range.start), ("range end", range.end)] {
+        if !value.is_finite() {
+            return Err(AtmosphereDefError::InvalidParameter { name, value });
+        }
+    }
+    Ok(())
+}
+
+impl AtmosphereDef {
+    /// Returns the same definition with all the temperatures increased by `offset` kelvins.
+    ///
+    /// This only shifts the functions, so it never fails; a non-finite offset makes an invalid
+    /// definition, which is reported by `validate` and `Atmosphere::try_from_def`.
+    pub fn with_temperature_offset(self, offset: f64) -> Self {
+        AtmosphereDef {
+            first_temperature_function: self.first_temperature_function.shifted(offset),
+            next_functions: self
+                .next_functions
+                .into_iter()
+                .map(|fun_def| FunctionDefWithAlt {
+                    altitude: fun_def.altitude,
+                    function: fun_def.function.shifted(offset),
+                })
+                .collect(),
+            temperature_fixed_point: self.temperature_fixed_point.map(|point| {
+                TemperatureFixedPoint {
+                    altitude: point.altitude,
+                    temperature: point.temperature + offset,
+                }
+            }),
+            ..self
+        }
+    }
+
+    /// Multiplies the temperature gradient within the given range of altitudes by `factor`.
+    ///
+    /// The temperatures below the range stay the same, and the profile above it is shifted so
+    /// that it continues from the top of the range.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the temperature profile of the definition can't be built or the parameters are
+    /// invalid; see `try_with_scaled_gradient`.
+    pub fn with_scaled_gradient(self, factor: f64, range: Range<f64>) -> Self {
+        match self.try_with_scaled_gradient(factor, range) {
+            Ok(def) => def,
+            Err(error) => panic!("couldn't scale the temperature gradient: {:?}", error),
+        }
+    }
+
+    /// Multiplies the temperature gradient within the given range of altitudes like
+    /// `with_scaled_gradient`, or returns the problem that prevents it.
+    ///
+    /// The temperature functions of the definition have to make a valid profile, and the factor
+    /// and the ends of the range have to be finite.
+    pub fn try_with_scaled_gradient(
+        self,
+        factor: f64,
+        range: Range<f64>,
+    ) -> Result<Self, AtmosphereDefError> {
+        if !factor.is_finite() {
+            return Err(AtmosphereDefError::InvalidParameter {
+                name: "factor",
+                value: factor,
+            });
+        }
+        check_range(&range)?;
+        if range.is_empty() {
+            return Ok(self);
+        }
+        let profile = self.checked_temperature_profile()?;
+        let bottom_temperature = profile.eval(range.start);
+        let top_temperature =
+            bottom_temperature + factor * (profile.eval(range.end) - bottom_temperature);
+
+        let mut functions = vec![FunctionDefWithAlt {
+            altitude: range.start,
+            function: self.temperature_function_at(range.start).clone(),
+        }];
+        functions.extend(
+            self.next_functions
+                .iter()
+                .filter(|fun_def| range.start < fun_def.altitude && fun_def.altitude < range.end)
+                .cloned(),
+        );
+        for fun_def in &mut functions {
+            fun_def.function = fun_def.function.clone().scaled(factor, bottom_temperature);
+        }
+        Ok(self.with_temperature_layers(
+            &profile,
+            range,
+            functions,
+            bottom_temperature,
+            top_temperature,
+        ))
+    }
+
+    /// Replaces the temperature within the given range of altitudes with the function.
+    ///
+    /// A function with its own values (a spline, a table or a custom function) is shifted so
+    /// that it continues from the temperature below the range. The temperatures below the range
+    /// stay the same, and the profile above it is shifted so that it continues from the top of
+    /// the range.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the temperature profile of the definition can't be built, the function is
+    /// invalid or the ends of the range aren't finite; see `try_with_layer_replaced`.
+    pub fn with_layer_replaced(self, range: Range<f64>, function: FunctionDef) -> Self {
+        match self.try_with_layer_replaced(range, function) {
+            Ok(def) => def,
+            Err(error) => panic!("couldn't replace the temperature layer: {:?}", error),
+        }
+    }
+
+    /// Replaces the temperature within the given range of altitudes like `with_layer_replaced`,
+    /// or returns the problem that prevents it.
+    ///
+    /// The temperature functions of the definition have to make a valid profile, the points of
+    /// the function (if it has any) have to be valid, and the ends of the range have to be
+    /// finite.
+    pub fn try_with_layer_replaced(
+        self,
+        range: Range<f64>,
+        function: FunctionDef,
+    ) -> Result<Self, AtmosphereDefError> {
+        check_range(&range)?;
+        if range.is_empty() {
+            return Ok(self);
+        }
+        // the index the function gets among the layers of the definition
+        let layer = 1 + self
+            .next_functions
+            .iter()
+            .filter(|fun_def| fun_def.altitude < range.start)
+            .count();
+        validate_function(Quantity::Temperature, layer, &function)?;
+        let profile = self.checked_temperature_profile()?;
+        let bottom_temperature = profile.eval(range.start);
+        let function = match VerticalProfileBuilder::new(function.clone()).build() {
+            Ok(own_profile) => function.shifted(bottom_temperature - own_profile.eval(range.start)),
+            // a linear function, which gets its value from the fixed point
+            Err(_) => function,
+        };
+        let top_temperature = VerticalProfileBuilder::new(function.clone())
+            .with_fixed_value(range.start, bottom_temperature)
+            .build()
+            .map_err(|error| AtmosphereDefError::Profile {
+                quantity: Quantity::Temperature,
+                error,
+            })?
+            .eval(range.end);
+
+        let functions = vec![FunctionDefWithAlt {
+            altitude: range.start,
+            function,
+        }];
+        Ok(self.with_temperature_layers(
+            &profile,
+            range,
+            functions,
+            bottom_temperature,
+            top_temperature,
+        ))
+    }
+
+    /// Puts the functions in place of the temperature functions within the range, which start
+    /// at `bottom_temperature` and end at `top_temperature`; `profile` is the current temperature
+    /// profile
+    fn with_temperature_layers(
+        self,
+        profile: &VerticalProfile,
+        range: Range<f64>,
+        functions: Vec<FunctionDefWithAlt>,
+        bottom_temperature: f64,
+        top_temperature: f64,
+    ) -> Self {
+        let offset = top_temperature - profile.eval(range.end);
+        let mut next_functions: Vec<_> = self
+            .next_functions
+            .iter()
+            .filter(|fun_def| fun_def.altitude < range.start)
+            .cloned()
+            .collect();
+        next_functions.extend(functions);
+        next_functions.push(FunctionDefWithAlt {
+            altitude: range.end,
+            function: self
+                .temperature_function_at(range.end)
+                .clone()
+                .shifted(offset),
+        });
+        next_functions.extend(
+            self.next_functions
+                .iter()
+                .filter(|fun_def| fun_def.altitude > range.end)
+                .map(|fun_def| FunctionDefWithAlt {
+                    altitude: fun_def.altitude,
+                    function: fun_def.function.clone().shifted(offset),
+                }),
+        );
+        AtmosphereDef {
+            next_functions,
+            temperature_fixed_point: Some(TemperatureFixedPoint {
+                altitude: range.start,
+                temperature: bottom_temperature,
+            }),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::Atmosphere;
+
+    #[test]
+    fn test_temperature_offset() {
+        let standard = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        let warmer =
+            Atmosphere::try_from_def(AtmosphereDef::us_76().with_temperature_offset(1.0)).unwrap();
+        for h in [0.0, 5e3, 15e3, 40e3] {
+            assert!((warmer.temperature(h) - standard.temperature(h) - 1.0).abs() < 1e-9);
+        }
+        assert!(warmer.pressure(5e3) > standard.pressure(5e3));
+
+        let mirage = AtmosphereDef::inferior_mirage(10.0);
+        let original = Atmosphere::try_from_def(mirage.clone()).unwrap();
+        let warmer = Atmosphere::try_from_def(mirage.with_temperature_offset(-2.0)).unwrap();
+        for h in [0.0, 0.5, 3.0, 100.0] {
+            assert!((warmer.temperature(h) - original.temperature(h) + 2.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_scaled_gradient() {
+        let standard = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        let def = AtmosphereDef::us_76().with_scaled_gradient(2.0, 5e3..15e3);
+        assert_eq!(def.validate(), Ok(()));
+        let scaled = Atmosphere::try_from_def(def).unwrap();
+        assert!((scaled.temperature(1e3) - standard.temperature(1e3)).abs() < 1e-9);
+        assert!((scaled.dtemperature(8e3) + 0.013).abs() < 1e-9);
+        assert!((scaled.dtemperature(12e3) - 0.0).abs() < 1e-9);
+        // the lowering of the temperature at the top of the range carries over above it
+        let difference = standard.temperature(11e3) - standard.temperature(5e3);
+        for h in [15e3, 25e3] {
+            let expected = standard.temperature(h) + difference;
+            assert!((scaled.temperature(h) - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_layer_replaced() {
+        let standard = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        let def = AtmosphereDef::us_76()
+            .with_layer_replaced(100.0..300.0, FunctionDef::Linear { gradient: 0.02 });
+        let replaced = Atmosphere::try_from_def(def).unwrap();
+        assert!((replaced.temperature(50.0) - standard.temperature(50.0)).abs() < 1e-9);
+        assert!((replaced.temperature(300.0) - standard.temperature(100.0) - 4.0).abs() < 1e-9);
+        assert!((replaced.dtemperature(1e3) + 0.0065).abs() < 1e-9);
+
+        let table = FunctionDef::Table {
+            points: vec![(0.0, 0.0), (100.0, 3.0)],
+            extrapolation: Default::default(),
+        };
+        let def = AtmosphereDef::us_76().with_layer_replaced(0.0..200.0, table);
+        let replaced = Atmosphere::try_from_def(def).unwrap();
+        assert!((replaced.temperature(0.0) - 288.0).abs() < 1e-9);
+        assert!((replaced.temperature(150.0) - 291.0).abs() < 1e-9);
+        assert!((replaced.temperature(1200.0) - (291.0 - 6.5)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_invalid_modifications() {
+        // a table without points can't define the temperature
+        let empty_table = FunctionDef::Table {
+            points: vec![],
+            extrapolation: Default::default(),
+        };
+        let result = AtmosphereDef::us_76().try_with_layer_replaced(12e3..15e3, empty_table);
+        assert_eq!(
+            result.err(),
+            Some(AtmosphereDefError::InvalidPoints {
+                quantity: Quantity::Temperature,
+                layer: 2
+            })
+        );
+        let result = AtmosphereDef::us_76()
+            .try_with_layer_replaced(0.0..f64::NAN, FunctionDef::Linear { gradient: 0.0 });
+        assert!(matches!(
+            result,
+            Err(AtmosphereDefError::InvalidParameter {
+                name: "range end",
+                ..
+            })
+        ));
+
+        let result = AtmosphereDef::us_76().try_with_scaled_gradient(f64::INFINITY, 0.0..1e3);
+        assert!(matches!(
+            result,
+            Err(AtmosphereDefError::InvalidParameter { name: "factor", .. })
+        ));
+        // a definition without a known temperature
+        let mut def = AtmosphereDef::us_76();
+        def.temperature_fixed_point = None;
+        let result = def.try_with_scaled_gradient(2.0, 0.0..1e3);
+        assert!(matches!(result, Err(AtmosphereDefError::Profile { .. })));
+    }
+}
@@ -0,0 +1,260 @@
+//! Ready-made planet shapes and atmospheres.
+//!
+//! The atmospheres of other planets are rough models of the lower atmosphere, with the surface
+//! pressure, temperature lapse rates, gravity and mean molar mass taken from the literature.
+//! Note that the refractive index formulas in this crate assume Earth's air, so for other
+//! planets they only give approximate results.
+
+use super::{
+    inversion::Inversion, vertical_profile::FunctionDef, AtmosphereDef, FunctionDefWithAlt,
+    HumidityFixedPoint, HumidityKind, PressureFixedPoint, TemperatureFixedPoint,
+    DRY_AIR_MOLAR_MASS, EARTH_GRAVITY,
+};
+use crate::EarthShape;
+
+/// The temperature gradient of the standard troposphere in K/m
+const STANDARD_GRADIENT: f64 = -0.0065;
+/// The height in meters over which the excess temperature of the hot surface decays e-fold in
+/// the inferior mirage preset
+const MIRAGE_LAYER_SCALE: f64 = 1.0;
+/// The altitudes (in meters) at which the surface layer of the inferior mirage preset is
+/// sampled; above the last one, the excess temperature is negligible
+const MIRAGE_LAYER_ALTITUDES: [f64; 12] =
+    [0.0, 0.1, 0.25, 0.5, 1.0, 1.5, 2.0, 3.0, 4.0, 6.0, 8.0, 12.0];
+
+/// The mean radius of the Earth in meters
+pub const EARTH_RADIUS: f64 = 6_371_000.0;
+/// The mean radius of Mars in meters
+pub const MARS_RADIUS: f64 = 3_389_500.0;
+/// The mean radius of Venus in meters
+pub const VENUS_RADIUS: f64 = 6_051_800.0;
+/// The mean radius of Titan in meters
+pub const TITAN_RADIUS: f64 = 2_574_730.0;
+
+fn dry_atmosphere(
+    surface_pressure: f64,
+    surface_temperature: f64,
+    first_gradient: f64,
+    next_gradients: &[(f64, f64)],
+    gravity: f64,
+    molar_mass: f64,
+) -> AtmosphereDef {
+    AtmosphereDef {
+        pressure: PressureFixedPoint {
+            altitude: 0.0,
+            pressure: surface_pressure,
+        },
+        first_temperature_function: FunctionDef::Linear {
+            gradient: first_gradient,
+        },
+        next_functions: next_gradients
+            .iter()
+            .map(|&(altitude, gradient)| FunctionDefWithAlt {
+                altitude,
+                function: FunctionDef::Linear { gradient },
+            })
+            .collect(),
+        temperature_fixed_point: Some(TemperatureFixedPoint {
+            altitude: 0.0,
+            temperature: surface_temperature,
+        }),
+        first_humidity_function: FunctionDef::Linear { gradient: 0.0 },
+        next_humidity_functions: vec![],
+        humidity_fixed_point: Some(HumidityFixedPoint {
+            altitude: 0.0,
+            humidity: 0.0,
+        }),
+        gravity,
+        molar_mass,
+        geopotential_radius: None,
+        virtual_temperature: false,
+        humidity_kind: HumidityKind::Relative,
+    }
+}
+
+/// The spherical Earth with the US-1976 standard atmosphere
+pub fn earth() -> (EarthShape, AtmosphereDef) {
+    (
+        EarthShape::Spherical {
+            radius: EARTH_RADIUS,
+        },
+        AtmosphereDef::us_76(),
+    )
+}
+
+/// The WGS84 ellipsoid observed from the given latitude in the direction of the given azimuth
+/// (both in radians), with the US-1976 standard atmosphere, including the decrease of gravity
+/// with altitude
+pub fn earth_wgs84(latitude: f64, azimuth: f64) -> (EarthShape, AtmosphereDef) {
+    (
+        EarthShape::wgs84(latitude, azimuth),
+        AtmosphereDef::us_76().with_variable_gravity(EARTH_RADIUS),
+    )
+}
+
+/// The flat Earth with the US-1976 standard atmosphere
+pub fn flat_earth() -> (EarthShape, AtmosphereDef) {
+    (EarthShape::Flat, AtmosphereDef::us_76())
+}
+
+/// Mars, with the atmosphere based on the NASA Glenn Research Center model
+pub fn mars() -> (EarthShape, AtmosphereDef) {
+    (
+        EarthShape::Spherical {
+            radius: MARS_RADIUS,
+        },
+        dry_atmosphere(699.0, 242.15, -0.000998, &[(7e3, -0.00222)], 3.721, 0.04334),
+    )
+}
+
+/// Venus, with the atmosphere based on the Venus International Reference Atmosphere
+pub fn venus() -> (EarthShape, AtmosphereDef) {
+    (
+        EarthShape::Spherical {
+            radius: VENUS_RADIUS,
+        },
+        dry_atmosphere(9.2e6, 737.0, -0.0078, &[(60e3, -0.0025)], 8.87, 0.04345),
+    )
+}
+
+/// Titan, with the atmosphere based on the Huygens probe measurements
+pub fn titan() -> (EarthShape, AtmosphereDef) {
+    (
+        EarthShape::Spherical {
+            radius: TITAN_RADIUS,
+        },
+        dry_atmosphere(146_700.0, 93.7, -0.00053, &[(44e3, 0.0012)], 1.352, 0.0276),
+    )
+}
+
+/// An Earth atmosphere with the given surface temperature and a constant temperature gradient,
+/// useful for simple experiments. Only valid at altitudes where the temperature stays positive.
+pub fn earth_constant_gradient(
+    surface_temperature: f64,
+    gradient: f64,
+) -> (EarthShape, AtmosphereDef) {
+    (
+        EarthShape::Spherical {
+            radius: EARTH_RADIUS,
+        },
+        dry_atmosphere(
+            101_325.0,
+            surface_temperature,
+            gradient,
+            &[],
+            EARTH_GRAVITY,
+            DRY_AIR_MOLAR_MASS,
+        ),
+    )
+}
+
+impl AtmosphereDef {
+    /// The US-1976 standard atmosphere above a surface hotter than the air by
+    /// `surface_excess_temp` kelvins, like a sunlit road or desert - the conditions of the
+    /// inferior mirage.
+    ///
+    /// The excess temperature decays exponentially with the altitude, e-fold every meter, which
+    /// gives gradients of several kelvins per meter near the surface. The temperature of the
+    /// air far above the surface is the standard one.
+    pub fn inferior_mirage(surface_excess_temp: f64) -> Self {
+        let us_76 = Self::us_76();
+        let temperature = |h: f64| {
+            288.0 + STANDARD_GRADIENT * h + surface_excess_temp * (-h / MIRAGE_LAYER_SCALE).exp()
+        };
+        let gradient = |h: f64| {
+            STANDARD_GRADIENT
+                - surface_excess_temp / MIRAGE_LAYER_SCALE * (-h / MIRAGE_LAYER_SCALE).exp()
+        };
+        let top = MIRAGE_LAYER_ALTITUDES[MIRAGE_LAYER_ALTITUDES.len() - 1];
+
+        let mut next_functions = vec![FunctionDefWithAlt {
+            altitude: top,
+            function: FunctionDef::Linear {
+                gradient: STANDARD_GRADIENT,
+            },
+        }];
+        next_functions.extend(us_76.next_functions);
+        AtmosphereDef {
+            first_temperature_function: FunctionDef::sampled(
+                temperature,
+                gradient,
+                &MIRAGE_LAYER_ALTITUDES,
+            ),
+            next_functions,
+            // the spline fixes the temperatures
+            temperature_fixed_point: None,
+            ..us_76
+        }
+    }
+
+    /// The US-1976 standard atmosphere with a temperature inversion - the conditions of the
+    /// superior mirage and of ducting.
+    ///
+    /// The temperature rises linearly by `delta_t` kelvins between `inversion_base` and
+    /// `inversion_base + thickness` meters, and follows the standard gradient below and above
+    /// the inversion. The standard layers above the inversion are kept, so the inversion should
+    /// lie in the troposphere (below 11 km).
+    pub fn superior_mirage(inversion_base: f64, thickness: f64, delta_t: f64) -> Self {
+        Self::us_76().with_inversion(&Inversion {
+            base: inversion_base,
+            thickness,
+            jump: delta_t,
+            transition: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::env_with;
+    use crate::{air::Atmosphere, Environment, Path};
+
+    #[test]
+    fn test_surface_densities() {
+        let density = |(_, def): (EarthShape, AtmosphereDef)| {
+            Atmosphere::try_from_def(def).unwrap().density(0.0)
+        };
+        assert!((density(earth()) - 1.225).abs() < 0.01);
+        assert!((density(mars()) - 0.015).abs() < 0.002);
+        assert!((density(venus()) - 65.0).abs() < 1.0);
+        assert!((density(titan()) - 5.3).abs() < 0.2);
+    }
+
+    fn environment(atmosphere: AtmosphereDef) -> Environment {
+        env_with(Atmosphere::try_from_def(atmosphere).unwrap())
+    }
+
+    #[test]
+    fn test_inferior_mirage() {
+        let env = environment(AtmosphereDef::inferior_mirage(10.0));
+        assert!((env.atmosphere.temperature(0.0) - 298.0).abs() < 1e-6);
+        assert!((env.atmosphere.temperature(100.0) - 287.35).abs() < 1e-3);
+        // the density increases with altitude near the surface
+        assert!(env.atmosphere.dtemperature(0.5) < -1.0);
+
+        // a ray aimed at the ground turns back up above it
+        let path = env.cast_ray(1.5, -0.002, false);
+        let lowest = (1..2000)
+            .map(|i| path.h_at_dist(i as f64))
+            .fold(f64::INFINITY, f64::min);
+        assert!(lowest > 0.0 && lowest < 1.0);
+        assert!(path.h_at_dist(2000.0) > 1.5);
+    }
+
+    #[test]
+    fn test_superior_mirage() {
+        let def = AtmosphereDef::superior_mirage(10.0, 20.0, 6.0);
+        let env = environment(def);
+        let temperature = |h| env.atmosphere.temperature(h);
+        assert!((temperature(30.0) - temperature(10.0) - 6.0).abs() < 1e-9);
+        // the standard stratosphere is kept
+        assert!((temperature(15e3) - temperature(12e3)).abs() < 1e-9);
+
+        // a horizontal ray inside the inversion bends down towards the surface
+        let path = env.cast_ray(20.0, 0.0, false);
+        assert!(path.h_at_dist(5e3) < 20.0);
+        let standard = environment(AtmosphereDef::us_76());
+        assert!(standard.cast_ray(20.0, 0.0, false).h_at_dist(5e3) > 20.0);
+    }
+}
@@ -1,13 +1,31 @@
 use std::collections::BTreeMap;
 
 use super::{
-    vertical_profile::{VerticalFunction, VerticalProfile},
-    A,
+    vertical_profile::{
+        CustomFunction, FunctionDef, IntervalHint, VerticalFunction, VerticalProfile,
+        VerticalProfileBuilder,
+    },
+    AtmosphereHints, EPSILON_VAPOR,
 };
 
-use cubic_splines::Factors;
+use cubic_splines::{BoundaryCondition, CubicPoly, Factors};
+
+/// The relative size of the highest-order term of a polynomial temperature, over the altitude
+/// scale, below which the term is neglected; the factorization of the polynomial is inaccurate
+/// in that case
+const DEGENERATE_TOLERANCE: f64 = 1e-6;
+/// The smallest altitude scale in meters used for deciding whether a term can be neglected
+const ALTITUDE_SCALE: f64 = 1e4;
+
+/// The absolute tolerance of the numerical integration of 1/T, in m/K
+const INTEGRATION_TOLERANCE: f64 = 1e-10;
+/// The maximal depth of the subdivisions in the numerical integration
+const MAX_INTEGRATION_DEPTH: u32 = 40;
+/// The maximal distance in meters between the altitudes at which the correction for the water
+/// vapor is calculated
+const MAX_VAPOR_STEP: f64 = 100.0;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub enum PressureFunction {
     /// p0 * exp(lambda * (h-h0))
@@ -37,6 +55,15 @@ pub enum PressureFunction {
         a3: f64,
         b3: f64,
     },
+    /// p0 * exp(-mu_g_r * integral of 1/T(h) from h0 to h), integrated numerically
+    /// Used when the temperature is a custom function
+    #[cfg_attr(feature = "serialization", serde(skip))]
+    Numeric {
+        p0: f64,
+        h0: f64,
+        mu_g_r: f64,
+        temperature: CustomFunction,
+    },
 }
 
 impl PressureFunction {
@@ -65,102 +92,224 @@ impl PressureFunction {
                     * (1.0 + b2 * (h - h0) + a2 * (h - h0) * (h - h0)).powf(exp2)
                     * (lambda * ((h - h0) / (a3 * (h - h0) + b3)).atan()).exp()
             }
+            PressureFunction::Numeric {
+                p0,
+                h0,
+                mu_g_r,
+                ref temperature,
+            } => {
+                let integral = integrate(&|h| 1.0 / temperature.eval(h), h0, h);
+                p0 * (-mu_g_r * integral).exp()
+            }
         }
     }
 
-    pub fn from_temperature_function(temp_function: &VerticalFunction, p0: f64, h0: f64) -> Self {
+    /// Writes the values of the function at the altitudes `hs` to `out`
+    fn eval_into(&self, hs: &[f64], out: &mut [f64]) {
+        match *self {
+            PressureFunction::Exponential { p0, h0, lambda } => {
+                for (p, &h) in out.iter_mut().zip(hs) {
+                    *p = p0 * (lambda * (h - h0)).exp();
+                }
+            }
+            PressureFunction::Power { p0, h0, a, exp } => {
+                for (p, &h) in out.iter_mut().zip(hs) {
+                    *p = p0 * (1.0 + a * (h - h0)).powf(exp);
+                }
+            }
+            _ => {
+                for (p, &h) in out.iter_mut().zip(hs) {
+                    *p = self.eval(h);
+                }
+            }
+        }
+    }
+
+    /// Calculates the pressure function for the given temperature function, with the pressure
+    /// `p0` at the altitude `h0`. `mu_g_r` is the hydrostatic constant mu*g/R.
+    pub fn from_temperature_function(
+        temp_function: &VerticalFunction,
+        p0: f64,
+        h0: f64,
+        mu_g_r: f64,
+    ) -> Self {
         match *temp_function {
+            VerticalFunction::Custom(ref temperature) => PressureFunction::Numeric {
+                p0,
+                h0,
+                mu_g_r,
+                temperature: temperature.clone(),
+            },
             VerticalFunction::Linear { a, b } => {
                 if a == 0.0 {
                     PressureFunction::Exponential {
                         p0,
                         h0,
-                        lambda: -A / b,
+                        lambda: -mu_g_r / b,
                     }
                 } else {
                     PressureFunction::Power {
                         p0,
                         h0,
                         a: a / (a * h0 + b),
-                        exp: -A / a,
+                        exp: -mu_g_r / a,
                     }
                 }
             }
-            VerticalFunction::Cubic(poly) => match poly.factors() {
-                Factors::ThreeLinear {
-                    a,
-                    x1: h1,
-                    x2: h2,
-                    x3: h3,
-                } => {
-                    let v = [
-                        1.0 / (h1 - h2) / (h1 - h3),
-                        1.0 / (h2 - h1) / (h2 - h3),
-                        1.0 / (h3 - h1) / (h3 - h2),
-                    ];
-                    let exp = [-A * v[0] / a, -A * v[1] / a, -A * v[2] / a];
-                    let a = [1.0 / (h0 - h1), 1.0 / (h0 - h2), 1.0 / (h0 - h3)];
-                    PressureFunction::TriplePower { p0, h0, a, exp }
-                }
-                Factors::LinearAndQuadratic { a, x1: h1, b, c } => {
-                    let u = h1 * h1 + b * h1 + c;
-                    let v = [1.0 / u, -1.0 / u, -(h1 + b) / u];
-                    let a1 = 1.0 / (h0 - h1);
-                    let exp1 = -A * v[0] / a;
-                    let a2 = 1.0 / (h0 * h0 + b * h0 + c);
-                    let two_h_b = 2.0 * h0 + b;
-                    let b2 = two_h_b * a2;
-                    let exp2 = -A * v[1] / 2.0 / a;
-                    let sqrt = (4.0 * c - b * b).sqrt();
-                    let lambda = -A * (2.0 * v[2] - v[1] * b) / a / sqrt;
-                    let a3 = two_h_b / sqrt;
-                    let b3 = two_h_b * two_h_b / 2.0 / sqrt + sqrt / 2.0;
-                    PressureFunction::PowerWithAtan {
+            VerticalFunction::Cubic(poly) => {
+                let (a, b, c, d) = coefficients(&poly);
+                let scale = h0.abs().max(ALTITUDE_SCALE);
+                if a.abs() * scale > DEGENERATE_TOLERANCE * b.abs() {
+                    Self::from_cubic(&poly, p0, h0, mu_g_r)
+                } else if b.abs() * scale > DEGENERATE_TOLERANCE * c.abs() {
+                    Self::from_quadratic(b, c, d, p0, h0, mu_g_r)
+                } else {
+                    Self::from_temperature_function(
+                        &VerticalFunction::Linear { a: c, b: d },
                         p0,
                         h0,
-                        a1,
-                        exp1,
-                        a2,
-                        b2,
-                        exp2,
-                        lambda,
-                        a3,
-                        b3,
-                    }
+                        mu_g_r,
+                    )
                 }
-            },
+            }
+        }
+    }
+
+    /// Calculates the pressure function for a temperature given by a cubic polynomial
+    fn from_cubic(poly: &CubicPoly<f64>, p0: f64, h0: f64, mu_g_r: f64) -> Self {
+        match poly.factors() {
+            Factors::ThreeLinear {
+                a,
+                x1: h1,
+                x2: h2,
+                x3: h3,
+            } => {
+                let v = [
+                    1.0 / (h1 - h2) / (h1 - h3),
+                    1.0 / (h2 - h1) / (h2 - h3),
+                    1.0 / (h3 - h1) / (h3 - h2),
+                ];
+                let exp = [-mu_g_r * v[0] / a, -mu_g_r * v[1] / a, -mu_g_r * v[2] / a];
+                let a = [1.0 / (h0 - h1), 1.0 / (h0 - h2), 1.0 / (h0 - h3)];
+                PressureFunction::TriplePower { p0, h0, a, exp }
+            }
+            Factors::LinearAndQuadratic { a, x1: h1, b, c } => {
+                let u = h1 * h1 + b * h1 + c;
+                let v = [1.0 / u, -1.0 / u, -(h1 + b) / u];
+                let a1 = 1.0 / (h0 - h1);
+                let exp1 = -mu_g_r * v[0] / a;
+                let a2 = 1.0 / (h0 * h0 + b * h0 + c);
+                let two_h_b = 2.0 * h0 + b;
+                let b2 = two_h_b * a2;
+                let exp2 = -mu_g_r * v[1] / 2.0 / a;
+                let sqrt = (4.0 * c - b * b).sqrt();
+                let lambda = -mu_g_r * (2.0 * v[2] - v[1] * b) / a / sqrt;
+                let a3 = two_h_b / sqrt;
+                let b3 = two_h_b * two_h_b / 2.0 / sqrt + sqrt / 2.0;
+                PressureFunction::PowerWithAtan {
+                    p0,
+                    h0,
+                    a1,
+                    exp1,
+                    a2,
+                    b2,
+                    exp2,
+                    lambda,
+                    a3,
+                    b3,
+                }
+            }
+        }
+    }
+
+    /// Calculates the pressure function for the temperature b*h^2 + c*h + d
+    fn from_quadratic(b: f64, c: f64, d: f64, p0: f64, h0: f64, mu_g_r: f64) -> Self {
+        let delta = c * c - 4.0 * b * d;
+        if delta > 0.0 {
+            // T = b(h-h1)(h-h2)
+            let q = -0.5 * (c + c.signum() * delta.sqrt());
+            let (h1, h2) = (q / b, d / q);
+            let exp = mu_g_r / b / (h1 - h2);
+            PressureFunction::TriplePower {
+                p0,
+                h0,
+                a: [1.0 / (h0 - h1), 1.0 / (h0 - h2), 0.0],
+                exp: [-exp, exp, 0.0],
+            }
+        } else {
+            // T = b((h+m)^2 + q^2)
+            let m = c / 2.0 / b;
+            let q = (-delta).sqrt() / 2.0 / b.abs();
+            PressureFunction::PowerWithAtan {
+                p0,
+                h0,
+                a1: 0.0,
+                exp1: 0.0,
+                a2: 0.0,
+                b2: 0.0,
+                exp2: 0.0,
+                lambda: -mu_g_r / b / q,
+                a3: (h0 + m) / q,
+                b3: (q * q + (h0 + m) * (h0 + m)) / q,
+            }
         }
     }
 }
 
+/// Returns the coefficients (a, b, c, d) of the polynomial a*h^3 + b*h^2 + c*h + d
+fn coefficients(poly: &CubicPoly<f64>) -> (f64, f64, f64, f64) {
+    let c = poly.derivative(0.0);
+    let (derivative_plus, derivative_minus) = (poly.derivative(1.0), poly.derivative(-1.0));
+    (
+        (derivative_plus + derivative_minus - 2.0 * c) / 6.0,
+        (derivative_plus - derivative_minus) / 4.0,
+        c,
+        poly.eval(0.0),
+    )
+}
+
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub struct PressureProfile {
     altitude_interval_ends: Vec<f64>,
     pressure_functions: Vec<PressureFunction>,
+    /// The logarithm of the ratio of the pressure to the pressure of dry air at the same
+    /// temperature
+    #[cfg_attr(feature = "serialization", serde(default))]
+    vapor_correction: Option<VerticalProfile>,
 }
 
 impl PressureProfile {
-    pub fn from_temperature_profile(temp: &VerticalProfile, p0: f64, h0: f64) -> Self {
+    /// Calculates the pressure profile for the given temperature profile, with the pressure `p0`
+    /// at the altitude `h0`. `mu_g_r` is the hydrostatic constant mu*g/R.
+    pub fn from_temperature_profile(temp: &VerticalProfile, p0: f64, h0: f64, mu_g_r: f64) -> Self {
         let (altitude_interval_ends, interval_functions) = temp.internals();
-        let (start_index, mut map) = match altitude_interval_ends
-            .binary_search_by(|h| h.partial_cmp(&h0).unwrap())
-        {
-            Ok(index) | Err(index) => {
-                let function =
-                    PressureFunction::from_temperature_function(&interval_functions[index], p0, h0);
-                let mut map = BTreeMap::new();
-                let _ = map.insert(index, function);
-                (index, map)
-            }
-        };
+        let (start_index, mut map) =
+            match altitude_interval_ends.binary_search_by(|h| h.partial_cmp(&h0).unwrap()) {
+                Ok(index) | Err(index) => {
+                    let function = PressureFunction::from_temperature_function(
+                        &interval_functions[index],
+                        p0,
+                        h0,
+                        mu_g_r,
+                    );
+                    let mut map = BTreeMap::new();
+                    let _ = map.insert(index, function);
+                    (index, map)
+                }
+            };
         if let Some(start_index_below) = start_index.checked_sub(1) {
             for index in (0..=start_index_below).rev() {
                 let h0 = altitude_interval_ends[index];
                 let p0 = map[&(index + 1)].eval(h0);
                 let _ = map.insert(
                     index,
-                    PressureFunction::from_temperature_function(&interval_functions[index], p0, h0),
+                    PressureFunction::from_temperature_function(
+                        &interval_functions[index],
+                        p0,
+                        h0,
+                        mu_g_r,
+                    ),
                 );
             }
         }
@@ -172,7 +321,12 @@ impl PressureProfile {
                 let p0 = map[&(index - 1)].eval(h0);
                 let _ = map.insert(
                     index,
-                    PressureFunction::from_temperature_function(&interval_functions[index], p0, h0),
+                    PressureFunction::from_temperature_function(
+                        &interval_functions[index],
+                        p0,
+                        h0,
+                        mu_g_r,
+                    ),
                 );
             }
         }
@@ -182,15 +336,145 @@ impl PressureProfile {
         PressureProfile {
             altitude_interval_ends: altitude_interval_ends.clone(),
             pressure_functions,
+            vapor_correction: None,
+        }
+    }
+
+    /// Corrects the pressure for the water vapor, by integrating the hydrostatic equation with
+    /// the virtual temperature instead of the temperature, starting from the altitude `h0`.
+    /// `vapor_pressure` returns the partial pressure of water vapor given the altitude, the
+    /// temperature and the pressure.
+    ///
+    /// The correction is interpolated between the lowest and the highest of `altitudes` and `h0`,
+    /// and continued linearly outside of them.
+    pub fn with_vapor_correction<F: Fn(f64, f64, f64) -> f64>(
+        self,
+        temp: &VerticalProfile,
+        vapor_pressure: F,
+        altitudes: &[f64],
+        h0: f64,
+        mu_g_r: f64,
+    ) -> Self {
+        let mut grid: Vec<f64> = vec![];
+        let mut ends = altitudes.to_vec();
+        ends.push(h0);
+        ends.sort_by(f64::total_cmp);
+        ends.dedup();
+        for pair in ends.windows(2) {
+            let steps = ((pair[1] - pair[0]) / MAX_VAPOR_STEP).ceil().max(1.0) as usize;
+            grid.extend(
+                (0..steps).map(|i| pair[0] + (pair[1] - pair[0]) * i as f64 / steps as f64),
+            );
+        }
+        grid.extend(ends.last());
+        let start = grid
+            .iter()
+            .position(|&h| h == h0)
+            .expect("h0 should be one of the ends");
+
+        // d(ln p)/dh - d(ln p_dry)/dh = mu_g_r * (1/T - 1/T_v) = mu_g_r * (1 - eps) * e / (p * T),
+        // with the pressure p = p_dry * exp(correction)
+        let gradient = |h: f64, correction: f64| {
+            let t = temp.eval(h);
+            let p = self.eval(h) * correction.exp();
+            mu_g_r * (1.0 - EPSILON_VAPOR) * vapor_pressure(h, t, p) / (p * t)
+        };
+        let simpson = |a: f64, b: f64, correction: f64| {
+            (b - a) / 6.0
+                * (gradient(a, correction)
+                    + 4.0 * gradient(0.5 * (a + b), correction)
+                    + gradient(b, correction))
+        };
+        let mut correction = vec![0.0; grid.len()];
+        for i in start + 1..grid.len() {
+            correction[i] = correction[i - 1] + simpson(grid[i - 1], grid[i], correction[i - 1]);
+        }
+        for i in (0..start).rev() {
+            correction[i] = correction[i + 1] - simpson(grid[i], grid[i + 1], correction[i + 1]);
+        }
+
+        let function = if grid.len() > 1 {
+            FunctionDef::Spline {
+                boundary_condition: BoundaryCondition::Derivatives(
+                    gradient(grid[0], correction[0]),
+                    gradient(grid[grid.len() - 1], correction[grid.len() - 1]),
+                ),
+                points: grid.into_iter().zip(correction).collect(),
+            }
+        } else {
+            FunctionDef::Linear { gradient: 0.0 }
+        };
+        let vapor_correction = VerticalProfileBuilder::new(function)
+            .with_fixed_value(h0, 0.0)
+            .build()
+            .ok();
+        PressureProfile {
+            vapor_correction,
+            ..self
         }
     }
 
     pub fn eval(&self, h: f64) -> f64 {
-        match self
-            .altitude_interval_ends
-            .binary_search_by(|a| a.partial_cmp(&h).unwrap())
-        {
-            Ok(index) | Err(index) => self.pressure_functions[index].eval(h),
+        self.eval_with_hints(h, &AtmosphereHints::default())
+    }
+
+    /// Returns the pressures at all the altitudes `hs`, evaluated like in
+    /// `VerticalProfile::eval_many`
+    pub fn eval_many(&self, hs: &[f64]) -> Vec<f64> {
+        let mut result = vec![0.0; hs.len()];
+        for (index, range) in IntervalHint::default().runs(&self.altitude_interval_ends, hs) {
+            self.pressure_functions[index].eval_into(&hs[range.clone()], &mut result[range]);
+        }
+        if let Some(ref correction) = self.vapor_correction {
+            for (pressure, correction) in result.iter_mut().zip(correction.eval_many(hs)) {
+                *pressure *= correction.exp();
+            }
+        }
+        result
+    }
+
+    /// Evaluates the pressure like `eval`, using and updating the `hints` of the pressure and the
+    /// vapor correction
+    pub(crate) fn eval_with_hints(&self, h: f64, hints: &AtmosphereHints) -> f64 {
+        let index = hints.pressure.find(&self.altitude_interval_ends, h);
+        let pressure = self.pressure_functions[index].eval(h);
+        match self.vapor_correction {
+            Some(ref correction) => {
+                pressure * correction.eval_with_hint(h, &hints.vapor_correction).exp()
+            }
+            None => pressure,
         }
     }
 }
+
+/// Integrates `f` from `a` to `b` with the adaptive Simpson's rule
+fn integrate<F: Fn(f64) -> f64>(f: &F, a: f64, b: f64) -> f64 {
+    if a == b {
+        return 0.0;
+    }
+    let (fa, fb) = (f(a), f(b));
+    let fm = f(0.5 * (a + b));
+    let whole = (b - a) / 6.0 * (fa + 4.0 * fm + fb);
+    integrate_step(f, (a, fa), (b, fb), fm, whole, INTEGRATION_TOLERANCE, 0)
+}
+
+fn integrate_step<F: Fn(f64) -> f64>(
+    f: &F,
+    (a, fa): (f64, f64),
+    (b, fb): (f64, f64),
+    fm: f64,
+    whole: f64,
+    tolerance: f64,
+    depth: u32,
+) -> f64 {
+    let m = 0.5 * (a + b);
+    let (flm, frm) = (f(0.5 * (a + m)), f(0.5 * (m + b)));
+    let left = (m - a) / 6.0 * (fa + 4.0 * flm + fm);
+    let right = (b - m) / 6.0 * (fm + 4.0 * frm + fb);
+    let error = left + right - whole;
+    if depth >= MAX_INTEGRATION_DEPTH || error.abs() <= 15.0 * tolerance {
+        return left + right + error / 15.0;
+    }
+    integrate_step(f, (a, fa), (m, fm), flm, left, 0.5 * tolerance, depth + 1)
+        + integrate_step(f, (m, fm), (b, fb), frm, right, 0.5 * tolerance, depth + 1)
+}
@@ -0,0 +1,321 @@
+//! Reference atmospheres of the Earth: the ICAO standard atmosphere and the AFGL atmospheres
+//! for different climates (Anderson et al., "AFGL Atmospheric Constituent Profiles (0-120km)",
+//! 1986).
+
+use super::{import::tabulated_def, Atmosphere, AtmosphereDef, TemperatureFixedPoint};
+use crate::air::p_sv;
+
+/// The radius of the Earth in meters used by the ICAO standard for converting geopotential
+/// altitudes
+const ICAO_EARTH_RADIUS: f64 = 6_356_766.0;
+
+/// An AFGL atmosphere, given by the pressure at sea level in Pa and the levels as (altitude in
+/// km, temperature in K, water vapor volume mixing ratio in ppmv). Only the levels up to 50 km
+/// are included, as the air above has a negligible effect on refraction.
+struct AfglTable {
+    surface_pressure: f64,
+    levels: [(f64, f64, f64); 31],
+}
+
+const TROPICAL: AfglTable = AfglTable {
+    surface_pressure: 101_300.0,
+    levels: [
+        (0.0, 299.7, 2.593e4),
+        (1.0, 293.7, 1.949e4),
+        (2.0, 287.7, 1.534e4),
+        (3.0, 283.7, 8.600e3),
+        (4.0, 277.0, 4.441e3),
+        (5.0, 270.3, 3.346e3),
+        (6.0, 263.6, 2.101e3),
+        (7.0, 257.0, 1.289e3),
+        (8.0, 250.3, 7.637e2),
+        (9.0, 243.6, 4.098e2),
+        (10.0, 237.0, 1.912e2),
+        (11.0, 230.1, 7.306e1),
+        (12.0, 223.6, 2.905e1),
+        (13.0, 217.0, 9.900),
+        (14.0, 210.3, 6.220),
+        (15.0, 203.7, 4.000),
+        (16.0, 197.0, 3.000),
+        (17.0, 194.8, 2.900),
+        (18.0, 198.8, 2.750),
+        (19.0, 202.7, 2.600),
+        (20.0, 206.7, 2.600),
+        (21.0, 210.7, 2.650),
+        (22.0, 214.6, 2.800),
+        (23.0, 217.0, 2.900),
+        (24.0, 219.2, 3.200),
+        (25.0, 221.4, 3.250),
+        (30.0, 232.3, 4.000),
+        (35.0, 243.1, 4.600),
+        (40.0, 254.0, 5.200),
+        (45.0, 264.8, 5.700),
+        (50.0, 270.2, 6.000),
+    ],
+};
+
+const MIDLATITUDE_SUMMER: AfglTable = AfglTable {
+    surface_pressure: 101_300.0,
+    levels: [
+        (0.0, 294.2, 1.876e4),
+        (1.0, 289.7, 1.378e4),
+        (2.0, 285.2, 9.680e3),
+        (3.0, 279.2, 5.984e3),
+        (4.0, 273.2, 3.813e3),
+        (5.0, 267.2, 2.225e3),
+        (6.0, 261.2, 1.510e3),
+        (7.0, 254.7, 1.020e3),
+        (8.0, 248.2, 6.464e2),
+        (9.0, 241.7, 4.129e2),
+        (10.0, 235.3, 2.472e2),
+        (11.0, 228.8, 9.556e1),
+        (12.0, 222.3, 2.944e1),
+        (13.0, 215.8, 8.000),
+        (14.0, 215.7, 5.000),
+        (15.0, 215.7, 3.400),
+        (16.0, 215.7, 3.300),
+        (17.0, 215.7, 3.200),
+        (18.0, 216.8, 3.150),
+        (19.0, 217.9, 3.200),
+        (20.0, 219.2, 3.300),
+        (21.0, 220.4, 3.450),
+        (22.0, 221.6, 3.600),
+        (23.0, 222.8, 3.850),
+        (24.0, 223.9, 4.000),
+        (25.0, 225.1, 4.200),
+        (30.0, 233.7, 4.700),
+        (35.0, 245.2, 4.950),
+        (40.0, 257.5, 5.100),
+        (45.0, 269.9, 5.450),
+        (50.0, 275.7, 5.500),
+    ],
+};
+
+const MIDLATITUDE_WINTER: AfglTable = AfglTable {
+    surface_pressure: 101_800.0,
+    levels: [
+        (0.0, 272.2, 4.316e3),
+        (1.0, 268.7, 3.454e3),
+        (2.0, 265.2, 2.788e3),
+        (3.0, 261.7, 2.088e3),
+        (4.0, 255.7, 1.280e3),
+        (5.0, 249.7, 8.241e2),
+        (6.0, 243.7, 5.103e2),
+        (7.0, 237.7, 2.321e2),
+        (8.0, 231.7, 1.077e2),
+        (9.0, 225.7, 5.566e1),
+        (10.0, 219.7, 2.960e1),
+        (11.0, 219.2, 1.000e1),
+        (12.0, 218.7, 6.000),
+        (13.0, 218.2, 5.000),
+        (14.0, 217.7, 4.800),
+        (15.0, 217.2, 4.700),
+        (16.0, 216.7, 4.600),
+        (17.0, 216.2, 4.500),
+        (18.0, 215.7, 4.500),
+        (19.0, 215.2, 4.500),
+        (20.0, 215.2, 4.500),
+        (21.0, 215.2, 4.500),
+        (22.0, 215.2, 4.510),
+        (23.0, 215.2, 4.510),
+        (24.0, 215.2, 4.540),
+        (25.0, 215.2, 4.600),
+        (30.0, 217.4, 4.680),
+        (35.0, 227.9, 4.800),
+        (40.0, 243.2, 5.000),
+        (45.0, 256.7, 5.100),
+        (50.0, 265.2, 5.200),
+    ],
+};
+
+const SUBARCTIC_SUMMER: AfglTable = AfglTable {
+    surface_pressure: 101_000.0,
+    levels: [
+        (0.0, 287.2, 1.194e4),
+        (1.0, 281.7, 8.701e3),
+        (2.0, 276.3, 6.750e3),
+        (3.0, 270.9, 4.820e3),
+        (4.0, 265.5, 3.380e3),
+        (5.0, 260.1, 2.218e3),
+        (6.0, 253.1, 1.330e3),
+        (7.0, 246.1, 7.971e2),
+        (8.0, 239.2, 3.996e2),
+        (9.0, 232.2, 1.300e2),
+        (10.0, 225.2, 4.240e1),
+        (11.0, 225.2, 1.330e1),
+        (12.0, 225.2, 6.735),
+        (13.0, 225.2, 5.000),
+        (14.0, 225.2, 4.650),
+        (15.0, 225.2, 4.600),
+        (16.0, 225.2, 4.500),
+        (17.0, 225.2, 4.500),
+        (18.0, 225.2, 4.500),
+        (19.0, 225.2, 4.500),
+        (20.0, 225.2, 4.500),
+        (21.0, 225.2, 4.500),
+        (22.0, 225.2, 4.500),
+        (23.0, 225.2, 4.500),
+        (24.0, 226.6, 4.500),
+        (25.0, 228.1, 4.500),
+        (30.0, 235.1, 4.800),
+        (35.0, 243.8, 5.000),
+        (40.0, 253.4, 5.100),
+        (45.0, 263.4, 5.200),
+        (50.0, 270.0, 5.300),
+    ],
+};
+
+const SUBARCTIC_WINTER: AfglTable = AfglTable {
+    surface_pressure: 101_300.0,
+    levels: [
+        (0.0, 257.2, 1.405e3),
+        (1.0, 259.1, 1.615e3),
+        (2.0, 255.9, 1.427e3),
+        (3.0, 252.7, 1.166e3),
+        (4.0, 247.7, 7.666e2),
+        (5.0, 240.9, 4.090e2),
+        (6.0, 234.1, 2.603e2),
+        (7.0, 227.3, 1.252e2),
+        (8.0, 220.6, 6.524e1),
+        (9.0, 217.2, 2.389e1),
+        (10.0, 217.2, 1.206e1),
+        (11.0, 217.2, 5.500),
+        (12.0, 217.2, 5.000),
+        (13.0, 217.2, 4.800),
+        (14.0, 217.2, 4.700),
+        (15.0, 217.2, 4.600),
+        (16.0, 216.6, 4.500),
+        (17.0, 216.0, 4.500),
+        (18.0, 215.4, 4.500),
+        (19.0, 214.8, 4.500),
+        (20.0, 214.2, 4.500),
+        (21.0, 213.6, 4.500),
+        (22.0, 213.0, 4.500),
+        (23.0, 212.4, 4.500),
+        (24.0, 211.8, 4.500),
+        (25.0, 211.2, 4.500),
+        (30.0, 216.0, 4.800),
+        (35.0, 222.2, 5.000),
+        (40.0, 234.7, 5.100),
+        (45.0, 247.0, 5.200),
+        (50.0, 259.1, 5.300),
+    ],
+};
+
+impl AfglTable {
+    /// Builds the definition, with the relative humidity calculated from the mixing ratio and
+    /// the pressure profile of the dry atmosphere
+    fn def(&self) -> AtmosphereDef {
+        let temperature: Vec<_> = self
+            .levels
+            .iter()
+            .map(|&(altitude, temperature, _)| (altitude * 1e3, temperature))
+            .collect();
+        let pressure = Some((0.0, self.surface_pressure));
+        let dry = tabulated_def(temperature.clone(), vec![], pressure);
+        let atmosphere =
+            Atmosphere::try_from_def(dry).expect("the reference atmospheres should be valid");
+        let humidity = self
+            .levels
+            .iter()
+            .map(|&(altitude, temperature, mixing_ratio)| {
+                let altitude = altitude * 1e3;
+                let vapor_pressure = mixing_ratio * 1e-6 * atmosphere.pressure(altitude);
+                (altitude, 100.0 * vapor_pressure / p_sv(temperature))
+            })
+            .collect();
+        tabulated_def(temperature, humidity, pressure)
+    }
+}
+
+impl AtmosphereDef {
+    /// The ICAO standard atmosphere: dry air at 288.15 K and 101325 Pa at sea level, with the
+    /// layers of the US-1976 standard given in geopotential altitudes
+    pub fn icao_standard() -> Self {
+        AtmosphereDef {
+            temperature_fixed_point: Some(TemperatureFixedPoint {
+                altitude: 0.0,
+                temperature: 288.15,
+            }),
+            ..Self::us_76()
+        }
+        .with_variable_gravity(ICAO_EARTH_RADIUS)
+    }
+
+    /// The AFGL tropical atmosphere (15°N, annual average)
+    pub fn tropical() -> Self {
+        TROPICAL.def()
+    }
+
+    /// The AFGL midlatitude summer atmosphere (45°N, July)
+    pub fn midlatitude_summer() -> Self {
+        MIDLATITUDE_SUMMER.def()
+    }
+
+    /// The AFGL midlatitude winter atmosphere (45°N, January)
+    pub fn midlatitude_winter() -> Self {
+        MIDLATITUDE_WINTER.def()
+    }
+
+    /// The AFGL subarctic summer atmosphere (60°N, July)
+    pub fn subarctic_summer() -> Self {
+        SUBARCTIC_SUMMER.def()
+    }
+
+    /// The AFGL subarctic winter atmosphere (60°N, January)
+    pub fn subarctic_winter() -> Self {
+        SUBARCTIC_WINTER.def()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn references() -> Vec<AtmosphereDef> {
+        vec![
+            AtmosphereDef::icao_standard(),
+            AtmosphereDef::tropical(),
+            AtmosphereDef::midlatitude_summer(),
+            AtmosphereDef::midlatitude_winter(),
+            AtmosphereDef::subarctic_summer(),
+            AtmosphereDef::subarctic_winter(),
+        ]
+    }
+
+    #[test]
+    fn test_valid_references() {
+        for def in references() {
+            assert_eq!(def.validate(), Ok(()));
+            let atmosphere = Atmosphere::try_from_def(def).unwrap();
+            for i in 0..=50 {
+                let humidity = atmosphere.humidity(i as f64 * 1e3);
+                assert!(
+                    (0.0..=100.0).contains(&humidity),
+                    "{} at {} km",
+                    humidity,
+                    i
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_icao_standard() {
+        let atmosphere = Atmosphere::try_from_def(AtmosphereDef::icao_standard()).unwrap();
+        assert!((atmosphere.temperature(0.0) - 288.15).abs() < 1e-9);
+        assert!((atmosphere.pressure(0.0) - 101325.0).abs() < 1e-6);
+        // the tropopause at 11 km geopotential altitude
+        assert!((atmosphere.temperature(11_019.0) - 216.65).abs() < 0.01);
+        assert!((atmosphere.pressure(11_019.0) - 22632.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn test_tropical() {
+        let atmosphere = Atmosphere::try_from_def(AtmosphereDef::tropical()).unwrap();
+        assert!((atmosphere.temperature(0.0) - 299.7).abs() < 1e-9);
+        assert!((atmosphere.pressure(0.0) - 101300.0).abs() < 1e-6);
+        let humidity = atmosphere.humidity(0.0);
+        assert!(humidity > 60.0 && humidity < 90.0, "{}", humidity);
+    }
+}
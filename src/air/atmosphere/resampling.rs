@@ -0,0 +1,152 @@
+//! Resampling and smoothing of vertical profiles, for preparing noisy measured data (like
+//! radiosonde soundings) for building an atmosphere.
+
+use super::{
+    vertical_profile::{Extrapolation, FunctionDef, VerticalProfile},
+    AtmosphereDef,
+};
+
+/// The number of standard deviations beyond which the Gaussian weights are neglected
+const GAUSSIAN_CUTOFF: f64 = 4.0;
+
+/// A method of smoothing a profile in height
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum Smoothing {
+    /// The average of the values in a window of the given width in meters, centered on each
+    /// altitude
+    MovingAverage { window: f64 },
+    /// The average of the values weighted by a Gaussian with the given standard deviation in
+    /// meters
+    Gaussian { sigma: f64 },
+}
+
+impl Smoothing {
+    /// Smooths a profile given as (altitude, value) pairs sorted by the altitude. The values are
+    /// averaged at the same altitudes, using only the points available near the ends.
+    pub fn apply(&self, points: &[(f64, f64)]) -> Vec<(f64, f64)> {
+        let (reach, weight): (f64, Box<dyn Fn(f64) -> f64>) = match *self {
+            Smoothing::MovingAverage { window } => (0.5 * window, Box::new(|_| 1.0)),
+            Smoothing::Gaussian { sigma } => (
+                GAUSSIAN_CUTOFF * sigma,
+                Box::new(move |d: f64| (-0.5 * (d / sigma).powi(2)).exp()),
+            ),
+        };
+        points
+            .iter()
+            .map(|&(h, _)| {
+                let start = points.partition_point(|&(x, _)| x < h - reach);
+                let end = points.partition_point(|&(x, _)| x <= h + reach);
+                let (sum, total_weight) =
+                    points[start..end]
+                        .iter()
+                        .fold((0.0, 0.0), |(sum, total), &(x, y)| {
+                            let w = weight(x - h);
+                            (sum + w * y, total + w)
+                        });
+                (h, sum / total_weight)
+            })
+            .collect()
+    }
+}
+
+impl VerticalProfile {
+    /// Returns the values of the profile at altitudes spaced regularly by `step` meters,
+    /// between the ends of `range`.
+    pub fn resample(&self, range: (f64, f64), step: f64) -> Vec<(f64, f64)> {
+        let (start, end) = range;
+        let n = ((end - start) / step).ceil().max(1.0) as usize;
+        (0..=n)
+            .map(|i| {
+                let h = start + (end - start) * i as f64 / n as f64;
+                (h, self.eval(h))
+            })
+            .collect()
+    }
+}
+
+impl AtmosphereDef {
+    /// Replaces the temperature and humidity profiles with tables of their values at altitudes
+    /// spaced regularly by `step` meters between the ends of `range`, optionally smoothed.
+    ///
+    /// The values are interpolated linearly between the altitudes, and continued linearly with
+    /// the gradients at the ends outside of the range.
+    pub fn resampled(self, range: (f64, f64), step: f64, smoothing: Option<Smoothing>) -> Self {
+        let resample = |profile: VerticalProfile| {
+            let points = profile.resample(range, step);
+            FunctionDef::Table {
+                points: match smoothing {
+                    Some(smoothing) => smoothing.apply(&points),
+                    None => points,
+                },
+                extrapolation: Extrapolation::Linear,
+            }
+        };
+        AtmosphereDef {
+            first_temperature_function: resample(self.temperature_profile()),
+            next_functions: vec![],
+            temperature_fixed_point: None,
+            first_humidity_function: resample(self.humidity_profile()),
+            next_humidity_functions: vec![],
+            humidity_fixed_point: None,
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::Atmosphere;
+
+    #[test]
+    fn test_smoothing() {
+        let linear: Vec<(f64, f64)> = (0..100)
+            .map(|i| (i as f64 * 10.0, 288.0 - 0.065 * i as f64))
+            .collect();
+        // symmetric windows don't change linear profiles away from the ends
+        for smoothing in [
+            Smoothing::MovingAverage { window: 50.0 },
+            Smoothing::Gaussian { sigma: 20.0 },
+        ] {
+            let smoothed = smoothing.apply(&linear);
+            assert_eq!(smoothed.len(), linear.len());
+            for (&(h1, t1), &(h2, t2)) in linear[10..90].iter().zip(&smoothed[10..90]) {
+                assert_eq!(h1, h2);
+                assert!((t1 - t2).abs() < 1e-9);
+            }
+        }
+
+        let noisy: Vec<(f64, f64)> = linear
+            .iter()
+            .enumerate()
+            .map(|(i, &(h, t))| (h, t + if i % 2 == 0 { 0.5 } else { -0.5 }))
+            .collect();
+        let smoothed = Smoothing::Gaussian { sigma: 30.0 }.apply(&noisy);
+        for (&(_, t1), &(_, t2)) in linear[10..90].iter().zip(&smoothed[10..90]) {
+            assert!((t1 - t2).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_resampled() {
+        let def = AtmosphereDef::superior_mirage(100.0, 50.0, 5.0);
+        let original = Atmosphere::try_from_def(def.clone()).unwrap();
+        let resampled = Atmosphere::try_from_def(def.resampled((0.0, 1e3), 10.0, None)).unwrap();
+        for h in [0.0, 55.0, 120.0, 500.0, 1e3] {
+            assert!((original.temperature(h) - resampled.temperature(h)).abs() < 1e-9);
+        }
+        assert!((original.pressure(700.0) / resampled.pressure(700.0) - 1.0).abs() < 1e-9);
+        // the gradient at the end is continued
+        assert!((resampled.dtemperature(2e3) + 0.0065).abs() < 1e-9);
+
+        let smoothed =
+            Atmosphere::try_from_def(AtmosphereDef::superior_mirage(100.0, 50.0, 5.0).resampled(
+                (0.0, 1e3),
+                10.0,
+                Some(Smoothing::Gaussian { sigma: 20.0 }),
+            ))
+            .unwrap();
+        assert!(smoothed.dtemperature(100.0) > 0.0 && smoothed.dtemperature(100.0) < 0.09);
+    }
+}
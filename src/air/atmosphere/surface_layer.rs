@@ -0,0 +1,414 @@
+//! Profiles of the lowest layer of the atmosphere, in which the temperature is dominated by the
+//! exchange of heat with the surface.
+
+use super::{vertical_profile::FunctionDef, AtmosphereDef, FunctionDefWithAlt, EARTH_GRAVITY};
+
+/// The von Kármán constant
+pub const VON_KARMAN: f64 = 0.4;
+/// The specific heat of air at constant pressure, in J/(kg*K)
+pub const AIR_SPECIFIC_HEAT: f64 = 1005.0;
+
+/// The number of intervals into which the surface layer profiles are divided when approximating
+/// them with splines
+const LAYER_INTERVALS: usize = 32;
+/// The exponent of the power law of the temperature near the ground in Kukkamäki's model
+const KUKKAMAKI_EXPONENT: f64 = 1.0 / 3.0;
+/// The maximal number of iterations when matching the surface temperature to the profile above
+const MAX_ITERATIONS: usize = 20;
+
+/// The way in which the excess temperature of the air decreases with the height above the
+/// surface
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum SurfaceLayerDecay {
+    /// Linearly with the logarithm of the height, like in the classical surface layer
+    Logarithmic,
+    /// As a power of the height with the given positive exponent
+    PowerLaw { exponent: f64 },
+}
+
+/// A strongly superadiabatic layer of air above a surface heated by the Sun, like a road or a
+/// desert - the cause of inferior mirages.
+///
+/// The air at the surface is hotter than the ambient air by `surface_excess`. The excess decays
+/// with the height z above the surface as a function of (z + z0) / z0, where z0 is the roughness
+/// length, and vanishes at the top of the layer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct ConvectiveLayer {
+    /// The altitude of the surface in meters
+    pub surface_altitude: f64,
+    /// The excess temperature of the air at the surface in kelvins
+    pub surface_excess: f64,
+    /// The roughness length of the surface in meters
+    pub roughness_length: f64,
+    /// The thickness of the layer in meters
+    pub depth: f64,
+    /// The shape of the decay of the excess temperature
+    pub decay: SurfaceLayerDecay,
+}
+
+impl Default for ConvectiveLayer {
+    fn default() -> Self {
+        Self {
+            surface_altitude: 0.0,
+            surface_excess: 10.0,
+            roughness_length: 1e-3,
+            depth: 10.0,
+            decay: SurfaceLayerDecay::Logarithmic,
+        }
+    }
+}
+
+impl ConvectiveLayer {
+    /// Returns a layer over the surface at the altitude `surface_altitude`, in which the excess
+    /// temperature decays with the cube root of the height, like in Kukkamäki's model of the
+    /// refraction in geodetic leveling. The gradient of the excess temperature is `gradient`
+    /// (in K/m) at the height `height` (in meters) above the surface.
+    pub fn kukkamaki(surface_altitude: f64, gradient: f64, height: f64) -> Self {
+        let layer = ConvectiveLayer {
+            surface_altitude,
+            surface_excess: 1.0,
+            decay: SurfaceLayerDecay::PowerLaw {
+                exponent: KUKKAMAKI_EXPONENT,
+            },
+            ..Default::default()
+        };
+        ConvectiveLayer {
+            surface_excess: gradient / layer.dexcess(surface_altitude + height),
+            ..layer
+        }
+    }
+
+    /// Returns the excess temperature of the air at the altitude `h` - the surface one below
+    /// the surface, and zero above the layer.
+    pub fn excess(&self, h: f64) -> f64 {
+        self.surface_excess * self.shape(h).0
+    }
+
+    /// Returns the derivative of the excess temperature with respect to the altitude `h`.
+    pub fn dexcess(&self, h: f64) -> f64 {
+        self.surface_excess * self.shape(h).1
+    }
+
+    /// Returns the fraction of the surface excess left at the altitude `h`, and its derivative
+    fn shape(&self, h: f64) -> (f64, f64) {
+        let z = h - self.surface_altitude;
+        if z < 0.0 {
+            return (1.0, 0.0);
+        }
+        if z > self.depth {
+            return (0.0, 0.0);
+        }
+        let z0 = self.roughness_length;
+        let s = (z + z0) / z0;
+        let s_top = (self.depth + z0) / z0;
+        match self.decay {
+            SurfaceLayerDecay::Logarithmic => {
+                let norm = s_top.ln();
+                (1.0 - s.ln() / norm, -1.0 / (norm * (z + z0)))
+            }
+            SurfaceLayerDecay::PowerLaw { exponent } => {
+                let norm = 1.0 - s_top.powf(-exponent);
+                (
+                    (s.powf(-exponent) - s_top.powf(-exponent)) / norm,
+                    -exponent * s.powf(-exponent - 1.0) / z0 / norm,
+                )
+            }
+        }
+    }
+
+    /// Returns the altitudes at which the profile of the layer is sampled, denser near the
+    /// surface
+    fn altitudes(&self) -> Vec<f64> {
+        let z0 = self.roughness_length;
+        let s_top = (self.depth + z0) / z0;
+        let mut result: Vec<f64> = (0..LAYER_INTERVALS)
+            .map(|i| {
+                self.surface_altitude + z0 * (s_top.powf(i as f64 / LAYER_INTERVALS as f64) - 1.0)
+            })
+            .collect();
+        result.push(self.surface_altitude + self.depth);
+        result
+    }
+
+    /// Returns the temperature in the layer as a function of altitude, approximated with a
+    /// spline, for the ambient temperature equal to `air_temperature` at the surface and
+    /// changing with the altitude by `gradient` K/m.
+    pub fn temperature_function(&self, air_temperature: f64, gradient: f64) -> FunctionDef {
+        FunctionDef::sampled(
+            |h| air_temperature + gradient * (h - self.surface_altitude) + self.excess(h),
+            |h| gradient + self.dexcess(h),
+            &self.altitudes(),
+        )
+    }
+}
+
+/// The surface layer described by the Monin-Obukhov similarity theory, in which the temperature
+/// profile is determined by the turbulent flux of heat from the surface.
+///
+/// The temperature is given by the Businger-Dyer relations: it changes with the logarithm of the
+/// height z above the surface, corrected for the buoyancy by a function of z / L, where L is the
+/// Obukhov length. The profile starts at the roughness length above the surface, and below it
+/// is continued linearly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct MoninObukhovLayer {
+    /// The altitude of the surface in meters
+    pub surface_altitude: f64,
+    /// The friction velocity in m/s
+    pub friction_velocity: f64,
+    /// The sensible heat flux from the surface to the air in W/m^2; positive when the surface is
+    /// warmer than the air (unstable conditions), negative when it is cooler (stable conditions)
+    pub heat_flux: f64,
+    /// The roughness length of the surface in meters
+    pub roughness_length: f64,
+    /// The density of the air in kg/m^3
+    pub air_density: f64,
+    /// The thickness of the layer in meters
+    pub depth: f64,
+}
+
+impl Default for MoninObukhovLayer {
+    fn default() -> Self {
+        Self {
+            surface_altitude: 0.0,
+            friction_velocity: 0.3,
+            heat_flux: 100.0,
+            roughness_length: 1e-3,
+            air_density: 1.2,
+            depth: 50.0,
+        }
+    }
+}
+
+impl MoninObukhovLayer {
+    /// Returns the temperature scale θ* = -H / (ρ cp u*) in kelvins
+    pub fn temperature_scale(&self) -> f64 {
+        -self.heat_flux / (self.air_density * AIR_SPECIFIC_HEAT * self.friction_velocity)
+    }
+
+    /// Returns the Obukhov length in meters for the mean temperature of the layer `temperature`
+    /// - negative in unstable conditions, positive in stable ones, and infinite in neutral ones.
+    pub fn obukhov_length(&self, temperature: f64) -> f64 {
+        self.friction_velocity.powi(2) * temperature
+            / (VON_KARMAN * EARTH_GRAVITY * self.temperature_scale())
+    }
+
+    /// Returns the difference between the temperature at the altitude `h` and at the surface,
+    /// and its derivative, for the given surface temperature
+    fn profile(&self, h: f64, surface_temperature: f64) -> (f64, f64) {
+        let z0 = self.roughness_length;
+        let z = (h - self.surface_altitude).max(z0);
+        let obukhov_length = self.obukhov_length(surface_temperature);
+        let scale = self.temperature_scale() / VON_KARMAN;
+        // the temperature falls with the dry adiabatic lapse rate when the potential temperature
+        // is constant
+        let adiabatic = EARTH_GRAVITY / AIR_SPECIFIC_HEAT;
+        let potential = scale
+            * ((z / z0).ln() - stability_correction(z / obukhov_length)
+                + stability_correction(z0 / obukhov_length));
+        let dpotential = scale * stability_function(z / obukhov_length) / z;
+        (potential - adiabatic * (z - z0), dpotential - adiabatic)
+    }
+
+    /// Returns the temperature at the altitude `h` for the given temperature at the surface.
+    pub fn temperature(&self, h: f64, surface_temperature: f64) -> f64 {
+        surface_temperature + self.profile(h, surface_temperature).0
+    }
+
+    /// Returns the derivative of the temperature with respect to the altitude `h`, for the given
+    /// temperature at the surface.
+    pub fn dtemperature(&self, h: f64, surface_temperature: f64) -> f64 {
+        self.profile(h, surface_temperature).1
+    }
+
+    /// Returns the altitudes at which the profile of the layer is sampled, denser near the
+    /// surface
+    fn altitudes(&self) -> Vec<f64> {
+        let z0 = self.roughness_length;
+        let ratio = self.depth / z0;
+        let mut result: Vec<f64> = (0..LAYER_INTERVALS)
+            .map(|i| self.surface_altitude + z0 * ratio.powf(i as f64 / LAYER_INTERVALS as f64))
+            .collect();
+        result.push(self.surface_altitude + self.depth);
+        result
+    }
+
+    /// Returns the temperature in the layer as a function of altitude, approximated with a
+    /// spline, for the given temperature at the surface.
+    pub fn temperature_function(&self, surface_temperature: f64) -> FunctionDef {
+        FunctionDef::sampled(
+            |h| self.temperature(h, surface_temperature),
+            |h| self.dtemperature(h, surface_temperature),
+            &self.altitudes(),
+        )
+    }
+}
+
+/// The integrated stability correction ψh of the temperature profile as a function of z / L
+fn stability_correction(zeta: f64) -> f64 {
+    if zeta < 0.0 {
+        let x = (1.0 - 16.0 * zeta).sqrt();
+        2.0 * ((1.0 + x) / 2.0).ln()
+    } else {
+        -5.0 * zeta
+    }
+}
+
+/// The dimensionless temperature gradient φh as a function of z / L
+fn stability_function(zeta: f64) -> f64 {
+    if zeta < 0.0 {
+        1.0 / (1.0 - 16.0 * zeta).sqrt()
+    } else {
+        1.0 + 5.0 * zeta
+    }
+}
+
+impl AtmosphereDef {
+    /// Replaces the bottom of the temperature profile with the given convective layer.
+    ///
+    /// The ambient temperature in the layer continues the profile from the top of the layer
+    /// linearly downwards; the profile above the layer is unchanged.
+    pub fn with_convective_layer(self, layer: &ConvectiveLayer) -> Self {
+        let profile = self.temperature_profile();
+        let top = layer.surface_altitude + layer.depth;
+        let gradient = profile.eval_derivative(top);
+        let air_temperature = profile.eval(top) - gradient * layer.depth;
+        let function = layer.temperature_function(air_temperature, gradient);
+        self.with_surface_function(top, function)
+    }
+
+    /// Replaces the bottom of the temperature profile with the given Monin-Obukhov layer.
+    ///
+    /// The surface temperature is chosen so that the temperature at the top of the layer matches
+    /// the profile; the profile above the layer is unchanged.
+    pub fn with_monin_obukhov_layer(self, layer: &MoninObukhovLayer) -> Self {
+        let top = layer.surface_altitude + layer.depth;
+        let top_temperature = self.temperature_profile().eval(top);
+        // the difference depends on the surface temperature only weakly, through the Obukhov
+        // length, so simple iteration converges quickly
+        let mut surface_temperature = top_temperature;
+        for _ in 0..MAX_ITERATIONS {
+            let next = top_temperature - layer.profile(top, surface_temperature).0;
+            let converged = (next - surface_temperature).abs() < 1e-9;
+            surface_temperature = next;
+            if converged {
+                break;
+            }
+        }
+        let function = layer.temperature_function(surface_temperature);
+        self.with_surface_function(top, function)
+    }
+
+    /// Replaces the temperature profile below `top` with the given function
+    fn with_surface_function(self, top: f64, function: FunctionDef) -> Self {
+        let function_at_top = self.temperature_function_at(top).clone();
+        let mut next_functions = vec![FunctionDefWithAlt {
+            altitude: top,
+            function: function_at_top,
+        }];
+        next_functions.extend(
+            self.next_functions
+                .iter()
+                .filter(|fun_def| fun_def.altitude > top)
+                .cloned(),
+        );
+        AtmosphereDef {
+            first_temperature_function: function,
+            next_functions,
+            // the function fixes the temperatures
+            temperature_fixed_point: None,
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::Atmosphere;
+
+    #[test]
+    fn test_excess() {
+        let layer = ConvectiveLayer::default();
+        assert_eq!(layer.excess(-1.0), 10.0);
+        assert!((layer.excess(0.0) - 10.0).abs() < 1e-12);
+        assert!(layer.excess(10.0).abs() < 1e-12);
+        assert_eq!(layer.excess(11.0), 0.0);
+        // every decade of the height takes the same part of the excess
+        let decade = layer.excess(0.099) - layer.excess(0.999);
+        assert!((layer.excess(0.999) - layer.excess(9.999) - decade).abs() < 1e-9);
+
+        let layer = ConvectiveLayer {
+            decay: SurfaceLayerDecay::PowerLaw { exponent: 0.5 },
+            ..Default::default()
+        };
+        assert!((layer.excess(0.0) - 10.0).abs() < 1e-12);
+        assert!(layer.excess(10.0).abs() < 1e-12);
+        let (h, dh) = (0.3, 1e-6);
+        let derivative = (layer.excess(h + dh) - layer.excess(h - dh)) / (2.0 * dh);
+        assert!((layer.dexcess(h) - derivative).abs() < 1e-6);
+
+        let layer = ConvectiveLayer::kukkamaki(100.0, -0.5, 1.0);
+        assert!((layer.dexcess(101.0) + 0.5).abs() < 1e-12);
+        assert!(layer.excess(100.0) > 0.0);
+    }
+
+    #[test]
+    fn test_convective_layer() {
+        let layer = ConvectiveLayer {
+            decay: SurfaceLayerDecay::PowerLaw { exponent: 0.3 },
+            ..Default::default()
+        };
+        let atmosphere =
+            Atmosphere::try_from_def(AtmosphereDef::us_76().with_convective_layer(&layer)).unwrap();
+        let standard = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        for h in [0.0, 0.01, 0.2, 1.5, 6.0] {
+            let expected = standard.temperature(h) + layer.excess(h);
+            assert!((atmosphere.temperature(h) - expected).abs() < 0.05);
+        }
+        for h in [10.0, 100.0, 15e3] {
+            assert!((atmosphere.temperature(h) - standard.temperature(h)).abs() < 1e-9);
+        }
+        assert!(atmosphere.dtemperature(0.01) < -10.0);
+    }
+
+    #[test]
+    fn test_monin_obukhov() {
+        let unstable = MoninObukhovLayer::default();
+        assert!(unstable.obukhov_length(300.0) < 0.0);
+        assert!((unstable.temperature(0.0, 300.0) - 300.0).abs() < 1e-12);
+        // superadiabatic near the surface, closer to adiabatic higher up
+        assert!(unstable.dtemperature(1.0, 300.0) < -0.1);
+        assert!(unstable.dtemperature(1.0, 300.0) < unstable.dtemperature(30.0, 300.0));
+        let (h, dh) = (2.0, 1e-6);
+        let derivative = (unstable.temperature(h + dh, 300.0)
+            - unstable.temperature(h - dh, 300.0))
+            / (2.0 * dh);
+        assert!((unstable.dtemperature(h, 300.0) - derivative).abs() < 1e-6);
+
+        let stable = MoninObukhovLayer {
+            heat_flux: -30.0,
+            ..Default::default()
+        };
+        assert!(stable.obukhov_length(280.0) > 0.0);
+        assert!(stable.dtemperature(1.0, 280.0) > 0.0);
+
+        let neutral = MoninObukhovLayer {
+            heat_flux: 0.0,
+            ..Default::default()
+        };
+        let lapse_rate = EARTH_GRAVITY / AIR_SPECIFIC_HEAT;
+        assert!((neutral.dtemperature(5.0, 288.0) + lapse_rate).abs() < 1e-12);
+
+        let atmosphere =
+            Atmosphere::try_from_def(AtmosphereDef::us_76().with_monin_obukhov_layer(&unstable))
+                .unwrap();
+        let standard = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        assert!(atmosphere.temperature(0.01) > standard.temperature(0.01) + 1.0);
+        for h in [50.0, 100.0, 15e3] {
+            assert!((atmosphere.temperature(h) - standard.temperature(h)).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use super::Atmosphere;
+
+/// An atmosphere changing in time, given by its snapshots at some moments - e.g. for simulating
+/// sunsets or time-lapses.
+///
+/// Between the snapshots the atmosphere is blended linearly in time, and before the first or
+/// after the last one it's the same as in them. The times can be given in any units, e.g. in
+/// seconds.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct TimeVaryingAtmosphere {
+    snapshots: Vec<(f64, Atmosphere)>,
+}
+
+impl TimeVaryingAtmosphere {
+    /// Creates an atmosphere with a single snapshot at the time `t`
+    pub fn new(t: f64, atmosphere: Atmosphere) -> Self {
+        TimeVaryingAtmosphere {
+            snapshots: vec![(t, atmosphere)],
+        }
+    }
+
+    /// Adds the snapshot of the atmosphere at the time `t`, replacing the one at that time if
+    /// there is one
+    pub fn with_snapshot(mut self, t: f64, atmosphere: Atmosphere) -> Self {
+        match self
+            .snapshots
+            .binary_search_by(|(time, _)| time.total_cmp(&t))
+        {
+            Ok(index) => self.snapshots[index].1 = atmosphere,
+            Err(index) => self.snapshots.insert(index, (t, atmosphere)),
+        }
+        self
+    }
+
+    /// Returns the times of the snapshots, in ascending order
+    pub fn times(&self) -> Vec<f64> {
+        self.snapshots.iter().map(|(t, _)| *t).collect()
+    }
+
+    /// Returns the atmosphere at the time `t`
+    pub fn at(&self, t: f64) -> Atmosphere {
+        let index = self.snapshots.partition_point(|(time, _)| *time <= t);
+        if index == 0 {
+            return self.snapshots[0].1.clone();
+        }
+        if index == self.snapshots.len() {
+            return self.snapshots[index - 1].1.clone();
+        }
+        let (t0, a) = &self.snapshots[index - 1];
+        let (t1, b) = &self.snapshots[index];
+        Atmosphere::blend(a, b, (t - t0) / (t1 - t0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::AtmosphereDef;
+
+    #[test]
+    fn test_time_varying() {
+        let morning = Atmosphere::try_from_def(AtmosphereDef::us_76()).unwrap();
+        let noon =
+            Atmosphere::try_from_def(AtmosphereDef::us_76().with_temperature_offset(8.0)).unwrap();
+        let atmosphere = TimeVaryingAtmosphere::new(21600.0, morning.clone())
+            .with_snapshot(43200.0, noon.clone());
+        assert_eq!(atmosphere.times(), vec![21600.0, 43200.0]);
+
+        assert_eq!(
+            atmosphere.at(0.0).temperature(100.0),
+            morning.temperature(100.0)
+        );
+        assert_eq!(
+            atmosphere.at(50000.0).temperature(100.0),
+            noon.temperature(100.0)
+        );
+        let expected = 0.75 * morning.temperature(100.0) + 0.25 * noon.temperature(100.0);
+        assert!((atmosphere.at(27000.0).temperature(100.0) - expected).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,103 @@
+//! Profiles of the refractive index structure constant Cn^2, describing the strength of the
+//! optical turbulence at different altitudes.
+
+use super::Atmosphere;
+
+/// The ground-level Cn^2 (in m^(-2/3)) of the Hufnagel-Valley 5/7 model
+pub const HV57_GROUND_LEVEL: f64 = 1.7e-14;
+/// The RMS upper-altitude wind speed (in m/s) of the Hufnagel-Valley 5/7 model
+pub const HV57_WIND_SPEED: f64 = 21.0;
+
+/// A profile of the refractive index structure constant Cn^2, in m^(-2/3)
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum TurbulenceProfile {
+    /// The Hufnagel-Valley model, with the given RMS wind speed at high altitudes (in m/s) and
+    /// Cn^2 at the ground. The altitudes are taken as the heights above the ground.
+    HufnagelValley { wind_speed: f64, ground_level: f64 },
+    /// Pairs of the altitudes (in meters) and the values of Cn^2, sorted by altitude; the values
+    /// are interpolated linearly between the points, and held constant beyond them
+    Table(Vec<(f64, f64)>),
+}
+
+impl TurbulenceProfile {
+    /// Returns the Hufnagel-Valley 5/7 model, giving the Fried parameter of 5 cm and the
+    /// isoplanatic angle of 7 µrad at 0.5 µm for a vertical path
+    pub fn hv57() -> Self {
+        TurbulenceProfile::HufnagelValley {
+            wind_speed: HV57_WIND_SPEED,
+            ground_level: HV57_GROUND_LEVEL,
+        }
+    }
+
+    /// Returns Cn^2 (in m^(-2/3)) at the given altitude
+    pub fn structure_constant(&self, h: f64) -> f64 {
+        match self {
+            TurbulenceProfile::HufnagelValley {
+                wind_speed,
+                ground_level,
+            } => {
+                let h = h.max(0.0);
+                0.00594 * (wind_speed / 27.0).powi(2) * (1e-5 * h).powi(10) * (-h / 1000.0).exp()
+                    + 2.7e-16 * (-h / 1500.0).exp()
+                    + ground_level * (-h / 100.0).exp()
+            }
+            TurbulenceProfile::Table(points) => {
+                let i = points.partition_point(|&(alt, _)| alt <= h);
+                match (i.checked_sub(1).map(|j| points[j]), points.get(i)) {
+                    (Some((h0, v0)), Some(&(h1, v1))) => v0 + (v1 - v0) * (h - h0) / (h1 - h0),
+                    (Some((_, v)), None) | (None, Some(&(_, v))) => v,
+                    (None, None) => 0.0,
+                }
+            }
+        }
+    }
+}
+
+impl Atmosphere {
+    /// Returns the same atmosphere, with the given profile of the optical turbulence
+    pub fn with_turbulence(self, turbulence: TurbulenceProfile) -> Atmosphere {
+        Atmosphere {
+            turbulence: Some(turbulence),
+            ..self
+        }
+    }
+
+    /// Returns the profile of the optical turbulence, if the atmosphere has one
+    pub fn turbulence(&self) -> Option<&TurbulenceProfile> {
+        self.turbulence.as_ref()
+    }
+
+    /// Returns Cn^2 (in m^(-2/3)) at the given altitude, or 0 if the atmosphere has no profile of
+    /// the optical turbulence
+    pub fn structure_constant(&self, h: f64) -> f64 {
+        self.turbulence
+            .as_ref()
+            .map_or(0.0, |turbulence| turbulence.structure_constant(h))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::us76_atmosphere;
+
+    #[test]
+    fn test_profiles() {
+        let hv = TurbulenceProfile::hv57();
+        assert!((hv.structure_constant(0.0) - 1.7e-14 - 2.7e-16).abs() < 1e-20);
+        assert!(hv.structure_constant(1000.0) < 1e-15);
+        // the bump of the jet stream
+        assert!(hv.structure_constant(10e3) > hv.structure_constant(5e3));
+
+        let table = TurbulenceProfile::Table(vec![(0.0, 1e-14), (100.0, 3e-14)]);
+        assert_eq!(table.structure_constant(-10.0), 1e-14);
+        assert!((table.structure_constant(50.0) - 2e-14).abs() < 1e-28);
+        assert_eq!(table.structure_constant(200.0), 3e-14);
+
+        let atmosphere = us76_atmosphere();
+        assert_eq!(atmosphere.structure_constant(0.0), 0.0);
+        let atmosphere = atmosphere.with_turbulence(table);
+        assert_eq!(atmosphere.structure_constant(200.0), 3e-14);
+    }
+}
@@ -0,0 +1,315 @@
+//! Checking atmosphere definitions for errors that would make the profiles meaningless.
+
+use super::{
+    vertical_profile::{VerticalProfile, VerticalProfileError},
+    AtmosphereDef, FunctionDef, FunctionDefWithAlt,
+};
+use cubic_splines::BoundaryCondition;
+
+/// The number of altitudes in each interval of the temperature profile at which the temperature
+/// is checked
+const SAMPLES_PER_INTERVAL: usize = 16;
+
+/// The profile described by a part of an atmosphere definition
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+}
+
+/// An error in an atmosphere definition.
+///
+/// The layers are numbered from 0 for the first function, with the layer `i > 0` being
+/// defined by the `i - 1`-th of the next functions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AtmosphereDefError {
+    /// The pressure at the fixed point isn't a positive number
+    InvalidPressure { altitude: f64, pressure: f64 },
+    /// The layer doesn't start above the previous one
+    UnorderedLayer {
+        quantity: Quantity,
+        layer: usize,
+        altitude: f64,
+    },
+    /// The points of a spline or a table are missing, not finite, repeated, or (for a table)
+    /// not sorted by the altitude
+    InvalidPoints { quantity: Quantity, layer: usize },
+    /// The spline uses periodic boundary conditions, which aren't supported
+    UnsupportedBoundaryCondition { quantity: Quantity, layer: usize },
+    /// The profile couldn't be built from the functions and the fixed point
+    Profile {
+        quantity: Quantity,
+        error: VerticalProfileError,
+    },
+    /// The temperature isn't positive within the altitudes covered by the definition
+    NonPositiveTemperature {
+        layer: usize,
+        altitude: f64,
+        temperature: f64,
+    },
+    /// The pressure calculated from the temperatures isn't a positive number within the
+    /// altitudes covered by the definition
+    InvalidPressureProfile {
+        layer: usize,
+        altitude: f64,
+        pressure: f64,
+    },
+    /// A parameter of a modification of the definition, like the thickness of an inserted
+    /// inversion, is out of its range
+    InvalidParameter { name: &'static str, value: f64 },
+}
+
+impl AtmosphereDef {
+    /// Checks that the definition describes a physically meaningful atmosphere, returning the
+    /// first problem found.
+    ///
+    /// The temperature is checked between the lowest and the highest altitudes appearing in the
+    /// definition, as the functions continued beyond them can reach 0 K at some point.
+    pub fn validate(&self) -> Result<(), AtmosphereDefError> {
+        self.checked_profiles().map(|_| ())
+    }
+
+    /// Validates the definition, returning the temperature and humidity profiles
+    pub(super) fn checked_profiles(
+        &self,
+    ) -> Result<(VerticalProfile, VerticalProfile), AtmosphereDefError> {
+        let pressure = &self.pressure;
+        if !(pressure.altitude.is_finite()
+            && pressure.pressure.is_finite()
+            && pressure.pressure > 0.0)
+        {
+            return Err(AtmosphereDefError::InvalidPressure {
+                altitude: pressure.altitude,
+                pressure: pressure.pressure,
+            });
+        }
+        let temperature = self.checked_temperature_profile()?;
+        validate_functions(
+            Quantity::Humidity,
+            &self.first_humidity_function,
+            &self.next_humidity_functions,
+        )?;
+        let humidity =
+            self.try_humidity_profile()
+                .map_err(|error| AtmosphereDefError::Profile {
+                    quantity: Quantity::Humidity,
+                    error,
+                })?;
+
+        for h in self.sample_altitudes(&temperature) {
+            let value = temperature.eval(h);
+            if value.is_nan() || value <= 0.0 {
+                return Err(AtmosphereDefError::NonPositiveTemperature {
+                    layer: self.layer_at(h),
+                    altitude: h,
+                    temperature: value,
+                });
+            }
+        }
+        Ok((temperature, humidity))
+    }
+
+    /// Validates the temperature functions and builds the temperature profile from them
+    pub(super) fn checked_temperature_profile(
+        &self,
+    ) -> Result<VerticalProfile, AtmosphereDefError> {
+        validate_functions(
+            Quantity::Temperature,
+            &self.first_temperature_function,
+            &self.next_functions,
+        )?;
+        self.try_temperature_profile()
+            .map_err(|error| AtmosphereDefError::Profile {
+                quantity: Quantity::Temperature,
+                error,
+            })
+    }
+
+    /// Returns the altitudes at which the profiles are checked: a number of altitudes in every
+    /// interval of the temperature profile, between the lowest and the highest altitudes
+    /// appearing in the definition
+    pub(super) fn sample_altitudes(&self, temperature: &VerticalProfile) -> Vec<f64> {
+        let altitudes = self.altitudes();
+        let min = altitudes.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = altitudes.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let mut ends: Vec<f64> = temperature
+            .internals()
+            .0
+            .iter()
+            .copied()
+            .filter(|&h| h > min && h < max)
+            .collect();
+        ends.insert(0, min);
+        ends.push(max);
+        let mut samples: Vec<f64> = ends
+            .windows(2)
+            .flat_map(|pair| {
+                (0..SAMPLES_PER_INTERVAL).map(move |i| {
+                    pair[0] + (pair[1] - pair[0]) * i as f64 / SAMPLES_PER_INTERVAL as f64
+                })
+            })
+            .collect();
+        samples.push(max);
+        samples
+    }
+
+    /// Returns the index of the temperature layer containing the altitude `h`
+    pub(super) fn layer_at(&self, h: f64) -> usize {
+        self.next_functions
+            .iter()
+            .filter(|fun_def| fun_def.altitude <= h)
+            .count()
+    }
+
+    /// Returns all the altitudes appearing in the definition
+    fn altitudes(&self) -> Vec<f64> {
+        let mut altitudes = vec![self.pressure.altitude];
+        altitudes.extend(self.temperature_fixed_point.map(|point| point.altitude));
+        let functions = Some(&self.first_temperature_function)
+            .into_iter()
+            .chain(self.next_functions.iter().map(|fun_def| &fun_def.function));
+        for function in functions {
+            if let FunctionDef::Spline { points, .. } | FunctionDef::Table { points, .. } = function
+            {
+                altitudes.extend(points.iter().map(|point| point.0));
+            }
+        }
+        altitudes.extend(self.next_functions.iter().map(|fun_def| fun_def.altitude));
+        altitudes
+    }
+}
+
+/// Checks the ordering of the layers and the points of the functions
+fn validate_functions(
+    quantity: Quantity,
+    first_function: &FunctionDef,
+    next_functions: &[FunctionDefWithAlt],
+) -> Result<(), AtmosphereDefError> {
+    let mut previous_altitude = f64::NEG_INFINITY;
+    for (index, fun_def) in next_functions.iter().enumerate() {
+        if !(fun_def.altitude.is_finite() && fun_def.altitude > previous_altitude) {
+            return Err(AtmosphereDefError::UnorderedLayer {
+                quantity,
+                layer: index + 1,
+                altitude: fun_def.altitude,
+            });
+        }
+        previous_altitude = fun_def.altitude;
+    }
+
+    let functions = Some(first_function)
+        .into_iter()
+        .chain(next_functions.iter().map(|fun_def| &fun_def.function));
+    for (layer, function) in functions.enumerate() {
+        validate_function(quantity, layer, function)?;
+    }
+    Ok(())
+}
+
+/// Checks that the points of a spline or a table can define a function
+pub(super) fn validate_function(
+    quantity: Quantity,
+    layer: usize,
+    function: &FunctionDef,
+) -> Result<(), AtmosphereDefError> {
+    let valid = match function {
+        FunctionDef::Spline {
+            points,
+            boundary_condition,
+        } => {
+            if let BoundaryCondition::Periodic = boundary_condition {
+                return Err(AtmosphereDefError::UnsupportedBoundaryCondition { quantity, layer });
+            }
+            let mut altitudes: Vec<f64> = points.iter().map(|point| point.0).collect();
+            altitudes.sort_by(f64::total_cmp);
+            points.len() >= 2 && finite(points) && increasing(&altitudes)
+        }
+        FunctionDef::Table { points, .. } => {
+            let altitudes: Vec<f64> = points.iter().map(|point| point.0).collect();
+            !points.is_empty() && finite(points) && increasing(&altitudes)
+        }
+        FunctionDef::Linear { .. } | FunctionDef::Custom(_) => true,
+    };
+    if !valid {
+        return Err(AtmosphereDefError::InvalidPoints { quantity, layer });
+    }
+    Ok(())
+}
+
+fn finite(points: &[(f64, f64)]) -> bool {
+    points.iter().all(|(x, y)| x.is_finite() && y.is_finite())
+}
+
+fn increasing(values: &[f64]) -> bool {
+    values.windows(2).all(|pair| pair[0] < pair[1])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::atmosphere::{PressureFixedPoint, TemperatureFixedPoint};
+
+    #[test]
+    fn test_valid_definitions() {
+        assert_eq!(AtmosphereDef::us_76().validate(), Ok(()));
+        assert_eq!(AtmosphereDef::inferior_mirage(10.0).validate(), Ok(()));
+        assert_eq!(
+            AtmosphereDef::superior_mirage(100.0, 50.0, 5.0).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_invalid_definitions() {
+        let def = AtmosphereDef {
+            pressure: PressureFixedPoint {
+                altitude: 0.0,
+                pressure: -1.0,
+            },
+            ..AtmosphereDef::us_76()
+        };
+        assert!(matches!(
+            def.validate(),
+            Err(AtmosphereDefError::InvalidPressure { .. })
+        ));
+
+        let mut def = AtmosphereDef::us_76();
+        def.next_functions.swap(2, 3);
+        assert_eq!(
+            def.validate(),
+            Err(AtmosphereDefError::UnorderedLayer {
+                quantity: Quantity::Temperature,
+                layer: 4,
+                altitude: 32e3,
+            })
+        );
+
+        let mut def = AtmosphereDef::us_76();
+        def.next_functions[1].function = FunctionDef::Table {
+            points: vec![(20e3, 216.65), (20e3, 220.0)],
+            extrapolation: Default::default(),
+        };
+        assert_eq!(
+            def.validate(),
+            Err(AtmosphereDefError::InvalidPoints {
+                quantity: Quantity::Temperature,
+                layer: 2,
+            })
+        );
+
+        let mut def = AtmosphereDef::us_76();
+        def.temperature_fixed_point = Some(TemperatureFixedPoint {
+            altitude: 0.0,
+            temperature: 60.0,
+        });
+        match def.validate() {
+            Err(AtmosphereDefError::NonPositiveTemperature {
+                layer, temperature, ..
+            }) => {
+                assert_eq!(layer, 0);
+                assert!(temperature <= 0.0);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
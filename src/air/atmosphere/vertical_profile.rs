@@ -1,8 +1,53 @@
 use cubic_splines::{BoundaryCondition, CubicPoly, Spline};
 #[cfg(feature = "serialization")]
 use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// The step in meters used for differentiating custom functions numerically
+const DERIVATIVE_STEP: f64 = 1e-3;
+
+/// An arbitrary function of the altitude.
+///
+/// The function is shared, so that the definitions containing it can be cloned. Custom functions
+/// can't be serialized.
+#[derive(Clone)]
+pub struct CustomFunction(Arc<dyn Fn(f64) -> f64 + Send + Sync>);
+
+impl CustomFunction {
+    pub fn new<F: Fn(f64) -> f64 + Send + Sync + 'static>(f: F) -> Self {
+        CustomFunction(Arc::new(f))
+    }
+
+    pub fn eval(&self, x: f64) -> f64 {
+        (self.0)(x)
+    }
+
+    /// Returns the derivative of the function, calculated numerically with central differences
+    pub fn eval_derivative(&self, x: f64) -> f64 {
+        (self.eval(x + DERIVATIVE_STEP) - self.eval(x - DERIVATIVE_STEP)) / (2.0 * DERIVATIVE_STEP)
+    }
+}
+
+impl fmt::Debug for CustomFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomFunction")
+    }
+}
+
+impl PartialEq for CustomFunction {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub enum VerticalFunction {
     /// T(h) = a*h + b
@@ -11,6 +56,8 @@ pub enum VerticalFunction {
         b: f64,
     },
     Cubic(CubicPoly<f64>),
+    #[cfg_attr(feature = "serialization", serde(skip))]
+    Custom(CustomFunction),
 }
 
 impl VerticalFunction {
@@ -18,6 +65,7 @@ impl VerticalFunction {
         match self {
             VerticalFunction::Linear { a, b } => a * x + b,
             VerticalFunction::Cubic(poly) => poly.eval(x),
+            VerticalFunction::Custom(f) => f.eval(x),
         }
     }
 
@@ -25,7 +73,88 @@ impl VerticalFunction {
         match self {
             VerticalFunction::Linear { a, .. } => *a,
             VerticalFunction::Cubic(poly) => poly.derivative(x),
+            VerticalFunction::Custom(f) => f.eval_derivative(x),
+        }
+    }
+
+    /// Writes the values of the function at the altitudes `xs` to `out`
+    fn eval_into(&self, xs: &[f64], out: &mut [f64]) {
+        match self {
+            VerticalFunction::Linear { a, b } => {
+                for (y, x) in out.iter_mut().zip(xs) {
+                    *y = a * x + b;
+                }
+            }
+            VerticalFunction::Cubic(poly) => {
+                for (y, &x) in out.iter_mut().zip(xs) {
+                    *y = poly.eval(x);
+                }
+            }
+            VerticalFunction::Custom(f) => {
+                for (y, &x) in out.iter_mut().zip(xs) {
+                    *y = f.eval(x);
+                }
+            }
+        }
+    }
+}
+
+/// The index of the interval of a profile in which the last evaluated altitude was found.
+///
+/// Rays move slowly between the intervals, so the next altitude is usually in the same interval
+/// or in a neighbouring one, which are checked before falling back to a binary search. A hint
+/// belongs to a single sequence of evaluations, like the integration of one ray, and is passed to
+/// `VerticalProfile::eval_with_hint` - it isn't shared between clones of a profile or threads.
+/// The index is atomic only so that the path steppers holding their hints can be `Sync`.
+#[derive(Debug, Default)]
+pub struct IntervalHint(AtomicUsize);
+
+impl Clone for IntervalHint {
+    fn clone(&self) -> Self {
+        IntervalHint(AtomicUsize::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl IntervalHint {
+    /// Returns the index of the interval containing `h`, with the intervals given by their ends
+    /// sorted in ascending order
+    pub(crate) fn find(&self, ends: &[f64], h: f64) -> usize {
+        if ends.is_empty() {
+            return 0;
+        }
+        let contains = |index: usize| {
+            index <= ends.len()
+                && (index == 0 || ends[index - 1] < h)
+                && (index == ends.len() || h <= ends[index])
+        };
+        let hint = self.0.load(Ordering::Relaxed);
+        let index = [hint, hint + 1, hint.wrapping_sub(1)]
+            .iter()
+            .copied()
+            .find(|&index| contains(index))
+            .unwrap_or_else(
+                || match ends.binary_search_by(|a| a.partial_cmp(&h).unwrap()) {
+                    Ok(index) | Err(index) => index,
+                },
+            );
+        if index != hint {
+            self.0.store(index, Ordering::Relaxed);
+        }
+        index
+    }
+
+    /// Splits the altitudes `hs` into runs of consecutive ones lying in the same interval, and
+    /// returns the index of the interval and the range of the positions in `hs` of every run
+    pub(crate) fn runs(&self, ends: &[f64], hs: &[f64]) -> Vec<(usize, Range<usize>)> {
+        let mut runs: Vec<(usize, Range<usize>)> = vec![];
+        for (i, &h) in hs.iter().enumerate() {
+            let index = self.find(ends, h);
+            match runs.last_mut() {
+                Some((last, range)) if *last == index => range.end = i + 1,
+                _ => runs.push((index, i..i + 1)),
+            }
         }
+        runs
     }
 }
 
@@ -54,26 +183,117 @@ impl VerticalProfile {
     }
 
     pub fn eval(&self, h: f64) -> f64 {
-        match self
-            .altitude_interval_ends
-            .binary_search_by(|a| a.partial_cmp(&h).unwrap())
-        {
-            Ok(index) | Err(index) => self.interval_functions[index].eval(h),
-        }
+        self.eval_with_hint(h, &IntervalHint::default())
     }
 
     pub fn eval_derivative(&self, h: f64) -> f64 {
-        match self
-            .altitude_interval_ends
-            .binary_search_by(|a| a.partial_cmp(&h).unwrap())
-        {
-            Ok(index) | Err(index) => self.interval_functions[index].eval_derivative(h),
+        self.eval_derivative_with_hint(h, &IntervalHint::default())
+    }
+
+    /// Returns the values of the profile at all the altitudes `hs`.
+    ///
+    /// The altitudes are split into runs lying in the same interval - one per layer if they are
+    /// sorted - and the function of the interval is evaluated over the whole run at once.
+    pub fn eval_many(&self, hs: &[f64]) -> Vec<f64> {
+        let mut result = vec![0.0; hs.len()];
+        for (index, range) in IntervalHint::default().runs(&self.altitude_interval_ends, hs) {
+            self.interval_functions[index].eval_into(&hs[range.clone()], &mut result[range]);
         }
+        result
+    }
+
+    /// Evaluates the profile like `eval`, starting the search for the interval containing `h`
+    /// from the one remembered by the `hint`, and updating the hint
+    pub fn eval_with_hint(&self, h: f64, hint: &IntervalHint) -> f64 {
+        self.function_at(h, hint).eval(h)
+    }
+
+    /// Evaluates the derivative of the profile like `eval_derivative`, using and updating the
+    /// `hint` like `eval_with_hint`
+    pub fn eval_derivative_with_hint(&self, h: f64, hint: &IntervalHint) -> f64 {
+        self.function_at(h, hint).eval_derivative(h)
     }
 
     pub(crate) fn internals(&self) -> (&Vec<f64>, &Vec<VerticalFunction>) {
         (&self.altitude_interval_ends, &self.interval_functions)
     }
+
+    /// Returns the profile with the values `(1 - weight) * self + weight * other`
+    pub(crate) fn blend(&self, other: &VerticalProfile, weight: f64) -> VerticalProfile {
+        let mut ends: Vec<f64> = self
+            .altitude_interval_ends
+            .iter()
+            .chain(&other.altitude_interval_ends)
+            .copied()
+            .collect();
+        ends.sort_by(f64::total_cmp);
+        ends.dedup();
+        let interval_functions = (0..=ends.len())
+            .map(|index| {
+                // an altitude inside the interval, which isn't one of the ends
+                let h = match (index.checked_sub(1).map(|i| ends[i]), ends.get(index)) {
+                    (Some(start), Some(end)) => 0.5 * (start + end),
+                    (Some(start), None) => start + 1.0,
+                    (None, Some(end)) => end - 1.0,
+                    (None, None) => 0.0,
+                };
+                let (hint, other_hint) = (IntervalHint::default(), IntervalHint::default());
+                blend_functions(
+                    self.function_at(h, &hint),
+                    other.function_at(h, &other_hint),
+                    weight,
+                )
+            })
+            .collect();
+        VerticalProfile {
+            altitude_interval_ends: ends,
+            interval_functions,
+        }
+    }
+
+    fn function_at(&self, h: f64, hint: &IntervalHint) -> &VerticalFunction {
+        let index = hint.find(&self.altitude_interval_ends, h);
+        &self.interval_functions[index]
+    }
+}
+
+/// Returns the function with the values `(1 - weight) * f1 + weight * f2`
+fn blend_functions(f1: &VerticalFunction, f2: &VerticalFunction, weight: f64) -> VerticalFunction {
+    let cubic = |function: &VerticalFunction| match *function {
+        VerticalFunction::Linear { a, b } => Some(CubicPoly::new(0.0, 0.0, a, b)),
+        VerticalFunction::Cubic(poly) => Some(poly),
+        VerticalFunction::Custom(_) => None,
+    };
+    match (f1, f2) {
+        (VerticalFunction::Linear { a: a1, b: b1 }, VerticalFunction::Linear { a: a2, b: b2 }) => {
+            VerticalFunction::Linear {
+                a: (1.0 - weight) * a1 + weight * a2,
+                b: (1.0 - weight) * b1 + weight * b2,
+            }
+        }
+        _ => match (cubic(f1), cubic(f2)) {
+            (Some(poly1), Some(poly2)) => {
+                VerticalFunction::Cubic(poly1 * (1.0 - weight) + poly2 * weight)
+            }
+            _ => {
+                let (f1, f2) = (f1.clone(), f2.clone());
+                VerticalFunction::Custom(CustomFunction::new(move |h| {
+                    (1.0 - weight) * f1.eval(h) + weight * f2.eval(h)
+                }))
+            }
+        },
+    }
+}
+
+/// The way in which a tabulated function is continued outside of the range of the table
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum Extrapolation {
+    /// With the values at the ends of the table
+    #[default]
+    Constant,
+    /// With the gradients of the first and the last intervals of the table
+    Linear,
 }
 
 #[derive(Clone, Debug)]
@@ -86,9 +306,97 @@ pub enum FunctionDef {
         points: Vec<(f64, f64)>,
         boundary_condition: BoundaryCondition<f64>,
     },
+    /// Linear interpolation between (altitude, value) pairs, sorted by the altitude
+    Table {
+        points: Vec<(f64, f64)>,
+        #[cfg_attr(feature = "serialization", serde(default))]
+        extrapolation: Extrapolation,
+    },
+    /// An arbitrary function of the altitude; the pressure is then integrated numerically
+    #[cfg_attr(feature = "serialization", serde(skip))]
+    Custom(CustomFunction),
 }
 
 impl FunctionDef {
+    /// Creates a custom function definition from a closure
+    pub fn custom<F: Fn(f64) -> f64 + Send + Sync + 'static>(f: F) -> FunctionDef {
+        FunctionDef::Custom(CustomFunction::new(f))
+    }
+
+    /// Approximates a function of the altitude, given by its values `f` and derivatives `df`,
+    /// with a spline through its values at the given altitudes, in ascending order.
+    ///
+    /// Outside of the altitudes, the spline is continued linearly with the derivatives at the
+    /// ends.
+    pub fn sampled<F, D>(f: F, df: D, altitudes: &[f64]) -> FunctionDef
+    where
+        F: Fn(f64) -> f64,
+        D: Fn(f64) -> f64,
+    {
+        let first = altitudes[0];
+        let last = altitudes[altitudes.len() - 1];
+        FunctionDef::Spline {
+            points: altitudes.iter().map(|&h| (h, f(h))).collect(),
+            boundary_condition: BoundaryCondition::Derivatives(df(first), df(last)),
+        }
+    }
+
+    /// Returns the same function with all the values increased by `offset`
+    pub(crate) fn shifted(self, offset: f64) -> FunctionDef {
+        match self {
+            FunctionDef::Linear { gradient } => FunctionDef::Linear { gradient },
+            FunctionDef::Spline {
+                points,
+                boundary_condition,
+            } => FunctionDef::Spline {
+                points: points.into_iter().map(|(x, y)| (x, y + offset)).collect(),
+                boundary_condition,
+            },
+            FunctionDef::Table {
+                points,
+                extrapolation,
+            } => FunctionDef::Table {
+                points: points.into_iter().map(|(x, y)| (x, y + offset)).collect(),
+                extrapolation,
+            },
+            FunctionDef::Custom(f) => FunctionDef::custom(move |x| f.eval(x) + offset),
+        }
+    }
+
+    /// Returns the same function with the distances of all the values from `center` multiplied
+    /// by `factor`, which multiplies the gradients by `factor`
+    pub(crate) fn scaled(self, factor: f64, center: f64) -> FunctionDef {
+        let scale = move |y: f64| center + factor * (y - center);
+        match self {
+            FunctionDef::Linear { gradient } => FunctionDef::Linear {
+                gradient: gradient * factor,
+            },
+            FunctionDef::Spline {
+                points,
+                boundary_condition,
+            } => FunctionDef::Spline {
+                points: points.into_iter().map(|(x, y)| (x, scale(y))).collect(),
+                boundary_condition: match boundary_condition {
+                    BoundaryCondition::Derivatives(d1, d2) => {
+                        BoundaryCondition::Derivatives(d1 * factor, d2 * factor)
+                    }
+                    BoundaryCondition::SecondDerivatives(d1, d2) => {
+                        BoundaryCondition::SecondDerivatives(d1 * factor, d2 * factor)
+                    }
+                    condition => condition,
+                },
+            },
+            FunctionDef::Table {
+                points,
+                extrapolation,
+            } => FunctionDef::Table {
+                points: points.into_iter().map(|(x, y)| (x, scale(y))).collect(),
+                extrapolation,
+            },
+            FunctionDef::Custom(f) => FunctionDef::custom(move |x| scale(f.eval(x))),
+        }
+    }
+
     fn into_intermediate(
         self,
         start_alt: Option<f64>,
@@ -109,7 +417,7 @@ impl FunctionDef {
                 let spline = Spline::new(points, boundary_condition);
                 let mut alts = vec![];
                 let mut funs = vec![];
-                if start_alt.map_or(true, |start_alt| start_alt < spline.min_x()) {
+                if start_alt.is_none_or(|start_alt| start_alt < spline.min_x()) {
                     if let Some(start_alt) = start_alt {
                         alts.push(start_alt);
                     }
@@ -135,7 +443,7 @@ impl FunctionDef {
                     alts.push(start);
                     funs.push(IntermediateFunctionDef::Cubic { poly });
                 }
-                if end_alt.map_or(true, |end_alt| end_alt > spline.max_x()) {
+                if end_alt.is_none_or(|end_alt| end_alt > spline.max_x()) {
                     alts.push(spline.max_x());
                     funs.push(IntermediateFunctionDef::Linear {
                         gradient: spline.derivative_end(),
@@ -144,6 +452,65 @@ impl FunctionDef {
                 }
                 (alts, funs)
             }
+            FunctionDef::Table {
+                points,
+                extrapolation,
+            } => {
+                let (min_x, min_y) = points[0];
+                let (max_x, max_y) = points[points.len() - 1];
+                let gradient = |(x1, y1): (f64, f64), (x2, y2): (f64, f64)| (y2 - y1) / (x2 - x1);
+                let (gradient_start, gradient_end) = match extrapolation {
+                    Extrapolation::Linear if points.len() > 1 => (
+                        gradient(points[0], points[1]),
+                        gradient(points[points.len() - 2], points[points.len() - 1]),
+                    ),
+                    _ => (0.0, 0.0),
+                };
+                let mut alts = vec![];
+                let mut funs = vec![];
+                if start_alt.is_none_or(|start_alt| start_alt < min_x) {
+                    if let Some(start_alt) = start_alt {
+                        alts.push(start_alt);
+                    }
+                    funs.push(IntermediateFunctionDef::Linear {
+                        gradient: gradient_start,
+                        fixed_point: Some((min_x, min_y)),
+                    });
+                }
+                for pair in points.windows(2) {
+                    let (mut start, end) = (pair[0].0, pair[1].0);
+                    if let Some(start_alt) = start_alt {
+                        if start_alt > end {
+                            continue;
+                        }
+                        if start_alt > start {
+                            start = start_alt;
+                        }
+                    }
+                    if let Some(end_alt) = end_alt {
+                        if end_alt < start {
+                            continue;
+                        }
+                    }
+                    alts.push(start);
+                    funs.push(IntermediateFunctionDef::Linear {
+                        gradient: gradient(pair[0], pair[1]),
+                        fixed_point: Some(pair[0]),
+                    });
+                }
+                if end_alt.is_none_or(|end_alt| end_alt > max_x) {
+                    alts.push(start_alt.map_or(max_x, |start_alt| start_alt.max(max_x)));
+                    funs.push(IntermediateFunctionDef::Linear {
+                        gradient: gradient_end,
+                        fixed_point: Some((max_x, max_y)),
+                    });
+                }
+                (alts, funs)
+            }
+            FunctionDef::Custom(f) => (
+                start_alt.into_iter().collect(),
+                vec![IntermediateFunctionDef::Custom(f)],
+            ),
         }
     }
 }
@@ -157,6 +524,7 @@ pub enum IntermediateFunctionDef {
     Cubic {
         poly: CubicPoly<f64>,
     },
+    Custom(CustomFunction),
 }
 
 impl IntermediateFunctionDef {
@@ -168,13 +536,14 @@ impl IntermediateFunctionDef {
             } => Some(y0 + (x - x0) * gradient),
             IntermediateFunctionDef::Linear { .. } => None,
             IntermediateFunctionDef::Cubic { poly } => Some(poly.eval(x)),
+            IntermediateFunctionDef::Custom(f) => Some(f.eval(x)),
         }
     }
 
     fn has_fixed_point(&self) -> bool {
         match self {
             IntermediateFunctionDef::Linear { fixed_point, .. } => fixed_point.is_some(),
-            IntermediateFunctionDef::Cubic { .. } => true,
+            IntermediateFunctionDef::Cubic { .. } | IntermediateFunctionDef::Custom(_) => true,
         }
     }
 
@@ -191,6 +560,7 @@ impl IntermediateFunctionDef {
                 }
             }
             IntermediateFunctionDef::Cubic { poly } => VerticalFunction::Cubic(poly),
+            IntermediateFunctionDef::Custom(f) => VerticalFunction::Custom(f),
         }
     }
 }
@@ -237,9 +607,25 @@ impl VerticalProfileBuilder {
             function_defs,
             fixed_value,
         } = self;
-        let (interval_ends, mut intermediate_function_defs) =
+        let (interval_ends, mut intermediate_function_defs, layers) =
             Self::generate_intermediate_function_defs(interval_ends, function_defs);
-        Self::fill_fixed_points(&interval_ends, &mut intermediate_function_defs, fixed_value)?;
+        Self::fill_fixed_points(&interval_ends, &mut intermediate_function_defs, fixed_value)
+            .map_err(|error| match error {
+                VerticalProfileError::FixedPointConflict {
+                    index1,
+                    index2,
+                    point1,
+                    point2,
+                    gradient,
+                } => VerticalProfileError::FixedPointConflict {
+                    index1: layers[index1],
+                    index2: layers[index2],
+                    point1,
+                    point2,
+                    gradient,
+                },
+                error => error,
+            })?;
         Ok(VerticalProfile {
             altitude_interval_ends: interval_ends,
             interval_functions: intermediate_function_defs
@@ -249,25 +635,32 @@ impl VerticalProfileBuilder {
         })
     }
 
+    /// Splits the functions into intermediate ones, returning the ends of their intervals, the
+    /// functions and the indices of the functions they come from
     fn generate_intermediate_function_defs(
         mut interval_ends: Vec<f64>,
         mut function_defs: Vec<FunctionDef>,
-    ) -> (Vec<f64>, Vec<IntermediateFunctionDef>) {
+    ) -> (Vec<f64>, Vec<IntermediateFunctionDef>, Vec<usize>) {
         let mut new_interval_ends = vec![];
         let mut new_function_defs = vec![];
+        let mut layers = vec![];
         let first_function_def = function_defs.remove(0);
         let (alts, funs) =
             first_function_def.into_intermediate(None, interval_ends.first().cloned());
         new_interval_ends.extend(alts);
+        layers.extend(funs.iter().map(|_| 0));
         new_function_defs.extend(funs);
+        let mut layer = 0;
         while !interval_ends.is_empty() {
+            layer += 1;
             let start = interval_ends.remove(0);
             let fun = function_defs.remove(0);
             let (alts, funs) = fun.into_intermediate(Some(start), interval_ends.first().cloned());
             new_interval_ends.extend(alts);
+            layers.extend(funs.iter().map(|_| layer));
             new_function_defs.extend(funs);
         }
-        (new_interval_ends, new_function_defs)
+        (new_interval_ends, new_function_defs, layers)
     }
 
     fn fill_fixed_points(
@@ -289,9 +682,9 @@ impl VerticalProfileBuilder {
     ) -> Result<(), VerticalProfileError> {
         const EPSILON: f64 = 1e-4;
 
-        let has_fixed_point_below = index.checked_sub(1).map_or(false, |index_below| {
-            function_defs[index_below].has_fixed_point()
-        });
+        let has_fixed_point_below = index
+            .checked_sub(1)
+            .is_some_and(|index_below| function_defs[index_below].has_fixed_point());
         let has_fixed_point_above =
             (index + 1 < function_defs.len()) && function_defs[index + 1].has_fixed_point();
 
@@ -300,9 +693,7 @@ impl VerticalProfileBuilder {
         if let (Some((x, _)), IntermediateFunctionDef::Linear { fixed_point, .. }) =
             (fixed_value, &mut function_defs[index])
         {
-            if index
-                .checked_sub(1)
-                .map_or(true, |ib| interval_ends[ib] <= x)
+            if index.checked_sub(1).is_none_or(|ib| interval_ends[ib] <= x)
                 && (index >= interval_ends.len() || interval_ends[index] >= x)
             {
                 *fixed_point = fixed_value;
@@ -372,43 +763,42 @@ impl VerticalProfileBuilder {
                     Ok(())
                 }
             }
-            IntermediateFunctionDef::Cubic { poly } => {
-                // if the fixed value is in our interval, check its consistency with the polynomial
+            fun_def => {
+                let value = |x: f64| fun_def.get(x).expect("should have a value");
+                // if the fixed value is in our interval, check its consistency with the function
                 if let Some((x, y)) = fixed_value {
-                    if index
-                        .checked_sub(1)
-                        .map_or(true, |ib| interval_ends[ib] <= x)
+                    if index.checked_sub(1).is_none_or(|ib| interval_ends[ib] <= x)
                         && (index >= interval_ends.len() || interval_ends[index] >= x)
-                        && (y - poly.eval(x)).abs() > EPSILON
+                        && (y - value(x)).abs() > EPSILON
                     {
                         return Err(VerticalProfileError::FixedPointConflict {
                             index1: index,
                             index2: index,
                             point1: (x, y),
-                            point2: (x, poly.eval(x)),
+                            point2: (x, value(x)),
                             gradient: None,
                         });
                     }
                 }
                 // check the consistency of the previous function's fixed point and ours
                 if let Some((x, y)) = point_below {
-                    if (y - poly.eval(x)).abs() > EPSILON {
+                    if (y - value(x)).abs() > EPSILON {
                         return Err(VerticalProfileError::FixedPointConflict {
                             index1: index - 1,
                             index2: index,
                             point1: (x, y),
-                            point2: (x, poly.eval(x)),
+                            point2: (x, value(x)),
                             gradient: None,
                         });
                     }
                 }
                 // check the consistency of the next function's fixed point and ours
                 if let Some((x, y)) = point_above {
-                    if (y - poly.eval(x)).abs() > EPSILON {
+                    if (y - value(x)).abs() > EPSILON {
                         return Err(VerticalProfileError::FixedPointConflict {
                             index1: index,
                             index2: index + 1,
-                            point1: (x, poly.eval(x)),
+                            point1: (x, value(x)),
                             point2: (x, y),
                             gradient: None,
                         });
@@ -422,7 +812,11 @@ impl VerticalProfileBuilder {
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum VerticalProfileError {
+    /// None of the functions has a known value, and no fixed value was given
     NoFixedPoint,
+    /// The values of two functions don't match; the functions are numbered in the order in which
+    /// they were added to the builder, starting from 0 for the first one. The points are the
+    /// (altitude, value) pairs that don't fit together.
     FixedPointConflict {
         index1: usize,
         index2: usize,
@@ -436,6 +830,46 @@ pub enum VerticalProfileError {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_interval_hint() {
+        let ends = [0.0, 100.0, 200.0, 1000.0];
+        let hint = IntervalHint::default();
+        for h in [
+            -5.0, 0.0, 50.0, 100.0, 150.0, 950.0, 1000.0, 2e3, 150.0, -1.0, 200.0,
+        ] {
+            let expected = match ends.binary_search_by(|a| a.partial_cmp(&h).unwrap()) {
+                Ok(index) | Err(index) => index,
+            };
+            assert_eq!(hint.find(&ends, h), expected);
+        }
+        assert_eq!(IntervalHint::default().find(&[], 10.0), 0);
+
+        let hs = [-5.0, 50.0, 60.0, 150.0, 180.0, 50.0];
+        assert_eq!(
+            IntervalHint::default().runs(&ends, &hs),
+            vec![(0, 0..1), (1, 1..3), (2, 3..5), (1, 5..6)]
+        );
+    }
+
+    #[test]
+    fn test_eval_many() {
+        let profile = VerticalProfileBuilder::new(FunctionDef::Linear { gradient: -0.0065 })
+            .with_next_function(
+                100.0,
+                FunctionDef::Spline {
+                    points: vec![(100.0, 287.35), (200.0, 288.0), (300.0, 286.0)],
+                    boundary_condition: BoundaryCondition::Derivatives(0.0, 0.0),
+                },
+            )
+            .with_next_function(500.0, FunctionDef::Linear { gradient: 0.001 })
+            .with_fixed_value(0.0, 288.0)
+            .build()
+            .unwrap();
+        let hs = [-10.0, 0.0, 120.0, 700.0, 160.0, 350.0, 90.0, 2e3];
+        let values: Vec<f64> = hs.iter().map(|&h| profile.eval(h)).collect();
+        assert_eq!(profile.eval_many(&hs), values);
+    }
+
     #[test]
     fn should_build_correctly_with_fixed_point_in_first_interval() {
         let _ = VerticalProfileBuilder::new(FunctionDef::Linear { gradient: -0.0065 })
@@ -543,4 +977,49 @@ mod test {
             })
         );
     }
+
+    #[test]
+    fn should_interpolate_table() {
+        let points = vec![(0.0, 10.0), (10.0, 8.0), (20.0, 12.0)];
+        let profile = VerticalProfileBuilder::new(FunctionDef::Table {
+            points: points.clone(),
+            extrapolation: Extrapolation::Constant,
+        })
+        .build()
+        .expect("should build correctly");
+        assert_eq!(profile.eval(10.0), 8.0);
+        assert!((profile.eval(5.0) - 9.0).abs() < 1e-12);
+        assert!((profile.eval_derivative(15.0) - 0.4).abs() < 1e-12);
+        assert_eq!(profile.eval(-5.0), 10.0);
+        assert_eq!(profile.eval(30.0), 12.0);
+
+        let profile = VerticalProfileBuilder::new(FunctionDef::Table {
+            points,
+            extrapolation: Extrapolation::Linear,
+        })
+        .with_next_function(25.0, FunctionDef::Linear { gradient: -1.0 })
+        .build()
+        .expect("should build correctly");
+        assert!((profile.eval(-5.0) - 11.0).abs() < 1e-12);
+        assert!((profile.eval(25.0) - 14.0).abs() < 1e-12);
+        assert!((profile.eval(30.0) - 9.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn should_fail_if_table_conflicts() {
+        let result = VerticalProfileBuilder::new(FunctionDef::Linear { gradient: 1.0 })
+            .with_next_function(
+                0.0,
+                FunctionDef::Table {
+                    points: vec![(0.0, 0.0), (10.0, 2.0)],
+                    extrapolation: Extrapolation::Constant,
+                },
+            )
+            .with_fixed_value(-1.0, 0.0)
+            .build();
+        assert!(matches!(
+            result,
+            Err(VerticalProfileError::FixedPointConflict { .. })
+        ));
+    }
 }
@@ -0,0 +1,87 @@
+//! Empirical formulas for the astronomical refraction near the Earth's surface, for
+//! cross-checking the refraction calculated by tracing the rays.
+//!
+//! The angles are given in radians, the pressures in pascals and the temperatures in kelvins.
+
+/// The pressure in Pa for which the formulas are given
+const FORMULA_PRESSURE: f64 = 101_000.0;
+/// The temperature in K for which the formulas are given
+const FORMULA_TEMPERATURE: f64 = 283.0;
+
+/// Returns the factor by which the refraction differs from the one at the pressure and the
+/// temperature of the formulas
+fn scaling(pressure: f64, temperature: f64) -> f64 {
+    pressure / FORMULA_PRESSURE * FORMULA_TEMPERATURE / temperature
+}
+
+/// Returns the astronomical refraction at the given apparent altitude, according to Bennett's
+/// formula (G. G. Bennett, "The Calculation of Astronomical Refraction in Marine Navigation",
+/// 1982), for the given pressure and temperature at the observer.
+///
+/// The formula is accurate to about 0.07' between the horizon and the zenith.
+pub fn bennett(apparent_altitude: f64, pressure: f64, temperature: f64) -> f64 {
+    let h = apparent_altitude.to_degrees();
+    let arcmin = 1.0 / (h + 7.31 / (h + 4.4)).to_radians().tan();
+    (arcmin / 60.0).to_radians() * scaling(pressure, temperature)
+}
+
+/// Returns the astronomical refraction at the given true altitude, according to Sæmundsson's
+/// formula (T. Sæmundsson, Sky and Telescope, 1986), for the given pressure and temperature at
+/// the observer.
+///
+/// The formula is consistent with Bennett's to about 0.1', slightly worse at the horizon.
+pub fn saemundsson(true_altitude: f64, pressure: f64, temperature: f64) -> f64 {
+    let h = true_altitude.to_degrees();
+    let arcmin = 1.02 / (h + 10.3 / (h + 5.11)).to_radians().tan();
+    (arcmin / 60.0).to_radians() * scaling(pressure, temperature)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::us76_atmosphere_env;
+
+    const ARCMIN: f64 = 1.0 / 60.0 * std::f64::consts::PI / 180.0;
+
+    #[test]
+    fn test_formulas() {
+        // 34.5' at the horizon and about 1' at 45° at the standard conditions
+        let horizon = bennett(0.0, FORMULA_PRESSURE, FORMULA_TEMPERATURE) / ARCMIN;
+        assert!((horizon - 34.5).abs() < 0.1, "{}", horizon);
+        let refraction = bennett(45f64.to_radians(), FORMULA_PRESSURE, FORMULA_TEMPERATURE);
+        assert!((refraction / ARCMIN - 1.0).abs() < 0.02);
+        let scaled = bennett(
+            45f64.to_radians(),
+            0.5 * FORMULA_PRESSURE,
+            FORMULA_TEMPERATURE,
+        );
+        assert!((scaled - 0.5 * refraction).abs() < 1e-12);
+
+        // the formulas are inverse to each other
+        for apparent in [0.0, 1.0, 5.0, 20.0, 60.0] {
+            let apparent = f64::to_radians(apparent);
+            let refraction = bennett(apparent, 101_325.0, 288.15);
+            let inverse = saemundsson(apparent - refraction, 101_325.0, 288.15);
+            assert!((refraction - inverse).abs() < 0.15 * ARCMIN);
+        }
+    }
+
+    #[test]
+    fn test_compare_with_integration() {
+        let env = us76_atmosphere_env();
+        let pressure = env.atmosphere.pressure(0.0);
+        let temperature = env.atmosphere.temperature(0.0);
+        for (altitude, tolerance) in [(0.0, 1.0), (2.0, 0.2), (10.0, 0.1), (45.0, 0.05)] {
+            let apparent = f64::to_radians(altitude);
+            let integrated = env.astronomical_refraction(apparent, 0.0).unwrap();
+            let empirical = bennett(apparent, pressure, temperature);
+            assert!(
+                (integrated - empirical).abs() < tolerance * ARCMIN,
+                "{}: {} {}",
+                altitude,
+                integrated / ARCMIN,
+                empirical / ARCMIN
+            );
+        }
+    }
+}
@@ -4,9 +4,15 @@
 //! A module providing the tooling for atmospheric models.
 
 pub mod atmosphere;
+pub mod empirical;
 mod refractive;
 mod vapor;
+pub mod visibility;
 
-pub use self::atmosphere::{us76_atmosphere, Atmosphere, AtmosphereDef};
-pub use self::refractive::{air_index, d_air_index};
-pub use self::vapor::{dp_sv, p_sv};
+pub use self::atmosphere::{
+    comparison, import, inversion, presets, resampling, surface_layer, turbulence, us76_atmosphere,
+    validation, Atmosphere, AtmosphereDef, AtmosphereDefBuilder, HumidityKind,
+    TimeVaryingAtmosphere, TurbulenceProfile,
+};
+pub use self::refractive::{air_index, d_air_index, d_radio_air_index, radio_air_index};
+pub use self::vapor::{d_vapor_pressure, dp_sv, p_sv, vapor_pressure};
@@ -2,7 +2,7 @@
 // https://emtoolbox.nist.gov/wavelength/Documentation.asp#ComparisonCiddorandEdlenEquations
 // Uses the modified Edlen equation
 
-use super::{dp_sv, p_sv};
+use super::{d_vapor_pressure, vapor_pressure};
 
 const A: f64 = 8342.54;
 const B: f64 = 2406147.0;
@@ -26,7 +26,7 @@ pub fn air_index(lambda: f64, p: f64, t: f64, rh: f64) -> f64 {
     let epsilon = D * G;
     let zeta = (3.7345 - s * 0.0401) * 1e-10;
 
-    let pv = rh / 100.0 * p_sv(t);
+    let pv = vapor_pressure(rh, t);
 
     1.0 + alpha * p * (1.0 + beta * p + gamma * t1 * p) / (delta + epsilon * t1)
         - (292.75 / t) * zeta * pv
@@ -47,8 +47,8 @@ pub fn d_air_index(lambda: f64, p: f64, t: f64, rh: f64, dp: f64, dt: f64, drh:
     let epsilon = D * G;
     let zeta = (3.7345 - s * 0.0401) * 1e-10;
 
-    let pv = rh / 100.0 * p_sv(t);
-    let dpv = drh / 100.0 * p_sv(t) + rh / 100.0 * dp_sv(t) * dt;
+    let pv = vapor_pressure(rh, t);
+    let dpv = d_vapor_pressure(rh, t, drh, dt);
 
     alpha * dp * (1.0 + beta * p + gamma * t1 * p) / (delta + epsilon * t1)
         + alpha
@@ -60,3 +60,26 @@ pub fn d_air_index(lambda: f64, p: f64, t: f64, rh: f64, dp: f64, dt: f64, drh:
         + 292.75 / t / t * dt * zeta * pv
         - 292.75 / t * zeta * dpv
 }
+
+/// Returns the radio refractive index of air at the given pressure (`p`), temperature (`t`) and
+/// relative humidity (`rh`), using the Smith-Weintraub formula:
+/// N = 77.6/T * (P + 4810 * e/T), with the pressures in hPa
+pub fn radio_air_index(p: f64, t: f64, rh: f64) -> f64 {
+    let p_hpa = p / 100.0;
+    let e_hpa = vapor_pressure(rh, t) / 100.0;
+
+    1.0 + 77.6e-6 / t * (p_hpa + 4810.0 * e_hpa / t)
+}
+
+/// Returns the derivative of the radio refractive index of air as a function of pressure (`p`),
+/// temperature (`t`), relative humidity (`rh`) and their derivatives (`dp`, `dt`, `drh`)
+pub fn d_radio_air_index(p: f64, t: f64, rh: f64, dp: f64, dt: f64, drh: f64) -> f64 {
+    let p_hpa = p / 100.0;
+    let dp_hpa = dp / 100.0;
+    let e_hpa = vapor_pressure(rh, t) / 100.0;
+    let de_hpa = d_vapor_pressure(rh, t, drh, dt) / 100.0;
+
+    77.6e-6
+        * (dp_hpa / t - p_hpa * dt / t / t
+            + 4810.0 * (de_hpa / t / t - 2.0 * e_hpa * dt / t / t / t))
+}
@@ -93,3 +93,23 @@ pub fn dp_sv(temp: f64) -> f64 {
     let dx = dx(temp);
     4.0 * (2.0 * c / x).powi(3) * 1e6 * (2.0 * dc / x - 2.0 * c / x / x * dx)
 }
+
+/// calculates the partial pressure of water vapor at the given relative humidity (in percent) and
+/// temperature; dry air gives 0 even at temperatures where `p_sv` is undefined
+pub fn vapor_pressure(rh: f64, temp: f64) -> f64 {
+    if rh == 0.0 {
+        0.0
+    } else {
+        rh / 100.0 * p_sv(temp)
+    }
+}
+
+/// calculates the derivative of the partial pressure of water vapor, given the relative humidity
+/// (`rh`), temperature (`temp`) and their derivatives (`drh`, `dtemp`)
+pub fn d_vapor_pressure(rh: f64, temp: f64, drh: f64, dtemp: f64) -> f64 {
+    if rh == 0.0 && drh == 0.0 {
+        0.0
+    } else {
+        drh / 100.0 * p_sv(temp) + rh / 100.0 * dp_sv(temp) * dtemp
+    }
+}
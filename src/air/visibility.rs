@@ -0,0 +1,53 @@
+//! Koschmieder's law relating the extinction of light in the air to the visibility of distant
+//! objects.
+//!
+//! The extinction coefficients are given in 1/m, and the distances in meters.
+
+/// The contrast threshold of the meteorological optical range, as defined by the WMO
+pub const MOR_CONTRAST_THRESHOLD: f64 = 0.05;
+
+/// Returns the distance at which the contrast of a black object against the horizon sky drops
+/// to `threshold`, in air with the extinction coefficient `extinction`. Koschmieder originally
+/// used the threshold 0.02.
+pub fn visual_range(extinction: f64, threshold: f64) -> f64 {
+    -threshold.ln() / extinction
+}
+
+/// Returns the meteorological optical range for the extinction coefficient `extinction` - the
+/// visual range for the contrast threshold of 5%.
+pub fn meteorological_optical_range(extinction: f64) -> f64 {
+    visual_range(extinction, MOR_CONTRAST_THRESHOLD)
+}
+
+/// Returns the extinction coefficient corresponding to the reported visibility `visibility`,
+/// taken as the meteorological optical range.
+pub fn extinction_from_visibility(visibility: f64) -> f64 {
+    -MOR_CONTRAST_THRESHOLD.ln() / visibility
+}
+
+/// Returns the apparent contrast of an object against the horizon sky, seen through
+/// `path_length` meters of air with the extinction coefficient `extinction`, when its intrinsic
+/// contrast is `intrinsic_contrast` (-1 for a black object).
+///
+/// The path length can be taken from the path reaching the object, e.g. with
+/// `Path::arc_length_at_dist`.
+pub fn apparent_contrast(intrinsic_contrast: f64, extinction: f64, path_length: f64) -> f64 {
+    intrinsic_contrast * (-extinction * path_length).exp()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_koschmieder() {
+        // the classical 3.912 / extinction
+        assert!((visual_range(1e-4, 0.02) - 39_120.2).abs() < 0.1);
+        let extinction = extinction_from_visibility(10e3);
+        assert!((meteorological_optical_range(extinction) - 10e3).abs() < 1e-9);
+        // a black object at the meteorological optical range is at the threshold
+        let contrast = apparent_contrast(-1.0, extinction, 10e3);
+        assert!((contrast + MOR_CONTRAST_THRESHOLD).abs() < 1e-12);
+        assert!(apparent_contrast(-1.0, extinction, 20e3).abs() < MOR_CONTRAST_THRESHOLD);
+    }
+}
@@ -0,0 +1,538 @@
+use crate::{
+    air::atmosphere::AtmosphereHints, refine_crossing, sample_range, Environment, ImageOrientation,
+    Integration, IntegratorKind, RayOptions, RayState, SamplingError, TransferCurve,
+};
+use na::integration::{Integrator, StepSize};
+use std::f64::consts::FRAC_PI_2;
+
+/// The altitude in meters above which the refraction is neglected when tracing rays out of the
+/// atmosphere, if the environment doesn't set the top of the atmosphere
+pub(crate) const DEFAULT_TOP_OF_ATMOSPHERE: f64 = 100e3;
+/// The maximal step in meters when tracing rays out of the atmosphere
+const ASTRONOMICAL_STEP: f64 = 1000.0;
+/// The tolerance in meters of the integration of rays out of the atmosphere
+const ASTRONOMICAL_TOLERANCE: f64 = 1e-6;
+/// The distance in meters beyond which rays that haven't left the atmosphere are considered
+/// trapped in it
+const MAX_ASTRONOMICAL_DIST: f64 = 1e7;
+/// The precision in radians of the apparent altitude calculated from the true one
+const ALTITUDE_EPSILON: f64 = 1e-10;
+/// The maximal number of iterations when calculating the apparent altitude
+const MAX_ITERATIONS: usize = 50;
+/// The step in meters of the integration of the density over the vertical column of the air
+const COLUMN_STEP: f64 = 10.0;
+
+/// The number of rays per the radius of a disk, used for calculating its image
+const DISK_RAYS_PER_RADIUS: f64 = 25.0;
+/// The margin in radians by which the apparent altitudes of the rays forming the image of a disk
+/// extend beyond the true altitudes of its limbs
+const DISK_MARGIN: f64 = 0.01;
+/// The width of the junction of an erect and an inverted image of a disk, relative to its
+/// radius, below which the image is considered an omega
+const OMEGA_JUNCTION: f64 = 0.5;
+
+/// The wavelength of the red light in meters, for estimating the green flash
+const RED_WAVELENGTH: f64 = 650e-9;
+/// The wavelength of the green light in meters, for estimating the green flash
+const GREEN_WAVELENGTH: f64 = 520e-9;
+
+/// A ray reaching an observer from outside of the atmosphere
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct AstronomicalRay {
+    /// The astronomical refraction in radians
+    pub refraction: f64,
+    /// The relative airmass - the mass of the air along the ray, divided by the mass of the air
+    /// in the vertical column above the observer
+    pub airmass: f64,
+}
+
+/// The shape of the image of the Sun's or the Moon's disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum DiskShape {
+    /// A single erect image, possibly flattened
+    Regular,
+    /// An erect image joined at the bottom with its inverted image through a wide junction,
+    /// crossing the disk far from its lower limb
+    EtruscanVase,
+    /// An erect image joined at the bottom with its inverted image through a narrow junction,
+    /// close to the lower limb of the disk
+    Omega,
+    /// Any other distortion by mirages, e.g. detached or multiple images
+    Distorted,
+}
+
+/// The apparent image of the disk of the Sun or the Moon
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct DiskImage {
+    /// The apparent altitudes at which the disk is seen, in ascending order, paired with the
+    /// halves of the apparent widths of the disk at them (all in radians)
+    pub rows: Vec<(f64, f64)>,
+    /// The lowest apparent altitude at which the disk is seen, in radians
+    pub lower_limb: f64,
+    /// The highest apparent altitude at which the disk is seen, in radians
+    pub upper_limb: f64,
+    /// The ratio of the apparent height of the image to the diameter of the disk
+    pub flattening: f64,
+    pub shape: DiskShape,
+}
+
+impl Environment {
+    /// Returns the astronomical refraction (in radians) - the difference between the apparent
+    /// altitude of a celestial object and its true altitude - for an object seen at the apparent
+    /// altitude `apparent_altitude` (in radians) by an observer at the altitude `observer_h` (in
+    /// meters).
+    ///
+    /// The ray is traced from the observer to the top of the atmosphere set in the environment
+    /// (100 km if it's not set), above which it's assumed to be straight. Returns `None` if the
+    /// ray hits the ground (the altitude 0, or the observer's altitude if it's lower) or doesn't
+    /// leave the atmosphere.
+    pub fn astronomical_refraction(&self, apparent_altitude: f64, observer_h: f64) -> Option<f64> {
+        // vertical rays aren't bent, and can't be traced as functions of the distance
+        if apparent_altitude >= FRAC_PI_2 {
+            return Some(0.0);
+        }
+        let (state, _) = self.trace_out_of_atmosphere(apparent_altitude, observer_h, None)?;
+        Some(self.bending_out_of_atmosphere(apparent_altitude, &state))
+    }
+
+    /// Returns both the astronomical refraction and the airmass for a ray seen at the apparent
+    /// altitude `apparent_altitude` (in radians) by an observer at the altitude `observer_h` (in
+    /// meters), calculated in a single integration of the ray.
+    ///
+    /// Returns `None` in the same cases as `astronomical_refraction`.
+    pub fn astronomical_ray(
+        &self,
+        apparent_altitude: f64,
+        observer_h: f64,
+    ) -> Option<AstronomicalRay> {
+        if apparent_altitude >= FRAC_PI_2 {
+            return Some(AstronomicalRay {
+                refraction: 0.0,
+                airmass: 1.0,
+            });
+        }
+        let density = |state: &RayState| self.density(state.h);
+        let (state, mass) =
+            self.trace_out_of_atmosphere(apparent_altitude, observer_h, Some(&density))?;
+        Some(AstronomicalRay {
+            refraction: self.bending_out_of_atmosphere(apparent_altitude, &state),
+            airmass: mass / self.vertical_column(observer_h, state.h),
+        })
+    }
+
+    /// Returns the airmass along a ray seen at the apparent altitude `apparent_altitude` (in
+    /// radians) by an observer at the altitude `observer_h` (in meters) - the mass of the air
+    /// along the ray, relative to the one in the vertical column above the observer.
+    pub fn airmass(&self, apparent_altitude: f64, observer_h: f64) -> Option<f64> {
+        self.astronomical_ray(apparent_altitude, observer_h)
+            .map(|ray| ray.airmass)
+    }
+
+    /// Returns the apparent altitude (in radians) at which a celestial object at the true
+    /// altitude `true_altitude` (in radians) is seen by an observer at the altitude `observer_h`
+    /// (in meters) - the inverse of `astronomical_refraction`.
+    ///
+    /// Returns `None` if the object isn't visible, because the rays from it would have to pass
+    /// through the ground.
+    pub fn apparent_altitude(&self, true_altitude: f64, observer_h: f64) -> Option<f64> {
+        // the rays starting below the horizontal could hit the ground
+        let mut apparent = true_altitude.max(0.0);
+        for _ in 0..MAX_ITERATIONS {
+            let next = true_altitude + self.astronomical_refraction(apparent, observer_h)?;
+            if (next - apparent).abs() < ALTITUDE_EPSILON {
+                return Some(next);
+            }
+            apparent = next;
+        }
+        Some(apparent)
+    }
+
+    /// Returns the angle (in radians) by which the image of a celestial object at the true
+    /// altitude `true_altitude` (in radians) in the light of the wavelength `wavelengths.0` is
+    /// seen above its image in the light of the wavelength `wavelengths.1` (both in meters), by
+    /// an observer at the altitude `observer_h` (in meters).
+    ///
+    /// Returns `None` if the object isn't visible in one of the colors. The images only separate
+    /// for the optical refractive index model.
+    pub fn chromatic_separation(
+        &self,
+        true_altitude: f64,
+        observer_h: f64,
+        wavelengths: (f64, f64),
+    ) -> Option<f64> {
+        let apparent_altitude = |wavelength| {
+            Environment {
+                wavelength,
+                ..self.clone()
+            }
+            .apparent_altitude(true_altitude, observer_h)
+        };
+        Some(apparent_altitude(wavelengths.0)? - apparent_altitude(wavelengths.1)?)
+    }
+
+    /// Returns the angle (in radians) by which the green image of a point at the horizon (at the
+    /// true altitude 0) is seen above its red image by an observer at the altitude `observer_h`
+    /// (in meters) - the extent of the green rim of the setting Sun, which becomes the green
+    /// flash.
+    pub fn green_flash_separation(&self, observer_h: f64) -> Option<f64> {
+        self.chromatic_separation(0.0, observer_h, (GREEN_WAVELENGTH, RED_WAVELENGTH))
+    }
+
+    /// Tabulates the transfer curve for celestial objects seen by an observer at the altitude
+    /// `observer_h` (in meters): the true altitudes of the objects seen at the apparent altitudes
+    /// between the ends of `angle_range`, every `resolution` radians.
+    ///
+    /// The "heights" of the curve are the true altitudes in radians, and its distance is
+    /// infinite. The rays hitting the ground don't see any object.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the angles can't be sampled; see `try_astronomical_transfer_curve`.
+    pub fn astronomical_transfer_curve(
+        &self,
+        observer_h: f64,
+        angle_range: (f64, f64),
+        resolution: f64,
+    ) -> TransferCurve {
+        match self.try_astronomical_transfer_curve(observer_h, angle_range, resolution) {
+            Ok(curve) => curve,
+            Err(error) => panic!(
+                "invalid astronomical transfer curve parameters: {:?}",
+                error
+            ),
+        }
+    }
+
+    /// Tabulates the transfer curve like `astronomical_transfer_curve`, or returns an error if
+    /// the angles can't be sampled - if the ends of `angle_range` aren't finite, or `resolution`
+    /// isn't finite and positive.
+    pub fn try_astronomical_transfer_curve(
+        &self,
+        observer_h: f64,
+        angle_range: (f64, f64),
+        resolution: f64,
+    ) -> Result<TransferCurve, SamplingError> {
+        let angles = sample_range(
+            angle_range.0.min(angle_range.1),
+            angle_range.0.max(angle_range.1),
+            resolution,
+        )?;
+        let heights = angles
+            .iter()
+            .map(|&ang| {
+                self.astronomical_refraction(ang, observer_h)
+                    .map_or(f64::NAN, |refraction| ang - refraction)
+            })
+            .collect();
+        Ok(TransferCurve::new(f64::INFINITY, angles, heights))
+    }
+
+    /// Calculates the apparent image of the disk of the Sun or the Moon, with the center at the
+    /// true altitude `true_altitude` and the angular radius `radius` (both in radians), seen by
+    /// an observer at the altitude `observer_h` (in meters).
+    ///
+    /// The widths of the disk aren't changed by the refraction, so the image is determined by
+    /// the true altitudes seen at the apparent ones. Returns `None` if no part of the disk is
+    /// visible, or if `radius` or `true_altitude` isn't finite, or `radius` isn't positive.
+    pub fn disk_image(
+        &self,
+        true_altitude: f64,
+        radius: f64,
+        observer_h: f64,
+    ) -> Option<DiskImage> {
+        let range = (
+            true_altitude - radius - DISK_MARGIN,
+            true_altitude + radius + DISK_MARGIN,
+        );
+        let curve = self
+            .try_astronomical_transfer_curve(observer_h, range, radius / DISK_RAYS_PER_RADIUS)
+            .ok()?;
+        let half_width = |altitude: f64| {
+            let offset = altitude - true_altitude;
+            (offset.abs() <= radius).then(|| (radius * radius - offset * offset).sqrt())
+        };
+
+        let rows: Vec<(f64, f64)> = curve
+            .points()
+            .filter_map(|(angle, altitude)| half_width(altitude).map(|width| (angle, width)))
+            .collect();
+        // the limbs are found more precisely by interpolating the curve
+        let mut limbs: Vec<f64> = rows.iter().map(|&(angle, _)| angle).collect();
+        limbs.extend(curve.apparent_angles(true_altitude - radius));
+        limbs.extend(curve.apparent_angles(true_altitude + radius));
+        if limbs.is_empty() {
+            return None;
+        }
+        let lower_limb = limbs.iter().copied().fold(f64::INFINITY, f64::min);
+        let upper_limb = limbs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+
+        let segments: Vec<_> = curve
+            .segments()
+            .into_iter()
+            .filter(|segment| {
+                let (low, high) = (
+                    segment.heights.0.min(segment.heights.1),
+                    segment.heights.0.max(segment.heights.1),
+                );
+                low <= true_altitude + radius && high >= true_altitude - radius
+            })
+            .collect();
+        let shape = match segments.as_slice() {
+            [segment] if segment.orientation == ImageOrientation::Erect => DiskShape::Regular,
+            [inverted, erect]
+                if inverted.orientation == ImageOrientation::Inverted
+                    && erect.orientation == ImageOrientation::Erect
+                    && inverted.angles.1 == erect.angles.0 =>
+            {
+                match half_width(erect.heights.0) {
+                    Some(width) if width < OMEGA_JUNCTION * radius => DiskShape::Omega,
+                    Some(_) => DiskShape::EtruscanVase,
+                    // the images join below the disk, so they are separate
+                    None => DiskShape::Distorted,
+                }
+            }
+            _ => DiskShape::Distorted,
+        };
+
+        Some(DiskImage {
+            rows,
+            lower_limb,
+            upper_limb,
+            flattening: (upper_limb - lower_limb) / (2.0 * radius),
+            shape,
+        })
+    }
+
+    /// Returns the total bending of a ray launched at the angle `start_ang` that has reached the
+    /// given state
+    pub(crate) fn bending_out_of_atmosphere(&self, start_ang: f64, state: &RayState) -> f64 {
+        start_ang - state.get_angle(self) + self.surface_rotation(state.x)
+    }
+
+    /// Returns the mass of the air (in kg/m^2) in a vertical column between the given altitudes
+    fn vertical_column(&self, bottom: f64, top: f64) -> f64 {
+        self.vertical_integral(bottom, top, |h| self.density(h))
+    }
+
+    /// Integrates `f` over the altitude between `bottom` and `top`, with Simpson's rule
+    pub(crate) fn vertical_integral<F: Fn(f64) -> f64>(&self, bottom: f64, top: f64, f: F) -> f64 {
+        let n = 2 * (((top - bottom) / COLUMN_STEP / 2.0).ceil() as usize).max(1);
+        let step = (top - bottom) / n as f64;
+        let sum: f64 = (0..=n)
+            .map(|i| {
+                let weight = if i == 0 || i == n {
+                    1.0
+                } else if i % 2 == 1 {
+                    4.0
+                } else {
+                    2.0
+                };
+                weight * f(bottom + i as f64 * step)
+            })
+            .sum();
+        sum * step / 3.0
+    }
+
+    /// Traces a ray from the observer until it reaches the top of the atmosphere, and returns
+    /// its state there, along with the integral of `integrand` over the length of the ray if it's
+    /// given, or 0 otherwise
+    pub(crate) fn trace_out_of_atmosphere(
+        &self,
+        start_ang: f64,
+        observer_h: f64,
+        integrand: Option<&dyn Fn(&RayState) -> f64>,
+    ) -> Option<(RayState, f64)> {
+        let options = RayOptions {
+            step: ASTRONOMICAL_STEP,
+            integrator: IntegratorKind::DormandPrince,
+            tolerance: ASTRONOMICAL_TOLERANCE,
+            ..Default::default()
+        };
+        let curvature = self.curvature_at(0.0);
+        let mut state = RayState {
+            x: 0.0,
+            h: observer_h,
+            dh: start_ang.tan() * (1.0 + curvature * observer_h),
+        };
+        let wavelength = self.wavelength;
+        let hints = AtmosphereHints::default();
+        let diff_eq = |state: &RayState| self.calc_derivative(state, wavelength, &hints);
+        let ground_h = observer_h.min(0.0);
+        let top = self.top_of_atmosphere.unwrap_or(DEFAULT_TOP_OF_ATMOSPHERE);
+
+        // the length of the ray per meter of the distance along the surface
+        let length_element = |state: &RayState| {
+            let r_k = 1.0 + self.curvature_at(state.x) * state.h;
+            (state.dh * state.dh + r_k * r_k).sqrt()
+        };
+
+        let mut integrator = Integration::from_options(&options).integrator(options.step);
+        let mut integral = 0.0;
+        while state.h < top {
+            if state.x > MAX_ASTRONOMICAL_DIST {
+                return None;
+            }
+            // steep rays take shorter steps, so that the altitude changes by at most a step
+            let step = options.step / state.dh.abs().max(1.0);
+            let prev_state = state;
+            integrator.propagate_in_place(&mut state, diff_eq, StepSize::Step(step));
+            if state.h < ground_h {
+                return None;
+            }
+            if state.h >= top {
+                state = refine_crossing(&mut integrator, &prev_state, step, top, diff_eq);
+            }
+            if let Some(f) = integrand {
+                // Simpson's rule over the step
+                let step = state.x - prev_state.x;
+                let mid_state =
+                    integrator.propagate(&prev_state, diff_eq, StepSize::Step(0.5 * step));
+                let element = |state: &RayState| f(state) * length_element(state);
+                integral += (element(&prev_state) + 4.0 * element(&mid_state) + element(&state))
+                    * step
+                    / 6.0;
+            }
+        }
+        Some((state, integral))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::{Atmosphere, AtmosphereDef};
+    use crate::test_support::{us76_atmosphere_env, us76_radio_env};
+
+    #[test]
+    fn test_astronomical_refraction() {
+        let env = us76_atmosphere_env();
+        let arcmin = 1.0_f64.to_radians() / 60.0;
+        // about 1' at 45°, and about 35' at the horizon
+        let refraction = env.astronomical_refraction(0.5 * FRAC_PI_2, 0.0);
+        let refraction = refraction.unwrap() / arcmin;
+        assert!((refraction - 0.98).abs() < 0.03, "{}", refraction);
+        let refraction = env.astronomical_refraction(0.0, 0.0).unwrap() / arcmin;
+        assert!(refraction > 30.0 && refraction < 40.0, "{}", refraction);
+        // no refraction at the zenith
+        let near_zenith = env.astronomical_refraction(FRAC_PI_2 - 0.01, 0.0).unwrap();
+        assert!(near_zenith > 0.0 && near_zenith < 0.01 * arcmin);
+        assert_eq!(env.astronomical_refraction(FRAC_PI_2, 0.0), Some(0.0));
+        // the rays launched downwards hit the ground
+        assert_eq!(env.astronomical_refraction(-0.01, 0.0), None);
+        assert!(env.astronomical_refraction(-0.01, 1000.0).is_some());
+
+        // the air above 50 km barely contributes
+        let lower_top = Environment {
+            top_of_atmosphere: Some(50e3),
+            ..us76_atmosphere_env()
+        };
+        let difference = lower_top.astronomical_refraction(0.0, 0.0).unwrap()
+            - env.astronomical_refraction(0.0, 0.0).unwrap();
+        assert!(difference.abs() < 5e-6, "{}", difference);
+    }
+
+    #[test]
+    fn test_airmass() {
+        let env = us76_atmosphere_env();
+        let zenith = env.astronomical_ray(FRAC_PI_2 - 1e-3, 0.0).unwrap();
+        assert!((zenith.airmass - 1.0).abs() < 1e-4, "{}", zenith.airmass);
+        // close to the secant of the zenith angle high in the sky
+        let airmass = env.airmass(30f64.to_radians(), 0.0).unwrap();
+        assert!((airmass - 2.0).abs() < 0.01, "{}", airmass);
+        // about 38 at the horizon
+        let horizon = env.astronomical_ray(0.0, 0.0).unwrap();
+        assert!(
+            horizon.airmass > 35.0 && horizon.airmass < 42.0,
+            "{}",
+            horizon.airmass
+        );
+        assert_eq!(
+            Some(horizon.refraction),
+            env.astronomical_refraction(0.0, 0.0)
+        );
+        // the air above the observer is thinner, but so is the air along the ray
+        let mountain = env.airmass(30f64.to_radians(), 3000.0).unwrap();
+        assert!((mountain - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_apparent_altitude() {
+        let env = us76_atmosphere_env();
+        for true_altitude in [-0.005, 0.0, 0.1, 1.0] {
+            let apparent = env.apparent_altitude(true_altitude, 0.0).unwrap();
+            let refraction = env.astronomical_refraction(apparent, 0.0).unwrap();
+            assert!((apparent - refraction - true_altitude).abs() < 1e-9);
+        }
+        assert_eq!(env.apparent_altitude(-0.1, 0.0), None);
+    }
+
+    #[test]
+    fn test_disk_image() {
+        let sun_radius = 0.0046;
+        let env = us76_atmosphere_env();
+        let image = env.disk_image(0.05, sun_radius, 0.0).unwrap();
+        assert_eq!(image.shape, DiskShape::Regular);
+        assert!(image.flattening < 1.0 && image.flattening > 0.95);
+        // the setting Sun is flattened noticeably
+        let image = env.disk_image(0.0, sun_radius, 0.0).unwrap();
+        assert_eq!(image.shape, DiskShape::Regular);
+        assert!(image.flattening < 0.9, "{}", image.flattening);
+        assert!(image.rows.iter().all(|&(_, width)| width <= sun_radius));
+
+        // over a hot surface, the Sun merges with its inverted image when setting
+        let env = Environment {
+            atmosphere: Atmosphere::try_from_def(AtmosphereDef::inferior_mirage(10.0)).unwrap(),
+            ..us76_atmosphere_env()
+        };
+        let shape = |altitude| env.disk_image(altitude, sun_radius, 2.0).unwrap().shape;
+        assert_eq!(shape(0.003), DiskShape::Regular);
+        assert_eq!(shape(0.0), DiskShape::Distorted);
+        assert_eq!(shape(-0.008), DiskShape::EtruscanVase);
+        assert_eq!(shape(-0.012), DiskShape::Omega);
+        assert!(env.disk_image(-0.02, sun_radius, 2.0).is_none());
+        assert!(env.disk_image(0.05, 0.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_astronomical_transfer_curve() {
+        let env = us76_atmosphere_env();
+        let curve = env.astronomical_transfer_curve(0.0, (0.1, -0.01), 0.01);
+        let points: Vec<(f64, f64)> = curve.points().collect();
+        assert_eq!(points.len(), 12);
+        assert_eq!(points[0].0, -0.01);
+        // the rays launched downwards hit the ground
+        assert!(points[0].1.is_nan());
+        let (angle, altitude) = points[11];
+        let refraction = env.astronomical_refraction(angle, 0.0).unwrap();
+        assert!((angle - 0.1).abs() < 1e-12);
+        assert_eq!(altitude, angle - refraction);
+
+        assert_eq!(
+            env.try_astronomical_transfer_curve(0.0, (0.0, 0.1), 0.0)
+                .err(),
+            Some(SamplingError::InvalidResolution(0.0))
+        );
+        assert!(matches!(
+            env.try_astronomical_transfer_curve(0.0, (0.0, f64::INFINITY), 0.01),
+            Err(SamplingError::InvalidRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_green_flash_separation() {
+        let env = us76_atmosphere_env();
+        let arcsec = 1.0_f64.to_radians() / 3600.0;
+        // about 10'' for the standard atmosphere
+        let separation = env.green_flash_separation(0.0).unwrap() / arcsec;
+        assert!(separation > 8.0 && separation < 16.0, "{}", separation);
+        // the separation grows with the refraction
+        let high = env.chromatic_separation(0.1, 0.0, (GREEN_WAVELENGTH, RED_WAVELENGTH));
+        assert!(high.unwrap() / arcsec < 0.5 * separation);
+
+        let radio = us76_radio_env();
+        assert_eq!(radio.green_flash_separation(0.0), Some(0.0));
+    }
+}
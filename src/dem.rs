@@ -0,0 +1,424 @@
+//! Reading terrain profiles from digital elevation models: SRTM height files and GeoTIFF images
+
+use crate::{sample_range, SamplingError, Sightline, Terrain};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek},
+    path::Path,
+};
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    tags::Tag,
+};
+
+/// The value marking the missing data in SRTM height files
+const HGT_VOID: i16 = -32768;
+/// The GeoTIFF key giving the kind of the coordinates of the model
+const GT_MODEL_TYPE_GEO_KEY: u16 = 1024;
+/// The value of the model type key for the coordinates given by the latitude and the longitude
+const MODEL_TYPE_GEOGRAPHIC: u16 = 2;
+/// The GeoTIFF key giving the unit of the geographic coordinates
+const GEOG_ANGULAR_UNITS_GEO_KEY: u16 = 2054;
+/// The EPSG code of the degree, as the unit of the geographic coordinates
+const ANGULAR_DEGREE: u16 = 9102;
+/// The distance (in the spacings of the grid) by which the points can lie outside of the grid
+/// due to the rounding errors and still be considered to be on its edge
+const GRID_EPSILON: f64 = 1e-6;
+
+/// An error in reading an elevation model
+#[derive(Clone, Debug, PartialEq)]
+pub enum DemError {
+    /// Reading the file failed
+    Io(io::ErrorKind),
+    /// Decoding the GeoTIFF image failed, with the message from the decoder
+    Tiff(String),
+    /// The name of an SRTM height file doesn't give the coordinates of its corner, like
+    /// `N45E006.hgt`
+    InvalidName,
+    /// The size of the data doesn't match the grid
+    InvalidSize,
+    /// The grid has fewer than two rows or columns, so the elevations can't be interpolated
+    TooSmall,
+    /// The GeoTIFF image doesn't contain the georeferencing tags, its coordinates aren't
+    /// geographic ones in degrees, or its pixel format isn't supported
+    Unsupported,
+}
+
+impl From<io::Error> for DemError {
+    fn from(error: io::Error) -> Self {
+        DemError::Io(error.kind())
+    }
+}
+
+impl From<tiff::TiffError> for DemError {
+    fn from(error: tiff::TiffError) -> Self {
+        DemError::Tiff(error.to_string())
+    }
+}
+
+/// A grid of elevations spaced regularly in the latitude and the longitude.
+///
+/// The rows of the grid go from the north to the south, and the columns from the west to the
+/// east. The coordinates of the nodes are given in degrees; the missing data is NaN.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct ElevationGrid {
+    /// The latitude of the first row
+    pub north: f64,
+    /// The longitude of the first column
+    pub west: f64,
+    /// The difference of the latitudes of consecutive rows
+    pub latitude_step: f64,
+    /// The difference of the longitudes of consecutive columns
+    pub longitude_step: f64,
+    /// The number of columns
+    pub columns: usize,
+    /// The elevations in meters, row by row
+    pub elevations: Vec<f64>,
+}
+
+impl ElevationGrid {
+    /// Reads an SRTM height file. The coordinates of the south-west corner are taken from the
+    /// name of the file, like `N45E006.hgt`.
+    pub fn read_hgt<P: AsRef<Path>>(path: P) -> Result<Self, DemError> {
+        let name = path
+            .as_ref()
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .ok_or(DemError::InvalidName)?;
+        let (latitude, longitude) = parse_hgt_name(name).ok_or(DemError::InvalidName)?;
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Self::from_hgt_bytes(&bytes, latitude, longitude)
+    }
+
+    /// Reads the contents of an SRTM height file - a square grid of big-endian 16-bit integers
+    /// spanning one degree, with the south-west corner at the given latitude and longitude (in
+    /// degrees).
+    pub fn from_hgt_bytes(bytes: &[u8], latitude: f64, longitude: f64) -> Result<Self, DemError> {
+        let size = ((bytes.len() / 2) as f64).sqrt().round() as usize;
+        if size * size * 2 != bytes.len() {
+            return Err(DemError::InvalidSize);
+        }
+        if size < 2 {
+            return Err(DemError::TooSmall);
+        }
+        let elevations = bytes
+            .chunks_exact(2)
+            .map(|pair| match i16::from_be_bytes([pair[0], pair[1]]) {
+                HGT_VOID => f64::NAN,
+                value => value as f64,
+            })
+            .collect();
+        let step = 1.0 / (size - 1) as f64;
+        Ok(ElevationGrid {
+            north: latitude + 1.0,
+            west: longitude,
+            latitude_step: step,
+            longitude_step: step,
+            columns: size,
+            elevations,
+        })
+    }
+
+    /// Reads a single-band GeoTIFF image in geographic coordinates.
+    pub fn read_geotiff<P: AsRef<Path>>(path: P) -> Result<Self, DemError> {
+        Self::from_geotiff(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads a single-band GeoTIFF image in geographic coordinates from `reader`. The grid is
+    /// placed by the tie point of its first pixel and the scale of the pixels; the pixels
+    /// equal to the no-data value of GDAL are treated as missing.
+    ///
+    /// The GeoKey directory has to declare the geographic model type, and the degree as the
+    /// angular unit if it declares one - the images in projected coordinates are rejected as
+    /// `Unsupported`.
+    pub fn from_geotiff<R: Read + Seek>(reader: R) -> Result<Self, DemError> {
+        let mut decoder = Decoder::new(reader)?;
+        let (width, height) = decoder.dimensions()?;
+        if width < 2 || height < 2 {
+            return Err(DemError::TooSmall);
+        }
+        let geo_keys = decoder.get_tag_u16_vec(Tag::GeoKeyDirectoryTag)?;
+        if geo_key(&geo_keys, GT_MODEL_TYPE_GEO_KEY) != Some(MODEL_TYPE_GEOGRAPHIC)
+            || !matches!(
+                geo_key(&geo_keys, GEOG_ANGULAR_UNITS_GEO_KEY),
+                None | Some(ANGULAR_DEGREE)
+            )
+        {
+            return Err(DemError::Unsupported);
+        }
+        let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag)?;
+        let tie_point = decoder.get_tag_f64_vec(Tag::ModelTiepointTag)?;
+        if scale.len() < 2 || tie_point.len() < 6 {
+            return Err(DemError::Unsupported);
+        }
+        let no_data = match decoder.find_tag(Tag::GdalNodata)? {
+            Some(value) => value.into_string()?.trim().parse().ok(),
+            None => None,
+        };
+        let elevations: Vec<f64> = match decoder.read_image()? {
+            DecodingResult::I16(values) => values.into_iter().map(f64::from).collect(),
+            DecodingResult::U16(values) => values.into_iter().map(f64::from).collect(),
+            DecodingResult::I32(values) => values.into_iter().map(f64::from).collect(),
+            DecodingResult::F32(values) => values.into_iter().map(f64::from).collect(),
+            DecodingResult::F64(values) => values,
+            _ => return Err(DemError::Unsupported),
+        };
+        if elevations.len() != width as usize * height as usize {
+            return Err(DemError::Unsupported);
+        }
+        let elevations = elevations
+            .into_iter()
+            .map(|value| {
+                if Some(value) == no_data {
+                    f64::NAN
+                } else {
+                    value
+                }
+            })
+            .collect();
+
+        // the tie point maps the raster point (i, j) to the model point (x, y)
+        let (i, j, x, y) = (tie_point[0], tie_point[1], tie_point[3], tie_point[4]);
+        Ok(ElevationGrid {
+            north: y + j * scale[1],
+            west: x - i * scale[0],
+            latitude_step: scale[1],
+            longitude_step: scale[0],
+            columns: width as usize,
+            elevations,
+        })
+    }
+
+    /// Returns the number of rows of the grid, or 0 if it has no columns
+    pub fn rows(&self) -> usize {
+        self.elevations.len().checked_div(self.columns).unwrap_or(0)
+    }
+
+    /// Returns the elevation (in meters) at the given latitude and longitude (in radians),
+    /// interpolated bilinearly, or `None` if the point is outside of the grid or the data is
+    /// missing there. A grid with fewer than two rows or columns doesn't contain any point.
+    pub fn elevation(&self, latitude: f64, longitude: f64) -> Option<f64> {
+        if self.rows() < 2 || self.columns < 2 {
+            return None;
+        }
+        // the longitudes might be given in a different range than the ones of the grid
+        let longitude = (longitude.to_degrees() - self.west + 180.0).rem_euclid(360.0) - 180.0;
+        let on_grid = |x: f64, max: f64| {
+            (-GRID_EPSILON..=max + GRID_EPSILON)
+                .contains(&x)
+                .then(|| x.clamp(0.0, max))
+        };
+        let (rows, columns) = ((self.rows() - 1) as f64, (self.columns - 1) as f64);
+        let row = on_grid(
+            (self.north - latitude.to_degrees()) / self.latitude_step,
+            rows,
+        )?;
+        let column = on_grid(longitude / self.longitude_step, columns)?;
+        let (row0, column0) = (
+            row.floor().min(rows - 1.0),
+            column.floor().min(columns - 1.0),
+        );
+        let (t, u) = (row - row0, column - column0);
+        let at =
+            |row: f64, column: f64| self.elevations[row as usize * self.columns + column as usize];
+        // the nodes with zero weights are skipped, so that the missing data next to them doesn't
+        // matter
+        let nodes = [
+            (row0, column0, (1.0 - t) * (1.0 - u)),
+            (row0, column0 + 1.0, (1.0 - t) * u),
+            (row0 + 1.0, column0, t * (1.0 - u)),
+            (row0 + 1.0, column0 + 1.0, t * u),
+        ];
+        let value: f64 = nodes
+            .iter()
+            .filter(|&&(_, _, weight)| weight > 0.0)
+            .map(|&(row, column, weight)| weight * at(row, column))
+            .sum();
+        Some(value).filter(|value| !value.is_nan())
+    }
+
+    /// Builds the profile of the terrain along the sightline, sampled every `resolution` meters
+    /// up to the distance `max_dist`. The points outside of the grid or with missing data are
+    /// left out.
+    ///
+    /// Returns an error if `max_dist` isn't finite and non-negative, or `resolution` isn't
+    /// finite and positive.
+    pub fn terrain_profile(
+        &self,
+        sightline: &Sightline,
+        max_dist: f64,
+        resolution: f64,
+    ) -> Result<Terrain, SamplingError> {
+        let points = sample_range(0.0, max_dist, resolution)?
+            .into_iter()
+            .filter_map(|dist| {
+                let point = sightline.geo_point(dist, 0.0);
+                self.elevation(point.latitude, point.longitude)
+                    .map(|elevation| (dist, elevation))
+            })
+            .collect();
+        Ok(Terrain::from_points(points))
+    }
+}
+
+/// Returns the value of the key `id` stored directly in the GeoKey directory `directory`, or
+/// `None` if the key is missing or its value is stored in another tag
+fn geo_key(directory: &[u16], id: u16) -> Option<u16> {
+    // the header and the entries are quadruples: the key, the tag of the value (0 if the value
+    // is stored in the entry), the count and the value
+    directory
+        .chunks_exact(4)
+        .skip(1)
+        .find(|entry| entry[0] == id && entry[1] == 0)
+        .map(|entry| entry[3])
+}
+
+/// Parses the coordinates of the south-west corner from the name of an SRTM height file
+fn parse_hgt_name(name: &str) -> Option<(f64, f64)> {
+    if name.len() != 7 || !name.is_ascii() {
+        return None;
+    }
+    let latitude: f64 = name[1..3].parse().ok()?;
+    let longitude: f64 = name[4..7].parse().ok()?;
+    let latitude = match &name[0..1] {
+        "N" | "n" => latitude,
+        "S" | "s" => -latitude,
+        _ => return None,
+    };
+    let longitude = match &name[3..4] {
+        "E" | "e" => longitude,
+        "W" | "w" => -longitude,
+        _ => return None,
+    };
+    Some((latitude, longitude))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use tiff::encoder::{colortype::GrayI16, TiffEncoder};
+
+    /// Returns the contents of a height file with 3x3 points, rising to the east by 100 m per
+    /// column, with a void in the middle of the last row
+    fn hgt_bytes() -> Vec<u8> {
+        let values: [i16; 9] = [0, 100, 200, 0, 100, 200, 0, HGT_VOID, 200];
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_hgt() {
+        assert_eq!(parse_hgt_name("N45E006"), Some((45.0, 6.0)));
+        assert_eq!(parse_hgt_name("S01W120"), Some((-1.0, -120.0)));
+        assert_eq!(parse_hgt_name("X45E006"), None);
+
+        let grid = ElevationGrid::from_hgt_bytes(&hgt_bytes(), 45.0, 6.0).unwrap();
+        assert_eq!(grid.rows(), 3);
+        assert_eq!(grid.north, 46.0);
+        let at = |lat: f64, lon: f64| grid.elevation(lat.to_radians(), lon.to_radians());
+        assert_eq!(at(46.0, 6.0), Some(0.0));
+        assert!((at(45.75, 6.25).unwrap() - 50.0).abs() < 1e-9);
+        assert!((at(45.5, 7.0).unwrap() - 200.0).abs() < 1e-9);
+        assert_eq!(at(45.1, 6.5), None);
+        assert_eq!(at(47.0, 6.5), None);
+        assert_eq!(
+            ElevationGrid::from_hgt_bytes(&[0; 10], 45.0, 6.0),
+            Err(DemError::InvalidSize)
+        );
+        assert_eq!(
+            ElevationGrid::from_hgt_bytes(&[0; 2], 45.0, 6.0),
+            Err(DemError::TooSmall)
+        );
+
+        // the grids built by hand might be empty
+        let empty = ElevationGrid {
+            columns: 0,
+            elevations: vec![],
+            ..grid.clone()
+        };
+        assert_eq!(empty.rows(), 0);
+        assert_eq!(empty.elevation(46f64.to_radians(), 6f64.to_radians()), None);
+    }
+
+    /// Returns a GeoTIFF image with the contents of `hgt_bytes`, with the given model type
+    fn geotiff(model_type: u16) -> Cursor<Vec<u8>> {
+        let mut data = Cursor::new(vec![]);
+        {
+            let mut encoder = TiffEncoder::new(&mut data).unwrap();
+            let mut image = encoder.new_image::<GrayI16>(3, 3).unwrap();
+            let directory = image.encoder();
+            directory
+                .write_tag(
+                    Tag::GeoKeyDirectoryTag,
+                    &[1, 1, 0, 1, GT_MODEL_TYPE_GEO_KEY, 0, 1, model_type][..],
+                )
+                .unwrap();
+            directory
+                .write_tag(Tag::ModelPixelScaleTag, &[0.5, 0.5, 0.0][..])
+                .unwrap();
+            directory
+                .write_tag(Tag::ModelTiepointTag, &[0.0, 0.0, 0.0, 6.0, 46.0, 0.0][..])
+                .unwrap();
+            directory.write_tag(Tag::GdalNodata, "-32768").unwrap();
+            image
+                .write_data(&[0, 100, 200, 0, 100, 200, 0, HGT_VOID, 200])
+                .unwrap();
+        }
+        data.set_position(0);
+        data
+    }
+
+    #[test]
+    fn test_geotiff() {
+        let grid = ElevationGrid::from_geotiff(geotiff(MODEL_TYPE_GEOGRAPHIC)).unwrap();
+        let expected = ElevationGrid::from_hgt_bytes(&hgt_bytes(), 45.0, 6.0).unwrap();
+        assert_eq!(grid.north, expected.north);
+        assert_eq!(grid.west, expected.west);
+        assert_eq!(grid.latitude_step, expected.latitude_step);
+        assert!(grid.elevations[7].is_nan());
+        assert_eq!(grid.elevations[8], 200.0);
+
+        // the projected coordinates aren't the latitude and the longitude
+        assert_eq!(
+            ElevationGrid::from_geotiff(geotiff(1)),
+            Err(DemError::Unsupported)
+        );
+        assert_eq!(
+            geo_key(&[1, 1, 0, 2, 1024, 0, 1, 2, 2054, 0, 1, 9102], 2054),
+            Some(9102)
+        );
+        assert_eq!(geo_key(&[1, 1, 0, 1, 2054, 2737, 1, 0], 2054), None);
+    }
+
+    #[test]
+    fn test_terrain_profile() {
+        let grid = ElevationGrid::from_hgt_bytes(&hgt_bytes(), 45.0, 6.0).unwrap();
+        // looking to the east between the first two rows of the grid
+        let sightline = Sightline {
+            latitude: 45.75f64.to_radians(),
+            longitude: 6.0f64.to_radians(),
+            azimuth: std::f64::consts::FRAC_PI_2,
+            radius: 6_371_000.0,
+        };
+        let terrain = grid.terrain_profile(&sightline, 100e3, 1e3).unwrap();
+        assert_eq!(terrain.elevation(0.0), Some(0.0));
+        // a degree of longitude is about 78 km long at this latitude
+        let elevation = terrain.elevation(39e3).unwrap();
+        assert!((elevation - 100.0).abs() < 1.0, "{}", elevation);
+        assert!(terrain.elevation(90e3).is_none());
+
+        assert_eq!(
+            grid.terrain_profile(&sightline, 100e3, 0.0).err(),
+            Some(SamplingError::InvalidResolution(0.0))
+        );
+        assert!(matches!(
+            grid.terrain_profile(&sightline, f64::NAN, 1e3),
+            Err(SamplingError::InvalidRange { .. })
+        ));
+    }
+}
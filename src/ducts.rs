@@ -0,0 +1,382 @@
+use crate::{
+    air::atmosphere::AtmosphereHints, refine_crossing, Environment, Integration, Path, RayOptions,
+    RayState,
+};
+use na::integration::{Integrator, StepSize};
+
+/// The kind of a duct
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuctKind {
+    /// A duct extending down to the surface
+    Surface,
+    /// A duct with a base above the surface
+    Elevated,
+}
+
+/// A duct - a layer in which rays can be trapped, detected in the modified refractivity profile.
+///
+/// The top of the duct is the top of a trapping layer (a layer in which the modified refractivity
+/// decreases with altitude), and the bottom is the altitude below it at which the modified
+/// refractivity again reaches the value it has at the top, or the surface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Duct {
+    pub kind: DuctKind,
+    /// The bottom altitude of the duct in meters
+    pub bottom: f64,
+    /// The altitude of the base of the trapping layer in meters
+    pub trapping_layer_base: f64,
+    /// The top altitude of the duct in meters
+    pub top: f64,
+    /// The difference between the maximum of the modified refractivity in the duct and its value
+    /// at the top, in M-units
+    pub strength: f64,
+    top_m: f64,
+}
+
+impl Duct {
+    /// Returns whether a ray launched from the altitude `start_h` at the angle `start_ang` (in
+    /// radians) to the horizontal stays trapped in the duct.
+    ///
+    /// Uses the invariant n * (R + h) * cos(angle) = const, under which the ray turns back at the
+    /// altitude where M drops below its starting value by -ln(cos(start_ang)) * 10^6.
+    pub fn traps(&self, env: &Environment, start_h: f64, start_ang: f64) -> bool {
+        if start_h < self.bottom || start_h > self.top {
+            return false;
+        }
+        let turning_m = env.modified_refractivity(start_h) + start_ang.cos().ln() * 1e6;
+        turning_m >= self.top_m
+    }
+}
+
+/// The kind of refraction in a layer of the atmosphere, determined by the gradient of the
+/// refractivity N relative to the critical gradient -10^6 / R (-157 N-units/km for the Earth)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefractionClass {
+    /// The refractivity increases with altitude, so the rays bend upwards
+    SubRefractive,
+    /// The refractivity decreases slower than half of the critical gradient
+    Standard,
+    /// The refractivity decreases at between half of the critical gradient and the critical
+    /// gradient, so the rays bend more than usually
+    SuperRefractive,
+    /// The refractivity decreases faster than the critical gradient, so the rays bend more than
+    /// the surface and can be trapped
+    Ducting,
+}
+
+impl RefractionClass {
+    /// Classifies a gradient of the refractivity in N-units per meter, for the given curvature
+    /// of the surface in 1/m
+    fn from_gradient(dn: f64, curvature: f64) -> Self {
+        let critical = -curvature * 1e6;
+        if dn > 0.0 {
+            RefractionClass::SubRefractive
+        } else if dn >= 0.5 * critical {
+            RefractionClass::Standard
+        } else if dn >= critical {
+            RefractionClass::SuperRefractive
+        } else {
+            RefractionClass::Ducting
+        }
+    }
+}
+
+/// A layer of the atmosphere with a single kind of refraction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RefractionLayer {
+    pub class: RefractionClass,
+    /// The bottom altitude of the layer in meters
+    pub bottom: f64,
+    /// The top altitude of the layer in meters
+    pub top: f64,
+}
+
+/// The result of tracing a ray over a long distance, possibly through ducts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DuctedRay {
+    /// `true` if the ray reached the maximum distance without rising above the escape altitude
+    pub trapped: bool,
+    /// The distance in meters up to which the ray has been traced - the maximum distance if the
+    /// ray was trapped, or the distance at which it escaped
+    pub dist: f64,
+    /// The lowest altitude reached by the ray, in meters
+    pub min_h: f64,
+    /// The highest altitude reached by the ray, in meters
+    pub max_h: f64,
+    /// The number of times the ray turned downwards in the air
+    pub upper_turns: usize,
+    /// The number of times the ray turned upwards in the air
+    pub lower_turns: usize,
+    /// The number of times the ray has been reflected off the ground
+    pub ground_reflections: usize,
+}
+
+/// Returns the altitudes from the surface up to `max_h`, spaced by `step` (both in meters), or
+/// no altitudes if `max_h` isn't finite and non-negative or `step` isn't positive
+fn altitude_grid(max_h: f64, step: f64) -> Vec<f64> {
+    if !(max_h.is_finite() && max_h >= 0.0 && step > 0.0) {
+        return vec![];
+    }
+    let n_steps = (max_h / step).ceil() as usize;
+    (0..=n_steps)
+        .map(|i| (i as f64 * step).min(max_h))
+        .collect()
+}
+
+impl Environment {
+    /// Traces a ray starting at the altitude `start_h` at the angle `start_ang` (in radians) up to
+    /// the distance `max_dist` (in meters), reflecting it off the ground and counting its turning
+    /// points, in order to check whether it is trapped.
+    ///
+    /// The ray escapes when it rises above `options.max_altitude` (it never does if it's not set)
+    /// and is reflected at `options.ground_altitude` (0 if not set). The rest of the options
+    /// set the wavelength and the integration; the ray is always refracted, even if
+    /// `options.straight` is set. A step that isn't finite and positive is replaced with the
+    /// default one.
+    ///
+    /// The distance is measured along the surface, so over a spherical Earth the ray can be
+    /// traced around the whole planet.
+    pub fn trace_ducted_ray(
+        &self,
+        start_h: f64,
+        start_ang: f64,
+        max_dist: f64,
+        options: &RayOptions,
+    ) -> DuctedRay {
+        let options = &options.validated();
+        let ray_options = RayOptions {
+            straight: false,
+            ..*options
+        };
+        let path = self.cast_ray_with(start_h, start_ang, &ray_options);
+        let wavelength = path.wavelength();
+        let hints = AtmosphereHints::default();
+        let diff_eq = |state: &RayState| self.calc_derivative(state, wavelength, &hints);
+        let escape_h = options.max_altitude.unwrap_or(f64::INFINITY);
+        let ground_h = options.ground_altitude.unwrap_or(0.0);
+
+        let mut integrator = Integration::from_options(options).integrator(options.step);
+        let mut state = path.sample(&[0.0])[0];
+        let mut result = DuctedRay {
+            trapped: true,
+            dist: max_dist,
+            min_h: start_h,
+            max_h: start_h,
+            upper_turns: 0,
+            lower_turns: 0,
+            ground_reflections: 0,
+        };
+        while state.x < max_dist {
+            let step = options.step.min(max_dist - state.x);
+            let prev_state = state;
+            integrator.propagate_in_place(&mut state, diff_eq, StepSize::Step(step));
+            if state.h < ground_h {
+                state = refine_crossing(&mut integrator, &prev_state, step, ground_h, diff_eq);
+                state.dh = -state.dh;
+                result.ground_reflections += 1;
+            } else if prev_state.dh > 0.0 && state.dh <= 0.0 {
+                result.upper_turns += 1;
+            } else if prev_state.dh < 0.0 && state.dh >= 0.0 {
+                result.lower_turns += 1;
+            }
+            result.min_h = result.min_h.min(state.h);
+            result.max_h = result.max_h.max(state.h);
+            if state.h > escape_h {
+                result.trapped = false;
+                result.dist = state.x;
+                break;
+            }
+        }
+        result
+    }
+
+    /// Scans the modified refractivity profile from the surface up to `max_h` with the given
+    /// resolution `step` (both in meters) and returns the detected ducts, sorted by altitude.
+    ///
+    /// The boundaries of the ducts are accurate up to the resolution. A trapping layer extending
+    /// above `max_h` is truncated at `max_h`. Returns no ducts if `max_h` isn't finite and
+    /// non-negative or `step` isn't positive.
+    pub fn find_ducts(&self, max_h: f64, step: f64) -> Vec<Duct> {
+        let altitudes = altitude_grid(max_h, step);
+        let m: Vec<f64> = altitudes
+            .iter()
+            .map(|&h| self.modified_refractivity(h))
+            .collect();
+
+        let mut ducts = vec![];
+        let mut index = 0;
+        while index + 1 < m.len() {
+            if m[index + 1] >= m[index] {
+                index += 1;
+                continue;
+            }
+            // a trapping layer starts at `index`
+            let base = index;
+            while index + 1 < m.len() && m[index + 1] < m[index] {
+                index += 1;
+            }
+            let top = index;
+            let top_m = m[top];
+
+            let mut bottom_index = base;
+            while bottom_index > 0 && m[bottom_index] > top_m {
+                bottom_index -= 1;
+            }
+            let (kind, bottom) = if m[bottom_index] > top_m {
+                (DuctKind::Surface, altitudes[0])
+            } else {
+                // interpolate between the samples
+                let (h1, m1) = (altitudes[bottom_index], m[bottom_index]);
+                let (h2, m2) = (altitudes[bottom_index + 1], m[bottom_index + 1]);
+                (
+                    DuctKind::Elevated,
+                    h1 + (top_m - m1) / (m2 - m1) * (h2 - h1),
+                )
+            };
+
+            let max_m = m[bottom_index..=top]
+                .iter()
+                .fold(f64::NEG_INFINITY, |acc, &m| acc.max(m));
+
+            ducts.push(Duct {
+                kind,
+                bottom,
+                trapping_layer_base: altitudes[base],
+                top: altitudes[top],
+                strength: max_m - top_m,
+                top_m,
+            });
+        }
+
+        ducts
+    }
+
+    /// Scans the refractivity profile from the surface up to `max_h` with the given resolution
+    /// `step` (both in meters) and divides it into layers with different kinds of refraction,
+    /// sorted by altitude.
+    ///
+    /// The boundaries of the layers are accurate up to the resolution. Over a flat surface, all
+    /// the layers in which the refractivity decreases are ducting. Returns no layers if `max_h`
+    /// isn't finite and non-negative or `step` isn't positive.
+    pub fn classify_layers(&self, max_h: f64, step: f64) -> Vec<RefractionLayer> {
+        let altitudes = altitude_grid(max_h, step);
+        let curvature = self.curvature_at(0.0);
+
+        let mut layers: Vec<RefractionLayer> = vec![];
+        for pair in altitudes.windows(2) {
+            let (bottom, top) = (pair[0], pair[1]);
+            let dn = (self.refractivity(top) - self.refractivity(bottom)) / (top - bottom);
+            let class = RefractionClass::from_gradient(dn, curvature);
+            match layers.last_mut() {
+                Some(layer) if layer.class == class => layer.top = top,
+                _ => layers.push(RefractionLayer { class, bottom, top }),
+            }
+        }
+        layers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::{ducting_env, us76_atmosphere_env};
+
+    #[test]
+    fn test_duct_detection() {
+        let env = ducting_env();
+        let ducts = env.find_ducts(1000.0, 1.0);
+        assert_eq!(ducts.len(), 1);
+        let duct = ducts[0];
+        assert_eq!(duct.kind, DuctKind::Elevated);
+        assert_eq!(duct.trapping_layer_base, 100.0);
+        assert_eq!(duct.top, 150.0);
+        assert!(duct.bottom > 0.0 && duct.bottom < 100.0);
+
+        assert!(duct.traps(&env, 120.0, 0.0));
+        assert!(duct.traps(&env, 120.0, 0.001));
+        assert!(!duct.traps(&env, 120.0, 0.01));
+        assert!(!duct.traps(&env, 200.0, 0.0));
+
+        // check the trapping against actual rays
+        let trapped = env.cast_ray(120.0, 0.001, false);
+        let escaping = env.cast_ray(120.0, 0.01, false);
+        assert!(trapped.h_at_dist(50e3) < duct.top);
+        assert!(escaping.h_at_dist(50e3) > duct.top);
+
+        assert!(us76_atmosphere_env().find_ducts(1000.0, 1.0).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_duct_scan() {
+        let env = ducting_env();
+        for (max_h, step) in [(1000.0, 0.0), (1000.0, -1.0), (-10.0, 1.0)] {
+            assert!(env.find_ducts(max_h, step).is_empty());
+        }
+        assert!(env.find_ducts(f64::NAN, 1.0).is_empty());
+        assert!(env.find_ducts(1000.0, f64::NAN).is_empty());
+        assert!(env.find_ducts(f64::INFINITY, 1.0).is_empty());
+        assert!(env.find_ducts(0.0, 1.0).is_empty());
+    }
+
+    #[test]
+    fn test_layer_classification() {
+        let layers = ducting_env().classify_layers(1000.0, 1.0);
+        let classes: Vec<_> = layers.iter().map(|layer| layer.class).collect();
+        assert_eq!(
+            classes,
+            vec![
+                RefractionClass::Standard,
+                RefractionClass::Ducting,
+                RefractionClass::Standard
+            ]
+        );
+        assert_eq!((layers[1].bottom, layers[1].top), (100.0, 150.0));
+        assert_eq!(layers[2].top, 1000.0);
+
+        let layers = us76_atmosphere_env().classify_layers(1000.0, 10.0);
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].class, RefractionClass::Standard);
+    }
+
+    #[test]
+    fn test_invalid_layer_scan() {
+        let env = ducting_env();
+        assert!(env.classify_layers(1000.0, 0.0).is_empty());
+        assert!(env.classify_layers(-10.0, 1.0).is_empty());
+        assert!(env.classify_layers(f64::NAN, 1.0).is_empty());
+    }
+
+    #[test]
+    fn test_ducted_ray() {
+        let env = ducting_env();
+        let options = RayOptions {
+            max_altitude: Some(1000.0),
+            step: 50.0,
+            ..Default::default()
+        };
+        let trapped = env.trace_ducted_ray(120.0, 0.001, 300e3, &options);
+        assert!(trapped.trapped);
+        assert_eq!(trapped.dist, 300e3);
+        assert!(trapped.upper_turns >= 1 && trapped.lower_turns >= 1);
+        assert!(trapped.max_h < 150.0 && trapped.min_h > 0.0);
+        assert_eq!(trapped.ground_reflections, 0);
+
+        let escaping = env.trace_ducted_ray(120.0, 0.01, 300e3, &options);
+        assert!(!escaping.trapped);
+        assert!(escaping.dist < 300e3);
+
+        // a ray aimed at the ground bounces off it and escapes
+        let reflected = us76_atmosphere_env().trace_ducted_ray(10.0, -0.01, 300e3, &options);
+        assert_eq!(reflected.ground_reflections, 1);
+        assert!(reflected.min_h.abs() < 1e-3);
+        assert!(!reflected.trapped);
+
+        // invalid steps fall back to the default one instead of stalling the tracing
+        for step in [0.0, -50.0, f64::NAN] {
+            let options = RayOptions { step, ..options };
+            let ray = env.trace_ducted_ray(120.0, 0.001, 50e3, &options);
+            assert!(ray.trapped);
+            assert_eq!(ray.dist, 50e3);
+        }
+    }
+}
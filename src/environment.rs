@@ -1,12 +1,98 @@
-use crate::air::{air_index, d_air_index, Atmosphere};
-use crate::{flat, spherical, Path, PathStepper, RayState, RayStateDerivative};
+use crate::air::{
+    air_index, atmosphere::AtmosphereHints, d_air_index, d_radio_air_index, radio_air_index,
+    us76_atmosphere, Atmosphere,
+};
+use crate::{
+    arcs, custom, flat, spherical, AnyPath, CachedPath, IntegratorKind, Path, PathStep,
+    PathStepper, RayOptions, RayState, RayStateDerivative, SpectrumRay, TargetSolverOptions,
+    Vacuum,
+};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::ops::Deref;
+use std::sync::Arc;
 
 /// The shape of the simulated Earth
 #[derive(Clone, Copy)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub enum EarthShape {
-    Spherical { radius: f64 },
+    Spherical {
+        radius: f64,
+    },
     Flat,
+    /// An ellipsoid of revolution with the equatorial radius `a` and flattening `f`, observed
+    /// from the given `latitude` in the direction given by `azimuth` (both in radians).
+    ///
+    /// Rays are traced over a sphere with the ellipsoid's radius of curvature along the azimuth
+    /// at the observer's position.
+    Ellipsoid {
+        a: f64,
+        f: f64,
+        latitude: f64,
+        azimuth: f64,
+    },
+    /// A surface with a curvature (the inverse of the radius, in 1/m) given as a function of the
+    /// distance from the observer; can't be serialized
+    #[cfg_attr(feature = "serialization", serde(skip))]
+    Custom {
+        curvature: fn(f64) -> f64,
+    },
+}
+
+/// The equatorial radius of the WGS84 ellipsoid, in meters
+pub const WGS84_A: f64 = 6_378_137.0;
+/// The flattening of the WGS84 ellipsoid
+pub const WGS84_F: f64 = 1.0 / 298.257_223_563;
+/// The refraction coefficient commonly assumed for optical observations near the ground
+pub const STANDARD_K: f64 = 0.13;
+
+impl EarthShape {
+    /// Returns the WGS84 ellipsoid observed from the given latitude in the direction given by the
+    /// azimuth (both in radians).
+    pub fn wgs84(latitude: f64, azimuth: f64) -> Self {
+        EarthShape::Ellipsoid {
+            a: WGS84_A,
+            f: WGS84_F,
+            latitude,
+            azimuth,
+        }
+    }
+
+    /// Returns Some(radius of curvature in meters) along the direction of the rays if the shape
+    /// has a constant curvature, or None if it's flat or custom.
+    pub fn radius(&self) -> Option<f64> {
+        match *self {
+            EarthShape::Spherical { radius } => Some(radius),
+            EarthShape::Flat | EarthShape::Custom { .. } => None,
+            EarthShape::Ellipsoid {
+                a,
+                f,
+                latitude,
+                azimuth,
+            } => {
+                let e2 = f * (2.0 - f);
+                let w2 = 1.0 - e2 * latitude.sin().powi(2);
+                // meridional and prime vertical radii of curvature
+                let m = a * (1.0 - e2) / w2.powf(1.5);
+                let n = a / w2.sqrt();
+                Some(1.0 / (azimuth.cos().powi(2) / m + azimuth.sin().powi(2) / n))
+            }
+        }
+    }
+}
+
+/// The formula used for calculating the refractive index of air
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum RefractiveIndexModel {
+    /// The modified Edlén equation for visible light, using the wavelength set in the environment
+    #[default]
+    Optical,
+    /// The Smith-Weintraub formula for radio and microwave frequencies; independent of the
+    /// wavelength
+    Radio,
+    /// The refractive index is equal to 1 everywhere, so the rays are not refracted at all
+    Vacuum,
 }
 
 /// Structure storing the shape of the underlying world and the atmospheric model.
@@ -15,8 +101,19 @@ pub enum EarthShape {
 pub struct Environment {
     pub shape: EarthShape,
     pub atmosphere: Atmosphere,
+    /// The wavelength of the traced light, in meters
     #[cfg_attr(feature = "serialization", serde(default = "default_wavelength"))]
     pub wavelength: f64,
+    /// The formula used for calculating the refractive index
+    #[cfg_attr(feature = "serialization", serde(default))]
+    pub index_model: RefractiveIndexModel,
+    /// The altitude in meters above which the refractive index is exactly 1, so that the rays
+    /// continue as straight lines without evaluating the atmosphere; if `None`, the atmosphere
+    /// extends indefinitely. Over flat, spherical and ellipsoidal surfaces, the numerically
+    /// integrated rays and their steppers stop integrating once they ascend above the top, and
+    /// calculate the rest of the straight line directly.
+    #[cfg_attr(feature = "serialization", serde(default))]
+    pub top_of_atmosphere: Option<f64>,
 }
 
 #[cfg(feature = "serialization")]
@@ -24,43 +121,450 @@ fn default_wavelength() -> f64 {
     530e-9
 }
 
+/// A reference to the environment held by a path - either a borrowed one, or one shared through
+/// an `Arc`, in which case the path doesn't borrow anything and can be stored or sent anywhere.
+#[derive(Clone)]
+pub enum EnvironmentRef<'a> {
+    Borrowed(&'a Environment),
+    Shared(Arc<Environment>),
+}
+
+impl Deref for EnvironmentRef<'_> {
+    type Target = Environment;
+
+    fn deref(&self) -> &Environment {
+        match self {
+            EnvironmentRef::Borrowed(env) => env,
+            EnvironmentRef::Shared(env) => env,
+        }
+    }
+}
+
+impl<'a> From<&'a Environment> for EnvironmentRef<'a> {
+    fn from(env: &'a Environment) -> Self {
+        EnvironmentRef::Borrowed(env)
+    }
+}
+
+impl From<Arc<Environment>> for EnvironmentRef<'_> {
+    fn from(env: Arc<Environment>) -> Self {
+        EnvironmentRef::Shared(env)
+    }
+}
+
+/// The largest difference of altitudes (in meters) between the rays of a spectrum, for which the
+/// atmospheric values evaluated for one of them are reused for another
+const SPECTRUM_SPREAD: f64 = 1e-3;
+
+/// The pressure, temperature and humidity at some altitude, with their derivatives
+#[derive(Clone, Copy)]
+struct AirValues {
+    p: f64,
+    t: f64,
+    rh: f64,
+    dp: f64,
+    dt: f64,
+    drh: f64,
+}
+
+impl AirValues {
+    fn at(atmosphere: &Atmosphere, h: f64, hints: &AtmosphereHints) -> Self {
+        AirValues {
+            p: atmosphere.pressure_with_hints(h, hints),
+            t: atmosphere.temperature_with_hints(h, hints),
+            rh: atmosphere.humidity_with_hints(h, hints),
+            dp: atmosphere.dpressure_with_hints(h, hints),
+            dt: atmosphere.dtemperature_with_hints(h, hints),
+            drh: atmosphere.dhumidity_with_hints(h, hints),
+        }
+    }
+
+    /// Returns the values extrapolated linearly to the altitude higher by `dh`
+    fn extrapolated(&self, dh: f64) -> Self {
+        AirValues {
+            p: self.p + self.dp * dh,
+            t: self.t + self.dt * dh,
+            rh: self.rh + self.drh * dh,
+            ..*self
+        }
+    }
+}
+
+/// Returns the light path described by the parameters, in the environment `env`
+fn cast_ray_in<'a>(
+    env: EnvironmentRef<'a>,
+    start_h: f64,
+    start_ang: f64,
+    options: &RayOptions,
+) -> AnyPath<'a> {
+    let options = &options.validated();
+    match (options.straight, env.shape) {
+        (true, EarthShape::Flat) => {
+            AnyPath::FlatLine(flat::Line::from_h_ang(env, start_h, start_ang))
+        }
+        (true, EarthShape::Spherical { .. } | EarthShape::Ellipsoid { .. }) => {
+            AnyPath::SphericalLine(spherical::Line::from_h_ang(env, start_h, start_ang))
+        }
+        (false, EarthShape::Flat | EarthShape::Spherical { .. } | EarthShape::Ellipsoid { .. })
+            if options.integrator == IntegratorKind::CircularArcs =>
+        {
+            AnyPath::ArcRay(arcs::ArcRay::from_h_ang(env, start_h, start_ang).with_options(options))
+        }
+        (false, EarthShape::Flat) => {
+            AnyPath::FlatRay(flat::Ray::from_h_ang(env, start_h, start_ang).with_options(options))
+        }
+        (false, EarthShape::Spherical { .. } | EarthShape::Ellipsoid { .. })
+            if options.integrator == IntegratorKind::BouguerInvariant =>
+        {
+            AnyPath::SphericalInvariantRay(spherical::InvariantRay::from_h_ang(
+                env, start_h, start_ang, options,
+            ))
+        }
+        (false, EarthShape::Spherical { .. } | EarthShape::Ellipsoid { .. }) => {
+            AnyPath::SphericalRay(
+                spherical::Ray::from_h_ang(env, start_h, start_ang).with_options(options),
+            )
+        }
+        (straight, EarthShape::Custom { .. }) => AnyPath::CustomRay(
+            custom::Ray::from_h_ang(env, start_h, start_ang, straight).with_options(options),
+        ),
+    }
+}
+
 impl Environment {
+    /// Creates an environment approximating the refraction with the effective radius method.
+    ///
+    /// Rays travel without refraction over a sphere with the effective radius
+    /// `radius / (1 - k)`, where `k` is the refraction coefficient (the ratio of the curvature of
+    /// the rays to the curvature of the Earth), e.g. 0.13 for the standard optical refraction or
+    /// 0.25 for the "4/3 Earth" used for radio waves. For `k = 1` the Earth becomes flat.
+    ///
+    /// The atmosphere is the US-1976 standard, but it doesn't influence the rays.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the radius isn't finite and positive, or `k` isn't finite or is greater than 1;
+    /// see `try_with_k_factor`.
+    pub fn with_k_factor(radius: f64, k: f64) -> Environment {
+        match Self::try_with_k_factor(radius, k) {
+            Ok(env) => env,
+            Err(error) => panic!("invalid effective radius parameters: {:?}", error),
+        }
+    }
+
+    /// Creates an environment approximating the refraction with the effective radius method,
+    /// like `with_k_factor`, or returns an error if the parameters are invalid.
+    ///
+    /// The radius has to be finite and positive, and `k` has to be finite and at most 1. Rays
+    /// curving more than the surface (`k > 1`) would need a concave effective Earth, which isn't
+    /// supported.
+    pub fn try_with_k_factor(radius: f64, k: f64) -> Result<Environment, EnvironmentError> {
+        if !(radius.is_finite() && radius > 0.0) {
+            return Err(EnvironmentError::InvalidRadius(radius));
+        }
+        if !(k.is_finite() && k <= 1.0) {
+            return Err(EnvironmentError::InvalidRefractionCoefficient(k));
+        }
+        let shape = if k == 1.0 {
+            EarthShape::Flat
+        } else {
+            EarthShape::Spherical {
+                radius: radius / (1.0 - k),
+            }
+        };
+        Ok(Environment {
+            shape,
+            atmosphere: us76_atmosphere(),
+            wavelength: 530e-9,
+            index_model: RefractiveIndexModel::Vacuum,
+            top_of_atmosphere: None,
+        })
+    }
+
     /// Returns the refractive index of the air at the given altitude.
     pub fn n(&self, h: f64) -> f64 {
+        if self.is_above_atmosphere(h) {
+            return 1.0;
+        }
         let pressure = self.atmosphere.pressure(h);
         let temperature = self.atmosphere.temperature(h);
         let rh = self.atmosphere.humidity(h);
-        air_index(self.wavelength, pressure, temperature, rh)
+        match self.index_model {
+            RefractiveIndexModel::Optical => air_index(self.wavelength, pressure, temperature, rh),
+            RefractiveIndexModel::Radio => radio_air_index(pressure, temperature, rh),
+            RefractiveIndexModel::Vacuum => 1.0,
+        }
+    }
+
+    /// Returns the refractive indices of the air at the given altitudes - the same values as
+    /// calling `n` for every altitude, e.g. for building grids or tables.
+    ///
+    /// The pressures, the temperatures and the humidities are evaluated for all the altitudes
+    /// first, layer by layer (see `VerticalProfile::eval_many`), and the refractive indices are
+    /// then calculated from them. For sorted altitudes this skips the search for the layer of
+    /// every altitude, which makes it somewhat faster than calling `n` for every altitude.
+    pub fn n_many(&self, hs: &[f64]) -> Vec<f64> {
+        let atmosphere = &self.atmosphere;
+        let values = || {
+            (
+                atmosphere.pressure_many(hs),
+                atmosphere.temperature_many(hs),
+                atmosphere.humidity_many(hs),
+            )
+        };
+        let mut result: Vec<f64> = match self.index_model {
+            RefractiveIndexModel::Optical => {
+                let (p, t, rh) = values();
+                (0..hs.len())
+                    .map(|i| air_index(self.wavelength, p[i], t[i], rh[i]))
+                    .collect()
+            }
+            RefractiveIndexModel::Radio => {
+                let (p, t, rh) = values();
+                (0..hs.len())
+                    .map(|i| radio_air_index(p[i], t[i], rh[i]))
+                    .collect()
+            }
+            RefractiveIndexModel::Vacuum => vec![1.0; hs.len()],
+        };
+        for (n, &h) in result.iter_mut().zip(hs) {
+            if self.is_above_atmosphere(h) {
+                *n = 1.0;
+            }
+        }
+        result
+    }
+
+    /// Returns the space above the top of the atmosphere, if it's set and the straight lines over
+    /// the surface can be calculated directly
+    pub(crate) fn vacuum(&self) -> Option<Vacuum> {
+        let top = self.top_of_atmosphere?;
+        match self.shape {
+            EarthShape::Custom { .. } => None,
+            _ => Some(Vacuum {
+                top,
+                radius: self.radius(),
+            }),
+        }
+    }
+
+    /// Returns whether the altitude is above the top of the atmosphere set in the environment
+    fn is_above_atmosphere(&self, h: f64) -> bool {
+        self.top_of_atmosphere.is_some_and(|top| h > top)
     }
 
     /// Returns the derivative of the refractive index of the air with respect to the altitude, at
     /// the given altitude
     pub fn dn(&self, h: f64) -> f64 {
+        if self.is_above_atmosphere(h) {
+            return 0.0;
+        }
         let pressure = self.atmosphere.pressure(h);
         let temperature = self.atmosphere.temperature(h);
         let rh = self.atmosphere.humidity(h);
         let dp = self.atmosphere.dpressure(h);
         let dt = self.atmosphere.dtemperature(h);
         let drh = self.atmosphere.dhumidity(h);
-        d_air_index(self.wavelength, pressure, temperature, rh, dp, dt, drh)
+        match self.index_model {
+            RefractiveIndexModel::Optical => {
+                d_air_index(self.wavelength, pressure, temperature, rh, dp, dt, drh)
+            }
+            RefractiveIndexModel::Radio => {
+                d_radio_air_index(pressure, temperature, rh, dp, dt, drh)
+            }
+            RefractiveIndexModel::Vacuum => 0.0,
+        }
+    }
+
+    /// Returns the refractive index of the air and its derivative with respect to the altitude at
+    /// the given altitude, for the given wavelength instead of the one set in the environment.
+    ///
+    /// The atmospheric parameters are evaluated only once for both values.
+    pub fn n_dn_at_wavelength(&self, h: f64, wavelength: f64) -> (f64, f64) {
+        self.n_dn_with_hints(h, wavelength, &AtmosphereHints::default())
+    }
+
+    /// Returns the same values as `n_dn_at_wavelength`, evaluating the atmosphere with the
+    /// `hints` of the integration the altitude comes from
+    pub(crate) fn n_dn_with_hints(
+        &self,
+        h: f64,
+        wavelength: f64,
+        hints: &AtmosphereHints,
+    ) -> (f64, f64) {
+        if self.is_above_atmosphere(h) {
+            return (1.0, 0.0);
+        }
+        match self.index_model {
+            RefractiveIndexModel::Vacuum => (1.0, 0.0),
+            _ => self.n_dn_from_values(wavelength, &AirValues::at(&self.atmosphere, h, hints)),
+        }
+    }
+
+    /// Returns the refractive indices of the air and their derivatives with respect to the
+    /// altitude for the rays of the given `wavelengths`, passing at the altitudes `hs`.
+    ///
+    /// The rays are usually very close to each other, so the atmosphere is evaluated only at the
+    /// altitudes of some of them: the pressure, temperature and humidity for the rays within
+    /// `SPECTRUM_SPREAD` of an altitude at which they have been evaluated are extrapolated
+    /// linearly from there.
+    pub(crate) fn n_dn_spectrum(
+        &self,
+        hs: &[f64],
+        wavelengths: &[f64],
+        hints: &AtmosphereHints,
+    ) -> Vec<(f64, f64)> {
+        if let RefractiveIndexModel::Vacuum = self.index_model {
+            return vec![(1.0, 0.0); hs.len()];
+        }
+        let mut evaluated: Vec<(f64, AirValues)> = vec![];
+        hs.iter()
+            .zip(wavelengths)
+            .map(|(&h, &wavelength)| {
+                if self.is_above_atmosphere(h) {
+                    return (1.0, 0.0);
+                }
+                let values = match evaluated
+                    .iter()
+                    .find(|(ref_h, _)| (h - ref_h).abs() <= SPECTRUM_SPREAD)
+                {
+                    Some((ref_h, values)) => values.extrapolated(h - ref_h),
+                    None => {
+                        let values = AirValues::at(&self.atmosphere, h, hints);
+                        evaluated.push((h, values));
+                        values
+                    }
+                };
+                self.n_dn_from_values(wavelength, &values)
+            })
+            .collect()
+    }
+
+    /// Returns the refractive index of the air and its derivative with respect to the altitude,
+    /// calculated from the given atmospheric values
+    fn n_dn_from_values(&self, wavelength: f64, values: &AirValues) -> (f64, f64) {
+        let AirValues {
+            p,
+            t,
+            rh,
+            dp,
+            dt,
+            drh,
+        } = *values;
+        match self.index_model {
+            RefractiveIndexModel::Optical => (
+                air_index(wavelength, p, t, rh),
+                d_air_index(wavelength, p, t, rh, dp, dt, drh),
+            ),
+            RefractiveIndexModel::Radio => (
+                radio_air_index(p, t, rh),
+                d_radio_air_index(p, t, rh, dp, dt, drh),
+            ),
+            RefractiveIndexModel::Vacuum => (1.0, 0.0),
+        }
+    }
+
+    /// Returns the refractivity N = (n - 1) * 10^6 at the given altitude, in N-units.
+    pub fn refractivity(&self, h: f64) -> f64 {
+        (self.n(h) - 1.0) * 1e6
+    }
+
+    /// Returns the derivative of the refractivity with respect to the altitude, in N-units per
+    /// meter.
+    pub fn drefractivity(&self, h: f64) -> f64 {
+        self.dn(h) * 1e6
+    }
+
+    /// Returns the modified refractivity M = N + h / R * 10^6 at the given altitude, in M-units.
+    ///
+    /// For the Earth's radius, this is the familiar M = N + 157 * h (with h in km). Over a flat
+    /// Earth M is equal to N.
+    pub fn modified_refractivity(&self, h: f64) -> f64 {
+        self.refractivity(h) + h * self.curvature_at(0.0) * 1e6
+    }
+
+    /// Returns the derivative of the modified refractivity with respect to the altitude, in
+    /// M-units per meter.
+    pub fn dmodified_refractivity(&self, h: f64) -> f64 {
+        self.drefractivity(h) + self.curvature_at(0.0) * 1e6
+    }
+
+    /// Returns the curvature (in 1/m) of a ray passing through the altitude `h` at the angle
+    /// `angle` (in radians) to the horizontal. Positive values mean that the ray bends
+    /// downwards.
+    pub fn ray_curvature(&self, h: f64, angle: f64) -> f64 {
+        -self.dn(h) * angle.cos() / self.n(h)
+    }
+
+    /// Returns the refraction coefficient k - the ratio of the curvature of a ray passing through
+    /// the altitude `h` at the angle `angle` (in radians) to the curvature of the Earth.
+    ///
+    /// k is negative when the ray bends upwards, and infinite over a flat Earth.
+    pub fn refraction_coefficient(&self, h: f64, angle: f64) -> f64 {
+        self.ray_curvature(h, angle) / self.curvature_at(0.0)
+    }
+
+    /// Returns the density of the air at the given altitude, in kg/m^3.
+    pub fn density(&self, h: f64) -> f64 {
+        self.atmosphere.density(h)
     }
 
     /// Returns Some(radius in meters) if the planet model is spherical, or None if it's flat.
+    ///
+    /// For ellipsoidal models, this is the radius of curvature along the azimuth of the rays.
     pub fn radius(&self) -> Option<f64> {
+        self.shape.radius()
+    }
+
+    /// Returns the curvature of the surface (in 1/m) at the given distance from the observer.
+    pub fn curvature_at(&self, dist: f64) -> f64 {
         match self.shape {
-            EarthShape::Spherical { radius } => Some(radius),
-            EarthShape::Flat => None,
+            EarthShape::Custom { curvature } => curvature(dist),
+            _ => self.radius().map_or(0.0, |radius| 1.0 / radius),
+        }
+    }
+
+    /// Returns the angle (in radians) by which the local horizontal plane at the given distance
+    /// from the observer is rotated relative to the one at the observer.
+    pub fn surface_rotation(&self, dist: f64) -> f64 {
+        match self.shape {
+            EarthShape::Custom { curvature } => {
+                // Simpson's rule with at most 100 m long intervals
+                let n = 2 * ((dist.abs() / 200.0).ceil() as usize).max(1);
+                let step = dist / n as f64;
+                let sum: f64 = (0..=n)
+                    .map(|i| {
+                        let weight = if i == 0 || i == n {
+                            1.0
+                        } else if i % 2 == 1 {
+                            4.0
+                        } else {
+                            2.0
+                        };
+                        weight * curvature(i as f64 * step)
+                    })
+                    .sum();
+                sum * step / 3.0
+            }
+            _ => dist * self.curvature_at(0.0),
         }
     }
 
-    pub(crate) fn calc_derivative_spherical(&self, state: &RayState) -> RayStateDerivative {
+    pub(crate) fn calc_derivative_spherical(
+        &self,
+        state: &RayState,
+        wavelength: f64,
+        hints: &AtmosphereHints,
+    ) -> RayStateDerivative {
+        let (nr, dnr) = self.n_dn_with_hints(state.h, wavelength, hints);
+        self.spherical_derivative(state, nr, dnr)
+    }
+
+    fn spherical_derivative(&self, state: &RayState, nr: f64, dnr: f64) -> RayStateDerivative {
         let radius = self.radius().unwrap();
         let dh = state.dh * radius;
         let h = state.h;
 
-        let nr = self.n(h);
-        let dnr = self.dn(h);
-
         let r = h + radius;
         let d2h = dh * dh * dnr / nr + r * r * dnr / nr + 2.0 * dh * dh / r + r;
 
@@ -71,42 +575,146 @@ impl Environment {
         }
     }
 
-    pub(crate) fn calc_derivative_flat(&self, state: &RayState) -> RayStateDerivative {
+    pub(crate) fn calc_derivative_flat(
+        &self,
+        state: &RayState,
+        wavelength: f64,
+        hints: &AtmosphereHints,
+    ) -> RayStateDerivative {
+        let (nr, dnr) = self.n_dn_with_hints(state.h, wavelength, hints);
+        Self::flat_derivative(state, nr, dnr)
+    }
+
+    fn flat_derivative(state: &RayState, nr: f64, dnr: f64) -> RayStateDerivative {
         let dh = state.dh;
-        let h = state.h;
+        let d2h = dnr / nr * (1.0 + dh * dh);
+
+        RayStateDerivative { dx: 1.0, dh, d2h }
+    }
 
-        let nr = self.n(h);
-        let dnr = self.dn(h);
+    /// Calculates the derivative for a surface with the curvature varying along the path. The
+    /// equation is the same as in the spherical case, rewritten in terms of the local curvature.
+    /// If `straight` is true, the refractive index is ignored.
+    pub(crate) fn calc_derivative_custom(
+        &self,
+        state: &RayState,
+        wavelength: f64,
+        straight: bool,
+        hints: &AtmosphereHints,
+    ) -> RayStateDerivative {
+        let dn_n = if straight {
+            0.0
+        } else {
+            let (n, dn) = self.n_dn_with_hints(state.h, wavelength, hints);
+            dn / n
+        };
+        self.custom_derivative(state, dn_n)
+    }
 
-        let d2h = dnr / nr * (1.0 + dh * dh);
+    fn custom_derivative(&self, state: &RayState, dn_n: f64) -> RayStateDerivative {
+        let dh = state.dh;
+        let h = state.h;
+        let k = self.curvature_at(state.x);
+        let r_k = 1.0 + k * h;
+
+        let d2h = dn_n * (dh * dh + r_k * r_k) + 2.0 * k * dh * dh / r_k + k * r_k;
 
         RayStateDerivative { dx: 1.0, dh, d2h }
     }
 
+    /// Calculates the derivative of the state of a ray for the shape of the surface set in the
+    /// environment
+    pub(crate) fn calc_derivative(
+        &self,
+        state: &RayState,
+        wavelength: f64,
+        hints: &AtmosphereHints,
+    ) -> RayStateDerivative {
+        let (n, dn) = self.n_dn_with_hints(state.h, wavelength, hints);
+        self.calc_derivative_with_index(state, n, dn)
+    }
+
+    /// Calculates the derivative of the state of a ray like `calc_derivative`, given the
+    /// refractive index `n` and its derivative `dn` at the altitude of the ray
+    pub(crate) fn calc_derivative_with_index(
+        &self,
+        state: &RayState,
+        n: f64,
+        dn: f64,
+    ) -> RayStateDerivative {
+        match self.shape {
+            EarthShape::Flat => Self::flat_derivative(state, n, dn),
+            EarthShape::Spherical { .. } | EarthShape::Ellipsoid { .. } => {
+                self.spherical_derivative(state, n, dn)
+            }
+            EarthShape::Custom { .. } => self.custom_derivative(state, dn / n),
+        }
+    }
+
     /// Returns an object representing a light path.
     ///
     /// The path is defined by 3 parameters:
     /// * `start_h` - the starting altitude of the path in meters
     /// * `start_ang` - the initial angle in radians between the path and the horizontal plane;
-    /// -π/2 is down, 0 is horizontal, π/2 is up
+    ///   -π/2 is down, 0 is horizontal, π/2 is up
     /// * `straight` - `true` if the path should be a straight line, `false` if it should be a ray
-    /// affected by the atmosphere
+    ///   affected by the atmosphere
     pub fn cast_ray<'a>(
         &'a self,
         start_h: f64,
         start_ang: f64,
         straight: bool,
     ) -> Box<dyn Path<'a> + 'a> {
-        match (straight, self.shape) {
-            (true, EarthShape::Flat) => Box::new(flat::Line::from_h_ang(start_h, start_ang)),
-            (true, EarthShape::Spherical { .. }) => {
-                Box::new(spherical::Line::from_h_ang(self, start_h, start_ang))
-            }
-            (false, EarthShape::Flat) => Box::new(flat::Ray::from_h_ang(self, start_h, start_ang)),
-            (false, EarthShape::Spherical { .. }) => {
-                Box::new(spherical::Ray::from_h_ang(self, start_h, start_ang))
-            }
-        }
+        Box::new(self.cast_ray_with(
+            start_h,
+            start_ang,
+            &RayOptions {
+                straight,
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// Returns an object representing a light path starting at the altitude `start_h` (in meters)
+    /// at the initial angle `start_ang` (in radians), with the parameters of the path and its
+    /// integration given by `options`.
+    ///
+    /// Unlike `cast_ray`, it returns the path by value, which avoids the dynamic dispatch.
+    pub fn cast_ray_with<'a>(
+        &'a self,
+        start_h: f64,
+        start_ang: f64,
+        options: &RayOptions,
+    ) -> AnyPath<'a> {
+        cast_ray_in(self.into(), start_h, start_ang, options)
+    }
+
+    /// Returns a light path like `cast_ray_with`, which holds a shared reference to the
+    /// environment instead of borrowing it - so it can be stored or sent to other threads
+    /// independently of the environment.
+    pub fn cast_ray_shared(
+        env: &Arc<Environment>,
+        start_h: f64,
+        start_ang: f64,
+        options: &RayOptions,
+    ) -> AnyPath<'static> {
+        cast_ray_in(env.clone().into(), start_h, start_ang, options)
+    }
+
+    /// Returns a light path like `cast_ray_with`, wrapped so that the states it has been
+    /// integrated through are remembered every `options.step` meters - repeated queries along the
+    /// path then don't have to integrate it from the initial point every time.
+    pub fn cast_ray_cached<'a>(
+        &'a self,
+        start_h: f64,
+        start_ang: f64,
+        options: &RayOptions,
+    ) -> CachedPath<'a, AnyPath<'a>> {
+        CachedPath::new(
+            self,
+            self.cast_ray_with(start_h, start_ang, options),
+            options.validated().step,
+        )
     }
 
     /// Returns an object representing a light path.
@@ -114,29 +722,96 @@ impl Environment {
     /// The path is defined by 3 parameters:
     /// * `start_h` - the starting altitude of the path in meters
     /// * `start_ang` - the initial angle in radians between the path and the horizontal plane;
-    /// -π/2 is down, 0 is horizontal, π/2 is up
+    ///   -π/2 is down, 0 is horizontal, π/2 is up
     /// * `straight` - `true` if the path should be a straight line, `false` if it should be a ray
-    /// affected by the atmosphere
+    ///   affected by the atmosphere
     pub fn cast_ray_stepper<'a>(
         &'a self,
         start_h: f64,
         start_ang: f64,
         straight: bool,
-    ) -> Box<dyn PathStepper<Item = RayState> + 'a> {
-        match (straight, self.shape) {
-            (true, EarthShape::Flat) => {
-                flat::Line::from_h_ang(start_h, start_ang).into_path_stepper()
-            }
-            (true, EarthShape::Spherical { .. }) => {
-                spherical::Line::from_h_ang(self, start_h, start_ang).into_path_stepper()
-            }
-            (false, EarthShape::Flat) => {
-                flat::Ray::from_h_ang(self, start_h, start_ang).into_path_stepper()
-            }
-            (false, EarthShape::Spherical { .. }) => {
-                spherical::Ray::from_h_ang(self, start_h, start_ang).into_path_stepper()
-            }
-        }
+    ) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.cast_ray_stepper_with(
+            start_h,
+            start_ang,
+            &RayOptions {
+                straight,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Returns a stepper along a light path starting at the altitude `start_h` (in meters) at the
+    /// initial angle `start_ang` (in radians), with the parameters of the path given by
+    /// `options`. The step size of the stepper is set separately, with `set_step_size`.
+    pub fn cast_ray_stepper_with<'a>(
+        &'a self,
+        start_h: f64,
+        start_ang: f64,
+        options: &RayOptions,
+    ) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.cast_ray_with(start_h, start_ang, options)
+            .into_path_stepper()
+    }
+
+    /// Returns a set of rays of different colors, starting from the same point in the same
+    /// direction - one ray for each of the given `wavelengths` (in meters).
+    ///
+    /// * `start_h` - the starting altitude of the paths in meters
+    /// * `start_ang` - the initial angle in radians between the paths and the horizontal plane;
+    ///   -π/2 is down, 0 is horizontal, π/2 is up
+    ///
+    /// The rays are integrated together, with the default step, and remember the states they
+    /// have been integrated through, like `CachedPath`. The rays of different colors separate
+    /// slowly, so they share the evaluation of the atmosphere: in every integration stage, the
+    /// pressure, temperature and humidity are evaluated once for the rays that are within 1 mm of
+    /// each other, and only the refractive index and its derivative are calculated for each
+    /// wavelength.
+    ///
+    /// The wavelengths only matter for the optical refractive index model.
+    pub fn cast_ray_spectrum<'a>(
+        &'a self,
+        start_h: f64,
+        start_ang: f64,
+        wavelengths: &[f64],
+    ) -> Vec<Box<dyn Path<'a> + 'a>> {
+        SpectrumRay::cast(self, start_h, start_ang, wavelengths)
+            .into_iter()
+            .map(|ray| Box::new(ray) as Box<dyn Path<'a> + 'a>)
+            .collect()
+    }
+
+    /// Returns a fan of light paths starting at the altitude `start_h` (in meters), one for each
+    /// of the initial angles `angles` (in radians), with the parameters given by `options`. The
+    /// paths are created in parallel.
+    #[cfg(feature = "rayon")]
+    pub fn cast_ray_fan<'a>(
+        &'a self,
+        start_h: f64,
+        angles: &[f64],
+        options: &RayOptions,
+    ) -> Vec<AnyPath<'a>> {
+        angles
+            .par_iter()
+            .map(|&ang| self.cast_ray_with(start_h, ang, options))
+            .collect()
+    }
+
+    /// Traces a fan of light paths like `cast_ray_fan` and returns the states of each of them at
+    /// the distances `dists` (in meters, sorted in ascending order). The paths are traced in
+    /// parallel.
+    #[cfg(feature = "rayon")]
+    pub fn sample_ray_fan(
+        &self,
+        start_h: f64,
+        angles: &[f64],
+        dists: &[f64],
+        options: &RayOptions,
+    ) -> Vec<Vec<RayState>> {
+        angles
+            .par_iter()
+            .map(|&ang| self.cast_ray_with(start_h, ang, options).sample(dists))
+            .collect()
     }
 
     /// Returns an object representing a light path.
@@ -148,9 +823,12 @@ impl Environment {
     /// * `tgt_h` - the altitude of the target point in meters
     /// * `tgt_dist` - the distance of the target point from the initial point, in meters
     /// * `straight` - `true` if the path should be a straight line, `false` if it should be a ray
-    /// affected by the atmosphere
+    ///   affected by the atmosphere
     ///
-    /// The ray is calculated by performing a binary search on the initial angle.
+    /// The ray is calculated by searching for the initial angle numerically (except for straight
+    /// lines over flat or spherical surfaces, which are calculated directly). If no ray can reach
+    /// the target, the closest one found is returned; use `try_cast_ray_target` to detect such
+    /// cases.
     pub fn cast_ray_target<'a>(
         &'a self,
         start_h: f64,
@@ -158,35 +836,1207 @@ impl Environment {
         tgt_dist: f64,
         straight: bool,
     ) -> Box<dyn Path<'a> + 'a> {
-        if straight {
-            match self.shape {
-                EarthShape::Flat => {
-                    Box::new(flat::Line::from_two_points(start_h, 0.0, tgt_h, tgt_dist))
-                }
-                EarthShape::Spherical { radius } => Box::new(spherical::Line::from_two_points(
+        self.cast_ray_target_with(
+            start_h,
+            tgt_h,
+            tgt_dist,
+            straight,
+            &TargetSolverOptions::default(),
+        )
+    }
+
+    /// Like `cast_ray_target`, but with custom parameters of the search for the initial angle.
+    pub fn cast_ray_target_with<'a>(
+        &'a self,
+        start_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        straight: bool,
+        options: &TargetSolverOptions,
+    ) -> Box<dyn Path<'a> + 'a> {
+        match (straight, self.shape) {
+            (true, EarthShape::Flat) => Box::new(flat::Line::from_two_points(
+                self, start_h, 0.0, tgt_h, tgt_dist,
+            )),
+            (true, EarthShape::Spherical { .. } | EarthShape::Ellipsoid { .. }) => {
+                Box::new(spherical::Line::from_two_points(
                     self,
                     start_h,
                     0.0,
                     tgt_h,
-                    tgt_dist / radius,
-                )),
+                    tgt_dist / self.radius().unwrap(),
+                ))
+            }
+            _ => {
+                let ang = self.find_target_angle(start_h, tgt_h, tgt_dist, straight, options);
+                self.cast_ray(start_h, ang, straight)
+            }
+        }
+    }
+
+    /// Returns the apparent elevation angle (in radians) of a target at the altitude `target_h`
+    /// and distance `target_dist` (both in meters), seen by an observer at the altitude
+    /// `observer_h` - that is, the initial angle of the ray connecting them.
+    pub fn apparent_elevation(&self, observer_h: f64, target_h: f64, target_dist: f64) -> f64 {
+        self.cast_ray_target(observer_h, target_h, target_dist, false)
+            .angle_at_dist(0.0)
+    }
+
+    /// Returns the altitude of the point at the distance `dist` (in meters), which an observer at
+    /// the altitude `observer_h` sees at the elevation angle `apparent_angle` (in radians).
+    pub fn true_target_from_apparent(
+        &self,
+        observer_h: f64,
+        apparent_angle: f64,
+        dist: f64,
+    ) -> f64 {
+        self.cast_ray(observer_h, apparent_angle, false)
+            .h_at_dist(dist)
+    }
+
+    /// Returns the derivative of the apparent elevation angle of a target at the distance
+    /// `target_dist` with respect to its true altitude `target_h` (in radians per meter), seen by
+    /// an observer at the altitude `observer_h` - the vertical stretching of the image.
+    ///
+    /// Without refraction over a flat surface, it's `cos²(elevation) / target_dist`; larger
+    /// values mean that the image is stretched (towering), smaller - that it's compressed
+    /// (stooping), and negative ones - that it's inverted.
+    pub fn vertical_magnification(&self, observer_h: f64, target_h: f64, target_dist: f64) -> f64 {
+        let angle = self.apparent_elevation(observer_h, target_h, target_dist);
+        self.elevation_derivative(observer_h, angle, target_dist, false)
+    }
+
+    /// Returns the derivative of the initial angle of the path starting at the altitude
+    /// `start_h` at the angle `angle` with respect to its altitude at the distance `dist`
+    pub(crate) fn elevation_derivative(
+        &self,
+        start_h: f64,
+        angle: f64,
+        dist: f64,
+        straight: bool,
+    ) -> f64 {
+        const DELTA: f64 = 1e-6;
+        let h_above = self
+            .cast_ray(start_h, angle + DELTA, straight)
+            .h_at_dist(dist);
+        let h_below = self
+            .cast_ray(start_h, angle - DELTA, straight)
+            .h_at_dist(dist);
+        2.0 * DELTA / (h_above - h_below)
+    }
+}
+
+/// A builder for `Environment`, validating the settings before creating it.
+///
+/// By default, it uses a spherical Earth with the radius of 6371 km, the US-1976 standard
+/// atmosphere and the optical refractive index for the wavelength of 530 nm.
+#[derive(Clone)]
+pub struct EnvironmentBuilder {
+    shape: EarthShape,
+    atmosphere: Option<Atmosphere>,
+    wavelength: f64,
+    index_model: RefractiveIndexModel,
+    top_of_atmosphere: Option<f64>,
+    humidity_enabled: bool,
+}
+
+impl Default for EnvironmentBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EnvironmentBuilder {
+    /// Starts building an environment with the default settings
+    pub fn new() -> Self {
+        Self {
+            shape: EarthShape::Spherical {
+                radius: 6_371_000.0,
+            },
+            atmosphere: None,
+            wavelength: 530e-9,
+            index_model: RefractiveIndexModel::Optical,
+            top_of_atmosphere: None,
+            humidity_enabled: true,
+        }
+    }
+
+    /// Sets a spherical Earth with the given radius in meters, which has to be finite and
+    /// positive
+    pub fn spherical(mut self, radius: f64) -> Self {
+        self.shape = EarthShape::Spherical { radius };
+        self
+    }
+
+    /// Sets a flat Earth
+    pub fn flat(mut self) -> Self {
+        self.shape = EarthShape::Flat;
+        self
+    }
+
+    /// Sets the shape of the Earth; the radius of a sphere and the equatorial radius of an
+    /// ellipsoid have to be finite and positive, and the flattening of an ellipsoid has to be
+    /// between 0 (inclusive) and 1
+    pub fn shape(mut self, shape: EarthShape) -> Self {
+        self.shape = shape;
+        self
+    }
+
+    /// Sets the atmospheric model, which is the US-1976 standard by default
+    pub fn atmosphere(mut self, atmosphere: Atmosphere) -> Self {
+        self.atmosphere = Some(atmosphere);
+        self
+    }
+
+    /// Sets the wavelength of the light in meters, which has to be finite and positive
+    pub fn wavelength(mut self, wavelength: f64) -> Self {
+        self.wavelength = wavelength;
+        self
+    }
+
+    /// Sets the model used for calculating the refractive index of the air
+    pub fn index_model(mut self, index_model: RefractiveIndexModel) -> Self {
+        self.index_model = index_model;
+        self
+    }
+
+    /// Sets the altitude in meters above which the refractive index is exactly 1, which has to be
+    /// finite.
+    pub fn top_of_atmosphere(mut self, h: f64) -> Self {
+        self.top_of_atmosphere = Some(h);
+        self
+    }
+
+    /// If set to false, the humidity profile of the atmosphere is replaced with 0% everywhere.
+    pub fn humidity_enabled(mut self, enabled: bool) -> Self {
+        self.humidity_enabled = enabled;
+        self
+    }
+
+    /// Creates the environment, or returns the first invalid setting found
+    pub fn build(self) -> Result<Environment, EnvironmentError> {
+        match self.shape {
+            EarthShape::Spherical { radius } if !(radius.is_finite() && radius > 0.0) => {
+                return Err(EnvironmentError::InvalidRadius(radius));
+            }
+            EarthShape::Ellipsoid { a, f, .. }
+                if !(a.is_finite() && a > 0.0 && (0.0..1.0).contains(&f)) =>
+            {
+                return Err(EnvironmentError::InvalidEllipsoid { a, f });
             }
+            _ => (),
+        }
+        if !(self.wavelength.is_finite() && self.wavelength > 0.0) {
+            return Err(EnvironmentError::InvalidWavelength(self.wavelength));
+        }
+        if let Some(top) = self.top_of_atmosphere.filter(|top| !top.is_finite()) {
+            return Err(EnvironmentError::InvalidTopOfAtmosphere(top));
+        }
+
+        let atmosphere = self.atmosphere.unwrap_or_else(us76_atmosphere);
+        let atmosphere = if self.humidity_enabled {
+            atmosphere
         } else {
-            let (mut min_ang, mut max_ang) = (-1.5, 1.5);
-            let epsilon = 1e-9;
-
-            while max_ang - min_ang > epsilon {
-                let cur_ang = 0.5 * (min_ang + max_ang);
-                let ray = self.cast_ray(start_h, cur_ang, straight);
-                let h = ray.h_at_dist(tgt_dist);
-                if h > tgt_h {
-                    max_ang = cur_ang;
-                } else {
-                    min_ang = cur_ang;
+            atmosphere.without_humidity()
+        };
+
+        Ok(Environment {
+            shape: self.shape,
+            atmosphere,
+            wavelength: self.wavelength,
+            index_model: self.index_model,
+            top_of_atmosphere: self.top_of_atmosphere,
+        })
+    }
+}
+
+/// An invalid setting of an environment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnvironmentError {
+    /// The radius of the Earth (in meters) is not finite and positive
+    InvalidRadius(f64),
+    /// The equatorial radius `a` (in meters) of the ellipsoid is not finite and positive, or its
+    /// flattening `f` is not between 0 (inclusive) and 1
+    InvalidEllipsoid { a: f64, f: f64 },
+    /// The wavelength (in meters) is not finite and positive
+    InvalidWavelength(f64),
+    /// The refraction coefficient of the effective radius method is not finite or is greater
+    /// than 1
+    InvalidRefractionCoefficient(f64),
+    /// The altitude of the top of the atmosphere (in meters) is not finite
+    InvalidTopOfAtmosphere(f64),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::{inversion_env, us76_env_with_radius};
+    use crate::Integration;
+    use crate::{export_samples, sample_points, ExportFormat, StepEvent};
+    use std::cell::Cell;
+
+    fn us76_env(wavelength: f64) -> Environment {
+        Environment {
+            wavelength,
+            ..us76_env_with_radius(6_378_000.0)
+        }
+    }
+
+    #[test]
+    fn test_dispersion() {
+        let red = us76_env(650e-9);
+        let blue = us76_env(450e-9);
+        assert!(blue.n(0.0) > red.n(0.0));
+
+        // blue light is bent more strongly, so a horizontal ray stays lower
+        let h_red = red.cast_ray(10.0, 0.0, false).h_at_dist(50e3);
+        let h_blue = blue.cast_ray(10.0, 0.0, false).h_at_dist(50e3);
+        assert!(h_blue < h_red);
+
+        let spectrum = red.cast_ray_spectrum(10.0, 0.0, &[650e-9, 450e-9]);
+        assert!((spectrum[0].h_at_dist(50e3) - h_red).abs() < 1e-5);
+        assert!((spectrum[1].h_at_dist(50e3) - h_blue).abs() < 1e-5);
+
+        // the shared integration follows the separate rays, also across an inversion
+        let env = inversion_env();
+        let wavelengths = [650e-9, 550e-9, 450e-9];
+        for &ang in &[-0.002, 0.0, 0.001, 0.003] {
+            let spectrum = env.cast_ray_spectrum(5.0, ang, &wavelengths);
+            for (ray, &wavelength) in spectrum.iter().zip(&wavelengths) {
+                let options = RayOptions {
+                    wavelength: Some(wavelength),
+                    ..Default::default()
+                };
+                let single = env.cast_ray_with(5.0, ang, &options);
+                for &dist in &[-1e3, 1e3, 10e3, 30e3, 60e3] {
+                    assert!((ray.h_at_dist(dist) - single.h_at_dist(dist)).abs() < 1e-5);
                 }
             }
+        }
+
+        let hs = [20.0, 20.0005, 20.5];
+        let indices = env.n_dn_spectrum(&hs, &wavelengths, &AtmosphereHints::default());
+        for ((&h, &wavelength), &(n, dn)) in hs.iter().zip(&wavelengths).zip(&indices) {
+            let (n_single, dn_single) = env.n_dn_at_wavelength(h, wavelength);
+            assert!((n - n_single).abs() < 1e-12);
+            assert!((dn - dn_single).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_ray_options() {
+        let env = us76_env(530e-9);
+        let h_default = env.cast_ray(10.0, 0.01, false).h_at_dist(50e3);
+
+        let options = RayOptions {
+            step: 1.0,
+            integrator: IntegratorKind::RungeKutta8,
+            ..Default::default()
+        };
+        let h_precise = env.cast_ray_with(10.0, 0.01, &options).h_at_dist(50e3);
+        assert!((h_default - h_precise).abs() < 1e-3);
+
+        let options = RayOptions {
+            max_altitude: Some(200.0),
+            ..Default::default()
+        };
+        let ray = env.cast_ray_with(10.0, 0.01, &options);
+        assert!(ray.h_at_dist(10e3) < 200.0);
+        assert!(ray.h_at_dist(50e3).is_nan());
+
+        let stepper = env.cast_ray_stepper_with(10.0, 0.01, &options);
+        let last = stepper.last().unwrap();
+        assert!(last.h <= 200.0 && last.h > 199.0);
+    }
+
+    #[test]
+    fn test_invalid_ray_options() {
+        let env = us76_env(530e-9);
+        let ray = env.cast_ray(10.0, 0.001, false);
+        let h = ray.h_at_dist(20e3);
+        let crossings = ray.dist_at_h(15.0, (0.0, 20e3));
+        assert_eq!(crossings.len(), 1);
+        for step in [0.0, -5.0, f64::NAN] {
+            for integrator in [IntegratorKind::RungeKutta4, IntegratorKind::DormandPrince] {
+                let options = RayOptions {
+                    step,
+                    integrator,
+                    tolerance: 0.0,
+                    ..Default::default()
+                };
+                let ray = env.cast_ray_with(10.0, 0.001, &options);
+                assert!((ray.h_at_dist(20e3) - h).abs() < 1e-3);
+                assert_eq!(ray.dist_at_h(15.0, (0.0, 20e3)).len(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ground_intersection() {
+        let env = us76_env(530e-9);
+
+        let ray = env.cast_ray(100.0, -0.01, false);
+        let hit = ray.ground_intersection(0.0, 100e3).unwrap();
+        assert!(ray.h_at_dist(hit.dist).abs() < 1e-3);
+        assert!((ray.angle_at_dist(hit.dist) - hit.angle).abs() < 1e-9);
+        assert!(hit.angle < 0.0 && hit.angle > -0.01);
+        assert!(ray.ground_intersection(0.0, 0.5 * hit.dist).is_none());
+        assert!(env
+            .cast_ray(100.0, 0.01, false)
+            .ground_intersection(0.0, 100e3)
+            .is_none());
+
+        let line = env.cast_ray(100.0, -0.01, true);
+        let line_hit = line.ground_intersection(0.0, 100e3).unwrap();
+        assert!(line.h_at_dist(line_hit.dist).abs() < 1e-6);
+        // the rays are bent towards the ground, so they reach it closer than straight lines
+        assert!(hit.dist < line_hit.dist);
+
+        let flat = Environment {
+            shape: EarthShape::Flat,
+            ..env.clone()
+        };
+        let flat_hit = flat
+            .cast_ray(100.0, -0.01, true)
+            .ground_intersection(0.0, 100e3)
+            .unwrap();
+        assert!((flat_hit.dist - 100.0 / 0.01_f64.tan()).abs() < 1e-6);
+
+        let options = RayOptions {
+            ground_altitude: Some(0.0),
+            ..Default::default()
+        };
+        let ray = env.cast_ray_with(100.0, -0.01, &options);
+        assert!(ray.h_at_dist(0.5 * hit.dist) > 0.0);
+        assert!(ray.h_at_dist(hit.dist + 10.0).is_nan());
+        let last = env
+            .cast_ray_stepper_with(100.0, -0.01, &options)
+            .last()
+            .unwrap();
+        assert!(last.h >= 0.0 && last.dist < hit.dist);
+    }
+
+    #[test]
+    fn test_start_h_and_angle() {
+        let spherical = us76_env(530e-9);
+        let flat = Environment {
+            shape: EarthShape::Flat,
+            ..spherical.clone()
+        };
+        let custom = Environment {
+            shape: EarthShape::Custom {
+                curvature: |_| 1e-7,
+            },
+            ..spherical.clone()
+        };
+        for env in &[spherical, flat, custom] {
+            for &straight in &[false, true] {
+                let path = env.cast_ray(15.0, -0.02, straight);
+                assert!((path.start_h() - 15.0).abs() < 1e-9);
+                assert!((path.start_angle() + 0.02).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dist_at_h() {
+        let env = us76_env(530e-9);
+        for &straight in &[false, true] {
+            // the path descends to its lowest point and rises again
+            let path = env.cast_ray(100.0, -0.004, straight);
+            let dists = path.dist_at_h(70.0, (-100e3, 100e3));
+            assert_eq!(dists.len(), 2);
+            assert!(dists[0] > 0.0 && dists[1] > dists[0]);
+            for &dist in &dists {
+                assert!((path.h_at_dist(dist) - 70.0).abs() < 1e-3);
+            }
+            assert_eq!(path.dist_at_h(70.0, (dists[0] + 1.0, 100e3)).len(), 1);
+
+            // behind the observer, the path rises
+            let dists = path.dist_at_h(150.0, (-100e3, 100e3));
+            assert!(dists[0] < 0.0);
+            assert!((path.h_at_dist(dists[0]) - 150.0).abs() < 1e-3);
+        }
+    }
 
-            self.cast_ray(start_h, 0.5 * (min_ang + max_ang), straight)
+    #[test]
+    fn test_sample() {
+        let spherical = us76_env(530e-9);
+        let custom = Environment {
+            shape: EarthShape::Custom {
+                curvature: |_| 1.0 / 6_378_000.0,
+            },
+            ..spherical.clone()
+        };
+        let dists = [-20e3, -5e3, 0.0, 1234.5, 10e3, 30e3];
+        for env in &[spherical, custom] {
+            for &straight in &[false, true] {
+                let path = env.cast_ray(10.0, 0.002, straight);
+                let states = path.sample(&dists);
+                assert_eq!(states.len(), dists.len());
+                for (state, &dist) in states.iter().zip(&dists) {
+                    assert_eq!(state.x, dist);
+                    assert!((state.h - path.h_at_dist(dist)).abs() < 1e-6);
+                }
+            }
         }
     }
+
+    #[test]
+    fn test_point_at_dist() {
+        let spherical = us76_env(530e-9);
+        let flat = Environment {
+            shape: EarthShape::Flat,
+            ..spherical.clone()
+        };
+        for env in &[spherical, flat] {
+            for &straight in &[false, true] {
+                let path = env.cast_ray(10.0, 0.002, straight);
+                let point = path.point_at_dist(15e3);
+                assert_eq!(point.dist, 15e3);
+                assert!((point.h - path.h_at_dist(15e3)).abs() < 1e-9);
+                assert!((point.angle - path.angle_at_dist(15e3)).abs() < 1e-12);
+                assert_eq!(point.n, env.n(point.h));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bending() {
+        let spherical = us76_env(530e-9);
+        let flat = Environment {
+            shape: EarthShape::Flat,
+            ..spherical.clone()
+        };
+        let custom = Environment {
+            shape: EarthShape::Custom {
+                curvature: |_| 1.0 / 6_378_000.0,
+            },
+            ..spherical.clone()
+        };
+        assert!((custom.surface_rotation(50e3) - 50e3 / 6_378_000.0).abs() < 1e-12);
+
+        for env in &[&spherical, &flat, &custom] {
+            let line = env.cast_ray(10.0, 0.001, true);
+            assert!(line.bending_at_dist(50e3).abs() < 1e-9);
+        }
+
+        // near the surface the rays bend along circles with the curvature given by the
+        // refraction coefficient
+        let ray = spherical.cast_ray(10.0, 0.0, false);
+        let bending = ray.bending_at_dist(20e3);
+        let expected = spherical.refraction_coefficient(10.0, 0.0) * 20e3 / 6_378_000.0;
+        assert!(bending > 0.0);
+        assert!((bending - expected).abs() < 0.05 * expected);
+
+        let custom_ray = custom.cast_ray(10.0, 0.0, false);
+        assert!((custom_ray.bending_at_dist(20e3) - bending).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_path_lengths() {
+        let spherical = us76_env(530e-9);
+        let custom = Environment {
+            shape: EarthShape::Custom {
+                curvature: |_| 1.0 / 6_378_000.0,
+            },
+            ..spherical.clone()
+        };
+
+        // the closed-form length of a line agrees with the numerical one
+        let line = spherical.cast_ray(10.0, 0.01, true);
+        let custom_line = custom.cast_ray(10.0, 0.01, true);
+        let length = line.arc_length_at_dist(30e3);
+        assert!((length - custom_line.arc_length_at_dist(30e3)).abs() < 1e-3);
+        assert!(line.arc_length_at_dist(-30e3) < 0.0);
+
+        let ray = spherical.cast_ray(10.0, 0.0, false);
+        let arc = ray.arc_length_at_dist(30e3);
+        let optical = ray.optical_length_at_dist(30e3);
+        assert!(arc > 30e3 && arc < 30e3 * (1.0 + 100.0 / 6_378_000.0));
+        // the ray stays low, so the excess optical length is close to (n - 1) at the surface
+        let excess = (spherical.n(10.0) - 1.0) * arc;
+        assert!((optical - arc - excess).abs() < 0.01 * excess);
+        assert!(ray.arc_length_at_dist(-30e3) < 0.0);
+    }
+
+    #[test]
+    fn test_deviation() {
+        let env = us76_env(530e-9);
+        let line = env.cast_ray(10.0, 0.0, true);
+        assert_eq!(line.deviation_at_dist(20e3).h, 0.0);
+
+        // the ray bends down relative to the line approximately along a parabola
+        let ray = env.cast_ray(10.0, 0.0, false);
+        let deviation = ray.deviation_at_dist(20e3);
+        let k = env.refraction_coefficient(10.0, 0.0);
+        let expected = -k * 20e3 * 20e3 / 2.0 / 6_378_000.0;
+        assert!((deviation.h - expected).abs() < 0.05 * expected.abs());
+        assert!((deviation.angle - deviation.h / 20e3).abs() < 1e-3 * deviation.angle.abs());
+    }
+
+    #[test]
+    fn test_any_path() {
+        let env = us76_env(530e-9);
+        let path = env.cast_ray_with(10.0, 0.01, &RayOptions::default());
+        assert!(matches!(path, AnyPath::SphericalRay(_)));
+        let copy = path.clone();
+        assert_eq!(
+            copy.h_at_dist(20e3),
+            env.cast_ray(10.0, 0.01, false).h_at_dist(20e3)
+        );
+
+        let flat = Environment {
+            shape: EarthShape::Flat,
+            ..env
+        };
+        let options = RayOptions {
+            straight: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            flat.cast_ray_with(10.0, 0.01, &options),
+            AnyPath::FlatLine(_)
+        ));
+    }
+
+    #[test]
+    fn test_backward_propagation() {
+        let spherical = us76_env(530e-9);
+        let flat = Environment {
+            shape: EarthShape::Flat,
+            ..spherical.clone()
+        };
+        let custom = Environment {
+            shape: EarthShape::Custom {
+                curvature: |_| 1.0 / 6_378_000.0,
+            },
+            ..spherical.clone()
+        };
+        for env in &[&spherical, &flat, &custom] {
+            // a ray started from the point behind the initial one, in the direction the path has
+            // there, has to pass through the initial point
+            let path = env.cast_ray(10.0, 0.001, false);
+            let (h, ang) = (path.h_at_dist(-10e3), path.angle_at_dist(-10e3));
+            let ray = env.cast_ray(h, ang, false);
+            assert!((ray.h_at_dist(10e3) - 10.0).abs() < 1e-3);
+            assert!((ray.angle_at_dist(10e3) - 0.001).abs() < 1e-8);
+        }
+        let h_spherical = spherical.cast_ray(10.0, 0.001, false).h_at_dist(-10e3);
+        let h_custom = custom.cast_ray(10.0, 0.001, false).h_at_dist(-10e3);
+        assert!((h_spherical - h_custom).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_invariant_ray() {
+        let env = us76_env(530e-9);
+        let precise = RayOptions {
+            step: 5.0,
+            integrator: IntegratorKind::RungeKutta8,
+            ..Default::default()
+        };
+        let invariant = RayOptions {
+            integrator: IntegratorKind::BouguerInvariant,
+            ..Default::default()
+        };
+        for &ang in &[0.01, 0.0, -0.001] {
+            let ray = env.cast_ray_with(10.0, ang, &precise);
+            let fast = env.cast_ray_with(10.0, ang, &invariant);
+            assert!(matches!(fast, AnyPath::SphericalInvariantRay(_)));
+            for &dist in &[-20e3, -1e3, 0.0, 500.0, 20e3, 50e3] {
+                let (h, fast_h) = (ray.h_at_dist(dist), fast.h_at_dist(dist));
+                if h < 0.0 {
+                    // the invariant ray ends at the ground
+                    assert!(fast_h.is_nan());
+                    continue;
+                }
+                assert!((h - fast_h).abs() < 1e-3);
+                assert!((ray.angle_at_dist(dist) - fast.angle_at_dist(dist)).abs() < 1e-8);
+            }
+        }
+        let fast = env.cast_ray_with(10.0, -0.001, &invariant);
+        let crossings = fast.dist_at_h(9.6, (-50e3, 50e3));
+        assert_eq!(crossings.len(), 2);
+        for dist in crossings {
+            assert!((fast.h_at_dist(dist) - 9.6).abs() < 1e-6);
+        }
+        let fast = env.cast_ray_with(10.0, -0.01, &invariant);
+        let ground = fast.ground_intersection(0.0, 50e3).unwrap();
+        assert!(fast.h_at_dist(ground.dist).abs() < 1e-6);
+        let mut stepper = fast.into_path_stepper();
+        stepper.on_ground_hit();
+        let hit = stepper
+            .advance_while(&mut |step| step.event.is_none())
+            .unwrap();
+        assert!((hit.dist - ground.dist).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_arc_ray() {
+        let spherical = us76_env(530e-9);
+        let flat = Environment {
+            shape: EarthShape::Flat,
+            ..spherical.clone()
+        };
+        let precise = RayOptions {
+            integrator: IntegratorKind::RungeKutta8,
+            ..Default::default()
+        };
+        let arcs = RayOptions {
+            integrator: IntegratorKind::CircularArcs,
+            ..Default::default()
+        };
+        for env in &[&spherical, &flat] {
+            for &ang in &[0.002, 0.0, -0.001] {
+                let ray = env.cast_ray_with(10.0, ang, &precise);
+                let fast = env.cast_ray_with(10.0, ang, &arcs);
+                assert!(matches!(fast, AnyPath::ArcRay(_)));
+                for &dist in &[-10e3, 0.0, 500.0, 10e3, 30e3] {
+                    assert!((ray.h_at_dist(dist) - fast.h_at_dist(dist)).abs() < 5e-3);
+                    assert!((ray.angle_at_dist(dist) - fast.angle_at_dist(dist)).abs() < 1e-7);
+                }
+            }
+        }
+        let fast = spherical.cast_ray_with(10.0, 0.0, &arcs);
+        let crossings = fast.dist_at_h(12.0, (-50e3, 50e3));
+        assert_eq!(crossings.len(), 2);
+        assert!((crossings[0] + crossings[1]).abs() < 1e-6);
+        for &dist in &crossings {
+            assert!((fast.h_at_dist(dist) - 12.0).abs() < 1e-6);
+        }
+        let fast = flat.cast_ray_with(10.0, -0.001, &arcs);
+        let ground = fast.ground_intersection(0.0, 50e3).unwrap();
+        assert!(fast.h_at_dist(ground.dist).abs() < 1e-6);
+        let mut stepper = fast.into_path_stepper();
+        stepper.on_ground_hit();
+        let hit = stepper
+            .advance_while(&mut |step| step.event.is_none())
+            .unwrap();
+        assert!((hit.dist - ground.dist).abs() < 1e-3);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_ray_fan() {
+        let env = us76_env(530e-9);
+        let options = RayOptions::default();
+        let angles = [-0.001, 0.0, 0.001, 0.002];
+        let dists = [-1e3, 0.0, 10e3, 20e3];
+        let paths = env.cast_ray_fan(10.0, &angles, &options);
+        let states = env.sample_ray_fan(10.0, &angles, &dists, &options);
+        assert_eq!(paths.len(), angles.len());
+        for ((&ang, path), states) in angles.iter().zip(&paths).zip(&states) {
+            assert!((path.start_angle() - ang).abs() < 1e-12);
+            let expected = env.cast_ray_with(10.0, ang, &options).sample(&dists);
+            for (state, expected) in states.iter().zip(&expected) {
+                assert_eq!((state.h, state.dh), (expected.h, expected.dh));
+            }
+        }
+    }
+
+    #[test]
+    fn test_shared_path() {
+        let env = Arc::new(us76_env(530e-9));
+        let options = RayOptions::default();
+        let path = Environment::cast_ray_shared(&env, 10.0, 0.001, &options);
+        let expected = env.cast_ray_with(10.0, 0.001, &options).h_at_dist(10e3);
+        // the path doesn't borrow the environment, so it can be moved to another thread
+        let h = std::thread::spawn(move || path.h_at_dist(10e3))
+            .join()
+            .unwrap();
+        assert_eq!(h, expected);
+    }
+
+    #[test]
+    fn test_export_samples() {
+        let env = us76_env(530e-9);
+        let path = env.cast_ray(10.0, 0.001, false);
+
+        let mut csv = vec![];
+        export_samples(&path, &env, (0.0, 25.0), 10.0, ExportFormat::Csv, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "dist,h,angle,n");
+        let last: Vec<f64> = lines[4].split(',').map(|x| x.parse().unwrap()).collect();
+        let point = path.point_at_dist(25.0);
+        assert_eq!(last, vec![25.0, point.h, point.angle, point.n]);
+
+        let mut json = vec![];
+        export_samples(
+            &path,
+            &env,
+            (0.0, 20.0),
+            10.0,
+            ExportFormat::Json,
+            &mut json,
+        )
+        .unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("[{\"dist\":0,\"h\":10,"));
+        assert_eq!(json.matches("\"angle\"").count(), 3);
+    }
+
+    #[test]
+    fn test_sample_points() {
+        let env = us76_env(530e-9);
+        let path = env.cast_ray(10.0, 0.001, false);
+        let dists = [5e3, 1e3, 20e3, 1e3];
+        let points = sample_points(&path, &env, &dists);
+        assert_eq!(points.len(), dists.len());
+        for (point, &dist) in points.iter().zip(&dists) {
+            assert_eq!(point.dist, dist);
+            assert!((point.h - path.h_at_dist(dist)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_paths_between_threads() {
+        let env = Arc::new(us76_env(530e-9));
+        let options = RayOptions::default();
+        let expected = env.cast_ray_with(10.0, 0.001, &options).h_at_dist(10e3);
+
+        let path: Arc<dyn Path<'static>> =
+            Arc::new(Environment::cast_ray_shared(&env, 10.0, 0.001, &options));
+        let cached = Arc::new(CachedPath::new(
+            env.clone(),
+            Environment::cast_ray_shared(&env, 10.0, 0.001, &options),
+            options.step,
+        ));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (path, cached) = (path.clone(), cached.clone());
+                std::thread::spawn(move || {
+                    let mut stepper = path.path_stepper();
+                    stepper.set_step_size(5.0);
+                    let step = stepper.run_to_dist(10e3).unwrap();
+                    (path.h_at_dist(10e3), cached.h_at_dist(10e3), step.h)
+                })
+            })
+            .collect();
+        for thread in threads {
+            let (h, cached_h, step_h) = thread.join().unwrap();
+            assert_eq!(h, expected);
+            assert!((cached_h - expected).abs() < 1e-6);
+            assert!((step_h - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_spherical_line_range() {
+        let env = us76_env(530e-9);
+        let line = env.cast_ray(10.0, 0.0, true);
+        let quarter = 0.5 * std::f64::consts::PI * 6_378_000.0;
+        assert!(line.h_at_dist(0.9 * quarter) > 0.0);
+        assert!(line.h_at_dist(1.1 * quarter).is_nan());
+        assert!(line.h_at_dist(-1.1 * quarter).is_nan());
+    }
+
+    #[test]
+    fn test_path_steps() {
+        let spherical = us76_env(530e-9);
+        let custom = Environment {
+            shape: EarthShape::Custom {
+                curvature: |_| 1.0 / 6_378_000.0,
+            },
+            ..spherical.clone()
+        };
+        for env in &[&spherical, &custom] {
+            for &straight in &[false, true] {
+                let path = env.cast_ray(10.0, 0.01, straight);
+                let mut stepper = env.cast_ray_stepper(10.0, 0.01, straight);
+                stepper.set_step_size(100.0);
+                let step = stepper.nth(200).unwrap();
+                assert!((step.dist - 20e3).abs() < 1e-6);
+                assert!((step.h - path.h_at_dist(20e3)).abs() < 1e-3);
+                assert!((step.angle - path.angle_at_dist(20e3)).abs() < 1e-8);
+                assert!((step.arc_length - path.arc_length_at_dist(20e3)).abs() < 1e-3);
+                assert!((step.bending - path.bending_at_dist(20e3)).abs() < 1e-8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stepper_events() {
+        let env = Environment {
+            shape: EarthShape::Flat,
+            ..us76_env(530e-9)
+        };
+        for &straight in &[false, true] {
+            let start_ang = if straight { -0.001 } else { 0.0005 };
+            let mut stepper = env.cast_ray_stepper(10.0, start_ang, straight);
+            stepper.set_step_size(100.0);
+            stepper.on_altitude_crossing(12.0);
+            stepper.on_altitude_crossing(5.0);
+            stepper.on_ground_hit();
+            stepper.on_apex();
+            let steps: Vec<_> = stepper.take(1000).collect();
+            let events: Vec<_> = steps.iter().filter(|step| step.event.is_some()).collect();
+
+            let last = steps.last().unwrap();
+            assert_eq!(last.event, Some(StepEvent::GroundHit));
+            assert!(last.h.abs() < 1e-6);
+            for event in &events {
+                if let Some(StepEvent::AltitudeCrossing(h)) = event.event {
+                    assert!((event.h - h).abs() < 1e-6);
+                }
+            }
+            assert!(steps.windows(2).all(|pair| pair[0].dist <= pair[1].dist));
+
+            let kinds: Vec<_> = events.iter().map(|step| step.event.unwrap()).collect();
+            if straight {
+                assert_eq!(
+                    kinds,
+                    vec![StepEvent::AltitudeCrossing(5.0), StepEvent::GroundHit]
+                );
+            } else {
+                assert_eq!(
+                    kinds,
+                    vec![
+                        StepEvent::AltitudeCrossing(12.0),
+                        StepEvent::Apex,
+                        StepEvent::AltitudeCrossing(12.0),
+                        StepEvent::AltitudeCrossing(5.0),
+                        StepEvent::GroundHit,
+                    ]
+                );
+                assert!(events[1].dh.abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_to_dist() {
+        let env = us76_env(530e-9);
+        for &straight in &[false, true] {
+            let path = env.cast_ray(10.0, 0.005, straight);
+            let mut stepper = env.cast_ray_stepper(10.0, 0.005, straight);
+            stepper.set_step_size(100.0);
+            let step = stepper.run_to_dist(12_345.6).unwrap();
+            assert!((step.dist - 12_345.6).abs() < 1e-6);
+            assert!((step.h - path.h_at_dist(12_345.6)).abs() < 1e-3);
+            let next = stepper.next().unwrap();
+            assert!((next.dist - 12_445.6).abs() < 1e-6);
+
+            let step = stepper.advance_while(&mut |step| step.h < 200.0).unwrap();
+            assert!(step.h >= 200.0 && step.h < 210.0);
+        }
+
+        // the distance can lie before the regular step following an event
+        let mut stepper = env.cast_ray_stepper(10.0, 0.005, false);
+        stepper.set_step_size(100.0);
+        stepper.on_altitude_crossing(10.2);
+        let event = stepper
+            .advance_while(&mut |step| step.event.is_none())
+            .unwrap();
+        assert_eq!(event.event, Some(StepEvent::AltitudeCrossing(10.2)));
+        let step = stepper.run_to_dist(50.0).unwrap();
+        assert!((step.dist - 50.0).abs() < 1e-9);
+        assert!((step.h - env.cast_ray(10.0, 0.005, false).h_at_dist(50.0)).abs() < 1e-6);
+        assert!((stepper.next().unwrap().dist - 100.0).abs() < 1e-9);
+
+        let options = RayOptions {
+            ground_altitude: Some(0.0),
+            ..Default::default()
+        };
+        let mut stepper = env.cast_ray_stepper_with(10.0, -0.01, &options);
+        assert!(stepper.run_to_dist(5e3).is_none());
+    }
+
+    #[test]
+    fn test_stepper_grid() {
+        let env = us76_env(530e-9);
+        for &straight in &[false, true] {
+            let mut stepper = env.cast_ray_stepper(10.0, 0.005, straight);
+            let start = stepper.next().unwrap();
+            assert_eq!((start.dist, start.h, start.arc_length), (0.0, 10.0, 0.0));
+            assert!((start.angle - 0.005).abs() < 1e-12);
+
+            stepper.set_step_size(3.0);
+            stepper.next();
+            stepper.step_to_multiple_of(25.0);
+            let dists: Vec<f64> = stepper.by_ref().take(3).map(|step| step.dist).collect();
+            assert_eq!(dists, vec![25.0, 50.0, 75.0]);
+
+            // an invalid grid is replaced with the default step
+            for dx in [0.0, -25.0, f64::NAN] {
+                stepper.step_to_multiple_of(dx);
+                let dist = stepper.next().unwrap().dist;
+                assert_eq!(dist % RayOptions::default().step, 0.0, "{}", dist);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cached_path() {
+        let env = us76_env(530e-9);
+        let options = RayOptions::default();
+        let path = env.cast_ray_with(10.0, 0.001, &options);
+        let cached = env.cast_ray_cached(10.0, 0.001, &options);
+        assert_eq!(cached.cached_dist(), 0.0);
+        for &dist in &[12e3, 3e3, 7502.5, 20e3, 0.0, -5e3] {
+            assert!((cached.h_at_dist(dist) - path.h_at_dist(dist)).abs() < 1e-6);
+            assert!((cached.angle_at_dist(dist) - path.angle_at_dist(dist)).abs() < 1e-9);
+        }
+        assert!(cached.cached_dist() >= 20e3 && cached.cached_dist() < 20e3 + options.step);
+
+        // a path that ends at the ground
+        let options = RayOptions {
+            ground_altitude: Some(0.0),
+            ..Default::default()
+        };
+        let cached = env.cast_ray_cached(10.0, -0.01, &options);
+        assert!(cached.h_at_dist(500.0) > 0.0);
+        assert!(cached.h_at_dist(5e3).is_nan());
+    }
+
+    #[test]
+    fn test_dn_matches_numerical_derivative() {
+        let env = us76_env(530e-9);
+        let epsilon = 0.01;
+        for &h in &[0.0, 500.0, 5e3, 15e3, 30e3] {
+            let numerical = (env.n(h + epsilon) - env.n(h - epsilon)) / 2.0 / epsilon;
+            assert!((env.dn(h) - numerical).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_top_of_atmosphere() {
+        let env = Environment {
+            top_of_atmosphere: Some(50e3),
+            ..us76_env(530e-9)
+        };
+        assert_eq!(env.n(40e3), us76_env(530e-9).n(40e3));
+        assert_eq!(env.n_dn_at_wavelength(60e3, 650e-9), (1.0, 0.0));
+        assert_eq!(env.n_many(&[40e3, 60e3])[1], 1.0);
+
+        // the rays continue straight above the top
+        let ray = env.cast_ray(60e3, 0.01, false);
+        let line = env.cast_ray(60e3, 0.01, true);
+        for dist in [10e3, 100e3] {
+            assert!((ray.h_at_dist(dist) - line.h_at_dist(dist)).abs() < 1e-6);
+        }
+
+        // a ray leaving the atmosphere is integrated only up to the top
+        let ray = env.cast_ray(10.0, 0.2, false);
+        let start = ray.path_stepper().next().unwrap().state();
+        let evaluations = Cell::new(0);
+        let hints = AtmosphereHints::default();
+        let diff_eq = |state: &RayState| {
+            evaluations.set(evaluations.get() + 1);
+            env.calc_derivative(state, env.wavelength, &hints)
+        };
+        let integration = Integration::default();
+        let full = integration.state_at_dist(start, 1000e3, diff_eq);
+        let full_evaluations = evaluations.replace(0);
+        let continued = integration
+            .in_env(&env)
+            .state_at_dist(start, 1000e3, diff_eq);
+        // 4 evaluations per RK4 step of 5 m, up to about 250 km
+        assert_eq!(full_evaluations, 4 * 200_000);
+        assert!(evaluations.get() < 4 * 51_000);
+        assert!((continued.h - full.h).abs() < 1e-3);
+        assert!((continued.get_angle(&env) - full.get_angle(&env)).abs() < 1e-9);
+
+        assert!((ray.angle_at_dist(1000e3) - full.get_angle(&env)).abs() < 1e-9);
+        let crossings = integration.crossings(start, 300e3, 2000e3, diff_eq);
+        let dists = ray.dist_at_h(300e3, (0.0, 2000e3));
+        assert_eq!(dists.len(), 1);
+        assert!((dists[0] - crossings[0].x).abs() < 1e-2);
+        assert!(ray.ground_intersection(0.0, 2000e3).is_none());
+        let mut stepper = env.cast_ray_stepper(10.0, 0.2, false);
+        stepper.set_step_size(5.0);
+        let step = stepper.run_to_dist(1000e3).unwrap();
+        assert!((step.angle - full.get_angle(&env)).abs() < 1e-9);
+        assert!((step.h - full.h).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_n_many() {
+        let hs: Vec<f64> = (0..100).map(|i| i as f64 * 250.0).collect();
+        for index_model in [
+            RefractiveIndexModel::Optical,
+            RefractiveIndexModel::Radio,
+            RefractiveIndexModel::Vacuum,
+        ] {
+            let env = Environment {
+                index_model,
+                ..us76_env(530e-9)
+            };
+            let n: Vec<f64> = hs.iter().map(|&h| env.n(h)).collect();
+            assert_eq!(env.n_many(&hs), n);
+        }
+    }
+
+    #[test]
+    fn test_radio_refractivity() {
+        let env = Environment {
+            index_model: RefractiveIndexModel::Radio,
+            ..us76_env(530e-9)
+        };
+        // dry standard atmosphere at sea level: N = 77.6 * 1013.25 / 288 ~ 273
+        assert!(((env.n(0.0) - 1.0) * 1e6 - 273.0).abs() < 0.5);
+
+        let epsilon = 0.01;
+        let numerical = (env.n(epsilon) - env.n(-epsilon)) / 2.0 / epsilon;
+        assert!((env.dn(0.0) - numerical).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_ellipsoid_radius() {
+        // on the equator, the meridional radius is b^2/a and the prime vertical one is a
+        let meridional = EarthShape::wgs84(0.0, 0.0).radius().unwrap();
+        let prime_vertical = EarthShape::wgs84(0.0, std::f64::consts::FRAC_PI_2)
+            .radius()
+            .unwrap();
+        let b = WGS84_A * (1.0 - WGS84_F);
+        assert!((meridional - b * b / WGS84_A).abs() < 1e-6);
+        assert!((prime_vertical - WGS84_A).abs() < 1e-6);
+
+        // at the pole, all the radii are equal
+        let pole = std::f64::consts::FRAC_PI_2;
+        let r1 = EarthShape::wgs84(pole, 0.0).radius().unwrap();
+        let r2 = EarthShape::wgs84(pole, 1.0).radius().unwrap();
+        assert!((r1 - r2).abs() < 1e-6);
+        assert!((r1 - WGS84_A * WGS84_A / b).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_k_factor() {
+        let radius = 6_378_000.0;
+        let env = Environment::with_k_factor(radius, 0.25);
+        assert_eq!(env.radius(), Some(radius * 4.0 / 3.0));
+        assert_eq!(env.dn(0.0), 0.0);
+
+        // the curved ray is equivalent to a straight line over the effective radius
+        let ray = env.cast_ray(10.0, 0.0, false);
+        let line = env.cast_ray(10.0, 0.0, true);
+        assert!((ray.h_at_dist(30e3) - line.h_at_dist(30e3)).abs() < 1e-3);
+
+        assert_eq!(Environment::with_k_factor(radius, 1.0).radius(), None);
+        let nearly_flat = Environment::with_k_factor(radius, 1.0 - 1e-12);
+        assert!(nearly_flat.radius().unwrap() > 1e18);
+        // rays curving more than the surface aren't supported
+        for k in [1.5, f64::NAN, f64::INFINITY] {
+            match Environment::try_with_k_factor(radius, k) {
+                Err(EnvironmentError::InvalidRefractionCoefficient(_)) => (),
+                result => panic!("unexpected result for k = {}: {:?}", k, result.map(|_| ())),
+            }
+        }
+        let result = Environment::try_with_k_factor(-radius, 0.13);
+        assert_eq!(result.err(), Some(EnvironmentError::InvalidRadius(-radius)));
+    }
+
+    #[test]
+    fn test_custom_shape_matches_spherical() {
+        fn curvature(_dist: f64) -> f64 {
+            1.0 / 6_378_000.0
+        }
+        let spherical = us76_env(530e-9);
+        let custom = Environment {
+            shape: EarthShape::Custom { curvature },
+            ..spherical.clone()
+        };
+
+        for &straight in &[true, false] {
+            let ray1 = spherical.cast_ray(10.0, 0.001, straight);
+            let ray2 = custom.cast_ray(10.0, 0.001, straight);
+            assert!((ray1.h_at_dist(-20e3) - ray2.h_at_dist(-20e3)).abs() < 1e-3);
+            for &dist in &[10e3, 50e3] {
+                assert!((ray1.h_at_dist(dist) - ray2.h_at_dist(dist)).abs() < 1e-3);
+                assert!((ray1.angle_at_dist(dist) - ray2.angle_at_dist(dist)).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let env = EnvironmentBuilder::new()
+            .flat()
+            .wavelength(600e-9)
+            .humidity_enabled(false)
+            .build()
+            .expect("should build correctly");
+        assert_eq!(env.radius(), None);
+        assert_eq!(env.wavelength, 600e-9);
+
+        let result = EnvironmentBuilder::new().spherical(-1.0).build();
+        assert_eq!(result.err(), Some(EnvironmentError::InvalidRadius(-1.0)));
+        let result = EnvironmentBuilder::new().wavelength(0.0).build();
+        assert_eq!(result.err(), Some(EnvironmentError::InvalidWavelength(0.0)));
+        let result = EnvironmentBuilder::new()
+            .top_of_atmosphere(f64::INFINITY)
+            .build();
+        assert_eq!(
+            result.err(),
+            Some(EnvironmentError::InvalidTopOfAtmosphere(f64::INFINITY))
+        );
+        let result = EnvironmentBuilder::new()
+            .top_of_atmosphere(f64::NAN)
+            .build();
+        assert!(matches!(
+            result,
+            Err(EnvironmentError::InvalidTopOfAtmosphere(_))
+        ));
+        let env = EnvironmentBuilder::new()
+            .top_of_atmosphere(80e3)
+            .build()
+            .unwrap();
+        assert_eq!(env.top_of_atmosphere, Some(80e3));
+    }
+
+    #[test]
+    fn test_modified_refractivity() {
+        let env = Environment {
+            index_model: RefractiveIndexModel::Radio,
+            ..us76_env(530e-9)
+        };
+        let m0 = env.modified_refractivity(0.0);
+        let m1 = env.modified_refractivity(1e3);
+        let n0 = env.refractivity(0.0);
+        let n1 = env.refractivity(1e3);
+        assert!((m1 - m0 - (n1 - n0) - 156.8).abs() < 0.1);
+        // the standard atmosphere isn't ducting
+        assert!(env.dmodified_refractivity(0.0) > 0.0);
+    }
+
+    #[test]
+    fn test_vertical_magnification() {
+        let flat = Environment::with_k_factor(6_378_000.0, 1.0);
+        let angle = flat.apparent_elevation(10.0, 110.0, 1e3);
+        let magnification = flat.vertical_magnification(10.0, 110.0, 1e3);
+        assert!((magnification - angle.cos().powi(2) / 1e3).abs() < 1e-9);
+
+        // a constant refraction coefficient bends all the rays equally, so the image isn't
+        // stretched
+        let env = us76_env(530e-9);
+        let magnification = env.vertical_magnification(10.0, 100.0, 20e3);
+        assert!((magnification * 20e3 - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_refraction_coefficient() {
+        let env = us76_env(530e-9);
+        let k = env.refraction_coefficient(0.0, 0.0);
+        // the standard atmosphere gives k close to the commonly used 0.13-0.17
+        assert!(k > 0.13 && k < 0.2);
+        assert!(env.refraction_coefficient(0.0, 0.5) < k);
+
+        let k_env = Environment::with_k_factor(6_378_000.0, k);
+        assert_eq!(k_env.refraction_coefficient(0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_apparent_elevation() {
+        let env = us76_env(530e-9);
+        let apparent = env.apparent_elevation(10.0, 100.0, 20e3);
+        let geometric = env
+            .cast_ray_target(10.0, 100.0, 20e3, true)
+            .angle_at_dist(0.0);
+        // a target is seen higher than it really is in the standard atmosphere
+        assert!(apparent > geometric);
+
+        let h = env.true_target_from_apparent(10.0, apparent, 20e3);
+        assert!((h - 100.0).abs() < 1e-3);
+    }
 }
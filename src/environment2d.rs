@@ -0,0 +1,306 @@
+//! Environments in which the atmosphere changes with the distance from the observer, like at the
+//! transitions between land and sea
+
+use crate::air::Atmosphere;
+use crate::paths::Integration;
+use crate::{Environment, GroundIntersection, PathPoint, RayOptions, RayState, RayStateDerivative};
+
+/// An environment in which the atmosphere varies with the distance from the observer.
+///
+/// The atmosphere is given by columns - the atmospheres at some distances along the surface.
+/// Between the columns the refractive index is interpolated linearly in the distance, and beyond
+/// the outermost ones it's the same as in them.
+#[derive(Clone)]
+pub struct Environment2D {
+    env: Environment,
+    columns: Vec<(f64, Environment)>,
+}
+
+impl Environment2D {
+    /// Creates an environment with the shape of the surface, the wavelength and the refractive
+    /// index formula of `env`, and the atmosphere of `env` as the column at the observer.
+    pub fn new(env: Environment) -> Self {
+        Environment2D {
+            columns: vec![(0.0, env.clone())],
+            env,
+        }
+    }
+
+    /// Sets the atmosphere at the distance `dist` (in meters) from the observer, replacing the
+    /// column at that distance if there is one.
+    pub fn with_column(mut self, dist: f64, atmosphere: Atmosphere) -> Self {
+        let column = Environment {
+            atmosphere,
+            ..self.env.clone()
+        };
+        if dist == 0.0 {
+            self.env = column.clone();
+        }
+        match self.columns.binary_search_by(|(x, _)| x.total_cmp(&dist)) {
+            Ok(index) => self.columns[index].1 = column,
+            Err(index) => self.columns.insert(index, (dist, column)),
+        }
+        self
+    }
+
+    /// Returns the environment at the observer
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+
+    /// Returns the distances of the columns from the observer, in ascending order
+    pub fn column_distances(&self) -> Vec<f64> {
+        self.columns.iter().map(|(dist, _)| *dist).collect()
+    }
+
+    /// Returns the refractive index of the air at the given distance and altitude.
+    pub fn n(&self, dist: f64, h: f64) -> f64 {
+        self.n_dn_at_wavelength(dist, h, self.env.wavelength).0
+    }
+
+    /// Returns the refractive index of the air and its derivatives with respect to the altitude
+    /// and the distance, at the given distance and altitude, for the given wavelength.
+    pub fn n_dn_at_wavelength(&self, dist: f64, h: f64, wavelength: f64) -> (f64, f64, f64) {
+        let index = self.columns.partition_point(|(x, _)| *x <= dist);
+        if index == 0 || index == self.columns.len() {
+            let column = if index == 0 { 0 } else { index - 1 };
+            let (n, dn) = self.columns[column].1.n_dn_at_wavelength(h, wavelength);
+            return (n, dn, 0.0);
+        }
+        let (x0, env0) = &self.columns[index - 1];
+        let (x1, env1) = &self.columns[index];
+        let (n0, dn0) = env0.n_dn_at_wavelength(h, wavelength);
+        let (n1, dn1) = env1.n_dn_at_wavelength(h, wavelength);
+        let weight = (dist - x0) / (x1 - x0);
+        (
+            n0 + weight * (n1 - n0),
+            dn0 + weight * (dn1 - dn0),
+            (n1 - n0) / (x1 - x0),
+        )
+    }
+
+    /// Calculates the derivative of the state of a ray. The equation is the one for a surface
+    /// with a custom curvature, with an additional term for the horizontal gradient of the
+    /// refractive index. If `straight` is true, the refractive index is ignored.
+    pub(crate) fn calc_derivative(
+        &self,
+        state: &RayState,
+        wavelength: f64,
+        straight: bool,
+    ) -> RayStateDerivative {
+        let dh = state.dh;
+        let h = state.h;
+        let k = self.env.curvature_at(state.x);
+        let r_k = 1.0 + k * h;
+
+        let dn_n = if straight {
+            0.0
+        } else {
+            let (n, dn_dh, dn_dx) = self.n_dn_at_wavelength(state.x, h, wavelength);
+            (dn_dh - dn_dx * dh / (r_k * r_k)) / n
+        };
+
+        let d2h = dn_n * (dh * dh + r_k * r_k) + 2.0 * k * dh * dh / r_k + k * r_k;
+
+        RayStateDerivative { dx: 1.0, dh, d2h }
+    }
+
+    /// Returns a ray starting at the altitude `start_h` (in meters) at the initial angle
+    /// `start_ang` (in radians)
+    pub fn cast_ray(&self, start_h: f64, start_ang: f64) -> Ray2D<'_> {
+        self.cast_ray_with(start_h, start_ang, &Default::default())
+    }
+
+    /// Returns a ray like `cast_ray`, with the parameters of the path and its integration given
+    /// by `options`
+    pub fn cast_ray_with(&self, start_h: f64, start_ang: f64, options: &RayOptions) -> Ray2D<'_> {
+        let curvature = self.env.curvature_at(0.0);
+        Ray2D {
+            env: self,
+            start_h,
+            start_dh: start_ang.tan() * (1.0 + curvature * start_h),
+            wavelength: options.wavelength.unwrap_or(self.env.wavelength),
+            integration: Integration::from_options(options),
+            straight: options.straight,
+        }
+    }
+}
+
+/// A light path in an environment in which the atmosphere varies with the distance
+#[derive(Clone)]
+pub struct Ray2D<'a> {
+    env: &'a Environment2D,
+    start_h: f64,
+    start_dh: f64,
+    wavelength: f64,
+    integration: Integration,
+    straight: bool,
+}
+
+impl Ray2D<'_> {
+    fn initial_state(&self) -> RayState {
+        RayState {
+            x: 0.0,
+            h: self.start_h,
+            dh: self.start_dh,
+        }
+    }
+
+    fn derivative(&self, state: &RayState) -> RayStateDerivative {
+        self.env
+            .calc_derivative(state, self.wavelength, self.straight)
+    }
+
+    /// Returns the starting altitude of the path, in meters
+    pub fn start_h(&self) -> f64 {
+        self.start_h
+    }
+
+    /// Returns the initial angle of the path, in radians
+    pub fn start_angle(&self) -> f64 {
+        self.initial_state().get_angle(&self.env.env)
+    }
+
+    /// Returns the wavelength of the light, in meters
+    pub fn wavelength(&self) -> f64 {
+        self.wavelength
+    }
+
+    /// Returns the altitude (in meters) at the given distance (in meters) from the initial point
+    pub fn h_at_dist(&self, dist: f64) -> f64 {
+        self.sample(&[dist])[0].h
+    }
+
+    /// Returns the angle (in radians) between the path and the horizontal plane at the given
+    /// distance (in meters) from the initial point
+    pub fn angle_at_dist(&self, dist: f64) -> f64 {
+        self.sample(&[dist])[0].get_angle(&self.env.env)
+    }
+
+    /// Returns the parameters of the path at the given distance (in meters) from the initial
+    /// point
+    pub fn point_at_dist(&self, dist: f64) -> PathPoint {
+        let state = self.sample(&[dist])[0];
+        PathPoint {
+            dist: state.x,
+            h: state.h,
+            angle: state.get_angle(&self.env.env),
+            n: self
+                .env
+                .n_dn_at_wavelength(state.x, state.h, self.wavelength)
+                .0,
+            dh: state.dh,
+        }
+    }
+
+    /// Returns the angle (in radians) by which the path has been bent between the initial point
+    /// and the given distance (in meters)
+    pub fn bending_at_dist(&self, dist: f64) -> f64 {
+        let point = self.point_at_dist(dist);
+        self.start_angle() - point.angle + self.env.env.surface_rotation(point.dist)
+    }
+
+    /// Returns the states of the path at the given distances (in meters) from the initial point,
+    /// which have to be sorted in ascending order
+    pub fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        self.integration
+            .sample(self.initial_state(), dists, |state| self.derivative(state))
+    }
+
+    /// Returns the distances (in meters) from the initial point, sorted in ascending order, at
+    /// which the path crosses the altitude `tgt_h` (in meters), searching between the distances
+    /// given by `search_range`
+    pub fn dist_at_h(&self, tgt_h: f64, (min_dist, max_dist): (f64, f64)) -> Vec<f64> {
+        let mut result = vec![];
+        for end in [min_dist.min(0.0), max_dist.max(0.0)] {
+            if end == 0.0 {
+                continue;
+            }
+            let crossings = self
+                .integration
+                .crossings(self.initial_state(), tgt_h, end, |state| {
+                    self.derivative(state)
+                });
+            result.extend(crossings.into_iter().map(|state| state.x));
+        }
+        result.retain(|dist| (min_dist..=max_dist).contains(dist));
+        result.sort_by(|a, b| a.total_cmp(b));
+        result.dedup();
+        result
+    }
+
+    /// Returns the point at which the path descends below the altitude `ground_h` (in meters),
+    /// if it does so within the distance `max_dist` (in meters) from the initial point
+    pub fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        self.integration
+            .descent_below(self.initial_state(), ground_h, max_dist, |state| {
+                self.derivative(state)
+            })
+            .map(|state| GroundIntersection {
+                dist: state.x,
+                angle: state.get_angle(&self.env.env),
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::{us76_atmosphere, AtmosphereDef};
+    use crate::test_support::us76_atmosphere_env;
+    use crate::Path;
+
+    #[test]
+    fn test_uniform_columns() {
+        let env = us76_atmosphere_env();
+        let env2d = Environment2D::new(env.clone()).with_column(20e3, us76_atmosphere());
+        let ray = env.cast_ray(10.0, 0.001, false);
+        let ray2d = env2d.cast_ray(10.0, 0.001);
+        for dist in [1e3, 10e3, 30e3] {
+            assert!((ray.h_at_dist(dist) - ray2d.h_at_dist(dist)).abs() < 1e-6);
+            assert!((ray.angle_at_dist(dist) - ray2d.angle_at_dist(dist)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let mirage = Atmosphere::try_from_def(AtmosphereDef::inferior_mirage(10.0)).unwrap();
+        let env = us76_atmosphere_env();
+        let env2d = Environment2D::new(env.clone()).with_column(1e3, mirage.clone());
+        assert_eq!(env2d.column_distances(), vec![0.0, 1e3]);
+        let mirage_env = Environment {
+            atmosphere: mirage,
+            ..env.clone()
+        };
+        let expected = 0.75 * env.n(1.0) + 0.25 * mirage_env.n(1.0);
+        assert!((env2d.n(250.0, 1.0) - expected).abs() < 1e-12);
+        assert_eq!(env2d.n(-100.0, 1.0), env.n(1.0));
+        assert_eq!(env2d.n(5e3, 1.0), mirage_env.n(1.0));
+
+        let epsilon = 0.01;
+        let (_, dn_dh, dn_dx) = env2d.n_dn_at_wavelength(400.0, 1.0, env.wavelength);
+        let numerical_x =
+            (env2d.n(400.0 + epsilon, 1.0) - env2d.n(400.0 - epsilon, 1.0)) / 2.0 / epsilon;
+        let numerical_h =
+            (env2d.n(400.0, 1.0 + epsilon) - env2d.n(400.0, 1.0 - epsilon)) / 2.0 / epsilon;
+        assert!((dn_dx - numerical_x).abs() < 1e-12);
+        assert!((dn_dh - numerical_h).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_land_sea_transition() {
+        // the air over the heated land starts 4 km away from the observer
+        let mirage = Atmosphere::try_from_def(AtmosphereDef::inferior_mirage(10.0)).unwrap();
+        let env = us76_atmosphere_env();
+        let env2d = Environment2D::new(env.clone())
+            .with_column(4e3, us76_atmosphere())
+            .with_column(4.5e3, mirage);
+        let ray = env.cast_ray(10.0, -0.002, false);
+        let ray2d = env2d.cast_ray(10.0, -0.002);
+        assert!((ray.h_at_dist(3e3) - ray2d.h_at_dist(3e3)).abs() < 1e-6);
+        // the ray is bent upwards over the hot surface instead of hitting the ground
+        assert!(ray.ground_intersection(0.0, 20e3).is_some());
+        assert!(ray2d.ground_intersection(0.0, 20e3).is_none());
+        assert!(ray2d.angle_at_dist(10e3) > 0.0);
+    }
+}
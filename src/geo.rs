@@ -0,0 +1,131 @@
+//! Placing paths on the globe
+
+use crate::Path;
+
+/// The starting point and the direction of a path on a spherical Earth.
+///
+/// Angles are in radians; the azimuth is measured clockwise from the north. The distances along
+/// paths are interpreted as distances measured along the surface of a sphere with the given
+/// radius, which is how the spherical paths measure them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct Sightline {
+    /// The latitude of the observer
+    pub latitude: f64,
+    /// The longitude of the observer
+    pub longitude: f64,
+    /// The azimuth in which the path is directed
+    pub azimuth: f64,
+    /// The radius of the Earth in meters
+    pub radius: f64,
+}
+
+/// A point given by its geographic coordinates
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct GeoPoint {
+    /// The latitude in radians
+    pub latitude: f64,
+    /// The longitude in radians, in the range [-π, π]
+    pub longitude: f64,
+    /// The altitude above the surface in meters
+    pub altitude: f64,
+}
+
+impl Sightline {
+    /// Returns the coordinates of the point at the altitude `h`, `dist` meters away from the
+    /// observer along the sightline.
+    pub fn geo_point(&self, dist: f64, h: f64) -> GeoPoint {
+        let sigma = dist / self.radius;
+        let (sin_lat, cos_lat) = self.latitude.sin_cos();
+        let (sin_sigma, cos_sigma) = sigma.sin_cos();
+        let (sin_az, cos_az) = self.azimuth.sin_cos();
+
+        let sin_lat2 = sin_lat * cos_sigma + cos_lat * sin_sigma * cos_az;
+        let latitude = sin_lat2.clamp(-1.0, 1.0).asin();
+        let dlon = (sin_az * sin_sigma * cos_lat).atan2(cos_sigma - sin_lat * sin_lat2);
+        let longitude = (self.longitude + dlon + std::f64::consts::PI)
+            .rem_euclid(2.0 * std::f64::consts::PI)
+            - std::f64::consts::PI;
+
+        GeoPoint {
+            latitude,
+            longitude,
+            altitude: h,
+        }
+    }
+
+    /// Returns the Earth-centered, Earth-fixed coordinates (x towards the zero meridian on the
+    /// equator, z towards the north pole, in meters) of the point at the altitude `h`, `dist`
+    /// meters away from the observer along the sightline.
+    pub fn ecef(&self, dist: f64, h: f64) -> [f64; 3] {
+        let point = self.geo_point(dist, h);
+        let r = self.radius + h;
+        let (sin_lat, cos_lat) = point.latitude.sin_cos();
+        let (sin_lon, cos_lon) = point.longitude.sin_cos();
+        [r * cos_lat * cos_lon, r * cos_lat * sin_lon, r * sin_lat]
+    }
+
+    /// Returns the coordinates of the points of the `path` at the given distances.
+    pub fn path_points<'a, P: Path<'a> + ?Sized>(&self, path: &P, dists: &[f64]) -> Vec<GeoPoint> {
+        path.sample(dists)
+            .iter()
+            .map(|state| self.geo_point(state.x, state.h))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Environment;
+    use std::f64::consts::FRAC_PI_2;
+
+    const RADIUS: f64 = 6_371_000.0;
+
+    #[test]
+    fn test_geo_point() {
+        let north = Sightline {
+            latitude: 0.5,
+            longitude: 0.2,
+            azimuth: 0.0,
+            radius: RADIUS,
+        };
+        let point = north.geo_point(100e3, 50.0);
+        assert!((point.latitude - (0.5 + 100e3 / RADIUS)).abs() < 1e-12);
+        assert!((point.longitude - 0.2).abs() < 1e-12);
+        assert_eq!(point.altitude, 50.0);
+
+        // a quarter of the circumference eastwards along the equator, crossing the antimeridian
+        let east = Sightline {
+            latitude: 0.0,
+            longitude: 3.0,
+            azimuth: FRAC_PI_2,
+            radius: RADIUS,
+        };
+        let point = east.geo_point(FRAC_PI_2 * RADIUS, 0.0);
+        assert!(point.latitude.abs() < 1e-12);
+        assert!((point.longitude - (3.0 + FRAC_PI_2 - 2.0 * std::f64::consts::PI)).abs() < 1e-12);
+
+        let [x, y, z] = east.ecef(0.0, 1000.0);
+        assert!(((x * x + y * y + z * z).sqrt() - (RADIUS + 1000.0)).abs() < 1e-6);
+        assert!(z.abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_path_points() {
+        let env = Environment::with_k_factor(RADIUS, 0.0);
+        let path = env.cast_ray(10.0, 0.0, false);
+        let sightline = Sightline {
+            latitude: 0.0,
+            longitude: 0.0,
+            azimuth: FRAC_PI_2,
+            radius: RADIUS,
+        };
+        let points = sightline.path_points(&path, &[0.0, 10e3]);
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].altitude, 10.0);
+        assert!((points[1].longitude - 10e3 / RADIUS).abs() < 1e-12);
+        assert!((points[1].altitude - path.h_at_dist(10e3)).abs() < 1e-6);
+    }
+}
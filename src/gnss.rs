@@ -0,0 +1,144 @@
+//! Tropospheric delays of the signals of the global navigation satellite systems (GNSS)
+
+use crate::air::Atmosphere;
+use crate::{Environment, RayState};
+use std::f64::consts::FRAC_PI_2;
+
+/// The coefficient of the pressure in Saastamoinen's hydrostatic delay, in m/hPa
+const HYDROSTATIC_COEFFICIENT: f64 = 0.002_276_8;
+/// The coefficient of the vapor pressure in Saastamoinen's wet delay, in m/hPa
+const WET_COEFFICIENT: f64 = 0.002_277;
+
+/// The delay of a signal reaching an observer from a satellite
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct SlantDelay {
+    /// The excess of the path length of the signal over the straight line to the satellite, in
+    /// meters
+    pub delay: f64,
+    /// The angle by which the signal is bent - the difference between the apparent elevation of
+    /// the satellite and the geometric one, in radians
+    pub bending: f64,
+}
+
+impl Atmosphere {
+    /// Returns the zenith hydrostatic delay (in meters) according to Saastamoinen's model, for
+    /// an observer at the altitude `h` (in meters) and the latitude `latitude` (in radians).
+    pub fn zenith_hydrostatic_delay(&self, h: f64, latitude: f64) -> f64 {
+        let gravity_factor = 1.0 - 0.00266 * (2.0 * latitude).cos() - 0.28e-6 * h;
+        HYDROSTATIC_COEFFICIENT * self.pressure(h) / 100.0 / gravity_factor
+    }
+
+    /// Returns the zenith wet delay (in meters) according to Saastamoinen's model, for an
+    /// observer at the altitude `h` (in meters).
+    pub fn zenith_wet_delay(&self, h: f64) -> f64 {
+        WET_COEFFICIENT * (1255.0 / self.temperature(h) + 0.05) * self.vapor_pressure(h) / 100.0
+    }
+}
+
+impl Environment {
+    /// Returns the delay and the bending of the signal from a satellite at the geometric
+    /// elevation `elevation` (in radians), received by an observer at the altitude `observer_h`
+    /// (in meters). The satellite is assumed to be far above the atmosphere.
+    ///
+    /// The delay is calculated by integrating the refractive index along the refracted ray, so
+    /// the environment should use the radio refractive index model for the GNSS signals. Returns
+    /// `None` if the satellite isn't visible.
+    pub fn slant_delay(&self, elevation: f64, observer_h: f64) -> Option<SlantDelay> {
+        let top = self
+            .top_of_atmosphere
+            .unwrap_or(crate::astronomy::DEFAULT_TOP_OF_ATMOSPHERE);
+        if elevation >= FRAC_PI_2 {
+            let delay = self.vertical_integral(observer_h, top, |h| self.n(h) - 1.0);
+            return Some(SlantDelay {
+                delay,
+                bending: 0.0,
+            });
+        }
+        let apparent = self.apparent_altitude(elevation, observer_h)?;
+        let n = |state: &RayState| self.n(state.h);
+        let (state, optical_length) =
+            self.trace_out_of_atmosphere(apparent, observer_h, Some(&n))?;
+        let bending = self.bending_out_of_atmosphere(apparent, &state);
+
+        // the projection of the segment between the observer and the point at which the ray
+        // leaves the atmosphere on the direction to the satellite
+        let exit_angle = state.get_angle(self);
+        let curvature = self.curvature_at(0.0);
+        let projection = if curvature == 0.0 {
+            state.x * exit_angle.cos() + (state.h - observer_h) * exit_angle.sin()
+        } else {
+            let radius = 1.0 / curvature;
+            let rotation = self.surface_rotation(state.x);
+            (radius + state.h) * exit_angle.sin()
+                - (radius + observer_h) * (exit_angle - rotation).sin()
+        };
+
+        Some(SlantDelay {
+            delay: optical_length - projection,
+            bending,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::us76_atmosphere;
+    use crate::test_support::us76_radio_env;
+    use crate::RefractiveIndexModel;
+
+    #[test]
+    fn test_zenith_delays() {
+        let atmosphere = us76_atmosphere();
+        // about 2.3 m at the sea level
+        let hydrostatic = atmosphere.zenith_hydrostatic_delay(0.0, 45f64.to_radians());
+        assert!((hydrostatic - 2.306).abs() < 0.005, "{}", hydrostatic);
+        assert!(atmosphere.zenith_hydrostatic_delay(2000.0, 0.0) < 0.8 * hydrostatic);
+        assert_eq!(atmosphere.zenith_wet_delay(0.0), 0.0);
+
+        // the integrated delay agrees with the model
+        let env = us76_radio_env();
+        let zenith = env.slant_delay(FRAC_PI_2, 0.0).unwrap();
+        assert!(
+            (zenith.delay - hydrostatic).abs() < 0.01,
+            "{}",
+            zenith.delay
+        );
+    }
+
+    #[test]
+    fn test_slant_delay() {
+        let env = us76_radio_env();
+        let zenith = env.slant_delay(FRAC_PI_2, 0.0).unwrap().delay;
+        // close to the zenith delay divided by the sine of the elevation high in the sky, and
+        // slightly less due to the curvature of the atmosphere
+        let slant = env.slant_delay(30f64.to_radians(), 0.0).unwrap();
+        let mapping = slant.delay / zenith;
+        assert!(mapping < 2.0 && mapping > 1.98, "{}", mapping);
+        assert!(slant.bending > 0.0);
+        let near_zenith = env.slant_delay(FRAC_PI_2 - 1e-3, 0.0).unwrap();
+        assert!((near_zenith.delay - zenith).abs() < 1e-3);
+
+        // about 20 m at 5 degrees, and in dry air the bending is close to the one of the visible
+        // light
+        let low = env.slant_delay(5f64.to_radians(), 0.0).unwrap();
+        assert!(low.delay > 20.0 && low.delay < 30.0, "{}", low.delay);
+        let optical = Environment {
+            index_model: RefractiveIndexModel::Optical,
+            ..us76_radio_env()
+        };
+        let refraction = optical.astronomical_refraction(5f64.to_radians(), 0.0);
+        let refraction = refraction.unwrap();
+        assert!((low.bending - refraction).abs() < 0.05 * refraction);
+
+        // no delay without the atmosphere
+        let vacuum = Environment {
+            index_model: RefractiveIndexModel::Vacuum,
+            ..us76_radio_env()
+        };
+        let delay = vacuum.slant_delay(0.3, 0.0).unwrap();
+        assert!(delay.delay.abs() < 1e-3, "{}", delay.delay);
+        assert!(delay.bending.abs() < 1e-12);
+    }
+}
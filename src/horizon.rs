@@ -0,0 +1,389 @@
+use crate::{
+    air::atmosphere::AtmosphereHints, Environment, Integration, IntegratorKind, Path, RayOptions,
+    RayState, STANDARD_K, WGS84_A,
+};
+use na::integration::{Integrator, StepSize};
+
+/// The default precision of the dip angle in radians
+const DIP_EPSILON: f64 = 1e-9;
+/// The steepest angle at which the rays are launched when looking for the horizon
+const MIN_ANGLE: f64 = -1.5;
+/// The highest angle at which the rays are launched when the horizon is above the horizontal
+const MAX_ANGLE: f64 = 0.5;
+/// The default height in meters above the observer at which rays are considered to escape
+const ESCAPE_HEIGHT: f64 = 1000.0;
+/// The height in meters above the ground, beyond the uncertainty caused by the tolerance, at
+/// which the horizon ray has to pass for the horizon to be considered the edge of a duct
+const GRAZING_EPSILON: f64 = 0.01;
+
+/// The parameters of the search for the horizon
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct HorizonOptions {
+    /// The altitude of the surface (e.g. the sea level) in meters
+    pub ground_altitude: f64,
+    /// The integration step in meters; if `None`, the square root of the observer's height above
+    /// the ground (but at least 1 m) is used, which keeps the number of steps roughly the same
+    /// for all observers
+    pub step: Option<f64>,
+    /// The integration method
+    pub integrator: IntegratorKind,
+    /// The precision of the dip in radians
+    pub tolerance: f64,
+    /// The distance in meters beyond which rays that haven't reached the ground are considered to
+    /// miss it; if `None`, ten times the distance to the geometric horizon is used
+    pub max_dist: Option<f64>,
+    /// The altitude in meters above which the rays are considered to escape to the sky; if
+    /// `None`, 1 km above the observer
+    pub escape_altitude: Option<f64>,
+    /// The wavelength of the light in meters; if `None`, the one set in the environment is used
+    pub wavelength: Option<f64>,
+}
+
+impl Default for HorizonOptions {
+    fn default() -> Self {
+        Self {
+            ground_altitude: 0.0,
+            step: None,
+            integrator: IntegratorKind::RungeKutta4,
+            tolerance: DIP_EPSILON,
+            max_dist: None,
+            escape_altitude: None,
+            wavelength: None,
+        }
+    }
+}
+
+/// The horizon seen by an observer
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct Horizon {
+    /// The angle (in radians) by which the horizon is seen below the horizontal; negative if it
+    /// is seen above it
+    pub dip: f64,
+    /// The distance (in meters, along the surface) to the point where the horizon ray grazes the
+    /// ground - or, for the edge of a duct, its lowest turning point or the point where it's
+    /// closest to the horizontal
+    pub dist: f64,
+    /// The altitude of the grazing point in meters
+    pub grazing_h: f64,
+    /// `true` if the horizon ray doesn't graze the ground, but the edge of a duct instead - the
+    /// rays launched just above it turn back up above the ground or escape through the top of
+    /// the duct
+    pub duct_edge: bool,
+}
+
+/// The dip of the horizon (in radians) according to different models of the refraction
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct HorizonDips {
+    /// The dip without any refraction
+    pub geometric: f64,
+    /// The dip with the standard refraction coefficient [`STANDARD_K`]
+    pub standard: f64,
+    /// The dip of the horizon traced in the environment, if it exists
+    pub actual: Option<f64>,
+}
+
+impl HorizonDips {
+    /// Returns the difference between the largest and the smallest of the dips.
+    pub fn spread(&self) -> f64 {
+        let dips = [Some(self.geometric), Some(self.standard), self.actual];
+        let max = dips
+            .iter()
+            .flatten()
+            .fold(f64::NEG_INFINITY, |a, &b| a.max(b));
+        let min = dips.iter().flatten().fold(f64::INFINITY, |a, &b| a.min(b));
+        max - min
+    }
+}
+
+/// The outcome of tracing a ray launched towards the ground
+enum Descent {
+    /// The ray reaches the ground
+    Hit,
+    /// The ray misses the ground; the state is its lowest turning point or, if it doesn't turn,
+    /// the point where it's closest to the horizontal
+    Miss(RayState),
+}
+
+/// How far the rays are traced when looking for the horizon
+#[derive(Clone, Copy, PartialEq)]
+enum Tracing {
+    /// Until the ray turns upwards for the first time, escapes or reaches the maximal distance
+    FirstTurn,
+    /// Until the ray escapes or reaches the maximal distance
+    Full,
+}
+
+impl Environment {
+    /// Finds the horizon seen by an observer at the altitude `observer_h` (in meters) above a
+    /// ground at zero altitude, using the default options.
+    ///
+    /// Returns `None` if the observer is not above the ground.
+    pub fn horizon(&self, observer_h: f64) -> Option<Horizon> {
+        self.horizon_with(observer_h, &HorizonOptions::default())
+    }
+
+    /// Finds the horizon seen by an observer at the altitude `observer_h` (in meters).
+    ///
+    /// The horizon ray is the boundary between the rays reaching the ground and those that turn
+    /// upwards before reaching it, escape above the escape altitude or pass the maximal
+    /// distance; the boundary is found by bisection of the initial angle, starting from the
+    /// horizontal. Returns `None` if the observer is not above the ground,
+    /// if even the steepest rays miss the ground, or if rays launched up to 0.5 rad above the
+    /// horizontal still reach it.
+    pub fn horizon_with(&self, observer_h: f64, options: &HorizonOptions) -> Option<Horizon> {
+        let height = observer_h - options.ground_altitude;
+        if height <= 0.0 {
+            return None;
+        }
+
+        let descent =
+            |ang| self.descend_to_ground(observer_h, ang, height, Tracing::FirstTurn, options);
+        if let Descent::Miss(_) = descent(MIN_ANGLE) {
+            return None;
+        }
+
+        let mut min_ang = MIN_ANGLE;
+        let mut max_ang = 0.0;
+        let mut step = 1e-3;
+        let miss = loop {
+            match descent(max_ang) {
+                Descent::Miss(state) => break state,
+                Descent::Hit if max_ang >= MAX_ANGLE => return None,
+                Descent::Hit => {
+                    min_ang = max_ang;
+                    max_ang = (max_ang + step).min(MAX_ANGLE);
+                    step *= 2.0;
+                }
+            }
+        };
+
+        Some(self.refine_horizon((min_ang, max_ang), miss, descent, options))
+    }
+
+    /// Finds all the horizons seen by an observer at the altitude `observer_h` (in meters) -
+    /// during ducting, the sea can be seen in several bands separated by the sky.
+    ///
+    /// `samples` initial angles spread evenly over `angle_range` (in radians) are checked for
+    /// whether the rays reach the ground, and the transitions are refined by bisection; the rays
+    /// are traced until they escape above `options.escape_altitude` or reach the maximal
+    /// distance. Every transition from the sky above to the ground below is returned as a
+    /// horizon, sorted from the highest one. Bands narrower than the sampling interval can be
+    /// missed.
+    pub fn horizons(
+        &self,
+        observer_h: f64,
+        angle_range: (f64, f64),
+        samples: usize,
+        options: &HorizonOptions,
+    ) -> Vec<Horizon> {
+        let height = observer_h - options.ground_altitude;
+        if height <= 0.0 || samples < 2 {
+            return vec![];
+        }
+
+        let descent = |ang| self.descend_to_ground(observer_h, ang, height, Tracing::Full, options);
+        let (min_ang, max_ang) = angle_range;
+        let angle = |i: usize| max_ang - (max_ang - min_ang) * i as f64 / (samples - 1) as f64;
+
+        let mut result = vec![];
+        let mut upper = (angle(0), descent(angle(0)));
+        for i in 1..samples {
+            let lower = (angle(i), descent(angle(i)));
+            if let (Descent::Miss(miss), Descent::Hit) = (&upper.1, &lower.1) {
+                result.push(self.refine_horizon((lower.0, upper.0), *miss, descent, options));
+            }
+            upper = lower;
+        }
+        result
+    }
+
+    /// Returns the dips of the horizon seen by an observer at the altitude `observer_h` (in
+    /// meters) without refraction, with the standard refraction coefficient and traced in the
+    /// environment, in order to show how much the dip depends on the model of the refraction.
+    ///
+    /// The first two are calculated from the effective radius of the Earth, based on the
+    /// curvature of the surface at the observer.
+    pub fn horizon_dips(&self, observer_h: f64, options: &HorizonOptions) -> HorizonDips {
+        let height = observer_h - options.ground_altitude;
+        let curvature = self.curvature_at(0.0);
+        let dip = |k: f64| (1.0 / (1.0 + curvature * (1.0 - k) * height)).acos();
+        HorizonDips {
+            geometric: dip(0.0),
+            standard: dip(STANDARD_K),
+            actual: self
+                .horizon_with(observer_h, options)
+                .map(|horizon| horizon.dip),
+        }
+    }
+
+    /// Narrows down the boundary between the ray launched at `min_ang`, which hits the ground,
+    /// and the one launched at `max_ang`, which misses it with the lowest point `miss`
+    fn refine_horizon<F: Fn(f64) -> Descent>(
+        &self,
+        (mut min_ang, mut max_ang): (f64, f64),
+        mut miss: RayState,
+        descent: F,
+        options: &HorizonOptions,
+    ) -> Horizon {
+        while max_ang - min_ang > options.tolerance {
+            let ang = 0.5 * (min_ang + max_ang);
+            match descent(ang) {
+                Descent::Hit => min_ang = ang,
+                Descent::Miss(state) => {
+                    max_ang = ang;
+                    miss = state;
+                }
+            }
+        }
+
+        let grazing_epsilon = GRAZING_EPSILON + options.tolerance * miss.x;
+        Horizon {
+            dip: -max_ang,
+            dist: miss.x,
+            grazing_h: miss.h,
+            duct_edge: miss.h - options.ground_altitude > grazing_epsilon,
+        }
+    }
+
+    /// Traces a ray launched at the angle `ang` from the altitude `observer_h`, `height` meters
+    /// above the ground, until it reaches the ground or stops being traced
+    fn descend_to_ground(
+        &self,
+        observer_h: f64,
+        ang: f64,
+        height: f64,
+        tracing: Tracing,
+        options: &HorizonOptions,
+    ) -> Descent {
+        let radius = self.radius().unwrap_or(WGS84_A);
+        let step = options.step.unwrap_or_else(|| height.sqrt().max(1.0));
+        let max_dist = options
+            .max_dist
+            .unwrap_or_else(|| 10.0 * (2.0 * radius * height + height * height).sqrt());
+        let escape_h = options
+            .escape_altitude
+            .unwrap_or(observer_h + ESCAPE_HEIGHT);
+        let ray_options = RayOptions {
+            integrator: options.integrator,
+            wavelength: options.wavelength,
+            ..Default::default()
+        };
+
+        let path = self.cast_ray_with(observer_h, ang, &ray_options);
+        let wavelength = path.wavelength();
+        let hints = AtmosphereHints::default();
+        let diff_eq = |state: &RayState| self.calc_derivative(state, wavelength, &hints);
+        let mut integrator = Integration::from_options(&ray_options).integrator(step);
+        let mut state = path.sample(&[0.0])[0];
+        let mut lowest_turn: Option<RayState> = None;
+        let mut flattest = state;
+        while state.x < max_dist && state.h <= escape_h {
+            let step = step.min(max_dist - state.x);
+            let prev_state = state;
+            integrator.propagate_in_place(&mut state, diff_eq, StepSize::Step(step));
+            if state.h < options.ground_altitude {
+                return Descent::Hit;
+            }
+            if state.dh.abs() < flattest.dh.abs() {
+                flattest = state;
+            }
+            if (prev_state.dh < 0.0) != (state.dh < 0.0) {
+                // the turning point, assuming that the slope changes linearly within the step
+                let t = step * prev_state.dh / (prev_state.dh - state.dh);
+                let turn = RayState {
+                    x: prev_state.x + t,
+                    h: prev_state.h
+                        + prev_state.dh * t
+                        + 0.5 * (state.dh - prev_state.dh) * t * t / step,
+                    dh: 0.0,
+                };
+                if lowest_turn.is_none_or(|lowest| turn.h < lowest.h) {
+                    lowest_turn = Some(turn);
+                }
+                if tracing == Tracing::FirstTurn && state.dh >= 0.0 {
+                    break;
+                }
+            }
+        }
+        Descent::Miss(lowest_turn.unwrap_or(flattest))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::{inversion_env, us76_env_with_radius};
+
+    const RADIUS: f64 = 6_378_000.0;
+
+    #[test]
+    fn test_geometric_horizon() {
+        let env = Environment::with_k_factor(RADIUS, 0.0);
+        for &h in &[2.0, 100.0, 400e3] {
+            let horizon = env.horizon(h).unwrap();
+            let expected_dip = (RADIUS / (RADIUS + h)).acos();
+            assert!((horizon.dip - expected_dip).abs() < 1e-8);
+            assert!((horizon.dist - RADIUS * expected_dip).abs() < 1e-3 * RADIUS * expected_dip);
+            assert!(horizon.grazing_h.abs() < 1e-3 * h);
+        }
+        assert!(env.horizon(0.0).is_none());
+    }
+
+    #[test]
+    fn test_horizon_dips() {
+        let options = HorizonOptions::default();
+        let standard = Environment::with_k_factor(RADIUS, STANDARD_K);
+        let dips = standard.horizon_dips(10.0, &options);
+        // the environment already includes the effective radius
+        assert!((dips.actual.unwrap() - dips.geometric).abs() < 1e-8);
+        assert!(dips.standard < dips.geometric);
+
+        let env = us76_env_with_radius(RADIUS);
+        let dips = env.horizon_dips(10.0, &options);
+        let geometric = (RADIUS / (RADIUS + 10.0)).acos();
+        assert!((dips.geometric - geometric).abs() < 1e-12);
+        // the standard atmosphere refracts a bit more than the standard coefficient assumes
+        let actual = dips.actual.unwrap();
+        assert!(actual < dips.standard);
+        assert!((dips.spread() - (geometric - actual)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_refracted_horizon() {
+        let env = us76_env_with_radius(RADIUS);
+        let geometric = Environment::with_k_factor(RADIUS, 0.0);
+        for &h in &[10.0, 400e3] {
+            let horizon = env.horizon(h).unwrap();
+            let geometric = geometric.horizon(h).unwrap();
+            // the refraction lifts the horizon and pushes it farther away
+            assert!(horizon.dip < geometric.dip);
+            assert!(horizon.dist > geometric.dist);
+            assert!(horizon.grazing_h.abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_multiple_horizons() {
+        // an inversion between 10 and 30 m bends the rays launched upwards back to the sea
+        let env = inversion_env();
+        let options = Default::default();
+        let horizons = env.horizons(10.0, (-0.004, 0.003), 15, &options);
+        assert_eq!(horizons.len(), 2);
+        // the false horizon above the horizontal is the top of the inversion
+        assert!(horizons[0].duct_edge);
+        assert!((horizons[0].dip + 0.002526).abs() < 1e-5);
+        assert!((horizons[0].grazing_h - 30.0).abs() < 0.01);
+        // the true sea horizon is where the rays graze the sea
+        assert!(!horizons[1].duct_edge);
+        assert!((horizons[1].dip - 0.001614).abs() < 1e-5);
+
+        // an observer above the inversion sees its top as the horizon
+        let horizon = env.horizon(50.0).unwrap();
+        assert!(horizon.duct_edge);
+        assert!((horizon.grazing_h - 30.0).abs() < 0.01);
+        assert_eq!(env.horizons(50.0, (-0.004, 0.006), 11, &options).len(), 1);
+    }
+}
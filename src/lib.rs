@@ -11,10 +11,46 @@ extern crate serde_derive;
 
 /// Module containing tools for defining non-standard atmospheric models.
 pub mod air;
+mod astronomy;
+#[cfg(feature = "dem")]
+mod dem;
+mod ducts;
 mod environment;
+mod environment2d;
+mod geo;
+mod gnss;
+mod horizon;
+mod occultation;
 mod paths;
 mod ray_state;
+mod ray_table;
+mod sampling;
+mod surveying;
+mod target;
+mod terrain;
+#[cfg(test)]
+mod test_support;
+mod transfer;
+mod turbulence;
+mod viewshed;
 
+pub use crate::astronomy::*;
+#[cfg(feature = "dem")]
+pub use crate::dem::*;
+pub use crate::ducts::*;
 pub use crate::environment::*;
+pub use crate::environment2d::*;
+pub use crate::geo::*;
+pub use crate::gnss::*;
+pub use crate::horizon::*;
+pub use crate::occultation::*;
 pub use crate::paths::*;
 pub use crate::ray_state::*;
+pub use crate::ray_table::*;
+pub use crate::sampling::*;
+pub use crate::surveying::*;
+pub use crate::target::*;
+pub use crate::terrain::*;
+pub use crate::transfer::*;
+pub use crate::turbulence::*;
+pub use crate::viewshed::*;
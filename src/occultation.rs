@@ -0,0 +1,73 @@
+//! Bending of rays passing through the atmosphere, as observed in radio occultations
+
+use crate::Environment;
+
+/// The bending of a ray passing through the atmosphere with its lowest point at some altitude
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct OccultationPoint {
+    /// The altitude of the lowest point of the ray (the tangent point), in meters
+    pub tangent_altitude: f64,
+    /// The impact parameter - the product of the refractive index and the distance from the
+    /// center of the planet at the tangent point, in meters
+    pub impact_parameter: f64,
+    /// The total bending of the ray, in radians
+    pub bending: f64,
+}
+
+impl Environment {
+    /// Calculates the bending angles of rays passing horizontally through the given tangent
+    /// altitudes (in meters) - the profile measured in radio occultations.
+    ///
+    /// The ray is symmetric around the tangent point, so it is traced from it to the top of the
+    /// atmosphere only. Returns `None` if the surface isn't spherical. The rays that are trapped
+    /// in ducts are skipped.
+    pub fn occultation_profile(&self, tangent_altitudes: &[f64]) -> Option<Vec<OccultationPoint>> {
+        let radius = self.radius()?;
+        let profile = tangent_altitudes
+            .iter()
+            .filter_map(|&tangent_altitude| {
+                let (state, _) = self.trace_out_of_atmosphere(0.0, tangent_altitude, None)?;
+                Some(OccultationPoint {
+                    tangent_altitude,
+                    impact_parameter: self.n(tangent_altitude) * (radius + tangent_altitude),
+                    bending: 2.0 * self.bending_out_of_atmosphere(0.0, &state),
+                })
+            })
+            .collect();
+        Some(profile)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::us76_radio_env;
+
+    use crate::EarthShape;
+
+    #[test]
+    fn test_occultation_profile() {
+        let env = us76_radio_env();
+        let altitudes = [0.0, 5e3, 10e3, 20e3, 40e3];
+        let profile = env.occultation_profile(&altitudes).unwrap();
+        assert_eq!(profile.len(), altitudes.len());
+
+        // about 1.1° at the surface, twice the astronomical refraction at the horizon
+        let refraction = env.astronomical_refraction(0.0, 0.0).unwrap();
+        assert!((profile[0].bending - 2.0 * refraction).abs() < 1e-9);
+        assert!(profile[0].bending > 0.015 && profile[0].bending < 0.025);
+        // the bending decreases roughly exponentially with the altitude
+        for pair in profile.windows(2) {
+            assert!(pair[1].bending < 0.65 * pair[0].bending);
+            assert!(pair[1].impact_parameter > pair[0].impact_parameter);
+        }
+        assert!((profile[2].impact_parameter - env.n(10e3) * 6_381_000.0).abs() < 1e-6);
+
+        let flat = Environment {
+            shape: EarthShape::Flat,
+            ..env
+        };
+        assert_eq!(flat.occultation_profile(&altitudes), None);
+    }
+}
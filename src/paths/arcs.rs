@@ -0,0 +1,390 @@
+//! Rays approximated by circular arcs in layers with a constant gradient of the refractive index
+
+use super::{
+    arc_length, bending, deviation, next_step, optical_length, sort_and_filter, GroundIntersection,
+    Integration, Motion, Path, PathDeviation, PathPoint, PathStep, PathStepper, RayOptions,
+    StepperCore, Stepping,
+};
+use crate::{EnvironmentRef, RayState};
+
+/// A ray approximated by circular arcs.
+///
+/// The atmosphere is divided into layers `RayOptions::step` meters thick, within which the
+/// refractive index changes linearly between its values at the boundaries of the layer. A nearly
+/// horizontal ray is a circular arc in such a layer, so it can be followed from layer to layer in
+/// closed form instead of integrating the ray equation - which is much faster, but only
+/// approximate.
+///
+/// Over a spherical surface, the arcs are traced in the coordinates of the Earth-flattening
+/// transformation, in which the surface is flat, the altitude is replaced by R·ln(r/R) and the
+/// refractive index by n·r/R. Other surfaces aren't supported.
+#[derive(Clone)]
+pub struct ArcRay<'a> {
+    env: EnvironmentRef<'a>,
+    start_h: f64,
+    start_ang: f64,
+    wavelength: f64,
+    integration: Integration,
+}
+
+/// A part of an arc ray within a single layer, along which the altitude changes monotonically
+struct Arc {
+    /// The distance, the flattened altitude and the angle at the beginning
+    x: f64,
+    y: f64,
+    ang: f64,
+    /// The curvature of the arc, positive if it bends downwards
+    curvature: f64,
+    end_x: f64,
+    end_y: f64,
+}
+
+impl Arc {
+    /// Returns the flattened altitude and the angle at the distance `dx` from the beginning
+    fn at(&self, dx: f64) -> (f64, f64) {
+        let ang = (self.ang.sin() - self.curvature * dx)
+            .clamp(-1.0, 1.0)
+            .asin();
+        (self.y + dx * (0.5 * (self.ang + ang)).tan(), ang)
+    }
+
+    /// Returns the distance at which the arc crosses the flattened altitude `y`, if it does
+    fn crossing(&self, y: f64) -> Option<f64> {
+        if (y - self.y) * (y - self.end_y) > 0.0 {
+            return None;
+        }
+        if y == self.y {
+            return Some(self.x);
+        }
+        let ang = end_angle(self.ang, self.curvature, y - self.y, self.end_y > self.y)?;
+        Some(self.x + (y - self.y) / (0.5 * (self.ang + ang)).tan())
+    }
+}
+
+/// Returns the angle of an arc with the curvature `curvature` after its flattened altitude has
+/// changed by `dy`, if it gets that far before turning back
+fn end_angle(ang: f64, curvature: f64, dy: f64, up: bool) -> Option<f64> {
+    // cos(end_ang) = cos(ang) + curvature * dy, in a form that keeps the precision for nearly
+    // horizontal rays
+    let half = (0.5 * ang).sin();
+    let sin2 = half * half - 0.5 * curvature * dy;
+    (sin2 >= 0.0).then(|| {
+        let end_ang = 2.0 * sin2.sqrt().min(1.0).asin();
+        if up {
+            end_ang
+        } else {
+            -end_ang
+        }
+    })
+}
+
+impl ArcRay<'_> {
+    pub fn from_h_ang<'a>(env: impl Into<EnvironmentRef<'a>>, h: f64, ang: f64) -> ArcRay<'a> {
+        let env = env.into();
+        ArcRay {
+            start_h: h,
+            start_ang: ang,
+            wavelength: env.wavelength,
+            integration: Integration::default(),
+            env,
+        }
+    }
+
+    /// Applies the wavelength, the thickness of the layers and the limits of the path from the
+    /// options.
+    pub fn with_options(mut self, options: &RayOptions) -> Self {
+        if let Some(wavelength) = options.wavelength {
+            self.wavelength = wavelength;
+        }
+        self.integration = Integration::from_options(options);
+        self
+    }
+
+    /// Converts the altitude to the flattened one
+    fn flat_y(&self, h: f64) -> f64 {
+        match self.env.radius() {
+            Some(radius) => radius * (h / radius).ln_1p(),
+            None => h,
+        }
+    }
+
+    /// Converts the flattened altitude to the actual one
+    fn actual_h(&self, y: f64) -> f64 {
+        match self.env.radius() {
+            Some(radius) => radius * (y / radius).exp_m1(),
+            None => y,
+        }
+    }
+
+    /// Returns the flattened refractive index at the altitude `h`
+    fn flat_n(&self, h: f64) -> f64 {
+        let n = self.env.n_dn_at_wavelength(h, self.wavelength).0;
+        match self.env.radius() {
+            Some(radius) => n * (1.0 + h / radius),
+            None => n,
+        }
+    }
+
+    /// Follows the ray starting at the angle `ang` from the initial point, passing its arcs to
+    /// `visit` until it returns false. If `limited` is true, the ray ends at the maximum and
+    /// ground altitudes.
+    fn follow<F: FnMut(&Arc) -> bool>(&self, ang: f64, limited: bool, mut visit: F) {
+        let thickness = self.integration.step;
+        let h = self.start_h;
+        let (mut x, mut y, mut ang) = (0.0, self.flat_y(h), ang);
+        let mut layer = (h / thickness).floor();
+        if layer * thickness == h && ang < 0.0 {
+            layer -= 1.0;
+        }
+        let limits = if limited {
+            (
+                self.integration.ground_altitude.map(|h| self.flat_y(h)),
+                self.integration.max_altitude.map(|h| self.flat_y(h)),
+            )
+        } else {
+            (None, None)
+        };
+        let mut empty_arcs = 0;
+        loop {
+            let (bottom, top) = (layer * thickness, (layer + 1.0) * thickness);
+            let (bottom_y, top_y) = (self.flat_y(bottom), self.flat_y(top));
+            let (bottom_n, top_n) = (self.flat_n(bottom), self.flat_n(top));
+            let curvature = -(top_n - bottom_n) / (top_y - bottom_y) / (0.5 * (top_n + bottom_n));
+            let up = ang > 0.0 || (ang == 0.0 && curvature < 0.0);
+            let stays = ang == 0.0 && (curvature == 0.0 || empty_arcs > 2);
+            let (end_y, end_ang) = if stays {
+                // the ray stays at a constant altitude
+                (y, 0.0)
+            } else {
+                let end_y = if up { top_y } else { bottom_y };
+                match end_angle(ang, curvature, end_y - y, up) {
+                    Some(end_ang) => {
+                        layer += if up { 1.0 } else { -1.0 };
+                        (end_y, end_ang)
+                    }
+                    // the ray turns back within the layer
+                    None => {
+                        let half = (0.5 * ang).sin();
+                        (y + 2.0 * half * half / curvature, 0.0)
+                    }
+                }
+            };
+            let end_x = if stays {
+                f64::INFINITY
+            } else if end_y == y {
+                x
+            } else {
+                x + (end_y - y) / (0.5 * (ang + end_ang)).tan()
+            };
+            empty_arcs = if end_x == x { empty_arcs + 1 } else { 0 };
+            let mut arc = Arc {
+                x,
+                y,
+                ang,
+                curvature,
+                end_x,
+                end_y,
+            };
+
+            let limit = match limits {
+                (Some(ground_y), _) if !up && end_y < ground_y => Some(ground_y),
+                (_, Some(max_y)) if up && end_y > max_y => Some(max_y),
+                _ => None,
+            };
+            if let Some(limit_x) = limit.and_then(|limit_y| arc.crossing(limit_y)) {
+                arc.end_x = limit_x;
+                arc.end_y = limit.unwrap();
+                visit(&arc);
+                return;
+            }
+            if !visit(&arc) || arc.end_x == f64::INFINITY {
+                return;
+            }
+            x = end_x;
+            y = end_y;
+            ang = end_ang;
+        }
+    }
+
+    fn state_at_dist(&self, dist: f64, limited: bool) -> RayState {
+        let (ang, x, sign) = if dist < 0.0 {
+            // the part of the ray before the initial point is the mirror image of a ray going in
+            // the opposite direction
+            (-self.start_ang, -dist, -1.0)
+        } else {
+            (self.start_ang, dist, 1.0)
+        };
+        let mut result = None;
+        self.follow(ang, limited, |arc| {
+            if x <= arc.end_x {
+                result = Some(arc.at(x - arc.x));
+                false
+            } else {
+                true
+            }
+        });
+        let (y, ang) = match result {
+            Some(result) => result,
+            None => {
+                return RayState {
+                    x: dist,
+                    h: f64::NAN,
+                    dh: f64::NAN,
+                }
+            }
+        };
+        let h = self.actual_h(y);
+        let dh = match self.env.radius() {
+            Some(radius) => ang.tan() * (radius + h) / radius,
+            None => ang.tan(),
+        };
+        RayState {
+            x: dist,
+            h,
+            dh: sign * dh,
+        }
+    }
+
+    /// Returns the distances from the initial point, in the direction given by the initial angle
+    /// `ang`, at which the ray crosses the altitude `h`, including at least all those below `max_x`
+    fn crossings(&self, ang: f64, h: f64, max_x: f64) -> Vec<f64> {
+        let y = self.flat_y(h);
+        let mut result = vec![];
+        self.follow(ang, true, |arc| {
+            if arc.x > max_x {
+                return false;
+            }
+            result.extend(arc.crossing(y));
+            true
+        });
+        result
+    }
+}
+
+impl<'a> Path<'a> for ArcRay<'a> {
+    fn start_h(&self) -> f64 {
+        self.start_h
+    }
+
+    fn start_angle(&self) -> f64 {
+        self.start_ang
+    }
+
+    fn wavelength(&self) -> f64 {
+        self.wavelength
+    }
+
+    fn h_at_dist(&self, dist: f64) -> f64 {
+        self.state_at_dist(dist, true).h
+    }
+
+    fn angle_at_dist(&self, dist: f64) -> f64 {
+        self.state_at_dist(dist, true).get_angle(&self.env)
+    }
+
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        PathPoint::from_state(&self.state_at_dist(dist, true), &self.env, self.wavelength)
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        bending(&self.env, self.start_angle(), &self.point_at_dist(dist))
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        deviation(self, &self.env, dist)
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        arc_length(self, &self.env, dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        optical_length(self, &self.env, self.wavelength, dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        dists
+            .iter()
+            .map(|&dist| self.state_at_dist(dist, true))
+            .collect()
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, (min_dist, max_dist): (f64, f64)) -> Vec<f64> {
+        let mut result = self.crossings(self.start_ang, tgt_h, max_dist);
+        result.extend(
+            self.crossings(-self.start_ang, tgt_h, -min_dist)
+                .into_iter()
+                .map(|x| -x),
+        );
+        sort_and_filter(result, (min_dist, max_dist))
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        let dist = if self.start_h < ground_h {
+            0.0
+        } else {
+            self.crossings(self.start_ang, ground_h, max_dist)
+                .into_iter()
+                .filter(|&dist| dist <= max_dist)
+                .min_by(|a, b| a.total_cmp(b))?
+        };
+        Some(GroundIntersection {
+            dist,
+            angle: self.angle_at_dist(dist),
+        })
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        Box::new(ArcRayStepper::new(self, 1.0))
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.clone().into_path_stepper()
+    }
+}
+
+/// A stepper along a ray approximated by circular arcs
+pub struct ArcRayStepper<'a> {
+    ray: ArcRay<'a>,
+    core: StepperCore<'a>,
+}
+
+impl<'a> ArcRayStepper<'a> {
+    fn new(ray: ArcRay<'a>, step: f64) -> Self {
+        let state = ray.state_at_dist(0.0, false);
+        Self {
+            core: StepperCore::new(ray.env.clone(), ray.wavelength, state, step),
+            ray,
+        }
+    }
+}
+
+impl Motion for ArcRay<'_> {
+    fn propagate(&mut self, state: &RayState, len: f64) -> RayState {
+        self.state_at_dist(state.x + len, false)
+    }
+
+    fn is_outside(&self, state: &RayState) -> bool {
+        self.integration.is_outside(state)
+    }
+
+    fn ground_h(&self) -> f64 {
+        self.integration.ground_altitude.unwrap_or(0.0)
+    }
+}
+
+impl<'a> Stepping<'a> for ArcRayStepper<'a> {
+    type Motion = ArcRay<'a>;
+
+    fn parts(&mut self) -> (&mut StepperCore<'a>, &mut ArcRay<'a>) {
+        (&mut self.core, &mut self.ray)
+    }
+}
+
+impl Iterator for ArcRayStepper<'_> {
+    type Item = PathStep;
+
+    fn next(&mut self) -> Option<PathStep> {
+        next_step(self)
+    }
+}
@@ -0,0 +1,162 @@
+//! A wrapper memoizing the integration of a path
+
+use super::{
+    arc_length, bending, deviation, optical_length, GroundIntersection, Path, PathDeviation,
+    PathPoint, PathStep, PathStepper,
+};
+use crate::{EnvironmentRef, RayState};
+use std::sync::Mutex;
+
+/// A path that remembers the states it has been integrated through.
+///
+/// The path is stepped once with the given step and the states after every step are stored, so
+/// that queries at non-negative distances only have to integrate beyond the farthest distance
+/// reached so far - the values between the stored states are obtained by cubic Hermite
+/// interpolation. Queries at negative distances, as well as `dist_at_h` and
+/// `ground_intersection`, are passed to the wrapped path.
+pub struct CachedPath<'a, P> {
+    env: EnvironmentRef<'a>,
+    path: P,
+    cache: Mutex<Cache<'a>>,
+}
+
+/// The states reached so far and the stepper that continues from the last one
+struct Cache<'a> {
+    states: Vec<RayState>,
+    stepper: Option<Box<dyn PathStepper<Item = PathStep> + 'a>>,
+}
+
+impl<'a, P: Path<'a> + Clone> CachedPath<'a, P> {
+    /// Wraps the `path` (traced in `env`), storing its states every `step` meters.
+    pub fn new(env: impl Into<EnvironmentRef<'a>>, path: P, step: f64) -> Self {
+        let mut stepper = path.clone().into_path_stepper();
+        stepper.set_step_size(step);
+        let start = stepper.next().unwrap().state();
+        Self {
+            env: env.into(),
+            path,
+            cache: Mutex::new(Cache {
+                states: vec![start],
+                stepper: Some(stepper),
+            }),
+        }
+    }
+
+    /// Returns the wrapped path.
+    pub fn inner(&self) -> &P {
+        &self.path
+    }
+
+    /// Returns the farthest distance (in meters) up to which the path has been integrated.
+    pub fn cached_dist(&self) -> f64 {
+        self.cache.lock().unwrap().states.last().unwrap().x
+    }
+
+    fn state_at_dist(&self, dist: f64) -> RayState {
+        if dist < 0.0 {
+            return self.path.sample(&[dist])[0];
+        }
+        let mut cache = self.cache.lock().unwrap();
+        while cache.states.last().unwrap().x < dist {
+            match cache.stepper.as_mut().and_then(|stepper| stepper.next()) {
+                Some(step) => cache.states.push(step.state()),
+                None => {
+                    // the path has ended
+                    cache.stepper = None;
+                    return RayState {
+                        x: dist,
+                        h: f64::NAN,
+                        dh: f64::NAN,
+                    };
+                }
+            }
+        }
+        let states = &cache.states;
+        let i = states.partition_point(|state| state.x <= dist);
+        if i == states.len() {
+            return RayState {
+                x: dist,
+                ..states[i - 1]
+            };
+        }
+        interpolate_state(&states[i - 1], &states[i], dist)
+    }
+}
+
+/// Interpolates the state of a path between two states with a cubic Hermite polynomial
+pub(crate) fn interpolate_state(state1: &RayState, state2: &RayState, dist: f64) -> RayState {
+    let len = state2.x - state1.x;
+    let t = (dist - state1.x) / len;
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let h = (2.0 * t3 - 3.0 * t2 + 1.0) * state1.h
+        + (t3 - 2.0 * t2 + t) * len * state1.dh
+        + (3.0 * t2 - 2.0 * t3) * state2.h
+        + (t3 - t2) * len * state2.dh;
+    let dh = 6.0 * (t2 - t) / len * (state1.h - state2.h)
+        + (3.0 * t2 - 4.0 * t + 1.0) * state1.dh
+        + (3.0 * t2 - 2.0 * t) * state2.dh;
+    RayState { x: dist, h, dh }
+}
+
+impl<'a, P: Path<'a> + Clone> Path<'a> for CachedPath<'a, P> {
+    fn start_h(&self) -> f64 {
+        self.path.start_h()
+    }
+
+    fn start_angle(&self) -> f64 {
+        self.path.start_angle()
+    }
+
+    fn wavelength(&self) -> f64 {
+        self.path.wavelength()
+    }
+
+    fn h_at_dist(&self, dist: f64) -> f64 {
+        self.state_at_dist(dist).h
+    }
+
+    fn angle_at_dist(&self, dist: f64) -> f64 {
+        self.state_at_dist(dist).get_angle(&self.env)
+    }
+
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        PathPoint::from_state(&self.state_at_dist(dist), &self.env, self.wavelength())
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        bending(&self.env, self.start_angle(), &self.point_at_dist(dist))
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        deviation(self, &self.env, dist)
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        arc_length(self, &self.env, dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        optical_length(self, &self.env, self.wavelength(), dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        dists.iter().map(|&dist| self.state_at_dist(dist)).collect()
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, search_range: (f64, f64)) -> Vec<f64> {
+        self.path.dist_at_h(tgt_h, search_range)
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        self.path.ground_intersection(ground_h, max_dist)
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.path.into_path_stepper()
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.path.path_stepper()
+    }
+}
@@ -0,0 +1,249 @@
+//! Paths over a surface with a curvature defined by the user
+
+use super::{
+    arc_length, bending, deviation, next_step, optical_length, sort_and_filter, AnyIntegrator,
+    GroundIntersection, Integration, Motion, Path, PathDeviation, PathPoint, PathStep, PathStepper,
+    RayOptions, StepperCore, Stepping,
+};
+use crate::{air::atmosphere::AtmosphereHints, EnvironmentRef, RayState};
+use na::integration::{Integrator, StepSize};
+
+/// A path over a surface with a curvature defined by the user.
+///
+/// Since there are no closed-form expressions for straight lines over such a surface, both rays
+/// and lines are integrated numerically - the lines just ignore the refractive index.
+#[derive(Clone)]
+pub struct Ray<'a> {
+    env: EnvironmentRef<'a>,
+    start_h: f64,
+    start_dh: f64,
+    wavelength: f64,
+    integration: Integration,
+    straight: bool,
+}
+
+impl Ray<'_> {
+    pub fn from_h_ang<'a>(
+        env: impl Into<EnvironmentRef<'a>>,
+        h: f64,
+        ang: f64,
+        straight: bool,
+    ) -> Ray<'a> {
+        let env = env.into();
+        let curvature = env.curvature_at(0.0);
+        let dh = ang.tan() * (1.0 + curvature * h);
+        Ray {
+            start_h: h,
+            start_dh: dh,
+            wavelength: env.wavelength,
+            integration: Integration::default(),
+            straight,
+            env,
+        }
+    }
+
+    /// Applies the wavelength and the integration settings from the options.
+    pub fn with_options(mut self, options: &RayOptions) -> Self {
+        if let Some(wavelength) = options.wavelength {
+            self.wavelength = wavelength;
+        }
+        self.integration = Integration::from_options(options);
+        self
+    }
+
+    fn initial_state(&self) -> RayState {
+        RayState {
+            x: 0.0,
+            h: self.start_h,
+            dh: self.start_dh,
+        }
+    }
+
+    fn state_at_dist(&self, dist: f64) -> RayState {
+        let state = self.initial_state();
+        let hints = AtmosphereHints::default();
+        self.integration.state_at_dist(state, dist, |state| {
+            self.env
+                .calc_derivative_custom(state, self.wavelength, self.straight, &hints)
+        })
+    }
+}
+
+impl<'a> Path<'a> for Ray<'a> {
+    fn start_h(&self) -> f64 {
+        self.start_h
+    }
+
+    fn start_angle(&self) -> f64 {
+        self.initial_state().get_angle(&self.env)
+    }
+
+    fn wavelength(&self) -> f64 {
+        self.wavelength
+    }
+
+    fn h_at_dist(&self, dist: f64) -> f64 {
+        let state = self.state_at_dist(dist);
+        state.h
+    }
+
+    fn angle_at_dist(&self, dist: f64) -> f64 {
+        let state = self.state_at_dist(dist);
+        state.get_angle(&self.env)
+    }
+
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        PathPoint::from_state(&self.sample(&[dist])[0], &self.env, self.wavelength)
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        bending(&self.env, self.start_angle(), &self.point_at_dist(dist))
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        deviation(self, &self.env, dist)
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        arc_length(self, &self.env, dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        optical_length(self, &self.env, self.wavelength, dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        let hints = AtmosphereHints::default();
+        self.integration
+            .sample(self.initial_state(), dists, |state| {
+                self.env
+                    .calc_derivative_custom(state, self.wavelength, self.straight, &hints)
+            })
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, (min_dist, max_dist): (f64, f64)) -> Vec<f64> {
+        let mut result = vec![];
+        for end in [min_dist.min(0.0), max_dist.max(0.0)] {
+            if end == 0.0 {
+                continue;
+            }
+            let hints = AtmosphereHints::default();
+            let crossings = self
+                .integration
+                .crossings(self.initial_state(), tgt_h, end, |state| {
+                    self.env
+                        .calc_derivative_custom(state, self.wavelength, self.straight, &hints)
+                });
+            result.extend(crossings.into_iter().map(|state| state.x));
+        }
+        sort_and_filter(result, (min_dist, max_dist))
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        let state = self.initial_state();
+        let hints = AtmosphereHints::default();
+        self.integration
+            .descent_below(state, ground_h, max_dist, |state| {
+                self.env
+                    .calc_derivative_custom(state, self.wavelength, self.straight, &hints)
+            })
+            .map(|state| GroundIntersection {
+                dist: state.x,
+                angle: state.get_angle(&self.env),
+            })
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        let state = self.initial_state();
+        Box::new(RayStepper::new(
+            state,
+            self.env,
+            self.wavelength,
+            self.integration,
+            self.straight,
+            1.0,
+        ))
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.clone().into_path_stepper()
+    }
+}
+
+/// A stepper along a path over a surface with a custom curvature
+pub struct RayStepper<'a> {
+    motion: RayMotion<'a>,
+    core: StepperCore<'a>,
+}
+
+impl<'a> RayStepper<'a> {
+    fn new(
+        state: RayState,
+        env: EnvironmentRef<'a>,
+        wavelength: f64,
+        integration: Integration,
+        straight: bool,
+        step_size: f64,
+    ) -> Self {
+        Self {
+            motion: RayMotion {
+                env: env.clone(),
+                wavelength,
+                integration,
+                straight,
+                integrator: integration.integrator(step_size),
+                hints: AtmosphereHints::default(),
+            },
+            core: StepperCore::new(env, wavelength, state, step_size),
+        }
+    }
+}
+
+/// The numerical integration of a path over a surface with a custom curvature, performed by a stepper
+pub(crate) struct RayMotion<'a> {
+    env: EnvironmentRef<'a>,
+    wavelength: f64,
+    integration: Integration,
+    straight: bool,
+    integrator: AnyIntegrator,
+    /// The hints of the profiles of the atmosphere, kept between the steps
+    hints: AtmosphereHints,
+}
+
+impl Motion for RayMotion<'_> {
+    fn propagate(&mut self, state: &RayState, len: f64) -> RayState {
+        let env = &self.env;
+        let wavelength = self.wavelength;
+        let straight = self.straight;
+        let hints = &self.hints;
+        self.integrator.propagate(
+            state,
+            |state| env.calc_derivative_custom(state, wavelength, straight, hints),
+            StepSize::Step(len),
+        )
+    }
+
+    fn is_outside(&self, state: &RayState) -> bool {
+        self.integration.is_outside(state)
+    }
+
+    fn ground_h(&self) -> f64 {
+        self.integration.ground_altitude.unwrap_or(0.0)
+    }
+}
+
+impl<'a> Stepping<'a> for RayStepper<'a> {
+    type Motion = RayMotion<'a>;
+
+    fn parts(&mut self) -> (&mut StepperCore<'a>, &mut RayMotion<'a>) {
+        (&mut self.core, &mut self.motion)
+    }
+}
+
+impl Iterator for RayStepper<'_> {
+    type Item = PathStep;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_step(self)
+    }
+}
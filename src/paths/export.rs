@@ -0,0 +1,106 @@
+//! Writing the points of a path to files
+
+use super::{Path, PathPoint};
+use crate::Environment;
+use std::io::{self, Write};
+
+/// The format of the exported samples
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum ExportFormat {
+    /// Comma-separated values with a header line: `dist,h,angle,n`
+    Csv,
+    /// A JSON array of objects with the fields `dist`, `h`, `angle` and `n`
+    Json,
+}
+
+/// Writes the points of the `path` (traced in `env`) every `step` meters between the ends of
+/// `range` to `out`.
+///
+/// Every record contains the distance and the altitude in meters, the angle to the horizontal
+/// in radians and the refractive index. The end of the range is always included, even if it
+/// is not a multiple of the step away from the start.
+pub fn export_samples<'a, P, W>(
+    path: &P,
+    env: &Environment,
+    (start, end): (f64, f64),
+    step: f64,
+    format: ExportFormat,
+    mut out: W,
+) -> io::Result<()>
+where
+    P: Path<'a> + ?Sized,
+    W: Write,
+{
+    let dists = sample_dists(start, end, step);
+    let points = sample_points(path, env, &dists).into_iter();
+    match format {
+        ExportFormat::Csv => {
+            writeln!(out, "dist,h,angle,n")?;
+            for point in points {
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    point.dist, point.h, point.angle, point.n
+                )?;
+            }
+        }
+        ExportFormat::Json => {
+            write!(out, "[")?;
+            for (i, point) in points.enumerate() {
+                if i > 0 {
+                    write!(out, ",")?;
+                }
+                write!(
+                    out,
+                    "{{\"dist\":{},\"h\":{},\"angle\":{},\"n\":{}}}",
+                    json_number(point.dist),
+                    json_number(point.h),
+                    json_number(point.angle),
+                    json_number(point.n)
+                )?;
+            }
+            writeln!(out, "]")?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the points of the `path` (traced in `env`) at the given distances, in the order in
+/// which the distances are given. The ray is integrated only once for all the distances.
+pub fn sample_points<'a, P>(path: &P, env: &Environment, dists: &[f64]) -> Vec<PathPoint>
+where
+    P: Path<'a> + ?Sized,
+{
+    let mut order: Vec<usize> = (0..dists.len()).collect();
+    order.sort_by(|&i, &j| dists[i].total_cmp(&dists[j]));
+    let sorted: Vec<f64> = order.iter().map(|&i| dists[i]).collect();
+    let wavelength = path.wavelength();
+    let mut points: Vec<(usize, PathPoint)> = order
+        .into_iter()
+        .zip(path.sample(&sorted))
+        .map(|(i, state)| (i, PathPoint::from_state(&state, env, wavelength)))
+        .collect();
+    points.sort_by_key(|&(i, _)| i);
+    points.into_iter().map(|(_, point)| point).collect()
+}
+
+/// The distances from `start` to `end` every `step` meters, with `end` itself appended
+fn sample_dists(start: f64, end: f64, step: f64) -> Vec<f64> {
+    let step = step.abs().max(f64::MIN_POSITIVE).copysign(end - start);
+    let n = ((end - start) / step + 1e-9).floor() as usize;
+    let mut dists: Vec<f64> = (0..=n).map(|i| start + i as f64 * step).collect();
+    if (dists[n] - end).abs() > 1e-9 * step.abs() {
+        dists.push(end);
+    }
+    dists
+}
+
+/// JSON has no representation of NaN or infinities, so they are written as `null`
+fn json_number(x: f64) -> String {
+    if x.is_finite() {
+        x.to_string()
+    } else {
+        "null".to_owned()
+    }
+}
@@ -1,26 +1,63 @@
-use super::{Path, PathStepper};
-use crate::{Environment, RayState};
-use na::integration::{Integrator, RK4Integrator, StepSize};
+//! Paths over a flat surface
 
-pub struct Line {
+use super::{
+    arc_length, bending, deviation, next_step, optical_length, sort_and_filter, AnyIntegrator,
+    GroundIntersection, Integration, Motion, Path, PathDeviation, PathPoint, PathStep, PathStepper,
+    RayOptions, StepperCore, Stepping,
+};
+use crate::{air::atmosphere::AtmosphereHints, EnvironmentRef, RayState};
+use na::integration::{Integrator, StepSize};
+
+/// A straight line over a flat surface
+#[derive(Clone)]
+pub struct Line<'a> {
+    env: EnvironmentRef<'a>,
     a: f64,
     b: f64,
 }
 
-impl Line {
-    pub fn from_h_ang(h: f64, ang: f64) -> Line {
+impl<'a> Line<'a> {
+    pub fn from_h_ang(env: impl Into<EnvironmentRef<'a>>, h: f64, ang: f64) -> Line<'a> {
+        let env = env.into();
         let a = ang.tan();
-        Line { a, b: h }
+        Line { env, a, b: h }
     }
 
-    pub fn from_two_points(h1: f64, x1: f64, h2: f64, x2: f64) -> Line {
+    pub fn from_two_points(
+        env: impl Into<EnvironmentRef<'a>>,
+        h1: f64,
+        x1: f64,
+        h2: f64,
+        x2: f64,
+    ) -> Line<'a> {
+        let env = env.into();
         let a = (h2 - h1) / (x2 - x1);
         let b = h1 - a * x1;
-        Line { a, b }
+        Line { env, a, b }
+    }
+
+    fn state_at_dist(&self, dist: f64) -> RayState {
+        RayState {
+            x: dist,
+            h: self.h_at_dist(dist),
+            dh: self.a,
+        }
     }
 }
 
-impl<'a> Path<'a> for Line {
+impl<'a, 'b: 'a> Path<'a> for Line<'b> {
+    fn start_h(&self) -> f64 {
+        self.b
+    }
+
+    fn start_angle(&self) -> f64 {
+        self.a.atan()
+    }
+
+    fn wavelength(&self) -> f64 {
+        self.env.wavelength
+    }
+
     fn h_at_dist(&self, dist: f64) -> f64 {
         self.a * dist + self.b
     }
@@ -29,97 +66,161 @@ impl<'a> Path<'a> for Line {
         self.a.atan()
     }
 
-    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = RayState> + 'a> {
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        PathPoint::from_state(&self.state_at_dist(dist), &self.env, self.env.wavelength)
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        bending(&self.env, self.start_angle(), &self.point_at_dist(dist))
+    }
+
+    fn deviation_at_dist(&self, _dist: f64) -> PathDeviation {
+        PathDeviation { h: 0.0, angle: 0.0 }
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        dist * (1.0 + self.a * self.a).sqrt()
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        optical_length(self, &self.env, self.env.wavelength, dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        dists.iter().map(|&dist| self.state_at_dist(dist)).collect()
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, range: (f64, f64)) -> Vec<f64> {
+        if self.a == 0.0 {
+            return vec![];
+        }
+        sort_and_filter(vec![(tgt_h - self.b) / self.a], range)
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        let dist = if self.b < ground_h {
+            0.0
+        } else if self.a < 0.0 {
+            (ground_h - self.b) / self.a
+        } else {
+            return None;
+        };
+        (dist <= max_dist).then(|| GroundIntersection {
+            dist,
+            angle: self.a.atan(),
+        })
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
         Box::new(LineStepper::new(self, 1.0))
     }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.clone().into_path_stepper()
+    }
 }
 
-pub struct LineStepper {
-    x: f64,
-    line: Line,
-    step: f64,
+/// A stepper along a straight line over a flat surface
+pub struct LineStepper<'a> {
+    line: Line<'a>,
+    core: StepperCore<'a>,
 }
 
-impl LineStepper {
-    fn new(line: Line, step: f64) -> Self {
-        Self { x: 0.0, line, step }
+impl<'a> LineStepper<'a> {
+    fn new(line: Line<'a>, step: f64) -> Self {
+        let state = line.state_at_dist(0.0);
+        Self {
+            core: StepperCore::new(line.env.clone(), line.env.wavelength, state, step),
+            line,
+        }
     }
+}
 
-    fn as_state(&self) -> RayState {
-        let h = self.line.h_at_dist(self.x);
-        RayState {
-            x: self.x,
-            h,
-            dh: self.line.angle_at_dist(self.x).tan(),
-        }
+impl Motion for Line<'_> {
+    fn propagate(&mut self, state: &RayState, len: f64) -> RayState {
+        self.state_at_dist(state.x + len)
     }
 }
 
-impl Iterator for LineStepper {
-    type Item = RayState;
+impl<'a> Stepping<'a> for LineStepper<'a> {
+    type Motion = Line<'a>;
 
-    fn next(&mut self) -> Option<RayState> {
-        self.x += self.step;
-        Some(self.as_state())
+    fn parts(&mut self) -> (&mut StepperCore<'a>, &mut Line<'a>) {
+        (&mut self.core, &mut self.line)
     }
 }
 
-impl PathStepper for LineStepper {
-    fn set_step_size(&mut self, step: f64) {
-        self.step = step;
+impl Iterator for LineStepper<'_> {
+    type Item = PathStep;
+
+    fn next(&mut self) -> Option<PathStep> {
+        next_step(self)
     }
 }
 
+/// A ray over a flat surface
+#[derive(Clone)]
 pub struct Ray<'a> {
     start_h: f64,
     start_dh: f64,
-    env: &'a Environment,
+    wavelength: f64,
+    integration: Integration,
+    env: EnvironmentRef<'a>,
 }
 
 impl Ray<'_> {
-    pub fn from_h_ang(env: &Environment, h: f64, ang: f64) -> Ray {
+    pub fn from_h_ang<'a>(env: impl Into<EnvironmentRef<'a>>, h: f64, ang: f64) -> Ray<'a> {
+        let env = env.into();
         let dh = ang.tan();
         Ray {
             start_h: h,
             start_dh: dh,
+            wavelength: env.wavelength,
+            integration: Integration::default().in_env(&env),
             env,
         }
     }
 
-    fn state_at_dist(&self, dist: f64) -> RayState {
-        let tgt_x = dist.abs();
+    /// Applies the wavelength and the integration settings from the options.
+    pub fn with_options(mut self, options: &RayOptions) -> Self {
+        if let Some(wavelength) = options.wavelength {
+            self.wavelength = wavelength;
+        }
+        self.integration = Integration::from_options(options).in_env(&self.env);
+        self
+    }
 
-        let mut state = RayState {
+    fn initial_state(&self) -> RayState {
+        RayState {
             x: 0.0,
             h: self.start_h,
-            dh: if dist >= 0.0 {
-                self.start_dh
-            } else {
-                -self.start_dh
-            },
-        };
-
-        let def_step = 5.0;
-        let mut integrator = RK4Integrator::new(def_step);
-        while state.x < tgt_x - def_step {
-            integrator.propagate_in_place(
-                &mut state,
-                |state| self.env.calc_derivative_flat(state),
-                StepSize::UseDefault,
-            );
+            dh: self.start_dh,
         }
-        let last_step = tgt_x - state.x;
-        integrator.propagate_in_place(
-            &mut state,
-            |state| self.env.calc_derivative_flat(state),
-            StepSize::Step(last_step),
-        );
+    }
 
-        state
+    fn state_at_dist(&self, dist: f64) -> RayState {
+        let state = self.initial_state();
+        let hints = AtmosphereHints::default();
+        self.integration.state_at_dist(state, dist, |state| {
+            self.env
+                .calc_derivative_flat(state, self.wavelength, &hints)
+        })
     }
 }
 
 impl<'a, 'b: 'a> Path<'a> for Ray<'b> {
+    fn start_h(&self) -> f64 {
+        self.start_h
+    }
+
+    fn start_angle(&self) -> f64 {
+        self.initial_state().get_angle(&self.env)
+    }
+
+    fn wavelength(&self) -> f64 {
+        self.wavelength
+    }
+
     fn h_at_dist(&self, dist: f64) -> f64 {
         let state = self.state_at_dist(dist);
         state.h
@@ -127,51 +228,159 @@ impl<'a, 'b: 'a> Path<'a> for Ray<'b> {
 
     fn angle_at_dist(&self, dist: f64) -> f64 {
         let state = self.state_at_dist(dist);
-        state.get_angle(self.env)
+        state.get_angle(&self.env)
     }
 
-    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = RayState> + 'a> {
-        let state = RayState {
-            x: 0.0,
-            h: self.start_h,
-            dh: self.start_dh,
-        };
-        Box::new(RayStepper::new(state, self.env, 1.0))
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        PathPoint::from_state(&self.sample(&[dist])[0], &self.env, self.wavelength)
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        bending(&self.env, self.start_angle(), &self.point_at_dist(dist))
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        deviation(self, &self.env, dist)
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        arc_length(self, &self.env, dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        optical_length(self, &self.env, self.wavelength, dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        let hints = AtmosphereHints::default();
+        self.integration
+            .sample(self.initial_state(), dists, |state| {
+                self.env
+                    .calc_derivative_flat(state, self.wavelength, &hints)
+            })
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, (min_dist, max_dist): (f64, f64)) -> Vec<f64> {
+        let mut result = vec![];
+        for end in [min_dist.min(0.0), max_dist.max(0.0)] {
+            if end == 0.0 {
+                continue;
+            }
+            let hints = AtmosphereHints::default();
+            let crossings = self
+                .integration
+                .crossings(self.initial_state(), tgt_h, end, |state| {
+                    self.env
+                        .calc_derivative_flat(state, self.wavelength, &hints)
+                });
+            result.extend(crossings.into_iter().map(|state| state.x));
+        }
+        sort_and_filter(result, (min_dist, max_dist))
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        let state = self.initial_state();
+        let hints = AtmosphereHints::default();
+        self.integration
+            .descent_below(state, ground_h, max_dist, |state| {
+                self.env
+                    .calc_derivative_flat(state, self.wavelength, &hints)
+            })
+            .map(|state| GroundIntersection {
+                dist: state.x,
+                angle: state.get_angle(&self.env),
+            })
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        let state = self.initial_state();
+        Box::new(RayStepper::new(
+            state,
+            self.env,
+            self.wavelength,
+            self.integration,
+            1.0,
+        ))
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.clone().into_path_stepper()
     }
 }
 
+/// A stepper along a ray over a flat surface
 pub struct RayStepper<'a> {
-    cur_state: RayState,
-    env: &'a Environment,
-    integrator: RK4Integrator,
+    motion: RayMotion<'a>,
+    core: StepperCore<'a>,
 }
 
 impl<'a> RayStepper<'a> {
-    fn new(state: RayState, env: &'a Environment, step_size: f64) -> Self {
+    fn new(
+        state: RayState,
+        env: EnvironmentRef<'a>,
+        wavelength: f64,
+        integration: Integration,
+        step_size: f64,
+    ) -> Self {
         Self {
-            cur_state: state,
-            env,
-            integrator: RK4Integrator::new(step_size),
+            motion: RayMotion {
+                env: env.clone(),
+                wavelength,
+                integration,
+                integrator: integration.integrator(step_size),
+                hints: AtmosphereHints::default(),
+            },
+            core: StepperCore::new(env, wavelength, state, step_size),
         }
     }
 }
 
-impl Iterator for RayStepper<'_> {
-    type Item = RayState;
+/// The numerical integration of a ray over a flat surface, performed by a stepper
+pub(crate) struct RayMotion<'a> {
+    env: EnvironmentRef<'a>,
+    wavelength: f64,
+    integration: Integration,
+    integrator: AnyIntegrator,
+    /// The hints of the profiles of the atmosphere, kept between the steps
+    hints: AtmosphereHints,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let env = self.env;
-        self.integrator.propagate_in_place(
-            &mut self.cur_state,
-            |state| env.calc_derivative_flat(state),
-            StepSize::UseDefault,
-        );
-        Some(self.cur_state)
+impl Motion for RayMotion<'_> {
+    fn propagate(&mut self, state: &RayState, len: f64) -> RayState {
+        if let Some(state) = self.integration.vacuum_continuation(state, len) {
+            return state;
+        }
+        let env = &self.env;
+        let wavelength = self.wavelength;
+        let hints = &self.hints;
+        self.integrator.propagate(
+            state,
+            |state| env.calc_derivative_flat(state, wavelength, hints),
+            StepSize::Step(len),
+        )
+    }
+
+    fn is_outside(&self, state: &RayState) -> bool {
+        self.integration.is_outside(state)
+    }
+
+    fn ground_h(&self) -> f64 {
+        self.integration.ground_altitude.unwrap_or(0.0)
     }
 }
 
-impl PathStepper for RayStepper<'_> {
-    fn set_step_size(&mut self, step: f64) {
-        self.integrator.set_default_step(step);
+impl<'a> Stepping<'a> for RayStepper<'a> {
+    type Motion = RayMotion<'a>;
+
+    fn parts(&mut self) -> (&mut StepperCore<'a>, &mut RayMotion<'a>) {
+        (&mut self.core, &mut self.motion)
+    }
+}
+
+impl Iterator for RayStepper<'_> {
+    type Item = PathStep;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_step(self)
     }
 }
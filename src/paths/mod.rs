@@ -1,24 +1,585 @@
-pub(crate) mod flat;
-pub(crate) mod spherical;
+pub mod arcs;
+mod cached;
+pub mod custom;
+mod export;
+pub mod flat;
+mod options;
+mod spectrum;
+pub mod spherical;
+mod steps;
 
-use crate::RayState;
+pub(crate) use self::cached::interpolate_state;
+pub use self::cached::CachedPath;
+pub use self::export::{export_samples, sample_points, ExportFormat};
+pub(crate) use self::options::{refine_crossing, AnyIntegrator, Integration, Vacuum};
+pub use self::options::{IntegratorKind, RayOptions};
+pub(crate) use self::spectrum::SpectrumRay;
+pub(crate) use self::steps::{next_step, Motion, StepperCore, Stepping};
+pub use self::steps::{PathStep, StepEvent};
+use crate::{Environment, RayState};
+use std::sync::Arc;
 
 /// The trait representing a light path.
-pub trait Path<'a> {
+///
+/// The paths can be shared between threads, also as trait objects like `Box<dyn Path>` or
+/// `Arc<dyn Path>` - which implement the trait themselves.
+pub trait Path<'a>: Send + Sync {
+    /// Returns the initial altitude of the path in meters.
+    fn start_h(&self) -> f64;
+    /// Returns the initial angle (in radians) between the path and the horizontal plane.
+    fn start_angle(&self) -> f64;
+    /// Returns the wavelength (in meters) of the light travelling along the path.
+    fn wavelength(&self) -> f64;
     /// Returns the altitude (in meters) at which the path is passing at the given distance (in
-    /// meters) from the initial point.
+    /// meters) from the initial point. Negative distances refer to the part of the path that
+    /// leads to the initial point.
     fn h_at_dist(&self, dist: f64) -> f64;
     /// Returns the angle (in radians) between the path and the horizontal plane at the given
     /// distance (in meters) from the initial point.
     fn angle_at_dist(&self, dist: f64) -> f64;
+    /// Returns the altitude, angle and other parameters of the path at the given distance (in
+    /// meters) from the initial point, calculated at once.
+    fn point_at_dist(&self, dist: f64) -> PathPoint;
+    /// Returns the total bending of the path (in radians) between the initial point and the given
+    /// distance (in meters) from it - the angle between the initial direction of the path and
+    /// its direction at that distance. Positive values mean bending towards the ground.
+    fn bending_at_dist(&self, dist: f64) -> f64;
+    /// Returns how much the path deviates at the given distance (in meters) from the straight line
+    /// starting at the same point in the same direction; zero for straight lines.
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation;
+    /// Returns the geometric length (in meters) of the path between the initial point and the
+    /// given distance (in meters) from it; negative for negative distances.
+    fn arc_length_at_dist(&self, dist: f64) -> f64;
+    /// Returns the optical length (in meters) of the path between the initial point and the
+    /// given distance (in meters) from it - the integral of the refractive index over the path;
+    /// negative for negative distances.
+    fn optical_length_at_dist(&self, dist: f64) -> f64;
+    /// Returns the states of the path at the given distances (in meters) from the initial point,
+    /// which have to be sorted in ascending order. Rays are integrated only once for all the
+    /// distances.
+    fn sample(&self, dists: &[f64]) -> Vec<RayState>;
+    /// Returns the distances (in meters) from the initial point, sorted in ascending order, at
+    /// which the path crosses the altitude `tgt_h` (in meters), searching between the distances
+    /// given by `search_range`.
+    fn dist_at_h(&self, tgt_h: f64, search_range: (f64, f64)) -> Vec<f64>;
+    /// Returns the point at which the path descends below the altitude `ground_h` (in meters),
+    /// if it does so within the distance `max_dist` (in meters) from the initial point. If the
+    /// path starts below `ground_h`, the initial point is returned.
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection>;
     /// Returns a "stepper" - an iterator that performs one integration step along the path on
     /// every call to `next()`
-    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = RayState> + 'a>;
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a>;
+    /// Returns a stepper along the path like `into_path_stepper`, without consuming the path -
+    /// which makes it available for trait objects
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a>;
+}
+
+/// Returns the bending of a path between the initial point and the given point
+fn bending(env: &Environment, start_angle: f64, point: &PathPoint) -> f64 {
+    start_angle - point.angle + env.surface_rotation(point.dist)
+}
+
+/// Returns the deviation of a path from the straight line starting at the same point in the same
+/// direction
+fn deviation<'a, P: Path<'a> + ?Sized>(path: &P, env: &Environment, dist: f64) -> PathDeviation {
+    let options = RayOptions {
+        straight: true,
+        ..Default::default()
+    };
+    let line = env.cast_ray_with(path.start_h(), path.start_angle(), &options);
+    let h = path.h_at_dist(dist);
+    let line_h = line.h_at_dist(dist);
+    PathDeviation {
+        h: h - line_h,
+        angle: elevation_from_start(env, path.start_h(), h, dist)
+            - elevation_from_start(env, path.start_h(), line_h, dist),
+    }
+}
+
+/// Returns the elevation angle at which a point at the altitude `h` and distance `dist` is seen
+/// from the point at the altitude `start_h` and distance 0
+fn elevation_from_start(env: &Environment, start_h: f64, h: f64, dist: f64) -> f64 {
+    let curvature = env.curvature_at(0.0);
+    if curvature == 0.0 {
+        return (h - start_h).atan2(dist);
+    }
+    let phi = dist * curvature;
+    let x = (1.0 / curvature + h) * phi.sin();
+    // (1/k + h) * cos(phi) - (1/k + start_h), avoiding the loss of precision
+    let y = h * phi.cos() - start_h - 2.0 * (0.5 * phi).sin().powi(2) / curvature;
+    y.atan2(x)
+}
+
+/// Integrates the given function of the states of a path over the path length, between the
+/// initial point and the given distance, using Simpson's rule with at most 5 m long intervals
+pub(crate) fn integrate_over_length<'a, P, F>(path: &P, env: &Environment, dist: f64, f: F) -> f64
+where
+    P: Path<'a> + ?Sized,
+    F: Fn(&RayState) -> f64,
+{
+    let n = 2 * ((dist.abs() / 10.0).ceil() as usize).max(1);
+    let step = dist / n as f64;
+    let dists: Vec<f64> = if step >= 0.0 {
+        (0..=n).map(|i| i as f64 * step).collect()
+    } else {
+        (0..=n).rev().map(|i| i as f64 * step).collect()
+    };
+    let sum: f64 = path
+        .sample(&dists)
+        .iter()
+        .enumerate()
+        .map(|(i, state)| {
+            let weight = if i == 0 || i == n {
+                1.0
+            } else if i % 2 == 1 {
+                4.0
+            } else {
+                2.0
+            };
+            weight * f(state) * length_element(env, state)
+        })
+        .sum();
+    sum * step / 3.0
+}
+
+/// Returns the derivative of the length of a path with respect to the distance along the surface
+fn length_element(env: &Environment, state: &RayState) -> f64 {
+    let r_k = 1.0 + env.curvature_at(state.x) * state.h;
+    (state.dh * state.dh + r_k * r_k).sqrt()
+}
+
+/// Returns the geometric length of a path between the initial point and the given distance
+fn arc_length<'a, P: Path<'a> + ?Sized>(path: &P, env: &Environment, dist: f64) -> f64 {
+    integrate_over_length(path, env, dist, |_| 1.0)
+}
+
+/// Returns the optical length of a path between the initial point and the given distance
+fn optical_length<'a, P: Path<'a> + ?Sized>(
+    path: &P,
+    env: &Environment,
+    wavelength: f64,
+    dist: f64,
+) -> f64 {
+    integrate_over_length(path, env, dist, |state| {
+        env.n_dn_at_wavelength(state.h, wavelength).0
+    })
+}
+
+/// Sorts the distances and leaves only the ones within the range
+fn sort_and_filter(mut dists: Vec<f64>, (min_dist, max_dist): (f64, f64)) -> Vec<f64> {
+    dists.retain(|dist| (min_dist..=max_dist).contains(dist));
+    dists.sort_by(|a, b| a.total_cmp(b));
+    dists.dedup();
+    dists
+}
+
+/// The parameters of a path at some point
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathPoint {
+    /// The distance from the initial point in meters
+    pub dist: f64,
+    /// The altitude in meters
+    pub h: f64,
+    /// The angle (in radians) between the path and the horizontal plane
+    pub angle: f64,
+    /// The refractive index of the air
+    pub n: f64,
+    /// The derivative of the altitude with respect to the distance, measured along the surface
+    pub dh: f64,
+}
+
+impl PathPoint {
+    pub(crate) fn from_state(state: &RayState, env: &Environment, wavelength: f64) -> Self {
+        PathPoint {
+            dist: state.x,
+            h: state.h,
+            angle: state.get_angle(env),
+            n: env.n_dn_at_wavelength(state.h, wavelength).0,
+            dh: state.dh,
+        }
+    }
+}
+
+/// The deviation of a path from the straight line starting at the same point in the same
+/// direction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathDeviation {
+    /// The difference between the altitudes of the path and the line, in meters
+    pub h: f64,
+    /// The difference between the elevation angles (in radians) at which the points of the path
+    /// and the line are seen from the initial point. Over custom surfaces, the surface is assumed
+    /// to have the curvature from the initial point for this purpose.
+    pub angle: f64,
+}
+
+/// The point at which a path hits the ground
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GroundIntersection {
+    /// The distance from the initial point in meters
+    pub dist: f64,
+    /// The angle (in radians) between the path and the horizontal plane at the point of impact
+    pub angle: f64,
+}
+
+impl<'a, P: Path<'a> + ?Sized> Path<'a> for Box<P> {
+    fn start_h(&self) -> f64 {
+        (**self).start_h()
+    }
+
+    fn start_angle(&self) -> f64 {
+        (**self).start_angle()
+    }
+
+    fn wavelength(&self) -> f64 {
+        (**self).wavelength()
+    }
+
+    fn h_at_dist(&self, dist: f64) -> f64 {
+        (**self).h_at_dist(dist)
+    }
+
+    fn angle_at_dist(&self, dist: f64) -> f64 {
+        (**self).angle_at_dist(dist)
+    }
+
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        (**self).point_at_dist(dist)
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        (**self).bending_at_dist(dist)
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        (**self).deviation_at_dist(dist)
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        (**self).arc_length_at_dist(dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        (**self).optical_length_at_dist(dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        (**self).sample(dists)
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, search_range: (f64, f64)) -> Vec<f64> {
+        (**self).dist_at_h(tgt_h, search_range)
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        (**self).ground_intersection(ground_h, max_dist)
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.path_stepper()
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        (**self).path_stepper()
+    }
+}
+
+impl<'a, P: Path<'a> + ?Sized> Path<'a> for Arc<P> {
+    fn start_h(&self) -> f64 {
+        (**self).start_h()
+    }
+
+    fn start_angle(&self) -> f64 {
+        (**self).start_angle()
+    }
+
+    fn wavelength(&self) -> f64 {
+        (**self).wavelength()
+    }
+
+    fn h_at_dist(&self, dist: f64) -> f64 {
+        (**self).h_at_dist(dist)
+    }
+
+    fn angle_at_dist(&self, dist: f64) -> f64 {
+        (**self).angle_at_dist(dist)
+    }
+
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        (**self).point_at_dist(dist)
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        (**self).bending_at_dist(dist)
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        (**self).deviation_at_dist(dist)
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        (**self).arc_length_at_dist(dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        (**self).optical_length_at_dist(dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        (**self).sample(dists)
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, search_range: (f64, f64)) -> Vec<f64> {
+        (**self).dist_at_h(tgt_h, search_range)
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        (**self).ground_intersection(ground_h, max_dist)
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.path_stepper()
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        (**self).path_stepper()
+    }
 }
 
 /// The trait representing a "stepper" - an iterator performing one integration step along the
-/// path on every call to `next()`
-pub trait PathStepper: Iterator {
-    /// Sets the step size for the iterations
+/// path on every call to `next()`. The first step yielded is the initial point of the path.
+///
+/// The stepper can also be asked to report events on the path - they are yielded as additional
+/// steps, landing exactly at the events, with the `event` field set.
+pub trait PathStepper: Iterator<Item = PathStep> + Send + Sync {
+    /// Sets the step size for the iterations. A step that isn't finite and positive is replaced
+    /// with the default one of `RayOptions`.
     fn set_step_size(&mut self, step: f64);
+    /// Makes the steps land on the multiples of `dx` (in meters) - the next step is shortened
+    /// to reach the nearest one, and then the steps are `dx` long. A `dx` that isn't finite and
+    /// positive is replaced with the default step of `RayOptions`.
+    fn step_to_multiple_of(&mut self, dx: f64);
+    /// Makes the stepper adapt the lengths of the steps, so that the estimated error of the
+    /// altitude in each step stays within `tolerance` (in meters). The steps get shorter where
+    /// the path bends sharply, like in inversion layers, and longer where it's nearly straight;
+    /// the step size set with the other methods becomes the maximum length of a step. A tolerance
+    /// that isn't finite and positive is replaced with the default one of `RayOptions`.
+    fn set_tolerance(&mut self, tolerance: f64);
+    /// Makes the stepper report the points at which the path crosses the altitude `h` (in
+    /// meters).
+    fn on_altitude_crossing(&mut self, h: f64);
+    /// Makes the stepper report the point at which the path descends below the ground - the
+    /// altitude set in `RayOptions::ground_altitude`, or 0 - and stop there.
+    fn on_ground_hit(&mut self);
+    /// Makes the stepper report the points at which the path stops ascending.
+    fn on_apex(&mut self);
+    /// Advances the stepper to exactly the distance `dist` (in meters), shortening the last step
+    /// so that it lands there, and returns the step at that distance, or None if the path ends
+    /// before reaching it. The steps in between are skipped.
+    ///
+    /// The steppers only move forward, so None is also returned, without moving the stepper, if
+    /// `dist` is NaN or lies behind the last step yielded - the two cases can be told apart by
+    /// comparing `dist` with the distance of that step.
+    fn run_to_dist(&mut self, dist: f64) -> Option<PathStep>;
+    /// Advances the stepper as long as `pred` returns true for the steps, and returns the first
+    /// step for which it doesn't, or None if the path ends before that.
+    fn advance_while(&mut self, pred: &mut dyn FnMut(&PathStep) -> bool) -> Option<PathStep> {
+        let mut steps = &mut *self;
+        Iterator::find(&mut steps, |step| !pred(step))
+    }
+}
+
+/// A light path of any of the types provided by the crate.
+///
+/// Can be used instead of `Box<dyn Path>` in order to avoid the dynamic dispatch, or to keep
+/// the paths by value.
+#[derive(Clone)]
+pub enum AnyPath<'a> {
+    /// A straight line over a flat surface
+    FlatLine(flat::Line<'a>),
+    /// A ray over a flat surface
+    FlatRay(flat::Ray<'a>),
+    /// A straight line over a spherical surface
+    SphericalLine(spherical::Line<'a>),
+    /// A ray over a spherical surface
+    SphericalRay(spherical::Ray<'a>),
+    /// A ray over a spherical surface traced using the Bouguer invariant
+    SphericalInvariantRay(spherical::InvariantRay<'a>),
+    /// A ray or a line over a surface with a custom curvature
+    CustomRay(custom::Ray<'a>),
+    /// A ray approximated by circular arcs
+    ArcRay(arcs::ArcRay<'a>),
+}
+
+impl<'a> Path<'a> for AnyPath<'a> {
+    fn start_h(&self) -> f64 {
+        match self {
+            AnyPath::FlatLine(path) => path.start_h(),
+            AnyPath::FlatRay(path) => path.start_h(),
+            AnyPath::SphericalLine(path) => path.start_h(),
+            AnyPath::SphericalRay(path) => path.start_h(),
+            AnyPath::SphericalInvariantRay(path) => path.start_h(),
+            AnyPath::CustomRay(path) => path.start_h(),
+            AnyPath::ArcRay(path) => path.start_h(),
+        }
+    }
+
+    fn start_angle(&self) -> f64 {
+        match self {
+            AnyPath::FlatLine(path) => path.start_angle(),
+            AnyPath::FlatRay(path) => path.start_angle(),
+            AnyPath::SphericalLine(path) => path.start_angle(),
+            AnyPath::SphericalRay(path) => path.start_angle(),
+            AnyPath::SphericalInvariantRay(path) => path.start_angle(),
+            AnyPath::CustomRay(path) => path.start_angle(),
+            AnyPath::ArcRay(path) => path.start_angle(),
+        }
+    }
+
+    fn wavelength(&self) -> f64 {
+        match self {
+            AnyPath::FlatLine(path) => path.wavelength(),
+            AnyPath::FlatRay(path) => path.wavelength(),
+            AnyPath::SphericalLine(path) => path.wavelength(),
+            AnyPath::SphericalRay(path) => path.wavelength(),
+            AnyPath::SphericalInvariantRay(path) => path.wavelength(),
+            AnyPath::CustomRay(path) => path.wavelength(),
+            AnyPath::ArcRay(path) => path.wavelength(),
+        }
+    }
+
+    fn h_at_dist(&self, dist: f64) -> f64 {
+        match self {
+            AnyPath::FlatLine(path) => path.h_at_dist(dist),
+            AnyPath::FlatRay(path) => path.h_at_dist(dist),
+            AnyPath::SphericalLine(path) => path.h_at_dist(dist),
+            AnyPath::SphericalRay(path) => path.h_at_dist(dist),
+            AnyPath::SphericalInvariantRay(path) => path.h_at_dist(dist),
+            AnyPath::CustomRay(path) => path.h_at_dist(dist),
+            AnyPath::ArcRay(path) => path.h_at_dist(dist),
+        }
+    }
+
+    fn angle_at_dist(&self, dist: f64) -> f64 {
+        match self {
+            AnyPath::FlatLine(path) => path.angle_at_dist(dist),
+            AnyPath::FlatRay(path) => path.angle_at_dist(dist),
+            AnyPath::SphericalLine(path) => path.angle_at_dist(dist),
+            AnyPath::SphericalRay(path) => path.angle_at_dist(dist),
+            AnyPath::SphericalInvariantRay(path) => path.angle_at_dist(dist),
+            AnyPath::CustomRay(path) => path.angle_at_dist(dist),
+            AnyPath::ArcRay(path) => path.angle_at_dist(dist),
+        }
+    }
+
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        match self {
+            AnyPath::FlatLine(path) => path.point_at_dist(dist),
+            AnyPath::FlatRay(path) => path.point_at_dist(dist),
+            AnyPath::SphericalLine(path) => path.point_at_dist(dist),
+            AnyPath::SphericalRay(path) => path.point_at_dist(dist),
+            AnyPath::SphericalInvariantRay(path) => path.point_at_dist(dist),
+            AnyPath::CustomRay(path) => path.point_at_dist(dist),
+            AnyPath::ArcRay(path) => path.point_at_dist(dist),
+        }
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        match self {
+            AnyPath::FlatLine(path) => path.bending_at_dist(dist),
+            AnyPath::FlatRay(path) => path.bending_at_dist(dist),
+            AnyPath::SphericalLine(path) => path.bending_at_dist(dist),
+            AnyPath::SphericalRay(path) => path.bending_at_dist(dist),
+            AnyPath::SphericalInvariantRay(path) => path.bending_at_dist(dist),
+            AnyPath::CustomRay(path) => path.bending_at_dist(dist),
+            AnyPath::ArcRay(path) => path.bending_at_dist(dist),
+        }
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        match self {
+            AnyPath::FlatLine(path) => path.deviation_at_dist(dist),
+            AnyPath::FlatRay(path) => path.deviation_at_dist(dist),
+            AnyPath::SphericalLine(path) => path.deviation_at_dist(dist),
+            AnyPath::SphericalRay(path) => path.deviation_at_dist(dist),
+            AnyPath::SphericalInvariantRay(path) => path.deviation_at_dist(dist),
+            AnyPath::CustomRay(path) => path.deviation_at_dist(dist),
+            AnyPath::ArcRay(path) => path.deviation_at_dist(dist),
+        }
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        match self {
+            AnyPath::FlatLine(path) => path.arc_length_at_dist(dist),
+            AnyPath::FlatRay(path) => path.arc_length_at_dist(dist),
+            AnyPath::SphericalLine(path) => path.arc_length_at_dist(dist),
+            AnyPath::SphericalRay(path) => path.arc_length_at_dist(dist),
+            AnyPath::SphericalInvariantRay(path) => path.arc_length_at_dist(dist),
+            AnyPath::CustomRay(path) => path.arc_length_at_dist(dist),
+            AnyPath::ArcRay(path) => path.arc_length_at_dist(dist),
+        }
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        match self {
+            AnyPath::FlatLine(path) => path.optical_length_at_dist(dist),
+            AnyPath::FlatRay(path) => path.optical_length_at_dist(dist),
+            AnyPath::SphericalLine(path) => path.optical_length_at_dist(dist),
+            AnyPath::SphericalRay(path) => path.optical_length_at_dist(dist),
+            AnyPath::SphericalInvariantRay(path) => path.optical_length_at_dist(dist),
+            AnyPath::CustomRay(path) => path.optical_length_at_dist(dist),
+            AnyPath::ArcRay(path) => path.optical_length_at_dist(dist),
+        }
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        match self {
+            AnyPath::FlatLine(path) => path.sample(dists),
+            AnyPath::FlatRay(path) => path.sample(dists),
+            AnyPath::SphericalLine(path) => path.sample(dists),
+            AnyPath::SphericalRay(path) => path.sample(dists),
+            AnyPath::SphericalInvariantRay(path) => path.sample(dists),
+            AnyPath::CustomRay(path) => path.sample(dists),
+            AnyPath::ArcRay(path) => path.sample(dists),
+        }
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, search_range: (f64, f64)) -> Vec<f64> {
+        match self {
+            AnyPath::FlatLine(path) => path.dist_at_h(tgt_h, search_range),
+            AnyPath::FlatRay(path) => path.dist_at_h(tgt_h, search_range),
+            AnyPath::SphericalLine(path) => path.dist_at_h(tgt_h, search_range),
+            AnyPath::SphericalRay(path) => path.dist_at_h(tgt_h, search_range),
+            AnyPath::SphericalInvariantRay(path) => path.dist_at_h(tgt_h, search_range),
+            AnyPath::CustomRay(path) => path.dist_at_h(tgt_h, search_range),
+            AnyPath::ArcRay(path) => path.dist_at_h(tgt_h, search_range),
+        }
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        match self {
+            AnyPath::FlatLine(path) => path.ground_intersection(ground_h, max_dist),
+            AnyPath::FlatRay(path) => path.ground_intersection(ground_h, max_dist),
+            AnyPath::SphericalLine(path) => path.ground_intersection(ground_h, max_dist),
+            AnyPath::SphericalRay(path) => path.ground_intersection(ground_h, max_dist),
+            AnyPath::SphericalInvariantRay(path) => path.ground_intersection(ground_h, max_dist),
+            AnyPath::CustomRay(path) => path.ground_intersection(ground_h, max_dist),
+            AnyPath::ArcRay(path) => path.ground_intersection(ground_h, max_dist),
+        }
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        match self {
+            AnyPath::FlatLine(path) => path.into_path_stepper(),
+            AnyPath::FlatRay(path) => path.into_path_stepper(),
+            AnyPath::SphericalLine(path) => path.into_path_stepper(),
+            AnyPath::SphericalRay(path) => path.into_path_stepper(),
+            AnyPath::SphericalInvariantRay(path) => path.into_path_stepper(),
+            AnyPath::CustomRay(path) => path.into_path_stepper(),
+            AnyPath::ArcRay(path) => path.into_path_stepper(),
+        }
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.clone().into_path_stepper()
+    }
 }
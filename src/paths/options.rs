@@ -0,0 +1,594 @@
+use crate::{Environment, RayState, RayStateDerivative};
+use na::integration::{Integrator, RK4Integrator, RK8Integrator, StepSize};
+use na::State;
+use std::f64::consts::FRAC_PI_2;
+
+/// The precision in meters of the distances at which paths cross given altitudes
+pub(crate) const CROSSING_EPSILON: f64 = 1e-6;
+
+/// The numerical method used for integrating the paths of rays
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum IntegratorKind {
+    /// The 4th order Runge-Kutta method
+    #[default]
+    RungeKutta4,
+    /// The 8th order Runge-Kutta method - more precise, but slower per step
+    RungeKutta8,
+    /// The Dormand-Prince method - an embedded pair of 5th and 4th order Runge-Kutta methods,
+    /// which adapts the step size to keep the estimated error within `RayOptions::tolerance`
+    DormandPrince,
+    /// Quadrature of the Bouguer invariant n·r·cos(angle) instead of integrating the ray
+    /// equation - see `spherical::InvariantRay`. Only applies to rays over spherical (and
+    /// ellipsoidal) surfaces; the other rays are integrated with the 4th order Runge-Kutta
+    /// method.
+    BouguerInvariant,
+    /// Following the ray as circular arcs through layers `RayOptions::step` meters thick, with a
+    /// constant gradient of the refractive index in each - a fast approximation, see
+    /// `arcs::ArcRay`. Rays over surfaces with a custom curvature are integrated with the 4th
+    /// order Runge-Kutta method instead.
+    CircularArcs,
+}
+
+/// The parameters of a light path
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct RayOptions {
+    /// `true` if the path should be a straight line, `false` if it should be a ray affected by the
+    /// atmosphere
+    pub straight: bool,
+    /// The integration step in meters used when querying the path at some distance; the maximum
+    /// step for the adaptive integration methods. A step that isn't finite and positive is
+    /// replaced with the default one.
+    pub step: f64,
+    /// The integration method
+    pub integrator: IntegratorKind,
+    /// The maximum estimated error of the altitude per step in meters, for the adaptive
+    /// integration methods. A tolerance that isn't finite and positive is replaced with the
+    /// default one.
+    pub tolerance: f64,
+    /// The altitude in meters above which the path isn't traced any further - beyond the point
+    /// where it is crossed, the altitude and angle of the path are NaN and the steppers stop.
+    /// Only applies to paths that are integrated numerically.
+    pub max_altitude: Option<f64>,
+    /// The altitude of the ground in meters - if set, the path ends where it descends below it:
+    /// beyond that point, the altitude and angle of the path are NaN and the steppers stop. Only
+    /// applies to paths that are integrated numerically.
+    pub ground_altitude: Option<f64>,
+    /// The wavelength of the light in meters; if `None`, the one set in the environment is used
+    pub wavelength: Option<f64>,
+}
+
+impl Default for RayOptions {
+    fn default() -> Self {
+        Self {
+            straight: false,
+            step: 5.0,
+            integrator: IntegratorKind::RungeKutta4,
+            tolerance: 1e-6,
+            max_altitude: None,
+            ground_altitude: None,
+            wavelength: None,
+        }
+    }
+}
+
+impl RayOptions {
+    /// Returns the options with the step and the tolerance that aren't finite and positive
+    /// replaced with the default ones, so that the integration always moves forward
+    pub(crate) fn validated(&self) -> RayOptions {
+        let defaults = RayOptions::default();
+        RayOptions {
+            step: positive_or(self.step, defaults.step),
+            tolerance: positive_or(self.tolerance, defaults.tolerance),
+            ..*self
+        }
+    }
+}
+
+/// Returns `value` if it's finite and positive, or `default` otherwise
+pub(crate) fn positive_or(value: f64, default: f64) -> f64 {
+    if value.is_finite() && value > 0.0 {
+        value
+    } else {
+        default
+    }
+}
+
+/// The settings of the numerical integration of a path
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Integration {
+    pub step: f64,
+    pub kind: IntegratorKind,
+    pub tolerance: f64,
+    pub max_altitude: Option<f64>,
+    pub ground_altitude: Option<f64>,
+    /// The space above the top of the atmosphere, in which the paths continue as straight lines
+    /// instead of being integrated
+    pub vacuum: Option<Vacuum>,
+}
+
+impl Default for Integration {
+    fn default() -> Self {
+        Self::from_options(&RayOptions::default())
+    }
+}
+
+impl Integration {
+    pub fn from_options(options: &RayOptions) -> Self {
+        let options = options.validated();
+        Self {
+            step: options.step,
+            kind: options.integrator,
+            tolerance: options.tolerance,
+            max_altitude: options.max_altitude,
+            ground_altitude: options.ground_altitude,
+            vacuum: None,
+        }
+    }
+
+    /// Makes the paths continue as straight lines above the top of the atmosphere set in `env`,
+    /// if it's set.
+    pub fn in_env(mut self, env: &Environment) -> Self {
+        self.vacuum = env.vacuum();
+        self
+    }
+
+    /// Returns the state at the distance `len` from `state` (which can be negative), if the path
+    /// has left the atmosphere for good at `state`, so that it continues as a straight line
+    pub fn vacuum_continuation(&self, state: &RayState, len: f64) -> Option<RayState> {
+        self.vacuum
+            .filter(|vacuum| vacuum.is_left(state, len))
+            .map(|vacuum| vacuum.propagate(state, len))
+    }
+
+    pub fn integrator(&self, step: f64) -> AnyIntegrator {
+        match self.kind {
+            IntegratorKind::RungeKutta4
+            | IntegratorKind::BouguerInvariant
+            | IntegratorKind::CircularArcs => AnyIntegrator::RK4(RK4Integrator::new(step)),
+            IntegratorKind::RungeKutta8 => AnyIntegrator::RK8(RK8Integrator::new(step)),
+            IntegratorKind::DormandPrince => {
+                AnyIntegrator::DormandPrince(DormandPrince::new(step.abs(), self.tolerance))
+            }
+        }
+    }
+
+    /// Returns whether the path should stop being traced at the given state
+    pub fn is_outside(&self, state: &RayState) -> bool {
+        self.max_altitude.is_some_and(|max_h| state.h > max_h)
+            || self
+                .ground_altitude
+                .is_some_and(|ground_h| state.h < ground_h)
+    }
+
+    /// Integrates the path from `state` to the distance `dist`, which can be negative
+    pub fn state_at_dist<D>(&self, state: RayState, dist: f64, diff_eq: D) -> RayState
+    where
+        D: Fn(&RayState) -> RayStateDerivative,
+    {
+        self.states_at_dists(state, &[dist], diff_eq)[0]
+    }
+
+    /// Integrates the path from `state` through the given distances, which have to be sorted in
+    /// the direction of the integration, and returns the states at them
+    pub fn states_at_dists<D>(
+        &self,
+        mut state: RayState,
+        dists: &[f64],
+        diff_eq: D,
+    ) -> Vec<RayState>
+    where
+        D: Fn(&RayState) -> RayStateDerivative,
+    {
+        let mut integrator = self.integrator(self.step);
+        let mut result = Vec::with_capacity(dists.len());
+        let mut outside = false;
+        for &dist in dists {
+            if !outside {
+                let def_step = self.step.copysign(dist - state.x);
+                while (dist - state.x).abs() > def_step.abs() && !outside {
+                    if self.vacuum_continuation(&state, def_step).is_some() {
+                        break;
+                    }
+                    integrator.propagate_in_place(&mut state, &diff_eq, StepSize::Step(def_step));
+                    outside = self.is_outside(&state);
+                }
+                if !outside {
+                    let last_step = dist - state.x;
+                    match self.vacuum_continuation(&state, last_step) {
+                        Some(end_state) => {
+                            // the states at the next distances are calculated from the point at
+                            // which the path has left the atmosphere
+                            outside = self.is_outside(&end_state);
+                            if !outside {
+                                result.push(end_state);
+                                continue;
+                            }
+                        }
+                        None => {
+                            integrator.propagate_in_place(
+                                &mut state,
+                                &diff_eq,
+                                StepSize::Step(last_step),
+                            );
+                            outside = self.is_outside(&state);
+                        }
+                    }
+                }
+            }
+            result.push(if outside {
+                RayState {
+                    x: dist,
+                    h: f64::NAN,
+                    dh: f64::NAN,
+                }
+            } else {
+                state
+            });
+        }
+        result
+    }
+
+    /// Integrates the path from the initial state `state` through the given distances, which have
+    /// to be sorted in ascending order, and returns the states at them. Negative distances are
+    /// reached by integrating backwards.
+    pub fn sample<D>(&self, state: RayState, dists: &[f64], diff_eq: D) -> Vec<RayState>
+    where
+        D: Fn(&RayState) -> RayStateDerivative,
+    {
+        let split = dists.partition_point(|&dist| dist < 0.0);
+        let (negative, positive) = dists.split_at(split);
+
+        let negative: Vec<f64> = negative.iter().rev().copied().collect();
+        let mut result = self.states_at_dists(state, &negative, &diff_eq);
+        result.reverse();
+        result.extend(self.states_at_dists(state, positive, &diff_eq));
+        result
+    }
+
+    /// Integrates the path from `state` until it descends below the altitude `tgt_h`, but not
+    /// farther than to the distance `max_dist`, and returns the state at the crossing
+    pub fn descent_below<D>(
+        &self,
+        mut state: RayState,
+        tgt_h: f64,
+        max_dist: f64,
+        diff_eq: D,
+    ) -> Option<RayState>
+    where
+        D: Fn(&RayState) -> RayStateDerivative,
+    {
+        if state.h < tgt_h {
+            return Some(state);
+        }
+        let mut integrator = self.integrator(self.step);
+        while state.x < max_dist {
+            if self.vacuum_continuation(&state, 1.0).is_some() {
+                // the path only ascends from here on
+                return None;
+            }
+            let step = self.step.min(max_dist - state.x);
+            let prev_state = state;
+            integrator.propagate_in_place(&mut state, &diff_eq, StepSize::Step(step));
+            if state.h < tgt_h {
+                return Some(refine_crossing(
+                    &mut integrator,
+                    &prev_state,
+                    step,
+                    tgt_h,
+                    &diff_eq,
+                ));
+            }
+            if self.max_altitude.is_some_and(|max_h| state.h > max_h) {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Integrates the path from `state` to the distance `end` (which can be smaller than the
+    /// initial distance) and returns the states at which it crosses the altitude `tgt_h`
+    pub fn crossings<D>(
+        &self,
+        mut state: RayState,
+        tgt_h: f64,
+        end: f64,
+        diff_eq: D,
+    ) -> Vec<RayState>
+    where
+        D: Fn(&RayState) -> RayStateDerivative,
+    {
+        let mut result = vec![];
+        if state.h == tgt_h {
+            result.push(state);
+        }
+        let def_step = self.step.copysign(end - state.x);
+        let mut integrator = self.integrator(def_step);
+        while (end - state.x) * def_step > 0.0 {
+            if let Some(vacuum) = self
+                .vacuum
+                .filter(|vacuum| vacuum.is_left(&state, def_step))
+            {
+                // the path ascends along a straight line from here on, crossing the altitude at
+                // most once
+                let crossing = vacuum
+                    .dist_to_h(&state, tgt_h, def_step)
+                    .filter(|&len| len != 0.0 && (end - state.x - len) * def_step >= 0.0)
+                    .map(|len| vacuum.propagate(&state, len))
+                    .filter(|crossing| !self.is_outside(crossing));
+                result.extend(crossing);
+                break;
+            }
+            let step = if (end - state.x).abs() < def_step.abs() {
+                end - state.x
+            } else {
+                def_step
+            };
+            let prev_state = state;
+            integrator.propagate_in_place(&mut state, &diff_eq, StepSize::Step(step));
+            if self.is_outside(&state) {
+                break;
+            }
+            if state.h == tgt_h {
+                result.push(state);
+            } else if (prev_state.h - tgt_h) * (state.h - tgt_h) < 0.0 {
+                result.push(refine_crossing(
+                    &mut integrator,
+                    &prev_state,
+                    step,
+                    tgt_h,
+                    &diff_eq,
+                ));
+            }
+        }
+        result
+    }
+}
+
+/// The space above the top of the atmosphere, in which the refractive index is 1 and the paths
+/// are straight lines
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Vacuum {
+    /// The altitude of the top of the atmosphere in meters
+    pub top: f64,
+    /// The radius of the surface in meters, or `None` if it is flat
+    pub radius: Option<f64>,
+}
+
+impl Vacuum {
+    /// Returns whether the path at `state`, traced in the direction given by the sign of `dir`,
+    /// has left the atmosphere for good - it is above the top and ascends, so it never comes back
+    fn is_left(&self, state: &RayState, dir: f64) -> bool {
+        state.h > self.top && state.dh * dir >= 0.0
+    }
+
+    /// Returns the state of the straight line through `state` at the distance `len` from it
+    fn propagate(&self, state: &RayState, len: f64) -> RayState {
+        let (h, dh) = match self.radius {
+            None => (state.h + state.dh * len, state.dh),
+            Some(radius) => {
+                let r0 = radius + state.h;
+                let ang = (state.dh * radius / r0).atan();
+                // the angle between the line and the horizontal at the distance `len`
+                let phi = len / radius + ang;
+                if phi.abs() < FRAC_PI_2 {
+                    let r = r0 * ang.cos() / phi.cos();
+                    (r - radius, phi.tan() * r / radius)
+                } else {
+                    (f64::NAN, f64::NAN)
+                }
+            }
+        };
+        RayState {
+            x: state.x + len,
+            h,
+            dh,
+        }
+    }
+
+    /// Returns the distance from `state` at which the straight line through it, traced in the
+    /// direction given by the sign of `dir` from a point where it has left the atmosphere,
+    /// reaches the altitude `h`
+    fn dist_to_h(&self, state: &RayState, h: f64, dir: f64) -> Option<f64> {
+        if h < state.h {
+            return None;
+        }
+        match self.radius {
+            None => (state.dh != 0.0).then(|| (h - state.h) / state.dh),
+            Some(radius) => {
+                let r0 = radius + state.h;
+                let ang = (state.dh * radius / r0).atan();
+                let phi = (r0 * ang.cos() / (radius + h)).acos();
+                Some(radius * (phi.copysign(dir) - ang))
+            }
+        }
+    }
+}
+
+/// Finds the state at which the path crosses the altitude `tgt_h` within the integration step
+/// `step` starting at `prev_state`, by bisection
+pub(crate) fn refine_crossing<D>(
+    integrator: &mut AnyIntegrator,
+    prev_state: &RayState,
+    step: f64,
+    tgt_h: f64,
+    diff_eq: D,
+) -> RayState
+where
+    D: Fn(&RayState) -> RayStateDerivative,
+{
+    let below_at_start = prev_state.h < tgt_h;
+    let (mut min_step, mut max_step) = (0.0, step);
+    while (max_step - min_step).abs() > CROSSING_EPSILON {
+        let mid_step = 0.5 * (min_step + max_step);
+        let mid_state = integrator.propagate(prev_state, &diff_eq, StepSize::Step(mid_step));
+        if (mid_state.h < tgt_h) == below_at_start {
+            min_step = mid_step;
+        } else {
+            max_step = mid_step;
+        }
+    }
+    integrator.propagate(
+        prev_state,
+        &diff_eq,
+        StepSize::Step(0.5 * (min_step + max_step)),
+    )
+}
+
+/// One of the integrators that can be chosen for tracing the paths
+pub(crate) enum AnyIntegrator {
+    RK4(RK4Integrator),
+    RK8(RK8Integrator),
+    DormandPrince(DormandPrince),
+}
+
+impl Integrator<RayState> for AnyIntegrator {
+    fn propagate_in_place<D>(&mut self, start: &mut RayState, diff_eq: D, step: StepSize)
+    where
+        D: Fn(&RayState) -> RayStateDerivative,
+    {
+        match self {
+            AnyIntegrator::RK4(integrator) => integrator.propagate_in_place(start, diff_eq, step),
+            AnyIntegrator::RK8(integrator) => integrator.propagate_in_place(start, diff_eq, step),
+            AnyIntegrator::DormandPrince(integrator) => {
+                integrator.propagate_in_place(start, diff_eq, step)
+            }
+        }
+    }
+}
+
+/// The shortest step in meters taken by the Dormand-Prince integrator
+const MIN_DP_STEP: f64 = 1e-3;
+
+/// The Dormand-Prince integrator, covering the requested distance in as many steps as needed to
+/// keep the estimated error within the tolerance
+pub(crate) struct DormandPrince {
+    max_step: f64,
+    tolerance: f64,
+    /// The length of the next step to try
+    step: f64,
+}
+
+impl DormandPrince {
+    fn new(max_step: f64, tolerance: f64) -> Self {
+        Self {
+            max_step,
+            tolerance,
+            step: max_step,
+        }
+    }
+
+    /// Performs a single step of the length `h`, given the derivative `k1` at the initial state;
+    /// returns the new state, the estimated error and the derivative at the new state
+    fn step<D>(
+        state: &RayState,
+        k1: &RayStateDerivative,
+        h: f64,
+        diff_eq: D,
+    ) -> (RayState, f64, RayStateDerivative)
+    where
+        D: Fn(&RayState) -> RayStateDerivative,
+    {
+        let k2 = diff_eq(&state.shift(&(*k1 / 5.0), h));
+        let k3 = diff_eq(&state.shift(&(*k1 * 3.0 / 40.0 + k2 * 9.0 / 40.0), h));
+        let k4 =
+            diff_eq(&state.shift(&(*k1 * 44.0 / 45.0 - k2 * 56.0 / 15.0 + k3 * 32.0 / 9.0), h));
+        let k5 = diff_eq(&state.shift(
+            &(*k1 * 19372.0 / 6561.0 - k2 * 25360.0 / 2187.0 + k3 * 64448.0 / 6561.0
+                - k4 * 212.0 / 729.0),
+            h,
+        ));
+        let k6 = diff_eq(&state.shift(
+            &(*k1 * 9017.0 / 3168.0 - k2 * 355.0 / 33.0
+                + k3 * 46732.0 / 5247.0
+                + k4 * 49.0 / 176.0
+                - k5 * 5103.0 / 18656.0),
+            h,
+        ));
+        let new_state = state.shift(
+            &(*k1 * 35.0 / 384.0 + k3 * 500.0 / 1113.0 + k4 * 125.0 / 192.0 - k5 * 2187.0 / 6784.0
+                + k6 * 11.0 / 84.0),
+            h,
+        );
+        let k7 = diff_eq(&new_state);
+        // the difference between the 5th and 4th order solutions
+        let error = (*k1 * 71.0 / 57600.0 - k3 * 71.0 / 16695.0 + k4 * 71.0 / 1920.0
+            - k5 * 17253.0 / 339200.0
+            + k6 * 22.0 / 525.0
+            - k7 / 40.0)
+            * h;
+        // the error of the slope shows up in the altitude farther along the path
+        let error = error.dh.abs() + error.d2h.abs() * h.abs();
+        (new_state, error, k7)
+    }
+}
+
+impl Integrator<RayState> for DormandPrince {
+    fn propagate_in_place<D>(&mut self, start: &mut RayState, diff_eq: D, step: StepSize)
+    where
+        D: Fn(&RayState) -> RayStateDerivative,
+    {
+        let len = match step {
+            StepSize::UseDefault => self.step,
+            StepSize::Step(len) => len,
+        };
+        let end = start.x + len;
+        let mut k1 = diff_eq(start);
+        while (end - start.x) * len > 0.0 {
+            let remaining = end - start.x;
+            let h = self.step.min(remaining.abs()).copysign(remaining);
+            let (new_state, error, k7) = Self::step(start, &k1, h, &diff_eq);
+            let factor = if error > 0.0 {
+                0.9 * (self.tolerance / error).powf(0.2)
+            } else {
+                5.0
+            };
+            if error <= self.tolerance || h.abs() <= MIN_DP_STEP {
+                *start = new_state;
+                k1 = k7;
+                if h == remaining {
+                    start.x = end;
+                }
+                if h.abs() == self.step {
+                    self.step = (self.step * factor.min(5.0)).clamp(MIN_DP_STEP, self.max_step);
+                }
+            } else {
+                self.step = (h.abs() * factor.max(0.1)).max(MIN_DP_STEP);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::ducting_env;
+    use crate::Path;
+
+    #[test]
+    fn test_dormand_prince() {
+        let env = ducting_env();
+        let precise = RayOptions {
+            step: 1.0,
+            integrator: IntegratorKind::RungeKutta8,
+            ..Default::default()
+        };
+        let adaptive = RayOptions {
+            step: 1000.0,
+            integrator: IntegratorKind::DormandPrince,
+            tolerance: 1e-9,
+            ..Default::default()
+        };
+        let precise = env.cast_ray_with(90.0, 0.002, &precise);
+        let adaptive = env.cast_ray_with(90.0, 0.002, &adaptive);
+        for &dist in &[-5e3, 1234.5, 30e3] {
+            assert!((adaptive.h_at_dist(dist) - precise.h_at_dist(dist)).abs() < 2e-3);
+        }
+        let crossings = adaptive.dist_at_h(100.0, (0.0, 30e3));
+        assert_eq!(crossings.len(), precise.dist_at_h(100.0, (0.0, 30e3)).len());
+        for dist in crossings {
+            assert!((adaptive.h_at_dist(dist) - 100.0).abs() < 1e-3);
+        }
+    }
+}
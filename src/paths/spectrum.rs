@@ -0,0 +1,274 @@
+//! Rays of several wavelengths integrated together
+
+use super::{
+    arc_length, bending, deviation, interpolate_state, optical_length, AnyPath, GroundIntersection,
+    Path, PathDeviation, PathPoint, PathStep, PathStepper, RayOptions,
+};
+use crate::{air::atmosphere::AtmosphereHints, Environment, RayState, RayStateDerivative};
+use na::integration::{Integrator, RK4Integrator, StepSize};
+use na::{State, StateDerivative};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::sync::{Arc, Mutex};
+
+/// The states of the rays of all the wavelengths at the same distance
+#[derive(Clone)]
+struct SpectrumState(Vec<RayState>);
+
+/// The derivatives of the states of the rays of all the wavelengths
+#[derive(Clone)]
+struct SpectrumDerivative(Vec<RayStateDerivative>);
+
+impl SpectrumDerivative {
+    fn zip_with<F>(self, other: SpectrumDerivative, f: F) -> SpectrumDerivative
+    where
+        F: Fn(RayStateDerivative, RayStateDerivative) -> RayStateDerivative,
+    {
+        SpectrumDerivative(
+            self.0
+                .into_iter()
+                .zip(other.0)
+                .map(|(a, b)| f(a, b))
+                .collect(),
+        )
+    }
+
+    fn map<F: Fn(RayStateDerivative) -> RayStateDerivative>(self, f: F) -> SpectrumDerivative {
+        SpectrumDerivative(self.0.into_iter().map(f).collect())
+    }
+}
+
+impl Add<SpectrumDerivative> for SpectrumDerivative {
+    type Output = SpectrumDerivative;
+    fn add(self, other: SpectrumDerivative) -> SpectrumDerivative {
+        self.zip_with(other, |a, b| a + b)
+    }
+}
+
+impl Sub<SpectrumDerivative> for SpectrumDerivative {
+    type Output = SpectrumDerivative;
+    fn sub(self, other: SpectrumDerivative) -> SpectrumDerivative {
+        self.zip_with(other, |a, b| a - b)
+    }
+}
+
+impl Mul<f64> for SpectrumDerivative {
+    type Output = SpectrumDerivative;
+    fn mul(self, other: f64) -> SpectrumDerivative {
+        self.map(|a| a * other)
+    }
+}
+
+impl Div<f64> for SpectrumDerivative {
+    type Output = SpectrumDerivative;
+    fn div(self, other: f64) -> SpectrumDerivative {
+        self.map(|a| a / other)
+    }
+}
+
+impl Neg for SpectrumDerivative {
+    type Output = SpectrumDerivative;
+    fn neg(self) -> SpectrumDerivative {
+        self.map(|a| -a)
+    }
+}
+
+impl StateDerivative for SpectrumDerivative {
+    fn abs(&self) -> f64 {
+        self.0
+            .iter()
+            .map(|derivative| derivative.abs().powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+}
+
+impl State for SpectrumState {
+    type Derivative = SpectrumDerivative;
+    fn shift_in_place(&mut self, dir: &SpectrumDerivative, amount: f64) {
+        for (state, derivative) in self.0.iter_mut().zip(&dir.0) {
+            state.shift_in_place(derivative, amount);
+        }
+    }
+}
+
+/// The integration shared by the rays of a spectrum
+struct Spectrum<'a> {
+    env: &'a Environment,
+    wavelengths: Vec<f64>,
+    step: f64,
+    /// The states of all the rays after every step
+    states: Mutex<Vec<SpectrumState>>,
+}
+
+impl Spectrum<'_> {
+    fn derivative(&self, state: &SpectrumState, hints: &AtmosphereHints) -> SpectrumDerivative {
+        let hs: Vec<f64> = state.0.iter().map(|state| state.h).collect();
+        let indices = self.env.n_dn_spectrum(&hs, &self.wavelengths, hints);
+        SpectrumDerivative(
+            state
+                .0
+                .iter()
+                .zip(indices)
+                .map(|(state, (n, dn))| self.env.calc_derivative_with_index(state, n, dn))
+                .collect(),
+        )
+    }
+
+    /// Returns the state of the ray number `index` at the distance `dist`, integrating all the
+    /// rays further if they haven't reached it yet
+    fn state_at_dist(&self, index: usize, dist: f64) -> RayState {
+        let mut states = self.states.lock().unwrap();
+        let mut integrator = RK4Integrator::new(self.step);
+        let hints = AtmosphereHints::default();
+        while states.last().unwrap().0[0].x < dist {
+            let next = integrator.propagate(
+                states.last().unwrap(),
+                |state: &SpectrumState| self.derivative(state, &hints),
+                StepSize::UseDefault,
+            );
+            states.push(next);
+        }
+        let i = states.partition_point(|state| state.0[0].x <= dist);
+        if i == states.len() {
+            return RayState {
+                x: dist,
+                ..states[i - 1].0[index]
+            };
+        }
+        interpolate_state(&states[i - 1].0[index], &states[i].0[index], dist)
+    }
+}
+
+/// One of the rays returned by `Environment::cast_ray_spectrum`.
+///
+/// The rays of all the wavelengths are integrated together, sharing the evaluation of the
+/// atmosphere, and the states after every step are stored - the values between them are
+/// obtained by cubic Hermite interpolation, like in `CachedPath`. Queries at negative distances,
+/// as well as `dist_at_h`, `ground_intersection` and the steppers, are passed to a separate path
+/// of the same wavelength.
+pub(crate) struct SpectrumRay<'a> {
+    spectrum: Arc<Spectrum<'a>>,
+    index: usize,
+    path: AnyPath<'a>,
+}
+
+impl<'a> SpectrumRay<'a> {
+    /// Returns one ray for each of the `wavelengths` (in meters), starting at the altitude
+    /// `start_h` (in meters) at the angle `start_ang` (in radians).
+    pub(crate) fn cast(
+        env: &'a Environment,
+        start_h: f64,
+        start_ang: f64,
+        wavelengths: &[f64],
+    ) -> Vec<SpectrumRay<'a>> {
+        let paths: Vec<AnyPath<'a>> = wavelengths
+            .iter()
+            .map(|&wavelength| {
+                env.cast_ray_with(
+                    start_h,
+                    start_ang,
+                    &RayOptions {
+                        wavelength: Some(wavelength),
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        let start = paths
+            .iter()
+            .map(|path| path.path_stepper().next().unwrap().state())
+            .collect();
+        let spectrum = Arc::new(Spectrum {
+            env,
+            wavelengths: wavelengths.to_vec(),
+            step: RayOptions::default().step,
+            states: Mutex::new(vec![SpectrumState(start)]),
+        });
+        paths
+            .into_iter()
+            .enumerate()
+            .map(|(index, path)| SpectrumRay {
+                spectrum: spectrum.clone(),
+                index,
+                path,
+            })
+            .collect()
+    }
+
+    fn state_at_dist(&self, dist: f64) -> RayState {
+        if dist < 0.0 {
+            return self.path.sample(&[dist])[0];
+        }
+        self.spectrum.state_at_dist(self.index, dist)
+    }
+}
+
+impl<'a> Path<'a> for SpectrumRay<'a> {
+    fn start_h(&self) -> f64 {
+        self.path.start_h()
+    }
+
+    fn start_angle(&self) -> f64 {
+        self.path.start_angle()
+    }
+
+    fn wavelength(&self) -> f64 {
+        self.path.wavelength()
+    }
+
+    fn h_at_dist(&self, dist: f64) -> f64 {
+        self.state_at_dist(dist).h
+    }
+
+    fn angle_at_dist(&self, dist: f64) -> f64 {
+        self.state_at_dist(dist).get_angle(self.spectrum.env)
+    }
+
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        PathPoint::from_state(
+            &self.state_at_dist(dist),
+            self.spectrum.env,
+            self.wavelength(),
+        )
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        bending(
+            self.spectrum.env,
+            self.start_angle(),
+            &self.point_at_dist(dist),
+        )
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        deviation(self, self.spectrum.env, dist)
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        arc_length(self, self.spectrum.env, dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        optical_length(self, self.spectrum.env, self.wavelength(), dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        dists.iter().map(|&dist| self.state_at_dist(dist)).collect()
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, search_range: (f64, f64)) -> Vec<f64> {
+        self.path.dist_at_h(tgt_h, search_range)
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        self.path.ground_intersection(ground_h, max_dist)
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.path.into_path_stepper()
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.path.path_stepper()
+    }
+}
@@ -1,47 +1,86 @@
-use super::{Path, PathStepper};
-use crate::{Environment, RayState};
-use na::integration::{Integrator, RK4Integrator, StepSize};
+//! Paths over a spherical surface
 
+use super::{
+    arc_length, bending, deviation, next_step, optical_length, sort_and_filter, AnyIntegrator,
+    GroundIntersection, Integration, Motion, Path, PathDeviation, PathPoint, PathStep, PathStepper,
+    RayOptions, StepperCore, Stepping,
+};
+use crate::{air::atmosphere::AtmosphereHints, Environment, EnvironmentRef, RayState};
+use na::integration::{Integrator, StepSize};
+use std::f64::consts::PI;
+
+/// A straight line over a spherical surface
+#[derive(Clone)]
 pub struct Line<'a> {
-    env: &'a Environment,
+    env: EnvironmentRef<'a>,
     rmin: f64,
     phimin: f64,
 }
 
 impl<'a> Line<'a> {
-    pub fn from_h_ang(env: &Environment, h: f64, ang: f64) -> Line {
+    pub fn from_h_ang(env: impl Into<EnvironmentRef<'a>>, h: f64, ang: f64) -> Line<'a> {
+        let env = env.into();
         Line {
-            env,
             rmin: (h + env.radius().unwrap()) * ang.cos(),
             phimin: -ang,
+            env,
         }
     }
 
     pub fn from_two_points(
-        env: &'a Environment,
+        env: impl Into<EnvironmentRef<'a>>,
         h1: f64,
         phi1: f64,
         h2: f64,
         phi2: f64,
     ) -> Line<'a> {
+        let env = env.into();
         let r1 = h1 + env.radius().unwrap();
         let r2 = h2 + env.radius().unwrap();
         let a = r1 / r2;
         let tanphi = (a * phi1.cos() - phi2.cos()) / (phi2.sin() - a * phi1.sin());
         let phimin = tanphi.atan();
         Line {
-            env,
             rmin: r1 * (phi1 - phimin).cos(),
             phimin,
+            env,
         }
     }
 
+    /// Returns the distance from the center of the Earth at the angle `phi` (in radians), or NaN
+    /// if the line doesn't reach that angle - it only spans up to π/2 from its lowest point.
     pub fn r(&self, phi: f64) -> f64 {
-        self.rmin / (phi - self.phimin).cos()
+        if (phi - self.phimin).abs() < std::f64::consts::FRAC_PI_2 {
+            self.rmin / (phi - self.phimin).cos()
+        } else {
+            f64::NAN
+        }
+    }
+
+    fn state_at_dist(&self, dist: f64) -> RayState {
+        let h = self.h_at_dist(dist);
+        let r = self.env.radius().unwrap();
+        RayState {
+            x: dist,
+            h,
+            dh: self.angle_at_dist(dist).tan() * (h + r) / r,
+        }
     }
 }
 
 impl<'a, 'b: 'a> Path<'a> for Line<'b> {
+    fn start_h(&self) -> f64 {
+        self.h_at_dist(0.0)
+    }
+
+    fn start_angle(&self) -> f64 {
+        self.angle_at_dist(0.0)
+    }
+
+    fn wavelength(&self) -> f64 {
+        self.env.wavelength
+    }
+
     fn h_at_dist(&self, dist: f64) -> f64 {
         let r = self.env.radius().unwrap();
         self.r(dist / r) - r
@@ -51,104 +90,177 @@ impl<'a, 'b: 'a> Path<'a> for Line<'b> {
         dist / self.env.radius().unwrap() - self.phimin
     }
 
-    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = RayState> + 'a> {
-        Box::new(LineStepper::new(self.env, self, 1.0))
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        PathPoint::from_state(&self.state_at_dist(dist), &self.env, self.env.wavelength)
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        bending(&self.env, self.start_angle(), &self.point_at_dist(dist))
+    }
+
+    fn deviation_at_dist(&self, _dist: f64) -> PathDeviation {
+        PathDeviation { h: 0.0, angle: 0.0 }
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        let phi = dist / self.env.radius().unwrap();
+        let r1 = self.r(0.0);
+        let r2 = self.r(phi);
+        // the law of cosines
+        (r1 * r1 + r2 * r2 - 2.0 * r1 * r2 * phi.cos())
+            .sqrt()
+            .copysign(dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        optical_length(self, &self.env, self.env.wavelength, dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        dists.iter().map(|&dist| self.state_at_dist(dist)).collect()
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, range: (f64, f64)) -> Vec<f64> {
+        let radius = self.env.radius().unwrap();
+        let tgt_r = radius + tgt_h;
+        if self.rmin > tgt_r {
+            return vec![];
+        }
+        let dphi = (self.rmin / tgt_r).acos();
+        sort_and_filter(
+            vec![(self.phimin - dphi) * radius, (self.phimin + dphi) * radius],
+            range,
+        )
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        let radius = self.env.radius().unwrap();
+        let ground_r = radius + ground_h;
+        let dist = if self.r(0.0) < ground_r {
+            0.0
+        } else if self.rmin < ground_r {
+            // the line descends to the ground before reaching the lowest point
+            (self.phimin - (self.rmin / ground_r).acos()) * radius
+        } else {
+            return None;
+        };
+        (dist >= 0.0 && dist <= max_dist).then(|| GroundIntersection {
+            dist,
+            angle: self.angle_at_dist(dist),
+        })
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        Box::new(LineStepper::new(self, 1.0))
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.clone().into_path_stepper()
     }
 }
 
+/// A stepper along a straight line over a spherical surface
 pub struct LineStepper<'a> {
-    env: &'a Environment,
-    x: f64,
     line: Line<'a>,
-    step: f64,
+    core: StepperCore<'a>,
 }
 
 impl<'a> LineStepper<'a> {
-    fn new(env: &'a Environment, line: Line<'a>, step: f64) -> Self {
+    fn new(line: Line<'a>, step: f64) -> Self {
+        let state = line.state_at_dist(0.0);
         Self {
-            env,
-            x: 0.0,
+            core: StepperCore::new(line.env.clone(), line.env.wavelength, state, step),
             line,
-            step,
         }
     }
+}
 
-    fn as_state(&self) -> RayState {
-        let h = self.line.h_at_dist(self.x);
-        let r = self.env.radius().unwrap();
-        RayState {
-            x: self.x,
-            h,
-            dh: self.line.angle_at_dist(self.x).tan() * (h + r) / r,
-        }
+impl Motion for Line<'_> {
+    fn propagate(&mut self, state: &RayState, len: f64) -> RayState {
+        self.state_at_dist(state.x + len)
     }
 }
 
-impl Iterator for LineStepper<'_> {
-    type Item = RayState;
+impl<'a> Stepping<'a> for LineStepper<'a> {
+    type Motion = Line<'a>;
 
-    fn next(&mut self) -> Option<RayState> {
-        self.x += self.step;
-        Some(self.as_state())
+    fn parts(&mut self) -> (&mut StepperCore<'a>, &mut Line<'a>) {
+        (&mut self.core, &mut self.line)
     }
 }
 
-impl PathStepper for LineStepper<'_> {
-    fn set_step_size(&mut self, step: f64) {
-        self.step = step;
+impl Iterator for LineStepper<'_> {
+    type Item = PathStep;
+
+    fn next(&mut self) -> Option<PathStep> {
+        next_step(self)
     }
 }
 
+/// A ray over a spherical surface
+#[derive(Clone)]
 pub struct Ray<'a> {
-    env: &'a Environment,
+    env: EnvironmentRef<'a>,
     start_h: f64,
     start_dh: f64,
+    wavelength: f64,
+    integration: Integration,
 }
 
 impl Ray<'_> {
-    pub fn from_h_ang(env: &Environment, h: f64, ang: f64) -> Ray {
+    pub fn from_h_ang<'a>(env: impl Into<EnvironmentRef<'a>>, h: f64, ang: f64) -> Ray<'a> {
+        let env = env.into();
         let r = env.radius().unwrap();
         let dh = (h + r) * ang.tan() / r;
         Ray {
-            env,
             start_h: h,
             start_dh: dh,
+            wavelength: env.wavelength,
+            integration: Integration::default().in_env(&env),
+            env,
         }
     }
 
-    fn state_at_dist(&self, dist: f64) -> RayState {
-        let tgt_dist = dist.abs();
-        let mut state = RayState {
+    /// Applies the wavelength and the integration settings from the options.
+    pub fn with_options(mut self, options: &RayOptions) -> Self {
+        if let Some(wavelength) = options.wavelength {
+            self.wavelength = wavelength;
+        }
+        self.integration = Integration::from_options(options).in_env(&self.env);
+        self
+    }
+
+    fn initial_state(&self) -> RayState {
+        RayState {
             x: 0.0,
             h: self.start_h,
-            dh: if dist >= 0.0 {
-                self.start_dh
-            } else {
-                -self.start_dh
-            },
-        };
-
-        let def_step = 5.0;
-        let mut integrator = RK4Integrator::new(def_step);
-        while state.x < tgt_dist - def_step {
-            integrator.propagate_in_place(
-                &mut state,
-                |state| self.env.calc_derivative_spherical(state),
-                StepSize::UseDefault,
-            );
-        }
-        let last_step = tgt_dist - state.x;
-        integrator.propagate_in_place(
-            &mut state,
-            |state| self.env.calc_derivative_spherical(state),
-            StepSize::Step(last_step),
-        );
+            dh: self.start_dh,
+        }
+    }
 
-        state
+    fn state_at_dist(&self, dist: f64) -> RayState {
+        let state = self.initial_state();
+        let hints = AtmosphereHints::default();
+        self.integration.state_at_dist(state, dist, |state| {
+            self.env
+                .calc_derivative_spherical(state, self.wavelength, &hints)
+        })
     }
 }
 
 impl<'a> Path<'a> for Ray<'a> {
+    fn start_h(&self) -> f64 {
+        self.start_h
+    }
+
+    fn start_angle(&self) -> f64 {
+        self.initial_state().get_angle(&self.env)
+    }
+
+    fn wavelength(&self) -> f64 {
+        self.wavelength
+    }
+
     fn h_at_dist(&self, dist: f64) -> f64 {
         let state = self.state_at_dist(dist);
         state.h
@@ -156,51 +268,771 @@ impl<'a> Path<'a> for Ray<'a> {
 
     fn angle_at_dist(&self, dist: f64) -> f64 {
         let state = self.state_at_dist(dist);
-        state.get_angle(self.env)
+        state.get_angle(&self.env)
     }
 
-    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = RayState> + 'a> {
-        let state = RayState {
-            x: 0.0,
-            h: self.start_h,
-            dh: self.start_dh,
-        };
-        Box::new(RayStepper::new(state, self.env, 1.0))
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        PathPoint::from_state(&self.sample(&[dist])[0], &self.env, self.wavelength)
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        bending(&self.env, self.start_angle(), &self.point_at_dist(dist))
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        deviation(self, &self.env, dist)
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        arc_length(self, &self.env, dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        optical_length(self, &self.env, self.wavelength, dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        let hints = AtmosphereHints::default();
+        self.integration
+            .sample(self.initial_state(), dists, |state| {
+                self.env
+                    .calc_derivative_spherical(state, self.wavelength, &hints)
+            })
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, (min_dist, max_dist): (f64, f64)) -> Vec<f64> {
+        let mut result = vec![];
+        for end in [min_dist.min(0.0), max_dist.max(0.0)] {
+            if end == 0.0 {
+                continue;
+            }
+            let hints = AtmosphereHints::default();
+            let crossings = self
+                .integration
+                .crossings(self.initial_state(), tgt_h, end, |state| {
+                    self.env
+                        .calc_derivative_spherical(state, self.wavelength, &hints)
+                });
+            result.extend(crossings.into_iter().map(|state| state.x));
+        }
+        sort_and_filter(result, (min_dist, max_dist))
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        let state = self.initial_state();
+        let hints = AtmosphereHints::default();
+        self.integration
+            .descent_below(state, ground_h, max_dist, |state| {
+                self.env
+                    .calc_derivative_spherical(state, self.wavelength, &hints)
+            })
+            .map(|state| GroundIntersection {
+                dist: state.x,
+                angle: state.get_angle(&self.env),
+            })
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        let state = self.initial_state();
+        Box::new(RayStepper::new(
+            state,
+            self.env,
+            self.wavelength,
+            self.integration,
+            1.0,
+        ))
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.clone().into_path_stepper()
     }
 }
 
+/// A stepper along a ray over a spherical surface
 pub struct RayStepper<'a> {
-    cur_state: RayState,
-    env: &'a Environment,
-    integrator: RK4Integrator,
+    motion: RayMotion<'a>,
+    core: StepperCore<'a>,
 }
 
 impl<'a> RayStepper<'a> {
-    fn new(state: RayState, env: &'a Environment, step_size: f64) -> Self {
+    fn new(
+        state: RayState,
+        env: EnvironmentRef<'a>,
+        wavelength: f64,
+        integration: Integration,
+        step_size: f64,
+    ) -> Self {
         Self {
-            cur_state: state,
-            env,
-            integrator: RK4Integrator::new(step_size),
+            motion: RayMotion {
+                env: env.clone(),
+                wavelength,
+                integration,
+                integrator: integration.integrator(step_size),
+                hints: AtmosphereHints::default(),
+            },
+            core: StepperCore::new(env, wavelength, state, step_size),
         }
     }
 }
 
+/// The numerical integration of a ray over a spherical surface, performed by a stepper
+pub(crate) struct RayMotion<'a> {
+    env: EnvironmentRef<'a>,
+    wavelength: f64,
+    integration: Integration,
+    integrator: AnyIntegrator,
+    /// The hints of the profiles of the atmosphere, kept between the steps
+    hints: AtmosphereHints,
+}
+
+impl Motion for RayMotion<'_> {
+    fn propagate(&mut self, state: &RayState, len: f64) -> RayState {
+        if let Some(state) = self.integration.vacuum_continuation(state, len) {
+            return state;
+        }
+        let env = &self.env;
+        let wavelength = self.wavelength;
+        let hints = &self.hints;
+        self.integrator.propagate(
+            state,
+            |state| env.calc_derivative_spherical(state, wavelength, hints),
+            StepSize::Step(len),
+        )
+    }
+
+    fn is_outside(&self, state: &RayState) -> bool {
+        self.integration.is_outside(state)
+    }
+
+    fn ground_h(&self) -> f64 {
+        self.integration.ground_altitude.unwrap_or(0.0)
+    }
+}
+
+impl<'a> Stepping<'a> for RayStepper<'a> {
+    type Motion = RayMotion<'a>;
+
+    fn parts(&mut self) -> (&mut StepperCore<'a>, &mut RayMotion<'a>) {
+        (&mut self.core, &mut self.motion)
+    }
+}
+
 impl Iterator for RayStepper<'_> {
-    type Item = RayState;
+    type Item = PathStep;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let env = self.env;
-        self.integrator.propagate_in_place(
-            &mut self.cur_state,
-            |state| env.calc_derivative_spherical(state),
-            StepSize::UseDefault,
+        next_step(self)
+    }
+}
+
+/// The number of intervals in which the distance is tabulated along every segment of an
+/// `InvariantRay`
+const SEGMENT_INTERVALS: usize = 1000;
+
+/// A ray over a spherical surface, traced using the Bouguer invariant.
+///
+/// In a spherically symmetric atmosphere, n·r·cos(angle) is constant along a ray, so the
+/// distance covered by the ray between two altitudes is a quadrature in the altitude alone. The
+/// ray is split at its turning points into segments along which the altitude changes
+/// monotonically, and the distance is tabulated along each of them when the ray is created -
+/// after that, the queries don't integrate anything. A ray trapped between two turning points
+/// just repeats the same segment back and forth.
+///
+/// The turning points are searched for with the step from the options. The ray ends at the
+/// maximum altitude (100 km above the initial point if not set) and at the ground altitude (the
+/// lower of 0 and the initial altitude if not set).
+#[derive(Clone)]
+pub struct InvariantRay<'a> {
+    env: EnvironmentRef<'a>,
+    start_h: f64,
+    start_ang: f64,
+    wavelength: f64,
+    /// The value of n·r·cos(angle) along the ray
+    invariant: f64,
+    ground_altitude: Option<f64>,
+    forward: Box<Trace>,
+    backward: Box<Trace>,
+}
+
+impl InvariantRay<'_> {
+    pub fn from_h_ang<'a>(
+        env: impl Into<EnvironmentRef<'a>>,
+        h: f64,
+        ang: f64,
+        options: &RayOptions,
+    ) -> InvariantRay<'a> {
+        let env = env.into();
+        let radius = env.radius().unwrap();
+        let wavelength = options.wavelength.unwrap_or(env.wavelength);
+        let mut invariant = Invariant {
+            wavelength,
+            radius,
+            value: 0.0,
+            min_h: options.ground_altitude.unwrap_or(h.min(0.0)),
+            max_h: options.max_altitude.unwrap_or(h.max(0.0) + 100e3),
+            step: options.step,
+            env: &env,
+        };
+        invariant.value = invariant.nr(h) * ang.cos();
+        let (forward, backward) = (
+            Trace::new(&invariant, h, ang),
+            // the part of the ray before the initial point is the mirror image of a ray going in
+            // the opposite direction
+            Trace::new(&invariant, h, -ang),
+        );
+        InvariantRay {
+            start_h: h,
+            start_ang: ang,
+            wavelength,
+            invariant: invariant.value,
+            ground_altitude: options.ground_altitude,
+            forward: Box::new(forward),
+            backward: Box::new(backward),
+            env,
+        }
+    }
+
+    fn state_at_dist(&self, dist: f64) -> RayState {
+        let (trace, x, sign) = if dist < 0.0 {
+            (&self.backward, -dist, -1.0)
+        } else {
+            (&self.forward, dist, 1.0)
+        };
+        let (h, ascending) = match trace.locate(x) {
+            Some((segment, x, reversed)) => (
+                segment.h(segment.t_at_x(x)),
+                segment.ascending() != reversed,
+            ),
+            None => {
+                return RayState {
+                    x: dist,
+                    h: f64::NAN,
+                    dh: f64::NAN,
+                }
+            }
+        };
+        let radius = self.env.radius().unwrap();
+        let r = radius + h;
+        let cos_ang =
+            (self.invariant / (self.env.n_dn_at_wavelength(h, self.wavelength).0 * r)).min(1.0);
+        let tan_ang = (1.0 - cos_ang * cos_ang).sqrt() / cos_ang;
+        let dh = if ascending { tan_ang } else { -tan_ang } * r / radius;
+        RayState {
+            x: dist,
+            h,
+            dh: sign * dh,
+        }
+    }
+}
+
+impl<'a> Path<'a> for InvariantRay<'a> {
+    fn start_h(&self) -> f64 {
+        self.start_h
+    }
+
+    fn start_angle(&self) -> f64 {
+        self.start_ang
+    }
+
+    fn wavelength(&self) -> f64 {
+        self.wavelength
+    }
+
+    fn h_at_dist(&self, dist: f64) -> f64 {
+        self.state_at_dist(dist).h
+    }
+
+    fn angle_at_dist(&self, dist: f64) -> f64 {
+        self.state_at_dist(dist).get_angle(&self.env)
+    }
+
+    fn point_at_dist(&self, dist: f64) -> PathPoint {
+        PathPoint::from_state(&self.state_at_dist(dist), &self.env, self.wavelength)
+    }
+
+    fn bending_at_dist(&self, dist: f64) -> f64 {
+        bending(&self.env, self.start_angle(), &self.point_at_dist(dist))
+    }
+
+    fn deviation_at_dist(&self, dist: f64) -> PathDeviation {
+        deviation(self, &self.env, dist)
+    }
+
+    fn arc_length_at_dist(&self, dist: f64) -> f64 {
+        arc_length(self, &self.env, dist)
+    }
+
+    fn optical_length_at_dist(&self, dist: f64) -> f64 {
+        optical_length(self, &self.env, self.wavelength, dist)
+    }
+
+    fn sample(&self, dists: &[f64]) -> Vec<RayState> {
+        dists.iter().map(|&dist| self.state_at_dist(dist)).collect()
+    }
+
+    fn dist_at_h(&self, tgt_h: f64, (min_dist, max_dist): (f64, f64)) -> Vec<f64> {
+        let mut result = self.forward.crossings(tgt_h, max_dist);
+        result.extend(
+            self.backward
+                .crossings(tgt_h, -min_dist)
+                .into_iter()
+                .map(|x| -x),
         );
-        Some(self.cur_state)
+        sort_and_filter(result, (min_dist, max_dist))
+    }
+
+    fn ground_intersection(&self, ground_h: f64, max_dist: f64) -> Option<GroundIntersection> {
+        let dist = if self.start_h < ground_h {
+            0.0
+        } else {
+            self.forward
+                .crossings(ground_h, max_dist)
+                .into_iter()
+                .filter(|&dist| dist <= max_dist)
+                .min_by(|a, b| a.total_cmp(b))?
+        };
+        Some(GroundIntersection {
+            dist,
+            angle: self.angle_at_dist(dist),
+        })
+    }
+
+    fn into_path_stepper(self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        Box::new(InvariantRayStepper::new(self, 1.0))
+    }
+
+    fn path_stepper(&self) -> Box<dyn PathStepper<Item = PathStep> + 'a> {
+        self.clone().into_path_stepper()
+    }
+}
+
+/// The values needed for tracing a ray using the Bouguer invariant
+struct Invariant<'a> {
+    env: &'a Environment,
+    wavelength: f64,
+    radius: f64,
+    /// The value of n·r·cos(angle) along the ray
+    value: f64,
+    min_h: f64,
+    max_h: f64,
+    step: f64,
+}
+
+impl Invariant<'_> {
+    /// Returns n·r at the given altitude
+    fn nr(&self, h: f64) -> f64 {
+        self.env.n_dn_at_wavelength(h, self.wavelength).0 * (self.radius + h)
+    }
+
+    /// Returns the derivative of n·r with respect to the altitude
+    fn dnr(&self, h: f64) -> f64 {
+        let (n, dn) = self.env.n_dn_at_wavelength(h, self.wavelength);
+        n + (self.radius + h) * dn
+    }
+
+    /// Returns the derivative of the distance along the surface with respect to the altitude
+    fn rate(&self, h: f64) -> f64 {
+        let nr = self.nr(h);
+        let r = self.radius + h;
+        self.radius * self.value / (r * (nr * nr - self.value * self.value).max(0.0).sqrt())
+    }
+
+    /// Returns the limit of `rate(h) * sqrt(|h - h_t|)` at the turning point `h_t`
+    fn turning_rate(&self, h_t: f64) -> f64 {
+        let r = self.radius + h_t;
+        self.radius * self.value / (r * (2.0 * self.value * self.dnr(h_t).abs()).sqrt())
+    }
+
+    /// Searches for the turning point of the ray starting from the altitude `h` upwards (if `up`
+    /// is true) or downwards. Returns the altitude of the turning point and true, or the end of
+    /// the ray and false if there is no turning point before it.
+    fn find_turning_point(&self, h: f64, up: bool) -> (f64, bool) {
+        let (limit, step) = if up {
+            (self.max_h, self.step)
+        } else {
+            (self.min_h, -self.step)
+        };
+        if (limit - h) * step <= 0.0 {
+            return (h, false);
+        }
+        let mut h = h;
+        loop {
+            let next = if up {
+                (h + step).min(limit)
+            } else {
+                (h + step).max(limit)
+            };
+            if self.nr(next) < self.value {
+                // bisect, keeping the altitude reached by the ray in `h`
+                let mut beyond = next;
+                loop {
+                    let mid = 0.5 * (h + beyond);
+                    if mid == h || mid == beyond {
+                        return (h, true);
+                    }
+                    if self.nr(mid) < self.value {
+                        beyond = mid;
+                    } else {
+                        h = mid;
+                    }
+                }
+            }
+            if next == limit {
+                return (limit, false);
+            }
+            h = next;
+        }
+    }
+}
+
+/// The part of an invariant ray on one side of the initial point
+#[derive(Clone)]
+struct Trace {
+    /// The segment from the initial point to the first turning point or the end of the ray
+    first: Segment,
+    /// The segment from the first turning point to the next one or to the end of the ray
+    second: Option<Segment>,
+    /// Whether the ray is trapped between two turning points and repeats `second` back and forth
+    trapped: bool,
+}
+
+impl Trace {
+    /// Traces the ray starting at the altitude `h` at the angle `ang`
+    fn new(invariant: &Invariant, h: f64, ang: f64) -> Self {
+        let start_turning = ang == 0.0;
+        // a horizontal ray moves to the altitudes at which n·r is larger
+        let up = if start_turning {
+            invariant.dnr(h) >= 0.0
+        } else {
+            ang > 0.0
+        };
+        let (turning_h, end_turning) = invariant.find_turning_point(h, up);
+        let first = Segment::new(invariant, h, turning_h, start_turning, end_turning);
+        if !end_turning {
+            return Self {
+                first,
+                second: None,
+                trapped: false,
+            };
+        }
+        let (next_h, trapped) = invariant.find_turning_point(turning_h, !up);
+        Self {
+            first,
+            second: Some(Segment::new(invariant, turning_h, next_h, true, trapped)),
+            trapped,
+        }
+    }
+
+    /// Returns the segment containing the point at the distance `x` from the initial point, the
+    /// distance of the point from the beginning of the segment and whether the segment is
+    /// traversed in reverse there; or `None` if the ray ends before the point
+    fn locate(&self, x: f64) -> Option<(&Segment, f64, bool)> {
+        if x <= self.first.len() {
+            return Some((&self.first, x, false));
+        }
+        let second = self.second.as_ref()?;
+        let x = x - self.first.len();
+        let len = second.len();
+        if !self.trapped {
+            return (x <= len).then_some((second, x, false));
+        }
+        if len <= 0.0 {
+            return Some((second, 0.0, false));
+        }
+        let repeats = (x / len).floor();
+        let x = x - repeats * len;
+        if repeats % 2.0 == 0.0 {
+            Some((second, x, false))
+        } else {
+            Some((second, len - x, true))
+        }
+    }
+
+    /// Returns the distances from the initial point at which the ray crosses the altitude `h`,
+    /// including at least all those below `max_x`
+    fn crossings(&self, h: f64, max_x: f64) -> Vec<f64> {
+        let mut result: Vec<f64> = self.first.x_at_h(h).into_iter().collect();
+        let second = match &self.second {
+            Some(second) => second,
+            None => return result,
+        };
+        if let Some(x) = second.x_at_h(h) {
+            let len = second.len();
+            let mut start = self.first.len();
+            if !self.trapped {
+                result.push(start + x);
+            } else if len > 0.0 {
+                let mut reversed = false;
+                while start <= max_x {
+                    result.push(start + if reversed { len - x } else { x });
+                    start += len;
+                    reversed = !reversed;
+                }
+            }
+        }
+        result
     }
+
+    /// Returns the distance from the initial point to the end of the ray (infinite for trapped
+    /// rays)
+    fn len(&self) -> f64 {
+        match &self.second {
+            _ if self.trapped => f64::INFINITY,
+            Some(second) => self.first.len() + second.len(),
+            None => self.first.len(),
+        }
+    }
+}
+
+/// A part of an invariant ray along which the altitude changes monotonically.
+///
+/// The altitude is parametrized by `t` in [0, 1] in a way which makes the distance a smooth
+/// function of `t` even at the turning points, where its derivative with respect to the altitude
+/// is infinite. Segments which don't start at a turning point are sampled more densely near the
+/// start, where the ray may still be close to horizontal.
+#[derive(Clone)]
+struct Segment {
+    h_start: f64,
+    h_end: f64,
+    start_turning: bool,
+    end_turning: bool,
+    /// The distances along the surface from the beginning of the segment at t = i / N
+    x: Vec<f64>,
+    /// The derivatives of the distances with respect to `t` at t = i / N
+    dx: Vec<f64>,
 }
 
-impl PathStepper for RayStepper<'_> {
-    fn set_step_size(&mut self, step: f64) {
-        self.integrator.set_default_step(step);
+impl Segment {
+    fn new(
+        invariant: &Invariant,
+        h_start: f64,
+        h_end: f64,
+        start_turning: bool,
+        end_turning: bool,
+    ) -> Self {
+        let mut segment = Segment {
+            h_start,
+            h_end,
+            start_turning,
+            end_turning,
+            x: vec![],
+            dx: vec![],
+        };
+        let rate = |t: f64| invariant.rate(segment.h(t)) * segment.dh_dt(t).abs();
+        // the limits of the rate at the turning points
+        let span = (h_end - h_start).abs();
+        let factor = if start_turning && end_turning {
+            PI
+        } else {
+            PI / 2.0f64.sqrt()
+        };
+        let dt = 1.0 / SEGMENT_INTERVALS as f64;
+        // the nodes of the 2-point Gauss-Legendre quadrature within an interval
+        let gauss = [0.5 - 0.5 / 3.0f64.sqrt(), 0.5 + 0.5 / 3.0f64.sqrt()];
+
+        let mut x = 0.0;
+        let mut dx = Vec::with_capacity(SEGMENT_INTERVALS + 1);
+        let mut xs = Vec::with_capacity(SEGMENT_INTERVALS + 1);
+        for i in 0..=SEGMENT_INTERVALS {
+            let t = i as f64 * dt;
+            dx.push(if i == 0 && start_turning {
+                invariant.turning_rate(h_start) * factor * span.sqrt()
+            } else if i == SEGMENT_INTERVALS && end_turning {
+                invariant.turning_rate(h_end) * factor * span.sqrt()
+            } else {
+                rate(t)
+            });
+            xs.push(x);
+            if i < SEGMENT_INTERVALS {
+                x += 0.5 * dt * gauss.iter().map(|g| rate(t + g * dt)).sum::<f64>();
+            }
+        }
+        segment.x = xs;
+        segment.dx = dx;
+        segment
+    }
+
+    /// Returns the fraction of the altitude change along the segment at `t`, and its derivative
+    fn fraction(&self, t: f64) -> (f64, f64) {
+        let half_pi = 0.5 * PI;
+        match (self.start_turning, self.end_turning) {
+            (false, false) => (t * t, 2.0 * t),
+            (false, true) => ((half_pi * t).sin(), half_pi * (half_pi * t).cos()),
+            (true, false) => (1.0 - (half_pi * t).cos(), half_pi * (half_pi * t).sin()),
+            (true, true) => (0.5 - 0.5 * (PI * t).cos(), half_pi * (PI * t).sin()),
+        }
+    }
+
+    fn h(&self, t: f64) -> f64 {
+        self.h_start + (self.h_end - self.h_start) * self.fraction(t).0
+    }
+
+    fn dh_dt(&self, t: f64) -> f64 {
+        (self.h_end - self.h_start) * self.fraction(t).1
+    }
+
+    fn t_at_h(&self, h: f64) -> f64 {
+        let s = ((h - self.h_start) / (self.h_end - self.h_start)).clamp(0.0, 1.0);
+        match (self.start_turning, self.end_turning) {
+            (false, false) => s.sqrt(),
+            (false, true) => s.asin() * 2.0 / PI,
+            (true, false) => (1.0 - s).acos() * 2.0 / PI,
+            (true, true) => (1.0 - 2.0 * s).acos() / PI,
+        }
+    }
+
+    fn ascending(&self) -> bool {
+        self.h_end > self.h_start
+    }
+
+    fn len(&self) -> f64 {
+        self.x[SEGMENT_INTERVALS]
+    }
+
+    /// Returns the distance from the beginning of the segment at which it crosses the altitude
+    /// `h`, if it does
+    fn x_at_h(&self, h: f64) -> Option<f64> {
+        let (min_h, max_h) = if self.ascending() {
+            (self.h_start, self.h_end)
+        } else {
+            (self.h_end, self.h_start)
+        };
+        (min_h..=max_h)
+            .contains(&h)
+            .then(|| self.x_at_t(self.t_at_h(h)))
+    }
+
+    /// Interpolates the distance within the interval `i` with a cubic Hermite polynomial
+    fn interpolate(&self, i: usize, s: f64) -> f64 {
+        let dt = 1.0 / SEGMENT_INTERVALS as f64;
+        let s2 = s * s;
+        let s3 = s2 * s;
+        (2.0 * s3 - 3.0 * s2 + 1.0) * self.x[i]
+            + (s3 - 2.0 * s2 + s) * dt * self.dx[i]
+            + (3.0 * s2 - 2.0 * s3) * self.x[i + 1]
+            + (s3 - s2) * dt * self.dx[i + 1]
+    }
+
+    fn x_at_t(&self, t: f64) -> f64 {
+        let pos = t * SEGMENT_INTERVALS as f64;
+        let i = (pos.floor() as usize).min(SEGMENT_INTERVALS - 1);
+        self.interpolate(i, pos - i as f64)
+    }
+
+    fn t_at_x(&self, x: f64) -> f64 {
+        let i = self
+            .x
+            .partition_point(|&node_x| node_x <= x)
+            .clamp(1, SEGMENT_INTERVALS)
+            - 1;
+        let (mut min_s, mut max_s) = (0.0, 1.0);
+        for _ in 0..60 {
+            let mid = 0.5 * (min_s + max_s);
+            if self.interpolate(i, mid) < x {
+                min_s = mid;
+            } else {
+                max_s = mid;
+            }
+        }
+        (i as f64 + 0.5 * (min_s + max_s)) / SEGMENT_INTERVALS as f64
+    }
+}
+
+/// A stepper along a ray traced using the Bouguer invariant
+pub struct InvariantRayStepper<'a> {
+    ray: InvariantRay<'a>,
+    core: StepperCore<'a>,
+}
+
+impl<'a> InvariantRayStepper<'a> {
+    fn new(ray: InvariantRay<'a>, step: f64) -> Self {
+        let state = ray.state_at_dist(0.0);
+        Self {
+            core: StepperCore::new(ray.env.clone(), ray.wavelength, state, step),
+            ray,
+        }
+    }
+}
+
+impl Motion for InvariantRay<'_> {
+    fn propagate(&mut self, state: &RayState, len: f64) -> RayState {
+        let dist = state.x + len;
+        let end = self.forward.len();
+        if dist <= end {
+            return self.state_at_dist(dist);
+        }
+        // continue straight beyond the end of the ray, so that the steppers find where the
+        // limiting altitude is crossed
+        let end_state = self.state_at_dist(end);
+        RayState {
+            x: dist,
+            h: end_state.h + end_state.dh * (dist - end),
+            dh: end_state.dh,
+        }
+    }
+
+    fn is_outside(&self, state: &RayState) -> bool {
+        let end = self.forward.len();
+        state.x > end
+    }
+
+    fn ground_h(&self) -> f64 {
+        self.ground_altitude.unwrap_or(0.0)
+    }
+}
+
+impl<'a> Stepping<'a> for InvariantRayStepper<'a> {
+    type Motion = InvariantRay<'a>;
+
+    fn parts(&mut self) -> (&mut StepperCore<'a>, &mut InvariantRay<'a>) {
+        (&mut self.core, &mut self.ray)
+    }
+}
+
+impl Iterator for InvariantRayStepper<'_> {
+    type Item = PathStep;
+
+    fn next(&mut self) -> Option<PathStep> {
+        next_step(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_support::ducting_env;
+    use crate::{IntegratorKind, Path, RayOptions};
+
+    #[test]
+    fn test_fast_ducted_rays() {
+        let env = ducting_env();
+        let precise = RayOptions {
+            step: 1.0,
+            integrator: IntegratorKind::RungeKutta8,
+            ..Default::default()
+        };
+        let invariant = RayOptions {
+            integrator: IntegratorKind::BouguerInvariant,
+            ..Default::default()
+        };
+        let arcs = RayOptions {
+            integrator: IntegratorKind::CircularArcs,
+            step: 1.0,
+            ..Default::default()
+        };
+        let precise = env.cast_ray_with(120.0, 0.001, &precise);
+        let fast = env.cast_ray_with(120.0, 0.001, &invariant);
+        let arcs = env.cast_ray_with(120.0, 0.001, &arcs);
+        for &dist in &[-10e3, 5e3, 20e3] {
+            assert!((fast.h_at_dist(dist) - precise.h_at_dist(dist)).abs() < 1e-2);
+            assert!((arcs.h_at_dist(dist) - precise.h_at_dist(dist)).abs() < 2e-2);
+        }
+        // the trapped ray keeps oscillating within the duct
+        let h = fast.h_at_dist(1000e3);
+        assert!(h > 0.0 && h < 150.0);
+        let crossings = fast.dist_at_h(120.0, (0.0, 300e3));
+        assert!(crossings.len() > 4);
+        for dist in crossings {
+            assert!((fast.h_at_dist(dist) - 120.0).abs() < 1e-6);
+        }
     }
 }
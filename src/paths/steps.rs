@@ -0,0 +1,475 @@
+//! The steps produced by the path steppers
+
+use super::{
+    length_element,
+    options::{positive_or, CROSSING_EPSILON},
+    PathPoint, PathStepper, RayOptions,
+};
+use crate::{EnvironmentRef, RayState};
+use std::collections::VecDeque;
+
+/// A point reached by a stepper, with the values accumulated along the path up to it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathStep {
+    /// The distance from the initial point in meters
+    pub dist: f64,
+    /// The altitude in meters
+    pub h: f64,
+    /// The angle (in radians) between the path and the horizontal plane
+    pub angle: f64,
+    /// The refractive index of the air
+    pub n: f64,
+    /// The derivative of the altitude with respect to the distance, measured along the surface
+    pub dh: f64,
+    /// The geometric length (in meters) of the path from the initial point
+    pub arc_length: f64,
+    /// The total bending of the path (in radians) from the initial point - see
+    /// `Path::bending_at_dist`
+    pub bending: f64,
+    /// The event that happens at this point, if the step has been inserted by the stepper to
+    /// report one; `None` for the regular steps
+    pub event: Option<StepEvent>,
+}
+
+impl PathStep {
+    /// Returns the state of the path at this point.
+    pub fn state(&self) -> RayState {
+        RayState {
+            x: self.dist,
+            h: self.h,
+            dh: self.dh,
+        }
+    }
+}
+
+/// An event on a path that a stepper can be asked to report
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StepEvent {
+    /// The path crosses the given altitude in meters
+    AltitudeCrossing(f64),
+    /// The path hits the ground - the path ends here
+    GroundHit,
+    /// The path reaches its highest point and starts descending
+    Apex,
+}
+
+/// Accumulates the arc length and the bending along the states reached by a stepper, using the
+/// trapezoidal rule
+#[derive(Clone)]
+struct StepAccumulator<'a> {
+    env: EnvironmentRef<'a>,
+    wavelength: f64,
+    start_angle: f64,
+    prev_state: RayState,
+    arc_length: f64,
+    surface_rotation: f64,
+}
+
+impl<'a> StepAccumulator<'a> {
+    fn new(env: EnvironmentRef<'a>, wavelength: f64, start: &RayState) -> Self {
+        Self {
+            start_angle: start.get_angle(&env),
+            env,
+            wavelength,
+            prev_state: *start,
+            arc_length: 0.0,
+            surface_rotation: 0.0,
+        }
+    }
+
+    /// Accumulates the values up to the state `state` and returns the step describing it
+    fn step(&mut self, state: &RayState, event: Option<StepEvent>) -> PathStep {
+        let env = &*self.env;
+        let prev = &self.prev_state;
+        let dx = state.x - prev.x;
+        self.arc_length += 0.5 * dx * (length_element(env, prev) + length_element(env, state));
+        self.surface_rotation += 0.5 * dx * (env.curvature_at(prev.x) + env.curvature_at(state.x));
+        self.prev_state = *state;
+
+        let point = PathPoint::from_state(state, env, self.wavelength);
+        PathStep {
+            dist: point.dist,
+            h: point.h,
+            angle: point.angle,
+            n: point.n,
+            dh: point.dh,
+            arc_length: self.arc_length,
+            bending: self.start_angle - point.angle + self.surface_rotation,
+            event,
+        }
+    }
+}
+
+/// Turns the states reached by a stepper into the steps it yields, starting with the initial one
+/// and inserting the steps at which the requested events happen
+pub(crate) struct StepOutput<'a> {
+    steps: StepAccumulator<'a>,
+    altitudes: Vec<f64>,
+    ground_h: Option<f64>,
+    apex: bool,
+    queue: VecDeque<(RayState, Option<StepEvent>)>,
+    finished: bool,
+}
+
+impl<'a> StepOutput<'a> {
+    pub fn new(env: EnvironmentRef<'a>, wavelength: f64, start: &RayState) -> Self {
+        Self {
+            steps: StepAccumulator::new(env, wavelength, start),
+            altitudes: vec![],
+            ground_h: None,
+            apex: false,
+            queue: VecDeque::from([(*start, None)]),
+            finished: false,
+        }
+    }
+
+    pub fn on_altitude_crossing(&mut self, h: f64) {
+        self.altitudes.push(h);
+    }
+
+    pub fn on_ground_hit(&mut self, ground_h: f64) {
+        self.ground_h = Some(ground_h);
+    }
+
+    pub fn on_apex(&mut self) {
+        self.apex = true;
+    }
+
+    /// Returns whether the path has ended, so no more states should be pushed
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns the next step waiting to be yielded
+    pub fn pop(&mut self) -> Option<PathStep> {
+        self.queue
+            .pop_front()
+            .map(|(state, event)| self.steps.step(&state, event))
+    }
+
+    /// Returns the distance of the next step waiting to be yielded
+    fn next_dist(&self) -> Option<f64> {
+        self.queue.front().map(|(state, _)| state.x)
+    }
+
+    /// Returns the state of the last yielded step
+    fn last_state(&self) -> RayState {
+        self.steps.prev_state
+    }
+
+    /// Yields a step at the given state, ahead of the queued ones - it must lie before them
+    fn insert(&mut self, state: &RayState) -> PathStep {
+        self.steps.step(state, None)
+    }
+
+    /// Queues the step from `prev` to `next`, preceded by the events happening within it.
+    /// `propagate(len)` has to return the state at the distance `len` from `prev`. If `last` is
+    /// true, the path ends at `next` and only the events before it are queued.
+    pub fn push<F>(&mut self, prev: &RayState, next: &RayState, last: bool, mut propagate: F)
+    where
+        F: FnMut(f64) -> RayState,
+    {
+        let len = next.x - prev.x;
+        let mut events = vec![];
+        for &h in &self.altitudes {
+            if next.h == h || (prev.h - h) * (next.h - h) < 0.0 {
+                let below_at_start = prev.h < h;
+                let state =
+                    refine_event(len, &mut propagate, |state| (state.h < h) == below_at_start);
+                events.push((state, StepEvent::AltitudeCrossing(h)));
+            }
+        }
+        if let Some(ground_h) = self.ground_h {
+            if prev.h >= ground_h && next.h < ground_h {
+                let state = refine_event(len, &mut propagate, |state| state.h >= ground_h);
+                events.push((state, StepEvent::GroundHit));
+            }
+        }
+        if self.apex && prev.dh > 0.0 && next.dh <= 0.0 {
+            let state = refine_event(len, &mut propagate, |state| state.dh > 0.0);
+            events.push((state, StepEvent::Apex));
+        }
+        events.sort_by(|(state1, _), (state2, _)| state1.x.total_cmp(&state2.x));
+
+        for (state, event) in events {
+            self.queue.push_back((state, Some(event)));
+            if event == StepEvent::GroundHit {
+                self.finished = true;
+                return;
+            }
+        }
+        if last {
+            self.finished = true;
+        } else {
+            self.queue.push_back((*next, None));
+        }
+    }
+}
+
+/// Finds the state at which an event happens within a step of the length `len`, by bisection.
+/// `at_start(state)` has to return true for the states before the event.
+fn refine_event<F, G>(len: f64, propagate: &mut F, at_start: G) -> RayState
+where
+    F: FnMut(f64) -> RayState,
+    G: Fn(&RayState) -> bool,
+{
+    let (mut min_len, mut max_len) = (0.0, len);
+    while (max_len - min_len).abs() > CROSSING_EPSILON {
+        let mid_len = 0.5 * (min_len + max_len);
+        if at_start(&propagate(mid_len)) {
+            min_len = mid_len;
+        } else {
+            max_len = mid_len;
+        }
+    }
+    propagate(0.5 * (min_len + max_len))
+}
+
+/// The way in which a stepper moves along its path
+pub(crate) trait Motion {
+    /// Returns the state at the distance `len` (in meters) from `state`
+    fn propagate(&mut self, state: &RayState, len: f64) -> RayState;
+
+    /// Returns whether the path ends before reaching `state`
+    fn is_outside(&self, _state: &RayState) -> bool {
+        false
+    }
+
+    /// Returns the altitude of the ground in meters
+    fn ground_h(&self) -> f64 {
+        0.0
+    }
+}
+
+/// The shortest step in meters taken by the steppers with adaptive steps
+const MIN_ADAPTIVE_STEP: f64 = 1e-3;
+
+/// The part of a stepper common to all kinds of paths
+pub(crate) struct StepperCore<'a> {
+    state: RayState,
+    step: f64,
+    on_grid: bool,
+    tolerance: Option<f64>,
+    adaptive_step: f64,
+    output: StepOutput<'a>,
+}
+
+impl<'a> StepperCore<'a> {
+    pub fn new(env: EnvironmentRef<'a>, wavelength: f64, state: RayState, step: f64) -> Self {
+        Self {
+            state,
+            step,
+            on_grid: false,
+            tolerance: None,
+            adaptive_step: step,
+            output: StepOutput::new(env, wavelength, &state),
+        }
+    }
+
+    /// Returns the distance at which the next regular step should land, not farther than `limit`
+    fn next_target<M: Motion>(&mut self, motion: &mut M, limit: f64) -> f64 {
+        let target = if self.on_grid {
+            // a small margin so that a state lying on the grid isn't repeated due to rounding
+            let index = (self.state.x / self.step + 1e-9).floor() + 1.0;
+            index * self.step
+        } else {
+            self.state.x + self.step
+        };
+        match self.tolerance {
+            Some(tolerance) => self.adapt_target(motion, target.min(limit), tolerance),
+            None => target.min(limit),
+        }
+    }
+
+    /// Shortens the step towards `max_target` until the difference between the altitudes reached
+    /// in one step and in two half-steps is within the tolerance, and adjusts the length for the
+    /// next steps accordingly
+    fn adapt_target<M: Motion>(&mut self, motion: &mut M, max_target: f64, tolerance: f64) -> f64 {
+        loop {
+            let max_len = max_target - self.state.x;
+            let len = self.adaptive_step.min(max_len);
+            let full = motion.propagate(&self.state, len);
+            let half = motion.propagate(&self.state, 0.5 * len);
+            let halves = motion.propagate(&half, 0.5 * len);
+            // the error of the slope shows up in the altitude farther along the path
+            let error = (full.h - halves.h).abs() + (full.dh - halves.dh).abs() * len;
+            let factor = if error > 0.0 {
+                0.9 * (tolerance / error).powf(0.2)
+            } else {
+                5.0
+            };
+            if error <= tolerance || len <= MIN_ADAPTIVE_STEP {
+                if len == self.adaptive_step {
+                    self.adaptive_step =
+                        (len * factor.min(5.0)).clamp(MIN_ADAPTIVE_STEP, self.step);
+                }
+                return self.state.x + len;
+            }
+            self.adaptive_step = (len * factor.max(0.1)).max(MIN_ADAPTIVE_STEP);
+        }
+    }
+
+    /// Moves the stepper to exactly the distance `target` and queues the resulting steps; returns
+    /// false if the path has already ended
+    fn advance<M: Motion>(&mut self, motion: &mut M, target: f64) -> bool {
+        if self.output.is_finished() || motion.is_outside(&self.state) {
+            return false;
+        }
+        let prev = self.state;
+        self.state = motion.propagate(&prev, target - prev.x);
+        self.state.x = target;
+        let outside = motion.is_outside(&self.state);
+        self.output.push(&prev, &self.state, outside, |len| {
+            motion.propagate(&prev, len)
+        });
+        true
+    }
+
+    fn next<M: Motion>(&mut self, motion: &mut M) -> Option<PathStep> {
+        if let Some(step) = self.output.pop() {
+            return Some(step);
+        }
+        let target = self.next_target(motion, f64::INFINITY);
+        if !self.advance(motion, target) {
+            return None;
+        }
+        self.output.pop()
+    }
+
+    fn run_to_dist<M: Motion>(&mut self, motion: &mut M, dist: f64) -> Option<PathStep> {
+        if dist.is_nan() || dist < self.output.last_state().x {
+            // the steppers only move forward
+            return None;
+        }
+        let mut last = None;
+        while self.output.next_dist().is_some_and(|x| x <= dist) {
+            last = self.output.pop();
+        }
+        if self.output.next_dist().is_some() {
+            // the distance lies between the last yielded step and the queued ones
+            if last.is_some_and(|step| step.dist == dist) {
+                return last;
+            }
+            let from = self.output.last_state();
+            let mut state = motion.propagate(&from, dist - from.x);
+            state.x = dist;
+            return Some(self.output.insert(&state));
+        }
+        while self.state.x < dist {
+            let target = self.next_target(motion, dist);
+            if !self.advance(motion, target) {
+                return None;
+            }
+            while let Some(step) = self.output.pop() {
+                last = Some(step);
+            }
+            if self.output.is_finished() {
+                return None;
+            }
+        }
+        last
+    }
+}
+
+/// A stepper consisting of the common part and a motion along a specific kind of path
+pub(crate) trait Stepping<'a> {
+    type Motion: Motion;
+
+    fn parts(&mut self) -> (&mut StepperCore<'a>, &mut Self::Motion);
+}
+
+impl<'a, T> PathStepper for T
+where
+    T: Stepping<'a> + Iterator<Item = PathStep> + Send + Sync,
+{
+    fn set_step_size(&mut self, step: f64) {
+        let core = self.parts().0;
+        core.step = positive_or(step, RayOptions::default().step);
+        core.on_grid = false;
+    }
+
+    fn step_to_multiple_of(&mut self, dx: f64) {
+        let core = self.parts().0;
+        core.step = positive_or(dx, RayOptions::default().step);
+        core.on_grid = true;
+    }
+
+    fn set_tolerance(&mut self, tolerance: f64) {
+        let core = self.parts().0;
+        core.tolerance = Some(positive_or(tolerance, RayOptions::default().tolerance));
+        core.adaptive_step = core.step;
+    }
+
+    fn on_altitude_crossing(&mut self, h: f64) {
+        self.parts().0.output.on_altitude_crossing(h);
+    }
+
+    fn on_ground_hit(&mut self) {
+        let (core, motion) = self.parts();
+        core.output.on_ground_hit(motion.ground_h());
+    }
+
+    fn on_apex(&mut self) {
+        self.parts().0.output.on_apex();
+    }
+
+    fn run_to_dist(&mut self, dist: f64) -> Option<PathStep> {
+        let (core, motion) = self.parts();
+        core.run_to_dist(motion, dist)
+    }
+}
+
+/// Yields the next step of a stepper
+pub(crate) fn next_step<'a, T: Stepping<'a>>(stepper: &mut T) -> Option<PathStep> {
+    let (core, motion) = stepper.parts();
+    core.next(motion)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_support::ducting_env;
+    use crate::{IntegratorKind, Path, RayOptions};
+
+    #[test]
+    fn test_adaptive_stepper() {
+        let env = ducting_env();
+        let precise = RayOptions {
+            step: 1.0,
+            integrator: IntegratorKind::RungeKutta8,
+            ..Default::default()
+        };
+        let expected = env.cast_ray_with(90.0, 0.002, &precise).h_at_dist(30e3);
+
+        let mut stepper = env.cast_ray_stepper(90.0, 0.002, false);
+        stepper.set_step_size(1000.0);
+        stepper.set_tolerance(1e-8);
+        let steps: Vec<_> = stepper.take_while(|step| step.dist < 30e3).collect();
+        let lengths: Vec<f64> = steps.windows(2).map(|w| w[1].dist - w[0].dist).collect();
+        // short steps in the inversion, long ones outside of it
+        assert!(lengths.iter().any(|&len| len < 100.0));
+        assert!(lengths.iter().any(|&len| len > 900.0));
+        assert!(steps.len() < 1000);
+
+        let mut stepper = env.cast_ray_stepper(90.0, 0.002, false);
+        stepper.set_step_size(1000.0);
+        stepper.set_tolerance(1e-8);
+        let h = stepper.run_to_dist(30e3).unwrap().h;
+        assert!((h - expected).abs() < 2e-3);
+        // the distances already passed can't be reached again
+        assert_eq!(stepper.run_to_dist(20e3), None);
+        assert_eq!(stepper.run_to_dist(f64::NAN), None);
+        assert!(stepper.run_to_dist(31e3).is_some());
+
+        // an invalid tolerance is replaced with the default one instead of stalling the stepper
+        let run_with_tolerance = |tolerance| {
+            let mut stepper = env.cast_ray_stepper(90.0, 0.002, false);
+            stepper.set_step_size(1000.0);
+            stepper.set_tolerance(tolerance);
+            stepper.run_to_dist(30e3).unwrap()
+        };
+        let default = run_with_tolerance(RayOptions::default().tolerance);
+        for tolerance in [0.0, -1.0, f64::NAN] {
+            assert_eq!(run_with_tolerance(tolerance), default);
+        }
+    }
+}
@@ -14,7 +14,7 @@ impl RayState {
         if let Some(r) = env.radius() {
             (self.dh * r / (self.h + r)).atan()
         } else {
-            self.dh.atan()
+            (self.dh / (1.0 + env.curvature_at(self.x) * self.h)).atan()
         }
     }
 }
@@ -0,0 +1,338 @@
+#[cfg(not(feature = "rayon"))]
+use crate::Path;
+use crate::{
+    interpolate_state, sample_range, Environment, RayOptions, RayState, SamplingError,
+    TransferCurve,
+};
+
+/// A fan of rays launched by an observer at regularly spaced angles, with the states of each of
+/// them sampled at regularly spaced distances - a precomputed table for finding the rays
+/// passing through arbitrary points, as needed for rendering refracted images.
+///
+/// The states between the samples are interpolated with cubic Hermite polynomials in the
+/// distance and linearly in the angle.
+#[derive(Clone, Debug)]
+pub struct RayTable {
+    observer_h: f64,
+    angles: Vec<f64>,
+    dists: Vec<f64>,
+    states: Vec<Vec<RayState>>,
+}
+
+/// A point where two neighbouring rays of a fan cross - a point of a caustic, where the rays
+/// focus and the derivative of the altitude with respect to the initial angle changes sign
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct Caustic {
+    /// The initial angles (in radians) of the crossing rays
+    pub angles: (f64, f64),
+    /// The distance of the crossing in meters
+    pub dist: f64,
+    /// The altitude of the crossing in meters
+    pub h: f64,
+}
+
+impl RayTable {
+    /// Returns the altitude of the observer in meters.
+    pub fn observer_h(&self) -> f64 {
+        self.observer_h
+    }
+
+    /// Returns the initial angles of the rays in radians, in ascending order.
+    pub fn angles(&self) -> &[f64] {
+        &self.angles
+    }
+
+    /// Returns the distances at which the rays are sampled, in ascending order.
+    pub fn dists(&self) -> &[f64] {
+        &self.dists
+    }
+
+    /// Returns the state of the ray with the given index at the distance `dist`, or `None` if the
+    /// distance is outside of the table or the ray doesn't reach it.
+    pub fn state(&self, ray: usize, dist: f64) -> Option<RayState> {
+        let states = self.states.get(ray)?;
+        let first = *self.dists.first()?;
+        let last = *self.dists.last()?;
+        if dist < first || dist > last {
+            return None;
+        }
+        let i = self
+            .dists
+            .partition_point(|&x| x <= dist)
+            .clamp(1, self.dists.len() - 1);
+        let state = interpolate_state(&states[i - 1], &states[i], dist);
+        (!state.h.is_nan()).then_some(state)
+    }
+
+    /// Returns the transfer curve at the distance `dist` - the altitudes reached there by the
+    /// rays, as functions of their initial angles.
+    pub fn transfer_curve(&self, dist: f64) -> TransferCurve {
+        let heights = (0..self.angles.len())
+            .map(|ray| self.state(ray, dist).map_or(f64::NAN, |state| state.h))
+            .collect();
+        TransferCurve::new(dist, self.angles.clone(), heights)
+    }
+
+    /// Returns the altitude at the distance `dist` of the ray launched at the angle `angle`,
+    /// interpolated between the rays in the table, or `None` if it's outside of the table or the
+    /// neighbouring rays don't reach the distance.
+    pub fn h_at(&self, angle: f64, dist: f64) -> Option<f64> {
+        let first = *self.angles.first()?;
+        let last = *self.angles.last()?;
+        if angle < first || angle > last {
+            return None;
+        }
+        if self.angles.len() == 1 {
+            return self.state(0, dist).map(|state| state.h);
+        }
+        let i = self
+            .angles
+            .partition_point(|&ang| ang <= angle)
+            .clamp(1, self.angles.len() - 1);
+        let h1 = self.state(i - 1, dist)?.h;
+        let h2 = self.state(i, dist)?.h;
+        let t = (angle - self.angles[i - 1]) / (self.angles[i] - self.angles[i - 1]);
+        Some(h1 + t * (h2 - h1))
+    }
+
+    /// Returns the initial angles (in radians, in ascending order) of the rays passing through
+    /// the point at the distance `dist` and the altitude `h`.
+    pub fn rays_through(&self, dist: f64, h: f64) -> Vec<f64> {
+        self.transfer_curve(dist).apparent_angles(h)
+    }
+
+    /// Returns the points where the neighbouring rays in the table cross, sorted by the initial
+    /// angles and then by the distance - together, they trace the caustics of the fan.
+    ///
+    /// The crossings are found between the sampled distances, by linear interpolation of the
+    /// difference between the altitudes of the rays; rays crossing more than once between two
+    /// samples can be missed.
+    pub fn caustics(&self) -> Vec<Caustic> {
+        let mut result = vec![];
+        for (k, pair) in self.states.windows(2).enumerate() {
+            let angles = (self.angles[k], self.angles[k + 1]);
+            let diffs: Vec<f64> = pair[0]
+                .iter()
+                .zip(&pair[1])
+                .map(|(lower, upper)| upper.h - lower.h)
+                .collect();
+            for i in 1..diffs.len() {
+                let (diff1, diff2) = (diffs[i - 1], diffs[i]);
+                // all the rays start at the same point, which isn't a crossing
+                if diff1 == 0.0
+                    || diff1.is_nan()
+                    || diff2.is_nan()
+                    || (diff1 > 0.0) == (diff2 > 0.0)
+                {
+                    continue;
+                }
+                let t = diff1 / (diff1 - diff2);
+                let dist = self.dists[i - 1] + t * (self.dists[i] - self.dists[i - 1]);
+                let (Some(lower), Some(upper)) = (self.state(k, dist), self.state(k + 1, dist))
+                else {
+                    continue;
+                };
+                result.push(Caustic {
+                    angles,
+                    dist,
+                    h: 0.5 * (lower.h + upper.h),
+                });
+            }
+        }
+        result
+    }
+
+    /// Returns the apparent elevation angles (in radians, in ascending order) at which the
+    /// observer sees each point of a terrain profile - for example, one column of a panorama.
+    ///
+    /// The profile consists of pairs of distances and altitudes in meters, sorted by the
+    /// distance. A point can be seen at several angles during mirages, or not at all if it's
+    /// hidden - either below the horizon, or behind the points of the profile closer to the
+    /// observer.
+    pub fn warp_column(&self, profile: &[(f64, f64)]) -> Vec<Vec<f64>> {
+        profile
+            .iter()
+            .enumerate()
+            .map(|(i, &(dist, h))| {
+                self.rays_through(dist, h)
+                    .into_iter()
+                    .filter(|&angle| {
+                        profile[..i].iter().all(|&(dist, terrain_h)| {
+                            self.h_at(angle, dist).is_some_and(|h| h >= terrain_h)
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Environment {
+    /// Traces rays launched from the altitude `observer_h` every `resolution` radians between
+    /// the ends of `angle_range`, and samples each of them every `dist_step` meters up to the
+    /// distance `max_dist`.
+    ///
+    /// The options set the integration of the rays; set the ground altitude in them to stop the
+    /// rays hitting the ground. With the `rayon` feature, the rays are traced in parallel.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the angles or the distances can't be sampled; see `try_ray_table`.
+    pub fn ray_table(
+        &self,
+        observer_h: f64,
+        angle_range: (f64, f64),
+        resolution: f64,
+        max_dist: f64,
+        dist_step: f64,
+        options: &RayOptions,
+    ) -> RayTable {
+        match self.try_ray_table(
+            observer_h,
+            angle_range,
+            resolution,
+            max_dist,
+            dist_step,
+            options,
+        ) {
+            Ok(table) => table,
+            Err(error) => panic!("invalid ray table parameters: {:?}", error),
+        }
+    }
+
+    /// Traces a fan of rays like `ray_table`, or returns an error if the angles or the distances
+    /// can't be sampled.
+    ///
+    /// The angle range has to be finite with the lower end first, `max_dist` finite and
+    /// non-negative, and `resolution` and `dist_step` finite and positive.
+    pub fn try_ray_table(
+        &self,
+        observer_h: f64,
+        angle_range: (f64, f64),
+        resolution: f64,
+        max_dist: f64,
+        dist_step: f64,
+        options: &RayOptions,
+    ) -> Result<RayTable, SamplingError> {
+        let angles = sample_range(angle_range.0, angle_range.1, resolution)?;
+        let dists = sample_range(0.0, max_dist, dist_step)?;
+
+        #[cfg(feature = "rayon")]
+        let states = self.sample_ray_fan(observer_h, &angles, &dists, options);
+        #[cfg(not(feature = "rayon"))]
+        let states = angles
+            .iter()
+            .map(|&ang| self.cast_ray_with(observer_h, ang, options).sample(&dists))
+            .collect();
+
+        Ok(RayTable {
+            observer_h,
+            angles,
+            dists,
+            states,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::inversion_env;
+
+    #[test]
+    fn test_ray_table() {
+        let flat = Environment::with_k_factor(6_378_000.0, 1.0);
+        let options = RayOptions {
+            ground_altitude: Some(0.0),
+            ..Default::default()
+        };
+        let table = flat.ray_table(10.0, (-0.01, 0.01), 1e-4, 2e3, 100.0, &options);
+        assert_eq!(table.angles().len(), 201);
+        assert_eq!(table.dists().len(), 21);
+
+        let state = table.state(150, 1250.0).unwrap();
+        let angle = table.angles()[150];
+        assert!((state.h - (10.0 + 1250.0 * angle.tan())).abs() < 1e-6);
+        // the steepest ray hits the ground after 1 km
+        assert!(table.state(0, 1500.0).is_none());
+        assert!(table.state(0, 2500.0).is_none());
+
+        let rays = table.rays_through(1500.0, 20.0);
+        assert_eq!(rays.len(), 1);
+        assert!((rays[0] - (10.0f64 / 1500.0).atan()).abs() < 1e-6);
+        assert!(table.rays_through(1500.0, 100.0).is_empty());
+
+        let try_table = |angle_range, resolution, max_dist, dist_step| {
+            flat.try_ray_table(10.0, angle_range, resolution, max_dist, dist_step, &options)
+                .err()
+        };
+        assert_eq!(
+            try_table((-0.01, 0.01), 0.0, 2e3, 100.0),
+            Some(SamplingError::InvalidResolution(0.0))
+        );
+        assert_eq!(
+            try_table((-0.01, 0.01), 1e-4, 2e3, f64::INFINITY),
+            Some(SamplingError::InvalidResolution(f64::INFINITY))
+        );
+        assert_eq!(
+            try_table((0.01, -0.01), 1e-4, 2e3, 100.0),
+            Some(SamplingError::InvalidRange {
+                start: 0.01,
+                end: -0.01
+            })
+        );
+        assert_eq!(
+            try_table((-0.01, 0.01), 1e-4, -2e3, 100.0),
+            Some(SamplingError::InvalidRange {
+                start: 0.0,
+                end: -2e3
+            })
+        );
+    }
+
+    #[test]
+    fn test_no_caustics() {
+        let flat = Environment::with_k_factor(6_378_000.0, 1.0);
+        let table = flat.ray_table(10.0, (-0.01, 0.01), 1e-3, 2e3, 100.0, &Default::default());
+        assert!(table.caustics().is_empty());
+    }
+
+    #[test]
+    fn test_warp_column() {
+        let flat = Environment::with_k_factor(6_378_000.0, 1.0);
+        let table = flat.ray_table(10.0, (-0.01, 0.05), 1e-4, 2e3, 100.0, &Default::default());
+        // the middle point is hidden behind the first one
+        let profile = [(1000.0, 30.0), (1500.0, 20.0), (1800.0, 50.0)];
+        let column = table.warp_column(&profile);
+        assert_eq!(column.len(), 3);
+        assert_eq!(column[0].len(), 1);
+        assert!((column[0][0] - 0.02f64.atan()).abs() < 1e-6);
+        assert!(column[1].is_empty());
+        assert_eq!(column[2].len(), 1);
+        assert!((column[2][0] - (40.0f64 / 1800.0).atan()).abs() < 1e-6);
+        assert!((table.h_at(0.02f64.atan(), 500.0).unwrap() - 20.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_mirage_caustics() {
+        let env = inversion_env();
+        let options = RayOptions {
+            ground_altitude: Some(0.0),
+            ..Default::default()
+        };
+        let table = env.ray_table(5.0, (-0.002, 0.002), 1e-4, 40e3, 500.0, &options);
+        let caustics = table.caustics();
+        assert!(!caustics.is_empty());
+        for caustic in &caustics {
+            assert!(caustic.dist > 0.0 && caustic.h >= 0.0);
+        }
+        // the rays first focus inside the inversion
+        let nearest = caustics
+            .iter()
+            .min_by(|c1, c2| c1.dist.partial_cmp(&c2.dist).unwrap())
+            .unwrap();
+        assert!(nearest.dist > 10e3);
+        assert!(nearest.h > 10.0 && nearest.h < 30.0);
+    }
+}
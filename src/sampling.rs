@@ -0,0 +1,79 @@
+//! Regularly spaced samples of ranges of angles and distances
+
+/// The largest number of intervals into which a range can be divided
+const MAX_INTERVALS: f64 = 1e8;
+
+/// A problem with the parameters of regularly spaced samples
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplingError {
+    /// The ends of the sampled range are not finite, or the end is lower than the start
+    InvalidRange { start: f64, end: f64 },
+    /// The spacing of the samples is not finite and positive
+    InvalidResolution(f64),
+    /// The spacing of the samples is so small compared to the range, that there would be more
+    /// than 10^8 intervals between them
+    TooManySamples { intervals: f64 },
+}
+
+/// Returns the points dividing the range from `start` to `end` into equal intervals not longer
+/// than `resolution`, including both ends - at least two of them, even if the range is empty.
+pub(crate) fn sample_range(
+    start: f64,
+    end: f64,
+    resolution: f64,
+) -> Result<Vec<f64>, SamplingError> {
+    if !(start.is_finite() && end.is_finite() && start <= end) {
+        return Err(SamplingError::InvalidRange { start, end });
+    }
+    if !(resolution.is_finite() && resolution > 0.0) {
+        return Err(SamplingError::InvalidResolution(resolution));
+    }
+    let intervals = ((end - start) / resolution).ceil().max(1.0);
+    if intervals > MAX_INTERVALS {
+        return Err(SamplingError::TooManySamples { intervals });
+    }
+    let n = intervals as usize;
+    Ok((0..=n)
+        .map(|i| start + (end - start) * i as f64 / n as f64)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sample_range() {
+        assert_eq!(
+            sample_range(0.0, 1.0, 0.3).unwrap(),
+            [0.0, 0.25, 0.5, 0.75, 1.0]
+        );
+        assert_eq!(sample_range(2.0, 2.0, 0.5).unwrap(), [2.0, 2.0]);
+
+        assert_eq!(
+            sample_range(1.0, 0.0, 0.1),
+            Err(SamplingError::InvalidRange {
+                start: 1.0,
+                end: 0.0
+            })
+        );
+        assert!(matches!(
+            sample_range(0.0, f64::NAN, 0.1),
+            Err(SamplingError::InvalidRange { .. })
+        ));
+        for resolution in [0.0, -0.1, f64::INFINITY] {
+            assert_eq!(
+                sample_range(0.0, 1.0, resolution),
+                Err(SamplingError::InvalidResolution(resolution))
+            );
+        }
+        assert!(matches!(
+            sample_range(0.0, 1.0, f64::NAN),
+            Err(SamplingError::InvalidResolution(_))
+        ));
+        assert!(matches!(
+            sample_range(0.0, 1.0, 1e-9),
+            Err(SamplingError::TooManySamples { .. })
+        ));
+    }
+}
@@ -0,0 +1,177 @@
+//! Helpers for terrestrial surveying
+
+use crate::Environment;
+
+/// The vertical angles measured simultaneously at two stations, each towards the other one
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct ReciprocalAngles {
+    /// The elevation angle of the second station seen from the first one, in radians
+    pub first: f64,
+    /// The elevation angle of the first station seen from the second one, in radians
+    pub second: f64,
+}
+
+impl Environment {
+    /// Predicts the reciprocal vertical angles between stations at the altitudes `first_h` and
+    /// `second_h`, separated by the distance `dist` (all in meters), both measured along the ray
+    /// connecting them.
+    pub fn reciprocal_angles(&self, first_h: f64, second_h: f64, dist: f64) -> ReciprocalAngles {
+        let ray = self.cast_ray_target(first_h, second_h, dist, false);
+        ReciprocalAngles {
+            first: ray.angle_at_dist(0.0),
+            // looking back along the ray
+            second: -ray.angle_at_dist(dist),
+        }
+    }
+
+    /// Returns the effective refraction coefficient k along the line of sight between two
+    /// stations separated by the distance `dist` (in meters), from the reciprocal vertical
+    /// angles measured between them.
+    ///
+    /// Without refraction, the angles add up to minus the angle subtended by the stations at the
+    /// center of the Earth, and a ray with the refraction coefficient k raises both of them by k
+    /// times half of that angle. Returns `None` if that angle is zero - over a flat surface, or
+    /// for stations at the same point - since the coefficient is then undefined.
+    pub fn refraction_coefficient_from_angles(
+        &self,
+        angles: ReciprocalAngles,
+        dist: f64,
+    ) -> Option<f64> {
+        let central_angle = self.surface_rotation(dist);
+        (central_angle != 0.0)
+            .then(|| (angles.first + angles.second + central_angle) / central_angle)
+    }
+
+    /// Returns the altitude (in meters) of a target at the distance `dist` (in meters), observed
+    /// at the vertical angle `observed_angle` (in radians) from the altitude `observer_h` (in
+    /// meters) - the inverse of `cast_ray_target`. The ray is traced through the atmosphere, so
+    /// no refraction coefficient has to be assumed.
+    pub fn height_from_vertical_angle(
+        &self,
+        observer_h: f64,
+        observed_angle: f64,
+        dist: f64,
+    ) -> f64 {
+        self.true_target_from_apparent(observer_h, observed_angle, dist)
+    }
+
+    /// Returns the altitude (in meters) of a target like `height_from_vertical_angle`, but with
+    /// the classical formula of trigonometric heighting, assuming the refraction coefficient `k`
+    /// (usually 0.13).
+    pub fn height_from_vertical_angle_with_k(
+        &self,
+        observer_h: f64,
+        observed_angle: f64,
+        dist: f64,
+        k: f64,
+    ) -> f64 {
+        let curvature = self.curvature_at(0.0);
+        observer_h + dist * observed_angle.tan() + (1.0 - k) * dist * dist * curvature / 2.0
+    }
+
+    /// Returns the refraction correction (in meters) of a rod reading in geodetic leveling - the
+    /// difference between the altitudes of the straight line of sight and the refracted ray at
+    /// the rod, for a horizontal line of sight at the altitude `line_h` and a sight of the
+    /// length `sight_length` (both in meters).
+    ///
+    /// The near-ground temperature gradients which cause the refraction can be modeled with
+    /// `ConvectiveLayer::kukkamaki`.
+    pub fn leveling_correction(&self, line_h: f64, sight_length: f64) -> f64 {
+        let line = self.cast_ray(line_h, 0.0, true);
+        let ray = self.cast_ray(line_h, 0.0, false);
+        line.h_at_dist(sight_length) - ray.h_at_dist(sight_length)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::surface_layer::ConvectiveLayer;
+    use crate::air::{Atmosphere, AtmosphereDef};
+    use crate::test_support::us76_atmosphere_env;
+    use crate::{EarthShape, RefractiveIndexModel};
+
+    #[test]
+    fn test_reciprocal_angles() {
+        let env = us76_atmosphere_env();
+        let angles = env.reciprocal_angles(100.0, 300.0, 10e3);
+        assert!(angles.first > 0.0 && angles.second < 0.0);
+        let swapped = env.reciprocal_angles(300.0, 100.0, 10e3);
+        assert!((swapped.first - angles.second).abs() < 1e-9);
+        assert!((swapped.second - angles.first).abs() < 1e-9);
+
+        // the coefficient agrees with the local one in the middle of the line of sight
+        let k = env
+            .refraction_coefficient_from_angles(angles, 10e3)
+            .unwrap();
+        let expected = env.refraction_coefficient(200.0, 0.0);
+        assert!((k - expected).abs() < 0.01, "{} {}", k, expected);
+
+        let vacuum = Environment {
+            index_model: RefractiveIndexModel::Vacuum,
+            ..us76_atmosphere_env()
+        };
+        let angles = vacuum.reciprocal_angles(100.0, 300.0, 10e3);
+        assert!(
+            vacuum
+                .refraction_coefficient_from_angles(angles, 10e3)
+                .unwrap()
+                .abs()
+                < 1e-6
+        );
+        assert_eq!(vacuum.refraction_coefficient_from_angles(angles, 0.0), None);
+
+        // the surface has to be curved for the coefficient to be defined
+        let flat = Environment {
+            shape: EarthShape::Flat,
+            ..us76_atmosphere_env()
+        };
+        let angles = flat.reciprocal_angles(100.0, 300.0, 10e3);
+        assert_eq!(flat.refraction_coefficient_from_angles(angles, 10e3), None);
+    }
+
+    #[test]
+    fn test_height_from_vertical_angle() {
+        let env = us76_atmosphere_env();
+        let angle = env.apparent_elevation(100.0, 300.0, 10e3);
+        let height = env.height_from_vertical_angle(100.0, angle, 10e3);
+        assert!((height - 300.0).abs() < 1e-3, "{}", height);
+
+        // the classical formula is close with the right coefficient, but not with the usual one
+        let k = env.refraction_coefficient(200.0, 0.0);
+        let classical = env.height_from_vertical_angle_with_k(100.0, angle, 10e3, k);
+        assert!((classical - 300.0).abs() < 0.05, "{}", classical);
+        let usual = env.height_from_vertical_angle_with_k(100.0, angle, 10e3, 0.13);
+        assert!((usual - 300.0).abs() > 0.1, "{}", usual);
+    }
+
+    #[test]
+    fn test_leveling_correction() {
+        // only a few hundredths of a millimeter in the standard atmosphere
+        let env = us76_atmosphere_env();
+        assert!(env.leveling_correction(1.0, 50.0).abs() < 1e-4);
+
+        // the air is warmer near the ground, so the rays bend upwards
+        let layer = ConvectiveLayer::kukkamaki(0.0, -0.5, 1.0);
+        let env = Environment {
+            atmosphere: Atmosphere::try_from_def(
+                AtmosphereDef::us_76().with_convective_layer(&layer),
+            )
+            .unwrap(),
+            ..us76_atmosphere_env()
+        };
+        let correction = env.leveling_correction(1.0, 50.0);
+        assert!(correction < 0.0);
+        // for a short sight the ray is close to a parabola
+        let expected = -0.5 * env.dn(1.0) / env.n(1.0) * 50.0 * 50.0;
+        assert!(
+            (correction - expected).abs() < 0.05 * expected.abs(),
+            "{} {}",
+            correction,
+            expected
+        );
+        // the lower sights are affected more
+        assert!(env.leveling_correction(0.5, 50.0) < correction);
+    }
+}
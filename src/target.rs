@@ -0,0 +1,793 @@
+use crate::{
+    air::atmosphere::AtmosphereHints, EarthShape, Environment, Integration, Path, RayOptions,
+    RayState,
+};
+
+/// The default angles between which the initial angle of a ray hitting a target is searched for
+const ANGLE_BRACKET: (f64, f64) = (-1.5, 1.5);
+/// The default precision of the initial angle
+const ANGLE_EPSILON: f64 = 1e-9;
+/// The default maximal number of iterations of the solver
+const MAX_ITERATIONS: usize = 100;
+/// The number of intervals between the points on the way to the target, at which the states of
+/// the rays launched by the solver are stored
+const CHECKPOINTS: usize = 32;
+
+/// The parameters of the search for the initial angle of a ray hitting a target
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TargetSolverOptions {
+    /// The range of initial angles (in radians) in which the ray is searched for
+    pub bracket: (f64, f64),
+    /// The precision of the initial angle in radians
+    pub tolerance: f64,
+    /// The maximal number of iterations of the solver - if it is reached, the best angle found
+    /// so far is used
+    pub max_iterations: usize,
+    /// The maximal vertical distance (in meters) by which a ray returned by
+    /// `cast_rays_to_target_with` can miss the target; `None` means 5 cm + 10^-6 of the distance
+    /// to the target
+    pub max_residual: Option<f64>,
+}
+
+impl Default for TargetSolverOptions {
+    fn default() -> Self {
+        Self {
+            bracket: ANGLE_BRACKET,
+            tolerance: ANGLE_EPSILON,
+            max_iterations: MAX_ITERATIONS,
+            max_residual: None,
+        }
+    }
+}
+
+/// The reason why no ray could be found that would hit a target
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TargetError {
+    /// All the rays in the searched range of initial angles pass above or below the target
+    OutOfRange { residual: f64 },
+    /// The target lies in a shadow zone - the altitude reached at the target distance jumps over
+    /// the target altitude at the given initial angle, so no ray passes through the target
+    ShadowZone { residual: f64, angle: f64 },
+    /// The target lies at the distance 0, but at a different altitude than the start - no ray
+    /// can reach it
+    ZeroDistance { residual: f64 },
+}
+
+impl TargetError {
+    /// Returns the vertical distance (in meters) by which the closest ray found misses the
+    /// target; positive if the ray passes above it
+    pub fn residual(&self) -> f64 {
+        match *self {
+            TargetError::OutOfRange { residual }
+            | TargetError::ShadowZone { residual, .. }
+            | TargetError::ZeroDistance { residual } => residual,
+        }
+    }
+}
+
+/// The orientation of the image of a target formed by a ray
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum ImageOrientation {
+    /// Rays launched higher reach higher points at the target distance
+    Erect,
+    /// Rays launched higher reach lower points at the target distance
+    Inverted,
+}
+
+/// One of possibly many rays hitting a target
+pub struct TargetRay<'a> {
+    /// The initial angle of the ray in radians
+    pub angle: f64,
+    /// The orientation of the image formed by the ray
+    pub orientation: ImageOrientation,
+    /// The vertical distance (in meters) by which the ray misses the target; positive if it
+    /// passes above it
+    pub residual: f64,
+    pub path: Box<dyn Path<'a> + 'a>,
+}
+
+/// One of possibly many images of a target seen by an observer
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct TargetImage {
+    /// The apparent elevation angle of the image in radians
+    pub elevation: f64,
+    /// The orientation of the image
+    pub orientation: ImageOrientation,
+    /// The vertical magnification of the image relative to the target seen without refraction;
+    /// negative for inverted images
+    pub magnification: f64,
+}
+
+/// The angles of a path between an observer and a target
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct TargetAngles {
+    /// The angle (in radians) between the path and the horizontal at the start - the elevation at
+    /// which the target is seen from the start
+    pub launch: f64,
+    /// The angle (in radians) between the path and the local horizontal at the target; the start
+    /// is seen from the target at the elevation `-arrival`
+    pub arrival: f64,
+    /// The total bending of the path between the start and the target, in radians
+    pub bending: f64,
+}
+
+impl TargetAngles {
+    /// Returns the angles of the `path` ending at the distance `tgt_dist`.
+    pub fn from_path<'a, P: Path<'a> + ?Sized>(path: &P, tgt_dist: f64) -> Self {
+        TargetAngles {
+            launch: path.start_angle(),
+            arrival: path.angle_at_dist(tgt_dist),
+            bending: path.bending_at_dist(tgt_dist),
+        }
+    }
+}
+
+impl Environment {
+    /// Finds the initial angle of a ray hitting the target.
+    ///
+    /// Starts from the angle of a straight line to the target, expands the search around it until
+    /// the rays pass on both sides of the target and then narrows it down using Brent's method.
+    /// If all the rays in the bracket pass on one side of the target, the edge of the bracket is
+    /// returned. The rays are traced with a `TargetSearch`, so the later ones don't have to be
+    /// integrated from the start.
+    pub(crate) fn find_target_angle(
+        &self,
+        start_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        straight: bool,
+        options: &TargetSolverOptions,
+    ) -> f64 {
+        let mut search = TargetSearch::new(self, start_h, tgt_h, tgt_dist, straight, options);
+        search.find_angle(options)
+    }
+
+    /// Refines the initial angle of a ray hitting the target between two angles, for which the
+    /// rays pass on different sides of the target
+    fn refine_target_angle(
+        &self,
+        start_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        straight: bool,
+        (min_ang, max_ang): (f64, f64),
+        options: &TargetSolverOptions,
+    ) -> f64 {
+        let mut search = TargetSearch::new(self, start_h, tgt_h, tgt_dist, straight, options);
+        let mut miss = |ang| search.miss(ang);
+        let (miss1, miss2) = (miss(min_ang), miss(max_ang));
+        brent(
+            miss,
+            (min_ang, miss1),
+            (max_ang, miss2),
+            options.tolerance,
+            options.max_iterations,
+        )
+    }
+
+    /// Returns all the rays hitting a given target - there can be more than one, for example
+    /// during mirages.
+    ///
+    /// * `start_h` - the initial altitude of the paths in meters
+    /// * `tgt_h` - the altitude of the target point in meters
+    /// * `tgt_dist` - the distance of the target point from the initial point, in meters
+    /// * `straight` - `true` if the paths should be straight lines, `false` if they should be
+    ///   rays affected by the atmosphere
+    /// * `angle_range` - the range of the initial angles (in radians) to be searched
+    /// * `samples` - the number of initial angles in the range to be checked; rays hitting the
+    ///   target with initial angles closer than the sampling interval can be missed
+    ///
+    /// The rays are sorted by the initial angle. Crossings of the target altitude caused by a jump
+    /// in the altitude (shadow zones) are not counted as hits; a crossing counts as a hit if the
+    /// ray passes within 5 cm + 10^-6 of the distance from the target - see
+    /// `cast_rays_to_target_with` for a custom limit.
+    pub fn cast_rays_to_target<'a>(
+        &'a self,
+        start_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        straight: bool,
+        angle_range: (f64, f64),
+        samples: usize,
+    ) -> Vec<TargetRay<'a>> {
+        let options = TargetSolverOptions {
+            bracket: angle_range,
+            ..Default::default()
+        };
+        self.cast_rays_to_target_with(start_h, tgt_h, tgt_dist, straight, samples, &options)
+    }
+
+    /// Like `cast_rays_to_target`, but with custom parameters of the search for the initial
+    /// angles: the range of the initial angles to be searched is `options.bracket`, the angle of
+    /// every ray is refined with the tolerance and the maximal number of iterations from the
+    /// options, and the rays missing the target by more than `options.max_residual` are dropped.
+    pub fn cast_rays_to_target_with<'a>(
+        &'a self,
+        start_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        straight: bool,
+        samples: usize,
+        options: &TargetSolverOptions,
+    ) -> Vec<TargetRay<'a>> {
+        let samples = samples.max(2);
+        let (min_ang, max_ang) = options.bracket;
+        // the numerical integration makes the altitude at the target distance slightly noisy
+        // near sharp changes of the refractive index gradient, hence the loose default
+        let max_residual = options.max_residual.unwrap_or(0.05 + 1e-6 * tgt_dist.abs());
+        let angles: Vec<f64> = (0..samples)
+            .map(|i| min_ang + (max_ang - min_ang) * i as f64 / (samples - 1) as f64)
+            .collect();
+        let misses: Vec<f64> = angles
+            .iter()
+            .map(|&ang| self.cast_ray(start_h, ang, straight).h_at_dist(tgt_dist) - tgt_h)
+            .collect();
+
+        let mut result = vec![];
+        for i in 0..samples - 1 {
+            let (miss1, miss2) = (misses[i], misses[i + 1]);
+            if (miss1 >= 0.0) == (miss2 >= 0.0) {
+                continue;
+            }
+            let angle = self.refine_target_angle(
+                start_h,
+                tgt_h,
+                tgt_dist,
+                straight,
+                (angles[i], angles[i + 1]),
+                options,
+            );
+            let path = self.cast_ray(start_h, angle, straight);
+            let residual = path.h_at_dist(tgt_dist) - tgt_h;
+            if residual.is_nan() || residual.abs() > max_residual {
+                continue;
+            }
+            let orientation = if miss2 > miss1 {
+                ImageOrientation::Erect
+            } else {
+                ImageOrientation::Inverted
+            };
+            result.push(TargetRay {
+                angle,
+                orientation,
+                residual,
+                path,
+            });
+        }
+
+        result
+    }
+
+    /// Returns all the images of a target seen by an observer at the altitude `observer_h`,
+    /// sorted from the lowest one.
+    ///
+    /// The images are found with `cast_rays_to_target`, which takes the same parameters. The
+    /// magnification is the derivative of the apparent elevation with respect to the altitude of
+    /// the target, divided by the same derivative for straight lines.
+    pub fn target_images(
+        &self,
+        observer_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        angle_range: (f64, f64),
+        samples: usize,
+    ) -> Vec<TargetImage> {
+        let options = TargetSolverOptions {
+            bracket: angle_range,
+            ..Default::default()
+        };
+        self.target_images_with(observer_h, tgt_h, tgt_dist, samples, &options)
+    }
+
+    /// Like `target_images`, but with custom parameters of the search for the initial angles,
+    /// used like in `cast_rays_to_target_with` - the range of the initial angles to be searched is
+    /// `options.bracket`.
+    pub fn target_images_with(
+        &self,
+        observer_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        samples: usize,
+        options: &TargetSolverOptions,
+    ) -> Vec<TargetImage> {
+        let straight_angle = self
+            .cast_ray_target(observer_h, tgt_h, tgt_dist, true)
+            .start_angle();
+        let reference = self.elevation_derivative(observer_h, straight_angle, tgt_dist, true);
+        self.cast_rays_to_target_with(observer_h, tgt_h, tgt_dist, false, samples, options)
+            .into_iter()
+            .map(|ray| TargetImage {
+                elevation: ray.angle,
+                orientation: ray.orientation,
+                magnification: self.elevation_derivative(observer_h, ray.angle, tgt_dist, false)
+                    / reference,
+            })
+            .collect()
+    }
+
+    /// Returns an object representing a light path hitting a given target, or an error if there
+    /// is no such path.
+    ///
+    /// The parameters are the same as for `cast_ray_target`. The target counts as hit if the
+    /// path passes within 1 mm + 10^-8 of the distance from it.
+    pub fn try_cast_ray_target<'a>(
+        &'a self,
+        start_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        straight: bool,
+    ) -> Result<Box<dyn Path<'a> + 'a>, TargetError> {
+        self.try_cast_ray_target_with(
+            start_h,
+            tgt_h,
+            tgt_dist,
+            straight,
+            &TargetSolverOptions::default(),
+        )
+    }
+
+    /// Like `try_cast_ray_target`, but with custom parameters of the search for the initial
+    /// angle.
+    pub fn try_cast_ray_target_with<'a>(
+        &'a self,
+        start_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        straight: bool,
+        options: &TargetSolverOptions,
+    ) -> Result<Box<dyn Path<'a> + 'a>, TargetError> {
+        if tgt_dist == 0.0 && tgt_h != start_h {
+            return Err(TargetError::ZeroDistance {
+                residual: start_h - tgt_h,
+            });
+        }
+        if straight && !matches!(self.shape, EarthShape::Custom { .. }) {
+            return Ok(self.cast_ray_target(start_h, tgt_h, tgt_dist, straight));
+        }
+
+        let ang = self.find_target_angle(start_h, tgt_h, tgt_dist, straight, options);
+        let ray = self.cast_ray(start_h, ang, straight);
+        let residual = ray.h_at_dist(tgt_dist) - tgt_h;
+
+        if residual.abs() <= 1e-3 + 1e-8 * tgt_dist.abs() {
+            Ok(ray)
+        } else if ang - options.bracket.0 < 2.0 * options.tolerance
+            || options.bracket.1 - ang < 2.0 * options.tolerance
+        {
+            Err(TargetError::OutOfRange { residual })
+        } else {
+            Err(TargetError::ShadowZone {
+                residual,
+                angle: ang,
+            })
+        }
+    }
+
+    /// Returns the launch and arrival angles of the path hitting a given target, or an error if
+    /// there is no such path - as needed for reciprocal observations of vertical angles.
+    ///
+    /// The parameters are the same as for `try_cast_ray_target`.
+    pub fn target_angles(
+        &self,
+        start_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        straight: bool,
+    ) -> Result<TargetAngles, TargetError> {
+        let path = self.try_cast_ray_target(start_h, tgt_h, tgt_dist, straight)?;
+        Ok(TargetAngles::from_path(&*path, tgt_dist))
+    }
+}
+
+/// A ray launched by the target solver, with its states at the checkpoints
+struct Probe {
+    angle: f64,
+    states: Vec<RayState>,
+}
+
+/// The search for the initial angle of a ray hitting a target.
+///
+/// The states of all the rays launched during the search are stored at a few checkpoints on the
+/// way to the target. Once the angles get close to each other, the states of a new ray can be
+/// interpolated between the rays with the nearest angles - quadratically, with the difference
+/// from the linear interpolation as the estimate of the error. The new ray is then integrated
+/// only from the farthest checkpoint at which the estimated error of the altitude reached at the
+/// target stays well below the one caused by an error of the angle equal to the tolerance.
+struct TargetSearch<'a> {
+    env: &'a Environment,
+    start_h: f64,
+    tgt_h: f64,
+    tgt_dist: f64,
+    straight: bool,
+    /// The distances at which the states of the rays are stored, starting at 0 and ending at the
+    /// target distance; empty if the rays are traced without storing them
+    checkpoints: Vec<f64>,
+    /// The maximal estimated error (in meters) of the altitude at the target for the
+    /// interpolated states
+    precision: f64,
+    probes: Vec<Probe>,
+    /// The number of the rays launched so far
+    launched: usize,
+    /// The total distance (in meters) over which the rays have been integrated so far
+    integrated: f64,
+}
+
+impl<'a> TargetSearch<'a> {
+    fn new(
+        env: &'a Environment,
+        start_h: f64,
+        tgt_h: f64,
+        tgt_dist: f64,
+        straight: bool,
+        options: &TargetSolverOptions,
+    ) -> Self {
+        // straight lines over flat and spherical surfaces aren't integrated
+        let integrated = !straight || matches!(env.shape, EarthShape::Custom { .. });
+        let checkpoints = if integrated && tgt_dist.is_finite() && tgt_dist > 0.0 {
+            // the checkpoints are placed on the integration steps, so that the rays launched
+            // from them follow the same steps as when traced from the start
+            let step = RayOptions::default().step;
+            let spacing = step * (tgt_dist / step / CHECKPOINTS as f64).ceil();
+            let mut checkpoints: Vec<f64> = (0..)
+                .map(|i| i as f64 * spacing)
+                .take_while(|&dist| dist < tgt_dist)
+                .collect();
+            checkpoints.push(tgt_dist);
+            checkpoints
+        } else {
+            vec![]
+        };
+        TargetSearch {
+            env,
+            start_h,
+            tgt_h,
+            tgt_dist,
+            straight,
+            checkpoints,
+            precision: 0.01 * options.tolerance * tgt_dist,
+            probes: vec![],
+            launched: 0,
+            integrated: 0.0,
+        }
+    }
+
+    /// Expands the search from the angle of a straight line to the target until the rays pass on
+    /// both sides of the target, and then finds the angle with Brent's method.
+    fn find_angle(&mut self, options: &TargetSolverOptions) -> f64 {
+        let (start_h, tgt_h, tgt_dist) = (self.start_h, self.tgt_h, self.tgt_dist);
+        let (min_ang, max_ang) = options.bracket;
+        // all the rays pass through the start, so there is nothing to search for
+        if tgt_dist == 0.0 {
+            return 0.0f64.clamp(min_ang, max_ang);
+        }
+
+        let guess =
+            ((tgt_h - start_h) / tgt_dist).atan() - 0.5 * tgt_dist * self.env.curvature_at(0.0);
+        let mut ang1 = guess.clamp(min_ang, max_ang);
+        let mut miss1 = self.miss(ang1);
+        if miss1 == 0.0 {
+            return ang1;
+        }
+
+        // the altitude at the target distance grows with the initial angle, so if the ray passes
+        // above the target, the next one has to be launched lower
+        let direction = if miss1 > 0.0 { -1.0 } else { 1.0 };
+        let mut step = 1e-3;
+        for _ in 0..options.max_iterations {
+            let ang2 = (ang1 + direction * step).clamp(min_ang, max_ang);
+            let miss2 = self.miss(ang2);
+            if (miss1 > 0.0) != (miss2 > 0.0) {
+                return brent(
+                    |ang| self.miss(ang),
+                    (ang1, miss1),
+                    (ang2, miss2),
+                    options.tolerance,
+                    options.max_iterations,
+                );
+            }
+            if ang2 == min_ang || ang2 == max_ang {
+                return ang2;
+            }
+            ang1 = ang2;
+            miss1 = miss2;
+            step *= 2.0;
+        }
+
+        ang1
+    }
+
+    /// Returns the vertical distance (in meters) by which the ray launched at the angle `ang`
+    /// misses the target; positive if it passes above it
+    fn miss(&mut self, ang: f64) -> f64 {
+        self.launched += 1;
+        let ray = self.env.cast_ray(self.start_h, ang, self.straight);
+        if self.checkpoints.is_empty() {
+            self.integrated += self.tgt_dist.abs();
+            return ray.h_at_dist(self.tgt_dist) - self.tgt_h;
+        }
+
+        let initial = ray.path_stepper().next().unwrap().state();
+        let mut states = self.interpolated_states(ang, initial);
+        let start = *states.last().unwrap();
+        let (env, wavelength, straight) = (self.env, self.env.wavelength, self.straight);
+        let hints = AtmosphereHints::default();
+        states.extend(Integration::default().in_env(env).states_at_dists(
+            start,
+            &self.checkpoints[states.len()..],
+            |state: &RayState| {
+                if straight {
+                    env.calc_derivative_custom(state, wavelength, true, &hints)
+                } else {
+                    env.calc_derivative(state, wavelength, &hints)
+                }
+            },
+        ));
+        self.integrated += self.tgt_dist - start.x;
+
+        let miss = states.last().unwrap().h - self.tgt_h;
+        self.probes.push(Probe { angle: ang, states });
+        miss
+    }
+
+    /// Returns the states of the ray launched at the angle `ang` from the state `initial`, at the
+    /// checkpoints (excluding the target) at which they can be interpolated between the earlier
+    /// rays
+    fn interpolated_states(&self, ang: f64, initial: RayState) -> Vec<RayState> {
+        let mut states = vec![initial];
+        let mut nearest: Vec<&Probe> = self.probes.iter().collect();
+        nearest.sort_by(|a, b| (a.angle - ang).abs().total_cmp(&(b.angle - ang).abs()));
+        let (p0, p1, p2) = match nearest[..] {
+            [p0, p1, p2, ..] => (p0, p1, p2),
+            _ => return states,
+        };
+        let (a0, a1, a2) = (p0.angle, p1.angle, p2.angle);
+        if a0 == a1 || a1 == a2 || a0 == a2 {
+            return states;
+        }
+        // the weights of the quadratic interpolation and the coefficient of its difference from
+        // the linear one between the two nearest rays
+        let w0 = (ang - a1) * (ang - a2) / ((a0 - a1) * (a0 - a2));
+        let w1 = (ang - a0) * (ang - a2) / ((a1 - a0) * (a1 - a2));
+        let w2 = (ang - a0) * (ang - a1) / ((a2 - a0) * (a2 - a1));
+        let t = (ang - a0) / (a1 - a0);
+
+        for (i, &dist) in self.checkpoints[..self.checkpoints.len() - 1]
+            .iter()
+            .enumerate()
+            .skip(1)
+        {
+            let (s0, s1, s2) = (&p0.states[i], &p1.states[i], &p2.states[i]);
+            let h = w0 * s0.h + w1 * s1.h + w2 * s2.h;
+            let dh = w0 * s0.dh + w1 * s1.dh + w2 * s2.dh;
+            let error_h = h - (s0.h + t * (s1.h - s0.h));
+            let error_dh = dh - (s0.dh + t * (s1.dh - s0.dh));
+            let error = error_h.abs() + error_dh.abs() * (self.tgt_dist - dist);
+            if error.is_nan() || error > self.precision {
+                break;
+            }
+            states.push(RayState { x: dist, h, dh });
+        }
+        states
+    }
+}
+
+/// Finds a root of `f` between `a` and `b` using Brent's method, given the values of `f` at both
+/// ends of the bracket, which have to be of different signs.
+fn brent<F: FnMut(f64) -> f64>(
+    mut f: F,
+    (mut a, mut fa): (f64, f64),
+    (mut b, mut fb): (f64, f64),
+    tolerance: f64,
+    max_iterations: usize,
+) -> f64 {
+    let (mut c, mut fc) = (a, fa);
+    let mut d = b - a;
+    let mut e = d;
+
+    for _ in 0..max_iterations {
+        if (fb > 0.0) == (fc > 0.0) {
+            c = a;
+            fc = fa;
+            d = b - a;
+            e = d;
+        }
+        if fc.abs() < fb.abs() {
+            a = b;
+            b = c;
+            c = a;
+            fa = fb;
+            fb = fc;
+            fc = fa;
+        }
+
+        let tol = 2.0 * f64::EPSILON * b.abs() + 0.5 * tolerance;
+        let m = 0.5 * (c - b);
+        if m.abs() <= tol || fb == 0.0 {
+            return b;
+        }
+
+        if e.abs() >= tol && fa.abs() > fb.abs() {
+            // try interpolation
+            let s = fb / fa;
+            let (mut p, mut q) = if a == c {
+                // secant method
+                (2.0 * m * s, 1.0 - s)
+            } else {
+                // inverse quadratic interpolation
+                let q = fa / fc;
+                let r = fb / fc;
+                (
+                    s * (2.0 * m * q * (q - r) - (b - a) * (r - 1.0)),
+                    (q - 1.0) * (r - 1.0) * (s - 1.0),
+                )
+            };
+            if p > 0.0 {
+                q = -q;
+            } else {
+                p = -p;
+            }
+            if 2.0 * p < (3.0 * m * q - (tol * q).abs()).min((e * q).abs()) {
+                e = d;
+                d = p / q;
+            } else {
+                // the interpolation failed, fall back to bisection
+                d = m;
+                e = m;
+            }
+        } else {
+            d = m;
+            e = m;
+        }
+
+        a = b;
+        fa = fb;
+        b += if d.abs() > tol { d } else { tol.copysign(m) };
+        fb = f(b);
+    }
+
+    b
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_support::{
+        ducting_env, inversion_env, us76_atmosphere_env, us76_env_with_radius,
+    };
+
+    #[test]
+    fn test_try_cast_ray_target() {
+        let env = Environment {
+            shape: EarthShape::Flat,
+            ..us76_atmosphere_env()
+        };
+
+        let ray = env
+            .try_cast_ray_target(10.0, 50.0, 10e3, false)
+            .expect("should reach the target");
+        assert!((ray.h_at_dist(10e3) - 50.0).abs() < 1e-3);
+
+        // even the steepest rays in the bracket don't go that low
+        let result = env.try_cast_ray_target(10.0, -1e6, 1e3, false);
+        match result {
+            Err(TargetError::OutOfRange { residual }) => assert!(residual > 0.0),
+            _ => panic!("expected an out of range error"),
+        }
+
+        // a narrow bracket excluding the right angle
+        let options = TargetSolverOptions {
+            bracket: (0.1, 0.2),
+            ..Default::default()
+        };
+        let result = env.try_cast_ray_target_with(10.0, 50.0, 10e3, false, &options);
+        match result {
+            Err(TargetError::OutOfRange { residual }) => assert!(residual > 0.0),
+            _ => panic!("expected an out of range error"),
+        }
+
+        // a target at the start can only be reached at the same altitude
+        let result = env.try_cast_ray_target(10.0, 50.0, 0.0, false);
+        assert_eq!(
+            result.err(),
+            Some(TargetError::ZeroDistance { residual: -40.0 })
+        );
+        let ray = env.try_cast_ray_target(10.0, 10.0, 0.0, false).unwrap();
+        assert_eq!(ray.start_angle(), 0.0);
+    }
+
+    #[test]
+    fn test_target_angles() {
+        let env = us76_env_with_radius(6_378_000.0);
+
+        let there = env.target_angles(10.0, 500.0, 30e3, false).unwrap();
+        let back = env.target_angles(500.0, 10.0, 30e3, false).unwrap();
+        // the reciprocal observation sees the same path in the opposite direction
+        assert!((back.launch + there.arrival).abs() < 1e-6);
+        assert!((back.arrival + there.launch).abs() < 1e-6);
+        assert!(there.bending > 0.0);
+        assert!((back.bending - there.bending).abs() < 1e-6);
+
+        let straight = env.target_angles(10.0, 500.0, 30e3, true).unwrap();
+        assert!(straight.bending.abs() < 1e-9);
+        assert!(straight.launch < there.launch);
+    }
+
+    #[test]
+    fn test_target_search_reuse() {
+        let env = us76_atmosphere_env();
+        let options = TargetSolverOptions::default();
+        let mut search = TargetSearch::new(&env, 10.0, 50.0, 10e3, false, &options);
+        let angle = search.find_angle(&options);
+        let mut cold = TargetSearch::new(&env, 10.0, 50.0, 10e3, false, &options);
+        cold.checkpoints.clear();
+        assert!((angle - cold.find_angle(&options)).abs() < 1e-12);
+        assert!((env.cast_ray(10.0, angle, false).h_at_dist(10e3) - 50.0).abs() < 1e-6);
+
+        // the last rays only have to be integrated over the final stretch to the target
+        assert_eq!(search.launched, 5);
+        assert_eq!(cold.integrated, 5.0 * 10e3);
+        assert!(search.integrated < 3.1 * 10e3);
+    }
+
+    #[test]
+    fn test_brent() {
+        let f = |x: f64| x * x * x - 2.0 * x - 5.0;
+        let root = brent(f, (2.0, f(2.0)), (3.0, f(3.0)), 1e-12, 100);
+        assert!((root - 2.0945514815423265).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_mirage_images() {
+        let env = inversion_env();
+        let images = env.target_images(25.0, 10.0, 50e3, (-0.005, 0.005), 50);
+        assert_eq!(images.len(), 2);
+        assert!(images[0].elevation < images[1].elevation);
+        // a superior mirage: an inverted image above an erect one, both stretched
+        assert_eq!(images[0].orientation, ImageOrientation::Erect);
+        assert_eq!(images[1].orientation, ImageOrientation::Inverted);
+        assert!(images[0].magnification > 1.0);
+        assert!(images[1].magnification < -1.0);
+    }
+
+    #[test]
+    fn test_mirage_multiple_rays() {
+        let env = ducting_env();
+        let rays = env.cast_rays_to_target(50.0, 140.0, 60e3, false, (-0.005, 0.005), 100);
+        // an erect image below an inverted one; the steeper rays jump over the target when they
+        // graze the top of the duct
+        assert_eq!(rays.len(), 2);
+        assert_eq!(rays[0].orientation, ImageOrientation::Erect);
+        assert_eq!(rays[1].orientation, ImageOrientation::Inverted);
+        assert!(rays[0].angle < rays[1].angle);
+        for ray in &rays {
+            assert!((ray.path.h_at_dist(60e3) - 140.0).abs() < 0.11);
+            assert_eq!(ray.residual, ray.path.h_at_dist(60e3) - 140.0);
+        }
+
+        // a limit on the residual below the errors of the rays found drops them
+        let limit = rays[0].residual.abs().min(rays[1].residual.abs()) / 2.0;
+        let options = TargetSolverOptions {
+            bracket: (-0.005, 0.005),
+            max_residual: Some(limit),
+            ..Default::default()
+        };
+        assert!(env
+            .cast_rays_to_target_with(50.0, 140.0, 60e3, false, 100, &options)
+            .is_empty());
+
+        // the options limit the searched angles and set the precision of the refined ones
+        let options = TargetSolverOptions {
+            bracket: (-0.005, 0.5 * (rays[0].angle + rays[1].angle)),
+            tolerance: 1e-6,
+            ..Default::default()
+        };
+        let coarse = env.cast_rays_to_target_with(50.0, 140.0, 60e3, false, 50, &options);
+        assert_eq!(coarse.len(), 1);
+        assert!((coarse[0].angle - rays[0].angle).abs() < 1e-6);
+        assert!(coarse[0].angle != rays[0].angle);
+    }
+}
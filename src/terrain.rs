@@ -0,0 +1,370 @@
+//! The elevation of the terrain along the paths, for checking whether they are obstructed
+
+use crate::{
+    sample_range, Environment, ImageOrientation, Path, PathStep, SamplingError, TargetRay,
+};
+use std::f64::consts::FRAC_PI_2;
+
+/// The range of the initial angles (in radians) around the straight line to a target, in which
+/// the rays reaching it are searched for
+const VISIBILITY_ANGLE_RANGE: f64 = 0.01;
+/// The number of the initial angles checked when searching for the rays reaching a target
+const VISIBILITY_SAMPLES: usize = 100;
+/// The maximal number of bisections when searching for the ray grazing the terrain
+const MAX_BISECTIONS: usize = 50;
+/// The precision in radians of the initial angle of the ray grazing the terrain
+const GRAZING_ANGLE_EPSILON: f64 = 1e-10;
+
+/// The profile of the terrain along the direction of the rays - the elevation of the surface as
+/// a function of the distance from the observer.
+///
+/// The profile is given by points, between which the elevation is interpolated linearly. Beyond
+/// the outermost points there is no terrain.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct Terrain {
+    points: Vec<(f64, f64)>,
+}
+
+/// The point at which a path enters the terrain
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct Obstruction {
+    /// The distance from the initial point in meters
+    pub dist: f64,
+    /// The height in meters by which the path would have to be raised to pass above the
+    /// obstructing part of the terrain
+    pub clearance: f64,
+}
+
+impl Terrain {
+    /// Creates a profile from pairs of the distances from the observer and the elevations (both
+    /// in meters), in any order.
+    pub fn from_points(mut points: Vec<(f64, f64)>) -> Self {
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        points.dedup_by(|a, b| a.0 == b.0);
+        Terrain { points }
+    }
+
+    /// Creates a profile by sampling the elevation given by `elevation` as a function of the
+    /// distance every `resolution` meters, up to the distance `max_dist`, or returns an error if
+    /// `max_dist` isn't finite and non-negative or `resolution` isn't finite and positive.
+    pub fn from_fn<F: Fn(f64) -> f64>(
+        elevation: F,
+        max_dist: f64,
+        resolution: f64,
+    ) -> Result<Self, SamplingError> {
+        let points = sample_range(0.0, max_dist, resolution)?
+            .into_iter()
+            .map(|dist| (dist, elevation(dist)))
+            .collect();
+        Ok(Terrain { points })
+    }
+
+    /// Returns the points of the profile, sorted by the distance
+    pub fn points(&self) -> &[(f64, f64)] {
+        &self.points
+    }
+
+    /// Returns the elevation of the terrain (in meters) at the given distance (in meters), or
+    /// `None` if it's outside of the profile.
+    pub fn elevation(&self, dist: f64) -> Option<f64> {
+        let index = self.points.partition_point(|(x, _)| *x < dist);
+        if index == self.points.len() {
+            return None;
+        }
+        let (x1, h1) = self.points[index];
+        if x1 == dist {
+            return Some(h1);
+        }
+        if index == 0 {
+            return None;
+        }
+        let (x0, h0) = self.points[index - 1];
+        Some(h0 + (h1 - h0) * (dist - x0) / (x1 - x0))
+    }
+
+    /// Returns the height (in meters) of a step of a path stepper above the terrain - negative
+    /// if the path is inside it - or `None` if the step is outside of the profile.
+    pub fn clearance(&self, step: &PathStep) -> Option<f64> {
+        self.elevation(step.dist)
+            .map(|elevation| step.h - elevation)
+    }
+
+    /// Returns the first point beyond the initial one at which the path enters the terrain, if
+    /// it does so within the profile.
+    ///
+    /// The path is sampled at the points of the profile, so the obstructions narrower than the
+    /// spacing of the points might be missed.
+    pub fn first_obstruction<'a, P: Path<'a> + ?Sized>(&self, path: &P) -> Option<Obstruction> {
+        let points: Vec<(f64, f64)> = self
+            .points
+            .iter()
+            .copied()
+            .filter(|&(dist, _)| dist > 0.0)
+            .collect();
+        let dists: Vec<f64> = points.iter().map(|&(dist, _)| dist).collect();
+        let states = path.sample(&dists);
+        // the heights of the path above the terrain
+        let heights: Vec<f64> = states
+            .iter()
+            .zip(&points)
+            .map(|(state, &(_, elevation))| state.h - elevation)
+            .collect();
+
+        let first = heights.iter().position(|&height| height < 0.0)?;
+        let dist = if first == 0 {
+            dists[0]
+        } else {
+            // the terrain and the path are both close to linear between the points
+            let (prev, next) = (heights[first - 1], heights[first]);
+            dists[first - 1] + (dists[first] - dists[first - 1]) * prev / (prev - next)
+        };
+        let clearance = heights[first..]
+            .iter()
+            .take_while(|&&height| height < 0.0)
+            .fold(0.0, |clearance: f64, &height| clearance.max(-height));
+        Some(Obstruction { dist, clearance })
+    }
+}
+
+/// Whether a target can be seen by an observer over the terrain
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct Visibility {
+    /// The initial angles (in radians) of the rays reaching the target without entering the
+    /// terrain, in ascending order; there can be more than one during mirages
+    pub angles: Vec<f64>,
+    /// If the target isn't visible, the obstruction of the ray reaching it which passes the
+    /// closest to clearing the terrain
+    pub obstruction: Option<Obstruction>,
+    /// The height in meters by which the target would have to be raised to become visible; 0 if
+    /// it's visible
+    pub extra_height: f64,
+}
+
+impl Visibility {
+    /// Returns whether the target is visible
+    pub fn is_visible(&self) -> bool {
+        !self.angles.is_empty()
+    }
+}
+
+impl Terrain {
+    /// Returns the first point beyond the initial one and closer than `max_dist` at which the
+    /// path enters the terrain
+    fn first_obstruction_before<'a, P: Path<'a> + ?Sized>(
+        &self,
+        path: &P,
+        max_dist: f64,
+    ) -> Option<Obstruction> {
+        let truncated = Terrain {
+            points: self
+                .points
+                .iter()
+                .copied()
+                .filter(|&(dist, _)| dist < max_dist)
+                .collect(),
+        };
+        truncated.first_obstruction(path)
+    }
+}
+
+impl Environment {
+    /// Checks whether the target at the altitude `target_h` and the distance `target_dist` can be
+    /// seen over the terrain by an observer at the altitude `observer_h` (all in meters).
+    ///
+    /// All the rays reaching the target are checked, so that the target can be seen through a
+    /// mirage even if the direct ray is blocked. If none of them clears the terrain, the extra
+    /// height of the target is found from the lowest ray that passes above the obstructions.
+    pub fn is_visible(
+        &self,
+        observer_h: f64,
+        target_h: f64,
+        target_dist: f64,
+        terrain: &Terrain,
+    ) -> Visibility {
+        let straight_angle = self
+            .cast_ray_target(observer_h, target_h, target_dist, true)
+            .start_angle();
+        let mut rays = self.cast_rays_to_target(
+            observer_h,
+            target_h,
+            target_dist,
+            false,
+            (
+                straight_angle - VISIBILITY_ANGLE_RANGE,
+                straight_angle + VISIBILITY_ANGLE_RANGE,
+            ),
+            VISIBILITY_SAMPLES,
+        );
+        if rays.is_empty() {
+            let path = self.cast_ray_target(observer_h, target_h, target_dist, false);
+            rays.push(TargetRay {
+                angle: path.start_angle(),
+                orientation: ImageOrientation::Erect,
+                residual: path.h_at_dist(target_dist) - target_h,
+                path,
+            });
+        }
+
+        let mut angles = vec![];
+        let mut highest_blocked: Option<(f64, Obstruction)> = None;
+        for ray in &rays {
+            match terrain.first_obstruction_before(&ray.path, target_dist) {
+                None => angles.push(ray.angle),
+                Some(obstruction) => highest_blocked = Some((ray.angle, obstruction)),
+            }
+        }
+        let (blocked_angle, obstruction) = match highest_blocked {
+            Some(blocked) if angles.is_empty() => blocked,
+            _ => {
+                return Visibility {
+                    angles,
+                    obstruction: None,
+                    extra_height: 0.0,
+                }
+            }
+        };
+
+        // find the lowest ray clearing the terrain by bisection
+        let clears = |angle: f64| {
+            let path = self.cast_ray(observer_h, angle, false);
+            terrain
+                .first_obstruction_before(&path, target_dist)
+                .is_none()
+        };
+        let mut low = blocked_angle;
+        let mut step = VISIBILITY_ANGLE_RANGE;
+        while !clears(low + step) {
+            low += step;
+            step *= 2.0;
+            if low + step >= FRAC_PI_2 {
+                // even the steepest rays are blocked
+                return Visibility {
+                    angles,
+                    obstruction: Some(obstruction),
+                    extra_height: f64::INFINITY,
+                };
+            }
+        }
+        let mut high = low + step;
+        for _ in 0..MAX_BISECTIONS {
+            if high - low < GRAZING_ANGLE_EPSILON {
+                break;
+            }
+            let mid = 0.5 * (low + high);
+            if clears(mid) {
+                high = mid;
+            } else {
+                low = mid;
+            }
+        }
+        let grazing_h = self
+            .cast_ray(observer_h, high, false)
+            .h_at_dist(target_dist);
+
+        Visibility {
+            angles,
+            obstruction: Some(obstruction),
+            extra_height: (grazing_h - target_h).max(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::{Atmosphere, AtmosphereDef};
+    use crate::test_support::us76_atmosphere_env;
+
+    fn hill(dist: f64) -> f64 {
+        // a 100 m high hill 10 km away
+        (100.0 - (dist - 10e3).abs() / 10.0).max(0.0)
+    }
+
+    #[test]
+    fn test_elevation() {
+        let terrain = Terrain::from_points(vec![(100.0, 5.0), (0.0, 1.0), (50.0, 3.0)]);
+        assert_eq!(terrain.points(), &[(0.0, 1.0), (50.0, 3.0), (100.0, 5.0)]);
+        assert_eq!(terrain.elevation(0.0), Some(1.0));
+        assert_eq!(terrain.elevation(75.0), Some(4.0));
+        assert_eq!(terrain.elevation(100.0), Some(5.0));
+        assert_eq!(terrain.elevation(-1.0), None);
+        assert_eq!(terrain.elevation(101.0), None);
+
+        let terrain = Terrain::from_fn(hill, 20e3, 100.0).unwrap();
+        assert_eq!(terrain.points().len(), 201);
+        assert_eq!(terrain.elevation(10e3), Some(100.0));
+        assert_eq!(terrain.elevation(9950.0), Some(95.0));
+
+        assert_eq!(
+            Terrain::from_fn(hill, 20e3, 0.0).err(),
+            Some(SamplingError::InvalidResolution(0.0))
+        );
+        assert_eq!(
+            Terrain::from_fn(hill, -1.0, 100.0).err(),
+            Some(SamplingError::InvalidRange {
+                start: 0.0,
+                end: -1.0
+            })
+        );
+    }
+
+    #[test]
+    fn test_first_obstruction() {
+        let env = us76_atmosphere_env();
+        let terrain = Terrain::from_fn(hill, 20e3, 10.0).unwrap();
+
+        let ray = env.cast_ray(50.0, 0.0, false);
+        let obstruction = terrain.first_obstruction(&ray).unwrap();
+        let entry_h = ray.h_at_dist(obstruction.dist);
+        assert!((entry_h - terrain.elevation(obstruction.dist).unwrap()).abs() < 0.01);
+        assert!(obstruction.dist > 9e3 && obstruction.dist < 10e3);
+        let expected = 100.0 - ray.h_at_dist(10e3);
+        assert!((obstruction.clearance - expected).abs() < 0.1);
+
+        // the ray raised by the clearance barely passes above the hill
+        let raised = env.cast_ray(50.0, 1.1 * obstruction.clearance / 10e3, false);
+        assert_eq!(terrain.first_obstruction(&raised), None);
+
+        let stepper = ray.path_stepper();
+        let step = stepper.take_while(|step| step.dist <= 10e3).last().unwrap();
+        assert!((terrain.clearance(&step).unwrap() + expected).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_is_visible() {
+        let env = us76_atmosphere_env();
+        let terrain = Terrain::from_fn(hill, 20e3, 10.0).unwrap();
+
+        let visibility = env.is_visible(50.0, 300.0, 20e3, &terrain);
+        assert!(visibility.is_visible());
+        assert_eq!(visibility.extra_height, 0.0);
+        assert_eq!(visibility.obstruction, None);
+
+        // hidden behind the hill
+        let visibility = env.is_visible(50.0, 20.0, 20e3, &terrain);
+        assert!(!visibility.is_visible());
+        let obstruction = visibility.obstruction.unwrap();
+        assert!(obstruction.dist > 9e3 && obstruction.dist < 10e3);
+        // the line grazing the hilltop rises by 100 m over the next 10 km, and the surface
+        // curves away from it
+        let extra = visibility.extra_height;
+        assert!(extra > 130.0 && extra < 150.0, "{}", extra);
+        assert!(env
+            .is_visible(50.0, 20.0 + extra + 0.1, 20e3, &terrain)
+            .is_visible());
+        assert!(!env
+            .is_visible(50.0, 20.0 + extra - 0.1, 20e3, &terrain)
+            .is_visible());
+
+        // over a hot surface, the target is seen both directly and through the mirage
+        let env = Environment {
+            atmosphere: Atmosphere::try_from_def(AtmosphereDef::inferior_mirage(10.0)).unwrap(),
+            ..us76_atmosphere_env()
+        };
+        let flat = Terrain::from_fn(|_| 0.0, 10e3, 10.0).unwrap();
+        assert_eq!(env.is_visible(2.0, 3.0, 2e3, &flat).angles.len(), 2);
+    }
+}
@@ -0,0 +1,60 @@
+//! Environments shared by the tests of different modules
+
+use crate::air::atmosphere::vertical_profile::FunctionDef;
+use crate::air::{us76_atmosphere, Atmosphere, AtmosphereDefBuilder};
+use crate::{EarthShape, Environment, RefractiveIndexModel};
+
+/// Returns an environment with the given atmosphere over a sphere with the Earth's mean radius
+pub(crate) fn env_with(atmosphere: Atmosphere) -> Environment {
+    Environment {
+        shape: EarthShape::Spherical {
+            radius: 6_371_000.0,
+        },
+        atmosphere,
+        wavelength: 530e-9,
+        index_model: RefractiveIndexModel::Optical,
+        top_of_atmosphere: None,
+    }
+}
+
+/// Returns the US-1976 troposphere with an inversion of 0.3 K/m between the given altitudes
+fn inversion_atmosphere(bottom: f64, top: f64) -> Atmosphere {
+    let def = AtmosphereDefBuilder::new(FunctionDef::Linear { gradient: -0.0065 })
+        .with_temperature(0.0, 288.0)
+        .with_temperature_function(bottom, FunctionDef::Linear { gradient: 0.3 })
+        .with_temperature_function(top, FunctionDef::Linear { gradient: -0.0065 })
+        .build()
+        .unwrap();
+    Atmosphere::try_from_def(def).unwrap()
+}
+
+/// The US-1976 atmosphere
+pub(crate) fn us76_atmosphere_env() -> Environment {
+    env_with(us76_atmosphere())
+}
+
+/// The US-1976 atmosphere, with the refractive index of radio waves
+pub(crate) fn us76_radio_env() -> Environment {
+    Environment {
+        index_model: RefractiveIndexModel::Radio,
+        ..us76_atmosphere_env()
+    }
+}
+
+/// The US-1976 atmosphere over a sphere with the given radius in meters
+pub(crate) fn us76_env_with_radius(radius: f64) -> Environment {
+    Environment {
+        shape: EarthShape::Spherical { radius },
+        ..us76_atmosphere_env()
+    }
+}
+
+/// An inversion between 10 and 30 m
+pub(crate) fn inversion_env() -> Environment {
+    env_with(inversion_atmosphere(10.0, 30.0))
+}
+
+/// An inversion between 100 and 150 m, strong enough to make an elevated duct
+pub(crate) fn ducting_env() -> Environment {
+    env_with(inversion_atmosphere(100.0, 150.0))
+}
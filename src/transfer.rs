@@ -0,0 +1,248 @@
+use crate::{sample_range, Environment, ImageOrientation, Path, RayOptions, SamplingError};
+
+/// The margin (in radians) by which the initial angles of the rays forming a transfer curve
+/// extend beyond the angles of straight lines to the ends of the altitude range
+const ANGLE_MARGIN: f64 = 0.01;
+
+/// A part of a transfer curve in which the altitude changes monotonically with the apparent
+/// elevation - the range of angles in which a single image is seen
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct TransferSegment {
+    /// The apparent elevation angles (in radians) at the ends of the segment
+    pub angles: (f64, f64),
+    /// The true altitudes (in meters) at the ends of the segment
+    pub heights: (f64, f64),
+    /// The orientation of the image seen in the segment
+    pub orientation: ImageOrientation,
+}
+
+/// The mapping between the apparent elevation angles seen by an observer and the true altitudes
+/// at a given distance, tabulated for a range of angles.
+///
+/// A point at some altitude can be seen at several angles (during mirages), so the mapping from
+/// the altitudes to the angles is multi-valued. The rays that hit the ground before reaching the
+/// distance don't see any altitude there. The values between the tabulated rays are interpolated
+/// linearly.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct TransferCurve {
+    dist: f64,
+    angles: Vec<f64>,
+    heights: Vec<f64>,
+}
+
+impl TransferCurve {
+    pub(crate) fn new(dist: f64, angles: Vec<f64>, heights: Vec<f64>) -> Self {
+        TransferCurve {
+            dist,
+            angles,
+            heights,
+        }
+    }
+
+    /// Returns the distance (in meters) for which the curve is calculated.
+    pub fn dist(&self) -> f64 {
+        self.dist
+    }
+
+    /// Returns the tabulated pairs of the apparent elevation angles and the true altitudes.
+    pub fn points(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.angles
+            .iter()
+            .copied()
+            .zip(self.heights.iter().copied())
+    }
+
+    /// Returns the true altitude seen at the given apparent elevation angle, or `None` if the
+    /// angle is outside the tabulated range or the ray hits the ground.
+    pub fn true_h(&self, angle: f64) -> Option<f64> {
+        let first = *self.angles.first()?;
+        let last = *self.angles.last()?;
+        if angle < first || angle > last {
+            return None;
+        }
+        let i = self
+            .angles
+            .partition_point(|&ang| ang <= angle)
+            .clamp(1, self.angles.len() - 1);
+        let t = (angle - self.angles[i - 1]) / (self.angles[i] - self.angles[i - 1]);
+        Some(self.heights[i - 1] + t * (self.heights[i] - self.heights[i - 1]))
+            .filter(|h| !h.is_nan())
+    }
+
+    /// Returns all the apparent elevation angles at which the given altitude is seen, in
+    /// ascending order.
+    pub fn apparent_angles(&self, h: f64) -> Vec<f64> {
+        let mut result = vec![];
+        for i in 1..self.angles.len() {
+            let (h1, h2) = (self.heights[i - 1], self.heights[i]);
+            // count a crossing exactly at a tabulated point only once
+            let crosses = (h1 < h && h2 >= h) || (h1 > h && h2 <= h) || (i == 1 && h1 == h);
+            if !crosses {
+                continue;
+            }
+            let t = if h2 == h1 { 0.0 } else { (h - h1) / (h2 - h1) };
+            result.push(self.angles[i - 1] + t * (self.angles[i] - self.angles[i - 1]));
+        }
+        result
+    }
+
+    /// Returns the lowest apparent elevation angle at which the given altitude is seen, or
+    /// `None` if it isn't seen in the tabulated range.
+    pub fn apparent_angle(&self, h: f64) -> Option<f64> {
+        self.apparent_angles(h).first().copied()
+    }
+
+    /// Returns the parts of the curve in which the altitude changes monotonically, in ascending
+    /// order of the angles. The curve is also split where the rays hit the ground.
+    pub fn segments(&self) -> Vec<TransferSegment> {
+        let mut result: Vec<TransferSegment> = vec![];
+        let mut broken = true;
+        for i in 1..self.angles.len() {
+            let (h1, h2) = (self.heights[i - 1], self.heights[i]);
+            if h1.is_nan() || h2.is_nan() {
+                broken = true;
+                continue;
+            }
+            if h1 == h2 {
+                continue;
+            }
+            let orientation = if h2 > h1 {
+                ImageOrientation::Erect
+            } else {
+                ImageOrientation::Inverted
+            };
+            match result.last_mut() {
+                Some(segment) if !broken && segment.orientation == orientation => {
+                    segment.angles.1 = self.angles[i];
+                    segment.heights.1 = h2;
+                }
+                _ => result.push(TransferSegment {
+                    angles: (self.angles[i - 1], self.angles[i]),
+                    heights: (h1, h2),
+                    orientation,
+                }),
+            }
+            broken = false;
+        }
+        result
+    }
+}
+
+impl Environment {
+    /// Tabulates the transfer curve for an observer at the altitude `observer_h` and the distance
+    /// `dist` (both in meters).
+    ///
+    /// The rays are launched every `resolution` radians, at angles between those of straight
+    /// lines to the ends of `h_range`, extended by 0.01 rad on both sides - so the rays curving
+    /// by more than that can be missed. The rays end where they descend below the sea level or
+    /// the lower end of `h_range`, whichever is lower.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the angles can't be sampled; see `try_transfer_curve`.
+    pub fn transfer_curve(
+        &self,
+        observer_h: f64,
+        dist: f64,
+        h_range: (f64, f64),
+        resolution: f64,
+    ) -> TransferCurve {
+        match self.try_transfer_curve(observer_h, dist, h_range, resolution) {
+            Ok(curve) => curve,
+            Err(error) => panic!("invalid transfer curve parameters: {:?}", error),
+        }
+    }
+
+    /// Tabulates the transfer curve like `transfer_curve`, or returns an error if the angles
+    /// can't be sampled - if `resolution` isn't finite and positive, or the angles of the straight
+    /// lines to the ends of `h_range` aren't finite.
+    pub fn try_transfer_curve(
+        &self,
+        observer_h: f64,
+        dist: f64,
+        h_range: (f64, f64),
+        resolution: f64,
+    ) -> Result<TransferCurve, SamplingError> {
+        let straight_angle = |h| {
+            self.cast_ray_target(observer_h, h, dist, true)
+                .start_angle()
+        };
+        let min_angle = straight_angle(h_range.0.min(h_range.1)) - ANGLE_MARGIN;
+        let max_angle = straight_angle(h_range.0.max(h_range.1)) + ANGLE_MARGIN;
+        let angles = sample_range(min_angle, max_angle, resolution)?;
+        let options = RayOptions {
+            ground_altitude: Some(h_range.0.min(h_range.1).min(0.0)),
+            ..Default::default()
+        };
+
+        let heights = angles
+            .iter()
+            .map(|&ang| {
+                self.cast_ray_with(observer_h, ang, &options)
+                    .h_at_dist(dist)
+            })
+            .collect();
+        Ok(TransferCurve::new(dist, angles, heights))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::inversion_env;
+
+    #[test]
+    fn test_transfer_curve() {
+        let flat = Environment::with_k_factor(6_378_000.0, 1.0);
+        let curve = flat.transfer_curve(10.0, 1e3, (0.0, 50.0), 1e-4);
+        assert_eq!(curve.dist(), 1e3);
+
+        let angle = curve.apparent_angle(30.0).unwrap();
+        assert!((angle - 0.02f64.atan()).abs() < 1e-6);
+        assert_eq!(curve.apparent_angles(30.0).len(), 1);
+        assert!((curve.true_h(0.02).unwrap() - (10.0 + 1e3 * 0.02f64.tan())).abs() < 1e-3);
+        assert!(curve.true_h(1.0).is_none());
+
+        let segments = curve.segments();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].orientation, ImageOrientation::Erect);
+        assert!(segments[0].heights.0 < 0.1 && segments[0].heights.1 > 50.0);
+
+        for resolution in [0.0, -1e-4, f64::INFINITY] {
+            assert_eq!(
+                flat.try_transfer_curve(10.0, 1e3, (0.0, 50.0), resolution)
+                    .err(),
+                Some(SamplingError::InvalidResolution(resolution))
+            );
+        }
+        assert!(matches!(
+            flat.try_transfer_curve(10.0, f64::NAN, (0.0, 50.0), 1e-4),
+            Err(SamplingError::InvalidRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_mirage_transfer_curve() {
+        let env = inversion_env();
+        let curve = env.transfer_curve(5.0, 40e3, (0.0, 20.0), 1e-4);
+        // an erect image between two inverted ones
+        let angles = curve.apparent_angles(5.0);
+        assert_eq!(angles.len(), 3);
+        let images = env.target_images(5.0, 5.0, 40e3, (-0.002, 0.002), 100);
+        for angle in angles {
+            assert!(images
+                .iter()
+                .any(|image| (image.elevation - angle).abs() < 3e-5));
+        }
+
+        let segments = curve.segments();
+        assert_eq!(segments[0].orientation, ImageOrientation::Inverted);
+        assert!(segments
+            .windows(2)
+            .all(|pair| pair[0].orientation != pair[1].orientation));
+        // the rays between the mirage and the sky beyond the inversion hit the sea
+        assert!(curve.true_h(0.0015).is_none());
+    }
+}
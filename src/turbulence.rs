@@ -0,0 +1,103 @@
+//! The blur and the scintillation of images caused by the optical turbulence along a path
+
+use crate::paths::integrate_over_length;
+use crate::{Environment, Path, RayState};
+use std::f64::consts::PI;
+
+/// The expected effects of the optical turbulence on the image of a point source, seen along a
+/// path. The source is treated as emitting a spherical wave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct TurbulenceEffects {
+    /// The Fried parameter - the diameter of the aperture above which the turbulence limits the
+    /// resolution, in meters
+    pub fried_parameter: f64,
+    /// The angular width (FWHM) of the long-exposure image of a point source, in radians
+    pub blur: f64,
+    /// The variance of the angle of arrival along one axis, for the given aperture, in square
+    /// radians - the square of the RMS jitter of the image
+    pub tilt_variance: f64,
+    /// The Rytov variance of the log-amplitude, describing the strength of the scintillation;
+    /// values above about 1 mean strong fluctuations, for which it overestimates them
+    pub scintillation_variance: f64,
+}
+
+impl Environment {
+    /// Returns the effects of the optical turbulence on the image of a source at the distance
+    /// `dist` (in meters) along the path, seen from its initial point through an aperture of
+    /// the diameter `aperture` (in meters).
+    ///
+    /// Returns `None` if the atmosphere has no profile of the optical turbulence or the distance
+    /// isn't positive.
+    pub fn turbulence_effects<'a, P: Path<'a> + ?Sized>(
+        &self,
+        path: &P,
+        dist: f64,
+        aperture: f64,
+    ) -> Option<TurbulenceEffects> {
+        let turbulence = self.atmosphere.turbulence()?;
+        if dist <= 0.0 {
+            return None;
+        }
+        // the relative distance of a point of the path from the source
+        let from_source = |state: &RayState| (1.0 - state.x / dist).max(0.0);
+        let weighted = integrate_over_length(path, self, dist, |state| {
+            turbulence.structure_constant(state.h) * from_source(state).powf(5.0 / 3.0)
+        });
+        let rytov_integral = integrate_over_length(path, self, dist, |state| {
+            let weight = (state.x.max(0.0) * from_source(state)).powf(5.0 / 6.0);
+            turbulence.structure_constant(state.h) * weight
+        });
+
+        let wavelength = path.wavelength();
+        let k = 2.0 * PI / wavelength;
+        let fried_parameter = (0.423 * k * k * weighted).powf(-0.6);
+        Some(TurbulenceEffects {
+            fried_parameter,
+            blur: 0.98 * wavelength / fried_parameter,
+            tilt_variance: 2.91 * aperture.powf(-1.0 / 3.0) * weighted,
+            scintillation_variance: 2.25 * k.powf(7.0 / 6.0) * rytov_integral,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::air::TurbulenceProfile;
+    use crate::test_support::us76_atmosphere_env;
+
+    #[test]
+    fn test_uniform_turbulence() {
+        let cn2 = 1e-14;
+        let mut env = us76_atmosphere_env();
+        let dist = 5e3;
+        {
+            let ray = env.cast_ray(2.0, 0.0, false);
+            assert!(env.turbulence_effects(&ray, dist, 0.1).is_none());
+        }
+
+        env.atmosphere = env
+            .atmosphere
+            .with_turbulence(TurbulenceProfile::Table(vec![(0.0, cn2)]));
+        let ray = env.cast_ray(2.0, 0.0, false);
+        let effects = env.turbulence_effects(&ray, dist, 0.1).unwrap();
+
+        // the closed forms for a constant Cn^2
+        let k = 2.0 * PI / 530e-9;
+        let r0 = (0.423 * k * k * cn2 * dist * 3.0 / 8.0).powf(-0.6);
+        assert!((effects.fried_parameter / r0 - 1.0).abs() < 1e-3);
+        let tilt = 2.91 * 0.1f64.powf(-1.0 / 3.0) * cn2 * dist * 3.0 / 8.0;
+        assert!((effects.tilt_variance / tilt - 1.0).abs() < 1e-3);
+        let rytov = 0.496 * cn2 * k.powf(7.0 / 6.0) * dist.powf(11.0 / 6.0);
+        assert!(
+            (effects.scintillation_variance / rytov - 1.0).abs() < 1e-2,
+            "{} {}",
+            effects.scintillation_variance,
+            rytov
+        );
+        // a few arcseconds of blur
+        let arcsec = effects.blur.to_degrees() * 3600.0;
+        assert!(arcsec > 1.0 && arcsec < 10.0, "{}", arcsec);
+    }
+}
@@ -0,0 +1,148 @@
+//! The parts of the terrain visible from a point, in many directions at once
+
+use crate::{Environment, RayOptions, RayTable, Terrain};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// The parameters of the fan of rays used for calculating viewsheds
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct ViewshedOptions {
+    /// The range of the initial angles of the rays, in radians
+    pub angle_range: (f64, f64),
+    /// The spacing of the initial angles of the rays, in radians
+    pub resolution: f64,
+    /// The spacing of the distances at which the rays are sampled, in meters
+    pub dist_step: f64,
+}
+
+impl Default for ViewshedOptions {
+    fn default() -> Self {
+        Self {
+            angle_range: (-0.05, 0.05),
+            resolution: 1e-4,
+            dist_step: 100.0,
+        }
+    }
+}
+
+/// The visible part of the terrain in one direction
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct ViewshedSector {
+    /// The azimuth of the direction, in radians
+    pub azimuth: f64,
+    /// The apparent elevation angle (in radians) of the visible horizon - the highest one at
+    /// which the terrain is seen, or `None` if no terrain is seen
+    pub horizon_angle: Option<f64>,
+    /// The distance (in meters) to the furthest visible point of the terrain, or `None` if no
+    /// terrain is seen
+    pub furthest_visible: Option<f64>,
+}
+
+impl RayTable {
+    /// Finds the visible part of the terrain profile, in the direction of which the rays of the
+    /// table are launched.
+    ///
+    /// A point of the terrain is visible if it is seen higher than all the points in front of
+    /// it; the highest of its images is used. The points beyond the sampled distances or seen
+    /// outside of the fan of rays are skipped.
+    pub fn viewshed_sector(&self, azimuth: f64, terrain: &Terrain) -> ViewshedSector {
+        let max_dist = self.dists().last().copied().unwrap_or(0.0);
+        let mut horizon_angle: Option<f64> = None;
+        let mut furthest_visible = None;
+        for &(dist, elevation) in terrain.points() {
+            if dist <= 0.0 || dist > max_dist {
+                continue;
+            }
+            let angle = match self.rays_through(dist, elevation).last() {
+                Some(&angle) => angle,
+                None => continue,
+            };
+            if horizon_angle.is_none_or(|horizon| angle > horizon) {
+                horizon_angle = Some(angle);
+                furthest_visible = Some(dist);
+            }
+        }
+        ViewshedSector {
+            azimuth,
+            horizon_angle,
+            furthest_visible,
+        }
+    }
+}
+
+impl Environment {
+    /// Finds the visible parts of the terrain in many directions from an observer at the altitude
+    /// `observer_h` (in meters), given by pairs of the azimuths and the profiles of the terrain.
+    ///
+    /// The atmosphere is the same in all directions, so the rays are traced only once, into a
+    /// table reaching the furthest point of the profiles. With the `rayon` feature, the rays and
+    /// the directions are processed in parallel.
+    pub fn viewshed(
+        &self,
+        observer_h: f64,
+        profiles: &[(f64, Terrain)],
+        options: &ViewshedOptions,
+    ) -> Vec<ViewshedSector> {
+        let max_dist = profiles
+            .iter()
+            .filter_map(|(_, terrain)| terrain.points().last().map(|&(dist, _)| dist))
+            .fold(0.0, f64::max);
+        let table = self.ray_table(
+            observer_h,
+            options.angle_range,
+            options.resolution,
+            max_dist,
+            options.dist_step,
+            &RayOptions::default(),
+        );
+
+        #[cfg(feature = "rayon")]
+        let profiles = profiles.par_iter();
+        #[cfg(not(feature = "rayon"))]
+        let profiles = profiles.iter();
+        profiles
+            .map(|(azimuth, terrain)| table.viewshed_sector(*azimuth, terrain))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::us76_atmosphere_env;
+
+    #[test]
+    fn test_viewshed() {
+        let env = us76_atmosphere_env();
+        // a 100 m high hill 10 km away to the north, and the sea to the east
+        let hill = Terrain::from_fn(
+            |dist| (100.0 - (dist - 10e3).abs() / 10.0).max(0.0),
+            30e3,
+            100.0,
+        )
+        .unwrap();
+        let sea = Terrain::from_fn(|_| 0.0, 40e3, 100.0).unwrap();
+        let profiles = vec![(0.0, hill), (std::f64::consts::FRAC_PI_2, sea)];
+        let options = ViewshedOptions {
+            angle_range: (-0.01, 0.01),
+            ..Default::default()
+        };
+        let sectors = env.viewshed(50.0, &profiles, &options);
+        assert_eq!(sectors.len(), 2);
+
+        // the hilltop hides the terrain behind it
+        assert_eq!(sectors[0].azimuth, 0.0);
+        assert_eq!(sectors[0].furthest_visible, Some(10e3));
+        let hilltop = env.apparent_elevation(50.0, 100.0, 10e3);
+        assert!((sectors[0].horizon_angle.unwrap() - hilltop).abs() < 1e-6);
+
+        // the sea is visible up to the horizon
+        let horizon = env.horizon(50.0).unwrap();
+        let furthest = sectors[1].furthest_visible.unwrap();
+        assert!((furthest - horizon.dist).abs() < 200.0, "{}", furthest);
+        let angle = sectors[1].horizon_angle.unwrap();
+        assert!((angle + horizon.dip).abs() < 1e-5, "{}", angle);
+    }
+}